@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS deliveries (
+    id TEXT PRIMARY KEY,
+    device TEXT NOT NULL,
+    sender TEXT NOT NULL,
+    received INTEGER NOT NULL,
+    updated INTEGER NOT NULL,
+    state TEXT NOT NULL,
+    message_id INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS deliveries_device_received ON deliveries (device, received);
@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS tenants (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    created INTEGER NOT NULL
+);
+
+-- the same keys as the deployment's secrets, e.g. bot_token, devices,
+-- {device} and {device}_chat_id
+CREATE TABLE IF NOT EXISTS tenant_secrets (
+    tenant_id TEXT NOT NULL REFERENCES tenants (id),
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (tenant_id, key)
+);
@@ -0,0 +1,5 @@
+-- forwards claimed by a replica, shared by every replica of the deployment
+CREATE TABLE IF NOT EXISTS forwards_seen (
+    key TEXT PRIMARY KEY,
+    seen INTEGER NOT NULL
+);
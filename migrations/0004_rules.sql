@@ -0,0 +1,24 @@
+-- filtering and routing of forwards, the first matching rule by id wins
+CREATE TABLE IF NOT EXISTS rules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    tenant_id TEXT NOT NULL DEFAULT '',
+    version INTEGER NOT NULL DEFAULT 1,
+    device TEXT,
+    sender TEXT,
+    pattern TEXT,
+    action TEXT NOT NULL,
+    chat_id TEXT,
+    updated INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS rules_tenant ON rules (tenant_id, id);
+
+-- every version of every rule, deleted ones included
+CREATE TABLE IF NOT EXISTS rule_changes (
+    rule_id INTEGER NOT NULL,
+    tenant_id TEXT NOT NULL,
+    version INTEGER NOT NULL,
+    change TEXT NOT NULL,
+    rule TEXT NOT NULL,
+    changed INTEGER NOT NULL
+);
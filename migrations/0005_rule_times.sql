@@ -0,0 +1,3 @@
+-- days of the week and hours in the device's time zone a rule applies at
+ALTER TABLE rules ADD COLUMN days TEXT;
+ALTER TABLE rules ADD COLUMN hours TEXT;
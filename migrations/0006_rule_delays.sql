@@ -0,0 +1,3 @@
+-- how long a delay rule holds forwards back, and what cancels them
+ALTER TABLE rules ADD COLUMN delay TEXT;
+ALTER TABLE rules ADD COLUMN cancel TEXT;
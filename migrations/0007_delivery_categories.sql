@@ -0,0 +1,2 @@
+-- code, sms or rcs, for the volume by category of the weekly report
+ALTER TABLE deliveries ADD COLUMN category TEXT;
@@ -7,6 +7,114 @@ cat <<EOF | curl --json @- "https://api.telegram.org/bot${1}/setMyCommands" | jq
       "command": "info",
       "description": "Command device to report current status"
     },
+    {
+      "command": "locate",
+      "description": "Command device to report its location"
+    },
+    {
+      "command": "ring",
+      "description": "Command device to ring"
+    },
+    {
+      "command": "screenshot",
+      "description": "Command device to take a screenshot"
+    },
+    {
+      "command": "send",
+      "description": "Command device to send an SMS"
+    },
+    {
+      "command": "commands",
+      "description": "Show commands queued for a device"
+    },
+    {
+      "command": "reconfigure",
+      "description": "Command device to re-fetch its config"
+    },
+    {
+      "command": "promote",
+      "description": "Serve the next config template to every device"
+    },
+    {
+      "command": "status",
+      "description": "Show last known status of a device"
+    },
+    {
+      "command": "history",
+      "description": "Show recent call history of a device"
+    },
+    {
+      "command": "stats",
+      "description": "Show Telegram API usage per chat"
+    },
+    {
+      "command": "deliveries",
+      "description": "Show delivery receipts of recent forwards"
+    },
+    {
+      "command": "topsenders",
+      "description": "Show senders with the most messages to a device"
+    },
+    {
+      "command": "reliability",
+      "description": "Show outages and time to recovery of a device"
+    },
+    {
+      "command": "rules",
+      "description": "List, add or delete filtering and routing rules"
+    },
+    {
+      "command": "settings",
+      "description": "Toggle stickers, digest, spam filter and quiet hours per device"
+    },
+    {
+      "command": "pair",
+      "description": "Send a one-time link to set up the phone of a device"
+    },
+    {
+      "command": "backup",
+      "description": "Save all KV state to R2"
+    },
+    {
+      "command": "restore",
+      "description": "Restore KV state from a snapshot in R2"
+    },
+    {
+      "command": "exportdevice",
+      "description": "Export a device to move it to another deployment"
+    },
+    {
+      "command": "importdevice",
+      "description": "Import a device exported by another deployment"
+    },
+    {
+      "command": "broadcast",
+      "description": "Send an announcement to every device's chat"
+    },
+    {
+      "command": "raw",
+      "description": "Show the last request body posted by a device"
+    },
+    {
+      "command": "inspect",
+      "description": "Show unrecognized payloads of a device"
+    },
+    {
+      "command": "discard",
+      "description": "Drop unrecognized payloads of a device"
+    },
+    {
+      "command": "ping",
+      "description": "Show the last heartbeat of a device, nudge it to check in"
+    },
+    {
+      "command": "pending",
+      "description": "Show messages held back for the next batch"
+    },
+    {
+      "command": "language",
+      "description": "Choose the language of replies"
+    },
     {
       "command": "version",
       "description": "Query bot version"
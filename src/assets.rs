@@ -0,0 +1,94 @@
+//! Files served besides the config, revalidated by ETag.
+
+use worker::{Headers, Request, Response};
+
+use crate::error::Result;
+
+/// How long browsers and the edge keep an asset before revalidating it.
+const MAX_AGE_SECONDS: u64 = 300;
+
+/// Files bundled into the worker, by name under `/assets/`.
+const BUNDLED: [(&str, &str); 2] = [
+    ("dashboard.css", include_str!("dashboard.css")),
+    ("dashboard.js", include_str!("dashboard.js")),
+];
+
+pub struct Asset {
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Asset {
+    pub fn new(name: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            content_type: content_type(name),
+            body: body.into(),
+        }
+    }
+}
+
+/// Content type by the extension of `name`.
+pub fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("html") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        Some("yaml") => "application/yaml; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+fn etag(body: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in body {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{hash:016x}\"")
+}
+
+fn not_modified(req: &Request, etag: &str) -> bool {
+    req.headers()
+        .get("If-None-Match")
+        .ok()
+        .flatten()
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == "*" || tag.trim() == etag)
+        })
+}
+
+fn headers(etag: &str) -> Result<Headers> {
+    let mut headers = Headers::new();
+    headers.set("ETag", etag)?;
+    headers.set(
+        "Cache-Control",
+        &format!("public, max-age={MAX_AGE_SECONDS}"),
+    )?;
+    Ok(headers)
+}
+
+fn unchanged(headers: Headers) -> Result<Response> {
+    Ok(Response::empty()?.with_status(304).with_headers(headers))
+}
+
+fn fresh(asset: Asset, etag: &str) -> Result<Response> {
+    let mut headers = headers(etag)?;
+    headers.set("Content-Type", asset.content_type)?;
+    Ok(Response::from_bytes(asset.body)?.with_headers(headers))
+}
+
+/// A file bundled into the worker, `None` when there is no such file.
+pub fn bundled(req: &Request, name: &str) -> Result<Option<Response>> {
+    let Some((name, body)) = BUNDLED.into_iter().find(|(bundled, _)| *bundled == name) else {
+        return Ok(None);
+    };
+    let etag = etag(body.as_bytes());
+    if not_modified(req, &etag) {
+        return unchanged(headers(&etag)?).map(Some);
+    }
+    fresh(Asset::new(name, body), &etag).map(Some)
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use worker::{Bucket, Env};
+
+use crate::{
+    domain::{format_date, format_time},
+    error::{Error, Result},
+    kv_store, log, to_json,
+};
+
+/// Snapshots shown by `/restore` without an argument.
+const SNAPSHOTS_SHOWN: usize = 10;
+
+/// Every KV entry of the namespace at one point in time.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    created: i64,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    value: String,
+    /// Unix time in seconds, as KV keeps it.
+    #[serde(default)]
+    expiration: Option<u64>,
+}
+
+fn bucket(env: &Env) -> Result<Bucket> {
+    env.bucket("backups")
+        .map_err(|_| Error::MissingBinding("backups".to_owned()))
+}
+
+/// Writes a snapshot of the KV namespace, a tenant's part of it under a
+/// tenant scope, to the `backups` R2 bucket, returning its name and size.
+pub async fn create(env: &Env, now: i64) -> Result<(String, usize)> {
+    let bucket = bucket(env)?;
+    let kv = kv_store(env)?;
+    let mut entries = Vec::new();
+    for key in kv.list("").await? {
+        // expired or deleted since the listing
+        let Some(value) = kv.get(&key.name).text().await? else {
+            continue;
+        };
+        entries.push(Entry {
+            key: key.name,
+            value,
+            expiration: key.expiration,
+        });
+    }
+    let name = format!(
+        "{}snapshots/{}T{}.json",
+        prefix(),
+        format_date(now),
+        format_time(now).replace(':', "")
+    );
+    let count = entries.len();
+    let snapshot = Snapshot {
+        created: now,
+        entries,
+    };
+    bucket.put(&name, to_json(&snapshot)).execute().await?;
+    log::info!("backup", name = name, entries = count);
+    Ok((name, count))
+}
+
+/// Names of the latest snapshots, newest first.
+pub async fn list(env: &Env) -> Result<Vec<String>> {
+    let objects = bucket(env)?
+        .list()
+        .prefix(format!("{}snapshots/", prefix()))
+        .execute()
+        .await?
+        .objects();
+    Ok(objects
+        .iter()
+        .map(|object| object.key())
+        .rev()
+        .take(SNAPSHOTS_SHOWN)
+        .collect())
+}
+
+/// Puts back every entry of a snapshot which has not expired since,
+/// returning how many were restored. Entries created after the snapshot are
+/// left alone.
+pub async fn restore(env: &Env, name: &str, now: i64) -> Result<usize> {
+    if !name.starts_with(&format!("{}snapshots/", prefix())) {
+        return Err(Error::NotFound(format!("snapshot {name}")));
+    }
+    let object = bucket(env)?
+        .get(name)
+        .execute()
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("snapshot {name}")))?;
+    let text = object
+        .body()
+        .ok_or_else(|| Error::NotFound(format!("snapshot {name}")))?
+        .text()
+        .await?;
+    let snapshot: Snapshot = serde_json::from_str(&text)
+        .map_err(|e| Error::Worker(worker::Error::RustError(format!("invalid snapshot: {e}"))))?;
+    let kv = kv_store(env)?;
+    let mut restored = 0;
+    for entry in snapshot.entries {
+        let mut put = kv.put(&entry.key, entry.value)?;
+        if let Some(expiration) = entry.expiration {
+            // KV refuses expirations less than a minute away
+            if expiration as i64 <= now / 1000 + 60 {
+                continue;
+            }
+            put = put.expiration(expiration);
+        }
+        put.execute().await?;
+        restored += 1;
+    }
+    log::info!("restore", name = name, entries = restored);
+    Ok(restored)
+}
+
+/// Keeps each tenant's snapshots apart, and out of reach of the others.
+fn prefix() -> String {
+    log::tenant()
+        .map(|tenant| format!("tenant/{tenant}/"))
+        .unwrap_or_default()
+}
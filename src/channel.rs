@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::{
+    HeartbeatPayload, PollCommandsResponse, from_json, log, random_uuid, secrets, to_json,
+};
+
+/// Holds the WebSocket a gateway device keeps open at `GET /v1/channel`,
+/// one object per device, hibernating between messages.
+#[durable_object]
+pub struct CommandChannel {
+    state: State,
+    env: Env,
+}
+
+/// Who is at the other end of a socket, kept across hibernation.
+#[derive(Debug, Serialize, Deserialize)]
+struct Connection {
+    device: String,
+    tenant: Option<String>,
+}
+
+#[durable_object]
+impl DurableObject for CommandChannel {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, env }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        if req.method() == Method::Post {
+            let commands = req.text().await?;
+            let sent = self
+                .state
+                .get_websockets()
+                .iter()
+                .filter(|ws| ws.send_with_str(&commands).is_ok())
+                .count();
+            return Response::ok(sent.to_string());
+        }
+        let url = req.url()?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        let Some(device) = param("device") else {
+            return Response::error("Bad Request", 400);
+        };
+        // a reconnecting device replaces its previous socket
+        for ws in self.state.get_websockets() {
+            ws.close(Some(1000), Some("replaced")).ok();
+        }
+        let pair = WebSocketPair::new()?;
+        self.state.accept_web_socket(&pair.server);
+        pair.server.serialize_attachment(Connection {
+            device,
+            tenant: param("tenant"),
+        })?;
+        Response::from_websocket(pair.client)
+    }
+
+    async fn websocket_message(
+        &mut self,
+        ws: WebSocket,
+        message: WebSocketIncomingMessage,
+    ) -> Result<()> {
+        let Some(connection) = ws.deserialize_attachment::<Connection>()? else {
+            return Ok(());
+        };
+        let payload = match message {
+            WebSocketIncomingMessage::String(text) => from_json::<HeartbeatPayload>(&text),
+            WebSocketIncomingMessage::Binary(_) => None,
+        };
+        let env = self.env.clone();
+        let Connection { device, tenant } = connection;
+        let heartbeat = async move {
+            if let Some(tenant) = tenant {
+                if !secrets::load_tenant(&env, &tenant).await? {
+                    return Ok(Vec::new());
+                }
+                log::tenant_scope(tenant, crate::channel_heartbeat(env, device, payload)).await
+            } else {
+                crate::channel_heartbeat(env, device, payload).await
+            }
+        };
+        match log::scope(random_uuid(), heartbeat).await {
+            Ok(commands) => ws.send_with_str(to_json(&PollCommandsResponse { commands })),
+            Err(e) => {
+                log::error!("channel", error = e.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    async fn websocket_close(
+        &mut self,
+        ws: WebSocket,
+        code: usize,
+        _reason: String,
+        _was_clean: bool,
+    ) -> Result<()> {
+        let device = ws
+            .deserialize_attachment::<Connection>()
+            .ok()
+            .flatten()
+            .map(|connection| connection.device);
+        log::info!("channel", device = device, code = code, outcome = "closed");
+        Ok(())
+    }
+
+    async fn websocket_error(&mut self, _ws: WebSocket, error: Error) -> Result<()> {
+        log::error!("channel", error = error.to_string());
+        Ok(())
+    }
+}
+
+/// Stub of the device's channel, `None` without the `channel` binding.
+fn stub(env: &Env, device: &str) -> Option<Stub> {
+    let namespace = env.durable_object("channel").ok()?;
+    let name = format!("{}/{device}", log::tenant().unwrap_or_default());
+    namespace.id_from_name(&name).ok()?.get_stub().ok()
+}
+
+/// Hands the WebSocket upgrade of `device` over to its channel, `None`
+/// without the `channel` binding.
+pub async fn connect(env: &Env, device: &str) -> Result<Option<Response>> {
+    let Some(stub) = stub(env, device) else {
+        return Ok(None);
+    };
+    let mut url = Url::parse("https://channel/connect")?;
+    url.query_pairs_mut().append_pair("device", device);
+    if let Some(tenant) = log::tenant() {
+        url.query_pairs_mut().append_pair("tenant", &tenant);
+    }
+    let mut headers = Headers::new();
+    headers.set("Upgrade", "websocket")?;
+    let request = Request::new_with_init(url.as_str(), RequestInit::new().with_headers(headers))?;
+    Ok(Some(stub.fetch_with_request(request).await?))
+}
+
+/// Sends `commands` down the open socket of `device`, returning whether it
+/// had one.
+pub async fn push(env: &Env, device: &str, commands: &PollCommandsResponse) -> Result<bool> {
+    let Some(stub) = stub(env, device) else {
+        return Ok(false);
+    };
+    let request = Request::new_with_init(
+        "https://channel/push",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(to_json(commands).into())),
+    )?;
+    let sent = stub.fetch_with_request(request).await?.text().await?;
+    Ok(sent.parse::<usize>().is_ok_and(|sent| sent > 0))
+}
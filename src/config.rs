@@ -0,0 +1,81 @@
+use std::{collections::BTreeMap, fmt::Display, sync::atomic::AtomicBool};
+
+use worker::Env;
+
+use crate::secrets;
+
+/// Whether this isolate already told the admin chat about the configuration.
+pub static REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Secrets and bindings required by every request, checked all at once.
+#[derive(Debug)]
+pub struct Config {
+    pub devices: Vec<String>,
+    pub chat_ids: BTreeMap<String, String>,
+}
+
+/// Everything missing from the configuration.
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    pub problems: Vec<String>,
+}
+
+impl Display for ConfigReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n- {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn load(env: &Env) -> Result<Self, ConfigReport> {
+        let mut report = ConfigReport::default();
+        let secret = |key: &str, report: &mut ConfigReport| {
+            let value = secrets::get(env, key);
+            if value.is_none() {
+                report.problems.push(format!("secret {key} not found"));
+            }
+            value
+        };
+        secret("bot_token", &mut report);
+        secret("trusted_chat_ids", &mut report);
+        secret("trusted_user_ids", &mut report);
+        if env.kv("sms-forward-heartbeat").is_err() {
+            report
+                .problems
+                .push("binding sms-forward-heartbeat not found".to_owned());
+        }
+        let devices = secret("devices", &mut report)
+            .unwrap_or_default()
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+        if devices.is_empty() {
+            report.problems.push("no devices configured".to_owned());
+        }
+        let mut chat_ids = BTreeMap::new();
+        let mut mail = false;
+        for device in &devices {
+            secret(device, &mut report);
+            if let Some(chat_id) = secret(&format!("{device}_chat_id"), &mut report) {
+                chat_ids.insert(device.clone(), chat_id);
+            }
+            if secrets::get(env, &format!("{device}_mail_to")).is_some() {
+                mail = true;
+                secret(&format!("{device}_mail_from"), &mut report);
+            }
+        }
+        if mail && env.get_binding::<crate::SendEmail>("command").is_err() {
+            report.problems.push("binding command not found".to_owned());
+        }
+        if report.problems.is_empty() {
+            Ok(Self { devices, chat_ids })
+        } else {
+            Err(report)
+        }
+    }
+}
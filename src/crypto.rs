@@ -0,0 +1,143 @@
+use worker::{
+    js_sys::{Array, Object, Reflect, Uint8Array},
+    wasm_bindgen::{self, prelude::*},
+    wasm_bindgen_futures,
+};
+
+use crate::error::{Error, Result};
+
+const IV_LENGTH: usize = 12;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(catch, js_namespace = ["crypto", "subtle"])]
+    async fn digest(algorithm: &str, data: &Uint8Array) -> std::result::Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch, js_namespace = ["crypto", "subtle"], js_name = importKey)]
+    async fn import_key(
+        format: &str,
+        key: &Uint8Array,
+        algorithm: &Object,
+        extractable: bool,
+        usages: &Array,
+    ) -> std::result::Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch, js_namespace = ["crypto", "subtle"])]
+    async fn sign(
+        algorithm: &str,
+        key: &JsValue,
+        data: &Uint8Array,
+    ) -> std::result::Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch, js_namespace = ["crypto", "subtle"])]
+    async fn encrypt(
+        algorithm: &Object,
+        key: &JsValue,
+        data: &Uint8Array,
+    ) -> std::result::Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(catch, js_namespace = ["crypto", "subtle"])]
+    async fn decrypt(
+        algorithm: &Object,
+        key: &JsValue,
+        data: &Uint8Array,
+    ) -> std::result::Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(js_namespace = crypto, js_name = getRandomValues)]
+    fn get_random_values(array: &Uint8Array);
+}
+
+fn crypto_error(e: JsValue) -> Error {
+    Error::Worker(worker::Error::JsError(
+        e.as_string().unwrap_or_else(|| "crypto failed".to_owned()),
+    ))
+}
+
+fn algorithm(fields: &[(&str, JsValue)]) -> Object {
+    let algorithm = Object::new();
+    for (key, value) in fields {
+        Reflect::set(&algorithm, &(*key).into(), value).unwrap();
+    }
+    algorithm
+}
+
+fn bytes(buffer: &JsValue) -> Vec<u8> {
+    Uint8Array::new(buffer).to_vec()
+}
+
+pub async fn sha256(data: &[u8]) -> Result<Vec<u8>> {
+    let hash = digest("SHA-256", &Uint8Array::from(data))
+        .await
+        .map_err(crypto_error)?;
+    Ok(bytes(&hash))
+}
+
+pub async fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let hmac = algorithm(&[("name", "HMAC".into()), ("hash", "SHA-256".into())]);
+    let key = import_key(
+        "raw",
+        &Uint8Array::from(key),
+        &hmac,
+        false,
+        &Array::of1(&"sign".into()),
+    )
+    .await
+    .map_err(crypto_error)?;
+    let signature = sign("HMAC", &key, &Uint8Array::from(data))
+        .await
+        .map_err(crypto_error)?;
+    Ok(bytes(&signature))
+}
+
+/// AES-GCM key from the SHA-256 of `secret`.
+async fn aes_key(secret: &str) -> Result<JsValue> {
+    let hash = sha256(secret.as_bytes()).await?;
+    import_key(
+        "raw",
+        &Uint8Array::from(hash.as_slice()),
+        &algorithm(&[("name", "AES-GCM".into())]),
+        false,
+        &Array::of2(&"encrypt".into(), &"decrypt".into()),
+    )
+    .await
+    .map_err(crypto_error)
+}
+
+/// Encrypts with a key derived from `secret`, the random IV first.
+pub async fn seal(secret: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let iv = Uint8Array::new_with_length(IV_LENGTH as u32);
+    get_random_values(&iv);
+    let sealed = encrypt(
+        &algorithm(&[("name", "AES-GCM".into()), ("iv", iv.clone().into())]),
+        &aes_key(secret).await?,
+        &Uint8Array::from(data),
+    )
+    .await
+    .map_err(crypto_error)?;
+    let mut blob = iv.to_vec();
+    blob.extend(bytes(&sealed));
+    Ok(blob)
+}
+
+/// Reverses `seal`, `None` when the blob was not sealed with `secret` or
+/// was tampered with.
+pub async fn open(secret: &str, blob: &[u8]) -> Result<Option<Vec<u8>>> {
+    if blob.len() <= IV_LENGTH {
+        return Ok(None);
+    }
+    let (iv, sealed) = blob.split_at(IV_LENGTH);
+    let data = decrypt(
+        &algorithm(&[
+            ("name", "AES-GCM".into()),
+            ("iv", Uint8Array::from(iv).into()),
+        ]),
+        &aes_key(secret).await?,
+        &Uint8Array::from(sealed),
+    )
+    .await;
+    Ok(data.ok().map(|data| bytes(&data)))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
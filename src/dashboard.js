@@ -0,0 +1,192 @@
+// tenants open the dashboard at `/admin?tenant={id}`, every call carries it
+const query = location.search;
+const $ = (id) => document.getElementById(id);
+const time = (ms) => ms ? new Date(ms).toLocaleString() : "";
+
+async function api(path, init = {}, raw = false) {
+  const token = localStorage.getItem("admin_token");
+  const url = path.startsWith("/") ? path : "/admin/api/" + path;
+  const search = query && (url.includes("?") ? "&" : "?") + query.slice(1);
+  const response = await fetch(url + search, {
+    ...init,
+    headers: { "Authorization": "Bearer " + token, "Content-Type": "application/json" },
+  });
+  if (response.status === 401) {
+    showLogin();
+    throw new Error("not signed in");
+  }
+  if (!response.ok) {
+    throw new Error(await response.text());
+  }
+  return raw ? response.text() : response.json();
+}
+
+function row(cells) {
+  const tr = document.createElement("tr");
+  for (const cell of cells) {
+    const td = document.createElement("td");
+    td.textContent = cell ?? "";
+    tr.append(td);
+  }
+  return tr;
+}
+
+function vitals(v) {
+  if (!v) return "";
+  const parts = [];
+  if (v.battery != null) parts.push(v.battery + "%" + (v.charger ? " charging" : ""));
+  if (v.signal != null) parts.push("signal " + v.signal);
+  return parts.join(", ");
+}
+
+async function showDevice(device) {
+  $("detail").hidden = false;
+  $("detail-title").textContent = device;
+  const [deliveries, messages] = await Promise.all([
+    api("devices/" + encodeURIComponent(device) + "/deliveries"),
+    api("devices/" + encodeURIComponent(device) + "/messages"),
+  ]);
+  $("deliveries").replaceChildren(...deliveries.map((d) => row([time(d.received), d.sender, d.state])));
+  $("messages").replaceChildren(...messages.map((m) => row([time(m.timestamp), m.sender, m.text])));
+}
+
+async function load() {
+  $("error").textContent = "";
+  try {
+    const devices = await api("devices");
+    $("devices").replaceChildren(...devices.map((d) => {
+      const tr = row([d.device, d.status, vitals(d.vitals), time(d.updated), d.skew_ms != null ? d.skew_ms + "ms" : ""]);
+      tr.className = "device";
+      tr.onclick = () => showDevice(d.device).catch((e) => $("error").textContent = e.message);
+      return tr;
+    }));
+    $("flags").value = JSON.stringify(await api("flags"), null, 2);
+    await loadRules();
+  } catch (e) {
+    $("error").textContent = e.message;
+  }
+}
+
+async function loadRules() {
+  const rules = await api("/api/rules");
+  $("rules").replaceChildren(...rules.map((r) => {
+    const when = [r.days, r.hours && r.hours + "h"].filter(Boolean).join(" ");
+    const tr = row([r.id, r.device ?? "*", r.sender ?? "*", r.pattern, when, r.action === "route" ? "route to " + r.chat_id : r.action === "delay" ? "delay " + r.delay : r.action]);
+    const button = document.createElement("button");
+    button.textContent = "Delete";
+    button.onclick = () => api("/api/rules/" + r.id, { method: "DELETE" })
+      .then(loadRules)
+      .catch((e) => $("rules-status").textContent = e.message);
+    const td = document.createElement("td");
+    td.append(button);
+    tr.append(td);
+    return tr;
+  }));
+}
+
+$("add-rule").onclick = async () => {
+  $("rules-status").textContent = "";
+  try {
+    await api("/api/rules", { method: "POST", body: JSON.stringify(JSON.parse($("new-rule").value)) });
+    await loadRules();
+  } catch (e) {
+    $("rules-status").textContent = e.message;
+  }
+};
+
+function showDiff(diff) {
+  const lines = [
+    ...diff.removed.map((r) => "- " + JSON.stringify(r)),
+    ...diff.added.map((r) => "+ " + JSON.stringify(r)),
+    diff.unchanged + " unchanged" + (diff.reordered ? ", reordered" : ""),
+  ];
+  $("rules-diff").textContent = lines.join("\n");
+}
+
+async function importRules(dryRun) {
+  $("rules-diff").textContent = "";
+  try {
+    const path = "/api/rules/import" + (dryRun ? "?dry_run=1" : "");
+    const result = await api(path, { method: "POST", body: $("rules-yaml").value });
+    showDiff(result.diff);
+    if (!dryRun) await loadRules();
+  } catch (e) {
+    $("rules-diff").textContent = e.message;
+  }
+}
+
+$("export-rules").onclick = async () => {
+  try {
+    $("rules-yaml").value = await api("/api/rules/export", {}, true);
+  } catch (e) {
+    $("rules-diff").textContent = e.message;
+  }
+};
+$("preview-rules").onclick = () => importRules(true);
+$("import-rules").onclick = () => importRules(false);
+
+// trusted Telegram users sign in with the login widget instead of the token
+async function showLogin() {
+  if (!$("login").hidden) return;
+  $("login").hidden = false;
+  const { bot } = await (await fetch("/admin/api/login" + query)).json();
+  if (!bot) return;
+  const script = document.createElement("script");
+  script.async = true;
+  script.src = "https://telegram.org/js/telegram-widget.js?22";
+  script.dataset.telegramLogin = bot;
+  script.dataset.size = "medium";
+  script.dataset.onauth = "onTelegramAuth(user)";
+  $("telegram-login").append(script);
+}
+
+async function onTelegramAuth(user) {
+  const response = await fetch("/admin/api/login" + query, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(user),
+  });
+  if (!response.ok) {
+    $("error").textContent = "not a trusted user";
+    return;
+  }
+  signIn((await response.json()).token);
+}
+
+function signIn(token) {
+  localStorage.setItem("admin_token", token);
+  $("login").hidden = true;
+  load();
+  watch();
+}
+
+let stream;
+
+function watch() {
+  stream?.close();
+  const params = new URLSearchParams(query);
+  params.set("token", localStorage.getItem("admin_token"));
+  stream = new EventSource("/api/stream?" + params);
+  const show = (text) => (e) => {
+    const event = JSON.parse(e.data);
+    $("live").prepend(row([time(event.timestamp), event.device, text(event)]));
+  };
+  stream.addEventListener("forward", show((e) => (e.sender ?? "unknown") + ": " + e.text));
+  stream.addEventListener("status", show((e) => e.status));
+}
+
+$("save-token").onclick = () => signIn($("token").value);
+
+$("save-flags").onclick = async () => {
+  $("flags-status").textContent = "";
+  try {
+    const flags = await api("flags", { method: "PUT", body: JSON.stringify(JSON.parse($("flags").value)) });
+    $("flags").value = JSON.stringify(flags, null, 2);
+    $("flags-status").textContent = "saved";
+  } catch (e) {
+    $("flags-status").textContent = e.message;
+  }
+};
+
+load();
+watch();
@@ -0,0 +1,138 @@
+use serde::Deserialize;
+use worker::{Env, Fetch, Method, Request, RequestInit, wasm_bindgen::JsValue};
+
+use crate::{
+    error::{Error, Result},
+    log, to_json,
+};
+
+/// How long a forward claimed by one replica keeps the others from sending
+/// it again.
+pub const WINDOW_SECONDS: i64 = 600;
+
+#[derive(Debug, Deserialize)]
+struct QueryResponse {
+    success: bool,
+    #[serde(default)]
+    result: Vec<QueryResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryResult {
+    meta: QueryMeta,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryMeta {
+    #[serde(default)]
+    changes: usize,
+}
+
+/// FNV-1a, enough to tell forwards of the same device apart.
+pub fn fingerprint(parts: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in part.bytes().chain([0]) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+/// Runs `sql` on the shared store, the `dedup` D1 binding when replicas
+/// share an account or the D1 HTTP API at `dedup_d1_url` otherwise,
+/// returning the rows changed. `None` when neither is configured.
+async fn execute(env: &Env, sql: &str, params: &[JsValue]) -> Result<Option<usize>> {
+    if let Ok(db) = env.d1("dedup") {
+        let result = db.prepare(sql).bind(params)?.run().await?;
+        return Ok(Some(result.meta()?.and_then(|m| m.changes).unwrap_or(0)));
+    }
+    let (Some(url), Some(token)) = (
+        crate::get_optional_secret(env, "dedup_d1_url"),
+        crate::get_optional_secret(env, "dedup_api_token"),
+    ) else {
+        return Ok(None);
+    };
+    let params = params
+        .iter()
+        .map(|p| match p.as_f64() {
+            Some(n) => serde_json::json!(n as i64),
+            None => serde_json::json!(p.as_string()),
+        })
+        .collect::<Vec<_>>();
+    let body = to_json(serde_json::json!({ "sql": sql, "params": params }));
+    let request = Request::new_with_init(
+        &url,
+        &RequestInit {
+            method: Method::Post,
+            headers: [
+                ("Content-Type", "application/json"),
+                ("Authorization", &format!("Bearer {token}")),
+            ]
+            .into_iter()
+            .collect(),
+            body: Some(body.into()),
+            ..RequestInit::default()
+        },
+    )?;
+    let mut response = Fetch::Request(request).send().await?;
+    let status = response.status_code();
+    let response: QueryResponse = response
+        .json()
+        .await
+        .map_err(|_| Error::Dedup(format!("invalid response with status {status}")))?;
+    if !response.success {
+        return Err(Error::Dedup(format!("query failed with status {status}")));
+    }
+    Ok(Some(response.result.iter().map(|r| r.meta.changes).sum()))
+}
+
+fn key(parts: &[&str]) -> String {
+    format!(
+        "{}/{}",
+        log::tenant().unwrap_or_default(),
+        fingerprint(parts)
+    )
+}
+
+/// Claims a forward for this replica, returning whether no other replica
+/// forwarded it within `WINDOW_SECONDS`. Always true without a shared store.
+pub async fn claim(env: &Env, parts: &[&str], now: i64) -> Result<bool> {
+    let key = key(parts);
+    let changes = execute(
+        env,
+        "INSERT INTO forwards_seen (key, seen) VALUES (?1, ?2) \
+         ON CONFLICT (key) DO UPDATE SET seen = excluded.seen WHERE seen < ?3",
+        &[
+            key.into(),
+            (now as f64).into(),
+            ((now - WINDOW_SECONDS * 1000) as f64).into(),
+        ],
+    )
+    .await?;
+    Ok(changes.is_none_or(|changes| changes > 0))
+}
+
+/// Gives up the claim on a forward this replica failed to send, so another
+/// one can still send it.
+pub async fn release(env: &Env, parts: &[&str]) -> Result<()> {
+    execute(
+        env,
+        "DELETE FROM forwards_seen WHERE key = ?1",
+        &[key(parts).into()],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Drops claims older than the window.
+pub async fn prune(env: &Env, now: i64) -> Result<()> {
+    execute(
+        env,
+        "DELETE FROM forwards_seen WHERE seen < ?1",
+        &[((now - WINDOW_SECONDS * 1000) as f64).into()],
+    )
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,436 @@
+//! Logic which does not depend on the Workers runtime, with KV, the clock
+//! and HTTP behind traits so that it can run natively.
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use worker::{Date, Fetch, Method, Request, RequestInit};
+
+use crate::error::Result;
+
+pub trait Clock {
+    fn now_ms(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> i64 {
+        Date::now().as_millis() as i64
+    }
+}
+
+pub trait Store {
+    async fn get_text(&self, key: &str) -> Result<Option<String>>;
+
+    async fn put_text(&self, key: &str, value: &str, ttl: Option<u64>) -> Result<()>;
+}
+
+pub trait Http {
+    /// Posts a body of `content_type`, returning the status code and the
+    /// response text.
+    async fn post(&self, url: &str, content_type: &str, body: &[u8]) -> Result<(u16, String)>;
+}
+
+pub struct FetchHttp;
+
+impl Http for FetchHttp {
+    async fn post(&self, url: &str, content_type: &str, body: &[u8]) -> Result<(u16, String)> {
+        let request = Request::new_with_init(
+            url,
+            &RequestInit {
+                method: Method::Post,
+                headers: [("Content-Type", content_type)].into_iter().collect(),
+                body: Some(js_sys::Uint8Array::from(body).into()),
+                ..RequestInit::default()
+            },
+        )?;
+        let mut response = Fetch::Request(request).send().await?;
+        Ok((response.status_code(), response.text().await?))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeartbeatStatus {
+    Active,   // ..(interval * 1.5)
+    Inactive, // (interval * 1.5)..(interval * 2.5)
+    Dead,     // (interval * 2.5)..
+}
+
+impl HeartbeatStatus {
+    pub fn from_last_seen(last_seen: Option<i64>, now: i64, interval_seconds: i64) -> Self {
+        match last_seen.map(|last_seen| now - last_seen) {
+            Some(elapsed) if elapsed < interval_seconds * 1500 => HeartbeatStatus::Active,
+            Some(elapsed) if elapsed < interval_seconds * 2500 => HeartbeatStatus::Inactive,
+            _ => HeartbeatStatus::Dead,
+        }
+    }
+
+    /// Status from the last heartbeat timestamp stored under the device name.
+    pub async fn load(
+        store: &impl Store,
+        clock: &impl Clock,
+        device: &str,
+        interval_seconds: i64,
+    ) -> Result<Self> {
+        let last_seen = store
+            .get_text(device)
+            .await?
+            .and_then(|v| v.parse::<i64>().ok());
+        Ok(Self::from_last_seen(
+            last_seen,
+            clock.now_ms(),
+            interval_seconds,
+        ))
+    }
+}
+
+/// `{device}/{token}` from an `Authorization` header value.
+pub fn parse_credentials(authorization: &str) -> Option<(String, String)> {
+    authorization
+        .trim()
+        .trim_start_matches("Bearer ")
+        .splitn(2, '/')
+        .map(ToOwned::to_owned)
+        .collect_tuple()
+}
+
+/// Whether `token` is `secret`, comparing every byte so that the time taken
+/// tells nothing about where they differ.
+pub fn token_matches(secret: Option<&str>, token: &str) -> bool {
+    secret.is_some_and(|secret| {
+        secret.len() == token.len()
+            && secret
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    })
+}
+
+pub fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// `url` escaped for an `href` attribute, `None` unless it is http(s).
+pub fn link_href(url: &str) -> Option<String> {
+    let scheme = url.split_once("://")?.0;
+    (scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https"))
+        .then(|| escape_html(url).replace('"', "&quot;"))
+}
+
+/// Text of an HTML message as Telegram shows it, without tags and entities.
+pub fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = rest[start..]
+            .find('>')
+            .map_or("", |end| &rest[start + end + 1..]);
+    }
+    text.push_str(rest);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Minutes of a UTC offset like `+08:00`, `+0530` or `-5`.
+pub fn parse_utc_offset(offset: &str) -> Option<i64> {
+    let (sign, rest) = match offset.trim().split_at_checked(1)? {
+        ("+", rest) => (1, rest),
+        ("-", rest) => (-1, rest),
+        _ => return None,
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+    (hours <= 14 && minutes < 60).then_some(sign * (hours * 60 + minutes))
+}
+
+pub fn format_duration(seconds: i64) -> String {
+    if seconds >= 3600 {
+        format!("{}h{}m", seconds / 3600, seconds % 3600 / 60)
+    } else {
+        format!("{}m{}s", seconds / 60, seconds % 60)
+    }
+}
+
+/// `YYYY-MM-DD` in UTC.
+pub fn format_date(timestamp_ms: i64) -> String {
+    // days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = timestamp_ms.div_euclid(24 * 3600 * 1000) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// `HH:MM` in UTC.
+pub fn format_time(timestamp_ms: i64) -> String {
+    let minutes = timestamp_ms.div_euclid(60 * 1000).rem_euclid(24 * 60);
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Outage {
+    pub start: i64,
+    pub end: Option<i64>,
+}
+
+/// Percentage of the time between `since` and `now` outside of outages.
+pub fn uptime(outages: &[Outage], since: i64, now: i64) -> f64 {
+    let downtime: i64 = outages
+        .iter()
+        .filter(|o| o.end.is_none_or(|end| end >= since))
+        .map(|o| o.end.unwrap_or(now) - o.start.max(since))
+        .sum();
+    100.0 - downtime as f64 * 100.0 / (now - since) as f64
+}
+
+/// Outage count, mean time to recovery and longest outages between `since`
+/// and `now`, an ongoing outage counting up to now.
+pub fn reliability_report(outages: &[Outage], since: i64, now: i64, shown: usize) -> String {
+    let outages = outages
+        .iter()
+        .filter(|o| o.end.is_none_or(|end| end >= since))
+        .map(|o| (o.start, o.start.max(since), o.end))
+        .collect_vec();
+    if outages.is_empty() {
+        return "no outages, 100% uptime".to_owned();
+    }
+    let downtime: i64 = outages
+        .iter()
+        .map(|&(_, start, end)| end.unwrap_or(now) - start)
+        .sum();
+    let recovered = outages
+        .iter()
+        .filter_map(|&(start, _, end)| Some(end? - start))
+        .collect_vec();
+    let uptime = 100.0 - downtime as f64 * 100.0 / (now - since) as f64;
+    let mut text = format!(
+        "{count} outages, {uptime:.2}% uptime, {downtime} down",
+        count = outages.len(),
+        downtime = format_duration(downtime / 1000),
+    );
+    if !recovered.is_empty() {
+        let mttr = recovered.iter().sum::<i64>() / recovered.len() as i64;
+        text.push_str(&format!("\nMTTR {}", format_duration(mttr / 1000)));
+    }
+    for (start, _, end) in outages
+        .iter()
+        .sorted_by_key(|&&(start, _, end)| start - end.unwrap_or(now))
+        .take(shown)
+    {
+        text.push_str(&format!(
+            "\n{date} {time} {duration}{ongoing}",
+            date = format_date(*start),
+            time = format_time(*start),
+            duration = format_duration((end.unwrap_or(now) - start) / 1000),
+            ongoing = if end.is_none() { " ongoing" } else { "" },
+        ));
+    }
+    text
+}
+
+/// Whether `text` carries a one-time code: a standalone run of 4 to 8 digits
+/// in a message mentioning a code or password.
+pub fn is_code(text: &str) -> bool {
+    const WORDS: [&str; 6] = ["code", "otp", "password", "验证码", "校验码", "动态码"];
+    let lower = text.to_lowercase();
+    WORDS.iter().any(|word| lower.contains(word))
+        && text
+            .split(|c: char| !c.is_ascii_digit())
+            .any(|run| (4..=8).contains(&run.len()))
+}
+
+/// Runs a future which never has to wait, as the fakes in tests don't.
+#[cfg(test)]
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+    let mut future = std::pin::pin!(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap};
+
+    use super::*;
+
+    struct FixedClock(i64);
+
+    impl Clock for FixedClock {
+        fn now_ms(&self) -> i64 {
+            self.0
+        }
+    }
+
+    #[derive(Default)]
+    struct MemoryStore(RefCell<BTreeMap<String, String>>);
+
+    impl Store for MemoryStore {
+        async fn get_text(&self, key: &str) -> Result<Option<String>> {
+            Ok(self.0.borrow().get(key).cloned())
+        }
+
+        async fn put_text(&self, key: &str, value: &str, _: Option<u64>) -> Result<()> {
+            self.0.borrow_mut().insert(key.to_owned(), value.to_owned());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn heartbeat_status_from_last_seen() {
+        let now = 1_000_000_000;
+        let status = |elapsed_seconds: i64| {
+            HeartbeatStatus::from_last_seen(Some(now - elapsed_seconds * 1000), now, 60)
+        };
+        assert_eq!(status(0), HeartbeatStatus::Active);
+        assert_eq!(status(89), HeartbeatStatus::Active);
+        assert_eq!(status(90), HeartbeatStatus::Inactive);
+        assert_eq!(status(149), HeartbeatStatus::Inactive);
+        assert_eq!(status(150), HeartbeatStatus::Dead);
+        assert_eq!(
+            HeartbeatStatus::from_last_seen(None, now, 60),
+            HeartbeatStatus::Dead
+        );
+    }
+
+    #[test]
+    fn heartbeat_status_load() {
+        let store = MemoryStore::default();
+        let clock = FixedClock(1_000_000_000);
+        let load = |device| block_on(HeartbeatStatus::load(&store, &clock, device, 60)).unwrap();
+        block_on(store.put_text("phone", "999990000", None)).unwrap();
+        block_on(store.put_text("tablet", "999880000", None)).unwrap();
+        block_on(store.put_text("broken", "yesterday", None)).unwrap();
+        assert_eq!(load("phone"), HeartbeatStatus::Active);
+        assert_eq!(load("tablet"), HeartbeatStatus::Inactive);
+        assert_eq!(load("broken"), HeartbeatStatus::Dead);
+        assert_eq!(load("missing"), HeartbeatStatus::Dead);
+    }
+
+    #[test]
+    fn credentials() {
+        assert_eq!(
+            parse_credentials("Bearer phone/s3cr/et"),
+            Some(("phone".to_owned(), "s3cr/et".to_owned()))
+        );
+        assert_eq!(
+            parse_credentials(" phone/token "),
+            Some(("phone".to_owned(), "token".to_owned()))
+        );
+        assert_eq!(parse_credentials("Bearer token"), None);
+    }
+
+    #[test]
+    fn tokens() {
+        assert!(token_matches(Some("token"), "token"));
+        assert!(!token_matches(Some("token"), "other"));
+        assert!(!token_matches(Some("token"), "tokens"));
+        assert!(!token_matches(Some("token"), "toke"));
+        assert!(!token_matches(Some("token"), ""));
+        assert!(!token_matches(None, "token"));
+    }
+
+    #[test]
+    fn html() {
+        assert_eq!(
+            escape_html("a < b && c > d"),
+            "a &lt; b &amp;&amp; c &gt; d"
+        );
+        assert_eq!(escape_html("&lt;"), "&amp;lt;");
+        assert_eq!(
+            plain_text("<b>From</b> <code>+1 &lt;555&gt;</code>\nR&amp;D &quot;hi&quot;"),
+            "From +1 <555>\nR&D \"hi\""
+        );
+        assert_eq!(plain_text("a <i>b"), "a b");
+        assert_eq!(plain_text("a <unterminated"), "a ");
+        let text = "<x> & \"y\"";
+        assert_eq!(plain_text(&escape_html(text)), text);
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            link_href("https://a.example/?q=\"x\"&r=<y>").as_deref(),
+            Some("https://a.example/?q=&quot;x&quot;&amp;r=&lt;y&gt;")
+        );
+        assert_eq!(
+            link_href("HTTP://a.example").as_deref(),
+            Some("HTTP://a.example")
+        );
+        assert_eq!(link_href("javascript:alert(1)"), None);
+        assert_eq!(link_href("tg://resolve"), None);
+        assert_eq!(link_href("a.example"), None);
+    }
+
+    #[test]
+    fn utc_offsets() {
+        assert_eq!(parse_utc_offset("+08:00"), Some(480));
+        assert_eq!(parse_utc_offset("+0530"), Some(330));
+        assert_eq!(parse_utc_offset("-5"), Some(-300));
+        assert_eq!(parse_utc_offset("8"), None);
+        assert_eq!(parse_utc_offset("+15"), None);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951_782_400_000), "2000-02-29");
+        assert_eq!(format_date(-1), "1969-12-31");
+        assert_eq!(
+            format_time(951_782_400_000 + 13 * 3_600_000 + 5 * 60_000),
+            "13:05"
+        );
+        assert_eq!(format_duration(59), "0m59s");
+        assert_eq!(format_duration(3_723), "1h2m");
+    }
+
+    #[test]
+    fn reliability() {
+        let hour = 3_600_000;
+        let outages = [
+            Outage {
+                start: hour,
+                end: Some(2 * hour),
+            },
+            Outage {
+                start: 8 * hour,
+                end: None,
+            },
+        ];
+        assert_eq!(uptime(&outages, 0, 10 * hour), 70.0);
+        assert_eq!(
+            reliability_report(&outages, 0, 10 * hour, 1),
+            "2 outages, 70.00% uptime, 3h0m down\nMTTR 1h0m\n1970-01-01 08:00 2h0m ongoing"
+        );
+        assert_eq!(
+            reliability_report(&[], 0, 10 * hour, 1),
+            "no outages, 100% uptime"
+        );
+    }
+
+    #[test]
+    fn codes() {
+        assert!(is_code("Your code is 123456"));
+        assert!(is_code("【银行】验证码 8421，5分钟内有效"));
+        assert!(!is_code("Your code is 12"));
+        assert!(!is_code("Order 123456 shipped"));
+    }
+}
@@ -0,0 +1,66 @@
+use worker::kv::KvError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("secret {0} not found")]
+    MissingSecret(String),
+    #[error("binding {0} not found")]
+    MissingBinding(String),
+    #[error("kv: {0}")]
+    Kv(String),
+    #[error("telegram: {0}")]
+    Telegram(String),
+    #[error("{0}")]
+    Email(String),
+    #[error("push: {0}")]
+    Push(String),
+    #[error("fallback: {0}")]
+    Fallback(String),
+    #[error("webhook: {0}")]
+    Webhook(String),
+    #[error("dedup: {0}")]
+    Dedup(String),
+    #[error("invalid rule: {0}")]
+    InvalidRule(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{device}: {source}")]
+    Device {
+        device: String,
+        #[source]
+        source: Box<Error>,
+    },
+    #[error(transparent)]
+    Worker(#[from] worker::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Attaches the device being handled, so one device's failure can be
+    /// told apart from the others'.
+    pub fn for_device(self, device: &str) -> Self {
+        match self {
+            Error::Device { .. } => self,
+            source => Error::Device {
+                device: device.to_owned(),
+                source: Box::new(source),
+            },
+        }
+    }
+}
+
+impl From<KvError> for Error {
+    fn from(e: KvError) -> Self {
+        Error::Kv(e.to_string())
+    }
+}
+
+impl From<Error> for worker::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Worker(e) => e,
+            e => worker::Error::RustError(e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use worker::{Env, Fetch, Method, Request, RequestInit};
+
+use crate::{
+    MimeMessage,
+    domain::{format_date, format_time},
+    error::{Error, Result},
+    get_optional_secret, is_dry_run,
+    kv::Kv,
+    kv_store, log, mime, send_mail, to_json,
+};
+
+/// Where Telegram being unreachable is counted until it is told about.
+const OUTAGE_KEY: &str = "fallback/outage";
+
+/// A forward which could not be sent to Telegram.
+#[derive(Debug, Serialize)]
+pub struct Forward<'a> {
+    pub device: &'a str,
+    pub sender: Option<&'a str>,
+    pub text: &'a str,
+    pub timestamp: i64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Outage {
+    since: i64,
+    forwards: u32,
+}
+
+async fn post(env: &Env, url: &str, headers: &[(&str, &str)], body: &str) -> Result<()> {
+    if is_dry_run(env) {
+        log::info!(
+            "dry_run",
+            target = "fallback",
+            url = url,
+            bytes = body.len()
+        );
+        return Ok(());
+    }
+    let request = Request::new_with_init(
+        url,
+        &RequestInit {
+            method: Method::Post,
+            headers: headers.iter().copied().collect(),
+            body: Some(body.into()),
+            ..RequestInit::default()
+        },
+    )?;
+    let status = Fetch::Request(request).send().await?.status_code();
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(Error::Fallback(format!("{url} answered {status}")))
+    }
+}
+
+/// Sends the forward to every fallback destination, ntfy at
+/// `fallback_ntfy_url`, JSON to `fallback_webhook_url` and email to
+/// `fallback_mail_to` from `{device}_mail_from`, returning whether one of
+/// them took it.
+pub async fn deliver(env: &Env, forward: &Forward<'_>, now: i64) -> bool {
+    let title = format!("{} {}", forward.device, forward.sender.unwrap_or("unknown"));
+    let mut delivered = false;
+    if let Some(url) = get_optional_secret(env, "fallback_ntfy_url") {
+        let headers = [("Title", title.as_str()), ("Tags", "sms")];
+        delivered |= report(
+            forward,
+            "ntfy",
+            post(env, &url, &headers, forward.text).await,
+        );
+    }
+    if let Some(url) = get_optional_secret(env, "fallback_webhook_url") {
+        let headers = [("Content-Type", "application/json")];
+        delivered |= report(
+            forward,
+            "webhook",
+            post(env, &url, &headers, &to_json(forward)).await,
+        );
+    }
+    if let Some(to) = get_optional_secret(env, "fallback_mail_to") {
+        delivered |= report(forward, "email", mail(env, forward, &title, &to).await);
+    }
+    if delivered && let Err(e) = count(env, now).await {
+        log::error!("fallback", device = forward.device, error = e.to_string());
+    }
+    delivered
+}
+
+fn report(forward: &Forward<'_>, destination: &str, result: Result<()>) -> bool {
+    match result {
+        Ok(()) => {
+            log::info!(
+                "fallback",
+                device = forward.device,
+                destination = destination,
+                outcome = "sent"
+            );
+            true
+        }
+        Err(e) => {
+            log::error!(
+                "fallback",
+                device = forward.device,
+                destination = destination,
+                error = e.to_string()
+            );
+            false
+        }
+    }
+}
+
+async fn mail(env: &Env, forward: &Forward<'_>, title: &str, to: &str) -> Result<()> {
+    let from = get_optional_secret(env, &format!("{}_mail_from", forward.device))
+        .ok_or_else(|| Error::MissingSecret(format!("{}_mail_from", forward.device)))?;
+    let recipients = to
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(ToOwned::to_owned)
+        .collect::<Vec<_>>();
+    let message = MimeMessage::new()
+        .header("From", &mime::mailbox("SMS Forward", &from))
+        .header("Subject", title)
+        .text(forward.text);
+    send_mail(env, forward.device, &from, &recipients, message, "fallback").await?;
+    Ok(())
+}
+
+async fn count(env: &Env, now: i64) -> Result<()> {
+    let kv = kv_store(env)?;
+    let mut outage: Outage = kv.get(OUTAGE_KEY).json().await?.unwrap_or_default();
+    if outage.forwards == 0 {
+        outage.since = now;
+    }
+    outage.forwards += 1;
+    kv.put(OUTAGE_KEY, to_json(&outage))?.execute().await?;
+    Ok(())
+}
+
+/// The catch-up notice for the forwards which went to the fallback
+/// destinations while Telegram was unreachable, if there were any.
+pub async fn catch_up_notice(kv: &Kv) -> Result<Option<String>> {
+    let Some(outage) = kv.get(OUTAGE_KEY).json::<Outage>().await? else {
+        return Ok(None);
+    };
+    Ok(Some(format!(
+        "📡 Telegram was unreachable, {} forward(s) since {} {} UTC went to the fallback \
+         destinations instead",
+        outage.forwards,
+        format_date(outage.since),
+        format_time(outage.since)
+    )))
+}
+
+/// Starts counting anew once the catch-up notice has been sent.
+pub async fn caught_up(kv: &Kv) -> Result<()> {
+    log::info!("fallback", outcome = "recovered");
+    kv.delete(OUTAGE_KEY).await?;
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Result, kv::Kv, log, to_json};
+
+/// Flags of the current invocation, keyed by its request id.
+static CACHE: Mutex<Option<(String, Flags)>> = Mutex::new(None);
+
+/// Optional behaviors toggled by the `flags` KV entry, e.g.
+/// `{"stickers": false, "spam_filter": true, "spam_senders": ["10690"]}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Flags {
+    /// Up and down stickers after status messages.
+    pub stickers: bool,
+    /// Only archive forwards for the digest of devices which have one.
+    pub digest_only: bool,
+    /// Drop forwards from `spam_senders`.
+    pub spam_filter: bool,
+    pub spam_senders: Vec<String>,
+    /// Echo bodies which are not understood back to the device chat.
+    pub debug_echo: bool,
+    /// React to forwards once everything after sending them is done.
+    pub reactions: bool,
+    /// Send the messages of each device to a forum topic of its own, created
+    /// as needed.
+    pub topics: bool,
+    /// UTC hours from and until which forwards of devices with `quiet` are
+    /// sent without notification.
+    pub quiet_hours: (u32, u32),
+    /// Overrides by device, as toggled by `/settings`.
+    pub devices: BTreeMap<String, DeviceFlags>,
+}
+
+/// Flags of one device, those absent falling back to the deployment's.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceFlags {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stickers: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spam_filter: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quiet: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summarize: Option<bool>,
+}
+
+/// A flag which can be toggled per device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceFlag {
+    Stickers,
+    DigestOnly,
+    SpamFilter,
+    Quiet,
+    /// Forwards go to the admin chat only, marked and left out of counts,
+    /// digests and batches.
+    Test,
+    /// Long forwards get their summary appended without pressing the
+    /// Summarize button.
+    Summarize,
+}
+
+impl DeviceFlag {
+    pub const ALL: [DeviceFlag; 6] = [
+        DeviceFlag::Stickers,
+        DeviceFlag::DigestOnly,
+        DeviceFlag::SpamFilter,
+        DeviceFlag::Quiet,
+        DeviceFlag::Test,
+        DeviceFlag::Summarize,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceFlag::Stickers => "stickers",
+            DeviceFlag::DigestOnly => "digest_only",
+            DeviceFlag::SpamFilter => "spam_filter",
+            DeviceFlag::Quiet => "quiet",
+            DeviceFlag::Test => "test",
+            DeviceFlag::Summarize => "summarize",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DeviceFlag::Stickers => "Stickers",
+            DeviceFlag::DigestOnly => "Digest only",
+            DeviceFlag::SpamFilter => "Spam filter",
+            DeviceFlag::Quiet => "Quiet hours",
+            DeviceFlag::Test => "Test device",
+            DeviceFlag::Summarize => "Auto-summarize",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|flag| flag.name() == name)
+    }
+
+    fn of(self, flags: &mut DeviceFlags) -> &mut Option<bool> {
+        match self {
+            DeviceFlag::Stickers => &mut flags.stickers,
+            DeviceFlag::DigestOnly => &mut flags.digest_only,
+            DeviceFlag::SpamFilter => &mut flags.spam_filter,
+            DeviceFlag::Quiet => &mut flags.quiet,
+            DeviceFlag::Test => &mut flags.test,
+            DeviceFlag::Summarize => &mut flags.summarize,
+        }
+    }
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Self {
+            stickers: true,
+            digest_only: false,
+            spam_filter: false,
+            spam_senders: Vec::new(),
+            debug_echo: true,
+            reactions: true,
+            topics: false,
+            quiet_hours: (22, 7),
+            devices: BTreeMap::new(),
+        }
+    }
+}
+
+impl Flags {
+    /// Reads the flags at most once per invocation, falling back to the
+    /// defaults when the entry is absent.
+    pub async fn get(kv: &Kv) -> Result<Self> {
+        let id = log::current();
+        if let Some(id) = &id
+            && let Some((cached_id, flags)) = &*CACHE.lock().unwrap()
+            && cached_id == id
+        {
+            return Ok(flags.clone());
+        }
+        let flags: Flags = kv.get("flags").json().await?.unwrap_or_default();
+        if let Some(id) = id {
+            *CACHE.lock().unwrap() = Some((id, flags.clone()));
+        }
+        Ok(flags)
+    }
+
+    pub async fn put(&self, kv: &Kv) -> Result<()> {
+        kv.put("flags", to_json(self))?.execute().await?;
+        if let Some(id) = log::current() {
+            *CACHE.lock().unwrap() = Some((id, self.clone()));
+        }
+        Ok(())
+    }
+
+    /// The flag in effect for `device`.
+    pub fn get_for(&self, device: &str, flag: DeviceFlag) -> bool {
+        let mut device_flags = self.devices.get(device).cloned().unwrap_or_default();
+        flag.of(&mut device_flags).unwrap_or(match flag {
+            DeviceFlag::Stickers => self.stickers,
+            DeviceFlag::DigestOnly => self.digest_only,
+            DeviceFlag::SpamFilter => self.spam_filter,
+            DeviceFlag::Quiet | DeviceFlag::Test | DeviceFlag::Summarize => false,
+        })
+    }
+
+    /// Flips the flag of `device` from the one in effect.
+    pub fn toggle(&mut self, device: &str, flag: DeviceFlag) {
+        let value = !self.get_for(device, flag);
+        *flag.of(self.devices.entry(device.to_owned()).or_default()) = Some(value);
+    }
+
+    pub fn is_spam(&self, device: &str, sender: Option<&str>) -> bool {
+        self.get_for(device, DeviceFlag::SpamFilter)
+            && sender.is_some_and(|sender| self.spam_senders.iter().any(|s| s == sender))
+    }
+
+    /// Whether forwards of `device` go without notification at `hour` UTC.
+    pub fn is_quiet(&self, device: &str, hour: u32) -> bool {
+        let (from, until) = self.quiet_hours;
+        let within = if from <= until {
+            (from..until).contains(&hour)
+        } else {
+            hour >= from || hour < until
+        };
+        within && self.get_for(device, DeviceFlag::Quiet)
+    }
+}
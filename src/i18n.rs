@@ -0,0 +1,224 @@
+use crate::{error::Result, kv::Kv};
+
+/// Language of the replies to a Telegram user, see `/language`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Zh,
+}
+
+impl Lang {
+    pub const ALL: [Lang; 2] = [Lang::En, Lang::Zh];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Zh => "zh",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lang::En => "English",
+            Lang::Zh => "中文",
+        }
+    }
+
+    /// Matches `zh`, `zh-hans` and the like as Telegram reports them.
+    pub fn from_code(code: &str) -> Option<Self> {
+        let code = code.to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|lang| code == lang.code() || code.starts_with(&format!("{}-", lang.code())))
+    }
+
+    /// The language chosen by `user_id`, or else the one of their Telegram
+    /// client.
+    pub async fn of(kv: &Kv, user_id: Option<i64>, client: Option<&str>) -> Result<Self> {
+        let chosen = match user_id {
+            Some(user_id) => kv.get(&key(user_id)).text().await?,
+            None => None,
+        };
+        Ok(chosen
+            .as_deref()
+            .or(client)
+            .and_then(Self::from_code)
+            .unwrap_or_default())
+    }
+
+    pub async fn set(self, kv: &Kv, user_id: i64) -> Result<()> {
+        kv.put(&key(user_id), self.code())?.execute().await?;
+        Ok(())
+    }
+}
+
+fn key(user_id: i64) -> String {
+    format!("language/{user_id}")
+}
+
+/// Replies of the bot commands, `{}` standing for their argument.
+#[derive(Debug, Clone, Copy)]
+pub enum Reply {
+    DeviceRequired,
+    DeviceNotFound,
+    TextRequired,
+    BlobRequired,
+    SendArgumentsRequired,
+    MailNotConfigured,
+    DeliveriesNotConfigured,
+    NoCommands,
+    NoDeliveries,
+    NoPending,
+    NoRawBody,
+    NoQuarantined,
+    Discarded,
+    CallHistory,
+    Deliveries,
+    Reliability,
+    TopSenders,
+    LanguageSet,
+    LanguageUnknown,
+    LanguageNoUser,
+    TelegramCalls,
+    TelegramCallCounts,
+    LastDays,
+    NoMessages,
+    PairLink,
+    BackupSaved,
+    BackupFailed,
+    NoSnapshots,
+    Restored,
+    RestoreFailed,
+    ExportReady,
+    ExportFailed,
+    Imported,
+    ImportedIncomplete,
+    ImportFailed,
+    ConfigMailed,
+    ConfigMailFailed,
+    CommandMailReloaded,
+    SendingCommand,
+    CommandPushed,
+    CommandSent,
+    CommandQueued,
+    CommandFailed,
+    CommandQueueFailed,
+}
+
+pub fn t(lang: Lang, reply: Reply) -> &'static str {
+    match (lang, reply) {
+        (Lang::En, Reply::DeviceRequired) => "Argument &lt;device&gt; required",
+        (Lang::Zh, Reply::DeviceRequired) => "需要参数 &lt;device&gt;",
+        (Lang::En, Reply::DeviceNotFound) => "Device not found",
+        (Lang::Zh, Reply::DeviceNotFound) => "设备不存在",
+        (Lang::En, Reply::TextRequired) => "Argument &lt;text&gt; required",
+        (Lang::Zh, Reply::TextRequired) => "需要参数 &lt;text&gt;",
+        (Lang::En, Reply::BlobRequired) => "Argument &lt;blob&gt; required",
+        (Lang::Zh, Reply::BlobRequired) => "需要参数 &lt;blob&gt;",
+        (Lang::En, Reply::SendArgumentsRequired) => {
+            "Arguments &lt;device&gt; &lt;number&gt; &lt;text&gt; required"
+        }
+        (Lang::Zh, Reply::SendArgumentsRequired) => {
+            "需要参数 &lt;device&gt; &lt;number&gt; &lt;text&gt;"
+        }
+        (Lang::En, Reply::MailNotConfigured) => "Device email not configured",
+        (Lang::Zh, Reply::MailNotConfigured) => "设备未配置邮件",
+        (Lang::En, Reply::DeliveriesNotConfigured) => "Delivery tracking not configured",
+        (Lang::Zh, Reply::DeliveriesNotConfigured) => "未配置送达记录",
+        (Lang::En, Reply::NoCommands) => "No commands queued for {}",
+        (Lang::Zh, Reply::NoCommands) => "{} 没有排队的命令",
+        (Lang::En, Reply::NoDeliveries) => "No deliveries recorded for {}",
+        (Lang::Zh, Reply::NoDeliveries) => "{} 没有送达记录",
+        (Lang::En, Reply::NoPending) => "No messages held back for {}",
+        (Lang::Zh, Reply::NoPending) => "{} 没有暂存的消息",
+        (Lang::En, Reply::NoRawBody) => "Nothing posted by {} yet",
+        (Lang::Zh, Reply::NoRawBody) => "{} 尚未发送过请求",
+        (Lang::En, Reply::NoQuarantined) => "No unrecognized payloads from {}",
+        (Lang::Zh, Reply::NoQuarantined) => "{} 没有无法识别的请求",
+        (Lang::En, Reply::Discarded) => "Discarded {} unrecognized payload(s)",
+        (Lang::Zh, Reply::Discarded) => "已丢弃 {} 个无法识别的请求",
+        (Lang::En, Reply::CallHistory) => "📞 {} call history",
+        (Lang::Zh, Reply::CallHistory) => "📞 {} 通话记录",
+        (Lang::En, Reply::Deliveries) => "📬 {} deliveries",
+        (Lang::Zh, Reply::Deliveries) => "📬 {} 送达记录",
+        (Lang::En, Reply::Reliability) => "🩺 {} reliability",
+        (Lang::Zh, Reply::Reliability) => "🩺 {} 可靠性",
+        (Lang::En, Reply::TopSenders) => "📈 {} top senders",
+        (Lang::Zh, Reply::TopSenders) => "📈 {} 最常见的发件人",
+        (Lang::En, Reply::LanguageSet) => "Replies are now in English",
+        (Lang::Zh, Reply::LanguageSet) => "之后将以中文回复",
+        (Lang::En, Reply::LanguageUnknown) => "Languages: {}",
+        (Lang::Zh, Reply::LanguageUnknown) => "可选语言：{}",
+        (Lang::En, Reply::LanguageNoUser) => "Posts in a channel have no language",
+        (Lang::Zh, Reply::LanguageNoUser) => "频道消息无法设置语言",
+        (Lang::En, Reply::TelegramCalls) => "📊 Telegram calls",
+        (Lang::Zh, Reply::TelegramCalls) => "📊 Telegram 调用次数",
+        (Lang::En, Reply::TelegramCallCounts) => "{} this hour, {} today",
+        (Lang::Zh, Reply::TelegramCallCounts) => "本小时 {} 次，今天 {} 次",
+        (Lang::En, Reply::LastDays) => "last {} days",
+        (Lang::Zh, Reply::LastDays) => "最近 {} 天",
+        (Lang::En, Reply::NoMessages) => "no messages",
+        (Lang::Zh, Reply::NoMessages) => "没有消息",
+        (Lang::En, Reply::PairLink) => {
+            "📱 Open {} on another screen to set up {}, the link works once within a day"
+        }
+        (Lang::Zh, Reply::PairLink) => {
+            "📱 在另一块屏幕上打开 {} 来设置 {}，链接只能在一天内使用一次"
+        }
+        (Lang::En, Reply::BackupSaved) => "💾 {} entries saved to <code>{}</code>",
+        (Lang::Zh, Reply::BackupSaved) => "💾 已将 {} 个条目保存到 <code>{}</code>",
+        (Lang::En, Reply::BackupFailed) => "failed to back up: {}",
+        (Lang::Zh, Reply::BackupFailed) => "备份失败：{}",
+        (Lang::En, Reply::NoSnapshots) => "No snapshots",
+        (Lang::Zh, Reply::NoSnapshots) => "没有备份",
+        (Lang::En, Reply::Restored) => "♻️ {} entries restored",
+        (Lang::Zh, Reply::Restored) => "♻️ 已恢复 {} 个条目",
+        (Lang::En, Reply::RestoreFailed) => "failed to restore: {}",
+        (Lang::Zh, Reply::RestoreFailed) => "恢复失败：{}",
+        (Lang::En, Reply::ExportReady) => "Send this to the new deployment",
+        (Lang::Zh, Reply::ExportReady) => "将以下内容发送给新的部署",
+        (Lang::En, Reply::ExportFailed) => "failed to export: {}",
+        (Lang::Zh, Reply::ExportFailed) => "导出失败：{}",
+        (Lang::En, Reply::Imported) => "{} imported",
+        (Lang::Zh, Reply::Imported) => "已导入 {}",
+        (Lang::En, Reply::ImportedIncomplete) => "{} imported, set {} to finish",
+        (Lang::Zh, Reply::ImportedIncomplete) => "已导入 {}，设置 {} 后即可完成",
+        (Lang::En, Reply::ImportFailed) => "failed to import: {}",
+        (Lang::Zh, Reply::ImportFailed) => "导入失败：{}",
+        (Lang::En, Reply::ConfigMailed) => "Config sent",
+        (Lang::Zh, Reply::ConfigMailed) => "配置已发送",
+        (Lang::En, Reply::ConfigMailFailed) => "failed to send config: {}",
+        (Lang::Zh, Reply::ConfigMailFailed) => "配置发送失败：{}",
+        (Lang::En, Reply::CommandMailReloaded) => "Command mail reloaded",
+        (Lang::Zh, Reply::CommandMailReloaded) => "命令邮件模板已重新加载",
+        (Lang::En, Reply::SendingCommand) => "Sending command",
+        (Lang::Zh, Reply::SendingCommand) => "正在发送命令",
+        (Lang::En, Reply::CommandPushed) => "Command pushed",
+        (Lang::Zh, Reply::CommandPushed) => "命令已推送",
+        (Lang::En, Reply::CommandSent) => "Command sent",
+        (Lang::Zh, Reply::CommandSent) => "命令已发送",
+        (Lang::En, Reply::CommandQueued) => "Command queued",
+        (Lang::Zh, Reply::CommandQueued) => "命令已排队",
+        (Lang::En, Reply::CommandFailed) => "failed to send command: {}",
+        (Lang::Zh, Reply::CommandFailed) => "命令发送失败：{}",
+        (Lang::En, Reply::CommandQueueFailed) => "failed to queue command",
+        (Lang::Zh, Reply::CommandQueueFailed) => "命令排队失败",
+    }
+}
+
+/// `reply` with `{}` replaced by `arg`.
+pub fn tf(lang: Lang, reply: Reply, arg: &str) -> String {
+    t(lang, reply).replace("{}", arg)
+}
+
+/// `reply` with each `{}` replaced by the next of `args`.
+pub fn tfs(lang: Lang, reply: Reply, args: &[&str]) -> String {
+    let mut parts = t(lang, reply).split("{}");
+    let mut text = parts.next().unwrap_or_default().to_owned();
+    for (part, arg) in parts.zip(args.iter().chain(std::iter::repeat(&""))) {
+        text.push_str(arg);
+        text.push_str(part);
+    }
+    text
+}
@@ -0,0 +1,168 @@
+use std::cell::RefCell;
+
+use crate::{domain::Store, error, log};
+use serde::{Deserialize, Serialize};
+use worker::kv::{GetOptionsBuilder, Key, KvError, KvStore, PutOptionsBuilder, ToRawKvValue};
+
+thread_local! {
+    static PENDING: RefCell<KvUsage> = RefCell::new(KvUsage::default());
+    static LAST_FLUSH: RefCell<f64> = const { RefCell::new(0.0) };
+}
+
+/// Operation counts of a day, as billed by Workers KV.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KvUsage {
+    #[serde(default)]
+    pub reads: u64,
+    #[serde(default)]
+    pub writes: u64,
+    #[serde(default)]
+    pub deletes: u64,
+    #[serde(default)]
+    pub lists: u64,
+    #[serde(default)]
+    pub warned: bool,
+}
+
+impl KvUsage {
+    fn add(&mut self, other: &KvUsage) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.deletes += other.deletes;
+        self.lists += other.lists;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.reads + self.writes + self.deletes + self.lists == 0
+    }
+}
+
+/// A `KvStore` which counts the operations it performs and keeps the keys
+/// of a tenant under `tenant/{id}/`.
+pub struct Kv {
+    store: KvStore,
+    prefix: String,
+}
+
+impl Kv {
+    pub fn new(store: KvStore) -> Self {
+        let prefix = log::tenant()
+            .map(|tenant| format!("tenant/{tenant}/"))
+            .unwrap_or_default();
+        Self { store, prefix }
+    }
+
+    pub fn get(&self, name: &str) -> GetOptionsBuilder {
+        PENDING.with_borrow_mut(|usage| usage.reads += 1);
+        self.store.get(&format!("{}{name}", self.prefix))
+    }
+
+    pub fn put<T: ToRawKvValue>(&self, name: &str, value: T) -> Result<PutOptionsBuilder, KvError> {
+        PENDING.with_borrow_mut(|usage| usage.writes += 1);
+        self.store.put(&format!("{}{name}", self.prefix), value)
+    }
+
+    /// Names of the keys starting with `prefix`, without the tenant's.
+    pub async fn list_keys(&self, prefix: &str) -> Result<Vec<String>, KvError> {
+        Ok(self
+            .list(prefix)
+            .await?
+            .into_iter()
+            .map(|key| key.name)
+            .collect())
+    }
+
+    /// Every key starting with `prefix` across all pages, named without the
+    /// tenant's prefix.
+    pub async fn list(&self, prefix: &str) -> Result<Vec<Key>, KvError> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            PENDING.with_borrow_mut(|usage| usage.lists += 1);
+            let mut list = self.store.list().prefix(format!("{}{prefix}", self.prefix));
+            if let Some(cursor) = cursor {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+            keys.extend(page.keys.into_iter().map(|mut key| {
+                key.name = key.name[self.prefix.len()..].to_owned();
+                key
+            }));
+            match page.cursor {
+                Some(next) if !page.list_complete => cursor = Some(next),
+                _ => return Ok(keys),
+            }
+        }
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), KvError> {
+        PENDING.with_borrow_mut(|usage| usage.deletes += 1);
+        self.store.delete(&format!("{}{name}", self.prefix)).await
+    }
+
+    /// Adds the operations counted by this isolate to the day's total at most
+    /// every `interval_ms`, returning the new total. Usage is kept for the
+    /// whole namespace, so `key` is never prefixed.
+    pub async fn flush(
+        &self,
+        key: &str,
+        now: f64,
+        interval_ms: f64,
+        ttl: u64,
+    ) -> Result<Option<KvUsage>, KvError> {
+        if now - LAST_FLUSH.with_borrow(|last| *last) < interval_ms {
+            return Ok(None);
+        }
+        let pending = PENDING.take();
+        if pending.is_empty() {
+            return Ok(None);
+        }
+        LAST_FLUSH.set(now);
+        let mut usage: KvUsage = match self.store.get(key).json().await {
+            Ok(usage) => usage.unwrap_or_default(),
+            Err(e) => {
+                PENDING.with_borrow_mut(|usage| usage.add(&pending));
+                return Err(e);
+            }
+        };
+        usage.add(&pending);
+        // this flush's own read and write
+        usage.reads += 1;
+        usage.writes += 1;
+        self.store
+            .put(key, serde_json::to_string(&usage)?)?
+            .expiration_ttl(ttl)
+            .execute()
+            .await?;
+        Ok(Some(usage))
+    }
+
+    pub async fn mark_warned(
+        &self,
+        key: &str,
+        mut usage: KvUsage,
+        ttl: u64,
+    ) -> Result<(), KvError> {
+        usage.warned = true;
+        PENDING.with_borrow_mut(|usage| usage.writes += 1);
+        self.store
+            .put(key, serde_json::to_string(&usage)?)?
+            .expiration_ttl(ttl)
+            .execute()
+            .await
+    }
+}
+
+impl Store for Kv {
+    async fn get_text(&self, key: &str) -> error::Result<Option<String>> {
+        Ok(self.get(key).text().await?)
+    }
+
+    async fn put_text(&self, key: &str, value: &str, ttl: Option<u64>) -> error::Result<()> {
+        let mut put = self.put(key, value)?;
+        if let Some(ttl) = ttl {
+            put = put.expiration_ttl(ttl);
+        }
+        Ok(put.execute().await?)
+    }
+}
@@ -1,328 +1,1930 @@
-#![feature(let_chains)]
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
 
-use std::{fmt::Display, sync::OnceLock};
-
-use indoc::indoc;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures_util::future::join_all;
 use itertools::Itertools;
 use regex::{Captures, Regex};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use wasm_bindgen::prelude::*;
-use worker::{kv::KvStore, *};
+use worker::{worker_sys::web_sys, *};
+
+mod assets;
+mod backup;
+mod channel;
+mod config;
+mod crypto;
+mod dedup;
+mod domain;
+mod error;
+mod fallback;
+mod flags;
+mod i18n;
+mod kv;
+mod log;
+mod login;
+mod migrate;
+mod mime;
+mod openapi;
+mod qr;
+mod rules;
+mod secrets;
+mod selftest;
+mod sentry;
+mod stream;
+mod summary;
+mod telegram;
+mod yaml;
+
+use config::Config;
+use domain::{
+    Clock, HeartbeatStatus, Outage, Store, SystemClock, escape_html, format_date, format_duration,
+    format_time, link_href, parse_credentials, reliability_report, token_matches, uptime,
+};
+use error::{Error, Result};
+use flags::{DeviceFlag, Flags};
+use i18n::{Lang, Reply, t, tf, tfs};
+use kv::Kv;
+use mime::{Attachment, MimeMessage};
+use telegram::{
+    AnswerCallbackQueryBody, AnswerInlineQueryBody, ApiResponse, CreateForumTopicBody,
+    DeleteMessageBody, EditMessageLiveLocationBody, EditMessageTextBody, InlineKeyboardButton,
+    InlineKeyboardMarkup, InlineQueryResultArticle, InputMedia, InputTextMessageContent,
+    LinkPreviewOptions, PinChatMessageBody, ReactionTypeEmoji, SendLocationBody,
+    SendMediaGroupBody, SendMessageBody, SendStickerBody, SetMessageReactionBody, TelegramClient,
+    UploadDocument,
+};
 
 const HEARTBEAT_INTERVAL_SECONDS: i64 = 300;
 
+/// Battery level at or below which a discharging device is reported, unless
+/// `low_battery_percent` says otherwise.
+const LOW_BATTERY_PERCENT: i32 = 15;
+
+/// Heartbeat intervals kept per device for its adaptive thresholds.
+const CADENCE_SAMPLES: usize = 50;
+
+/// Intervals needed before the thresholds follow the device's cadence.
+const CADENCE_MIN_SAMPLES: usize = 10;
+
+/// The adaptive interval is at most this many `HEARTBEAT_INTERVAL_SECONDS`,
+/// so that a device which all but stopped still gets reported.
+const CADENCE_MAX_FACTOR: i64 = 6;
+
+/// Change of the adaptive interval in percent from which the cadence is
+/// written again, once it has `CADENCE_MIN_SAMPLES`.
+const CADENCE_CHANGE_PERCENT: i64 = 10;
+
+/// Heartbeats arriving more often than this only refresh the isolate's cache.
+const HEARTBEAT_WRITE_INTERVAL_SECONDS: i64 = 60;
+
+const CLOCK_SKEW_THRESHOLD_SECONDS: i64 = 60;
+
+const COMMAND_TTL_SECONDS: u64 = 24 * 3600;
+
+const ACK_TIMEOUT_SECONDS: i64 = 600;
+
+/// Bounces within this window which make a device flapping.
+const FLAP_WINDOW_MINUTES: i64 = 60;
+
+/// Bounces within `FLAP_WINDOW_MINUTES` which trigger the flapping alert
+/// without `flap_threshold`.
+const FLAP_THRESHOLD: usize = 3;
+
+/// Minutes a DOWN alert waits for its ACK button without
+/// `escalation_minutes`.
+const ESCALATION_MINUTES: i64 = 15;
+
+const REPLY_TTL_SECONDS: u64 = 7 * 24 * 3600;
+
+/// How long a long forward keeps its Summarize button working.
+const SUMMARY_TTL_SECONDS: u64 = 7 * 24 * 3600;
+
+/// How long a `/broadcast` waits for its confirmation.
+const BROADCAST_TTL_SECONDS: u64 = 3600;
+
+/// How long `/ping` waits for the heartbeat of a nudged device.
+const PING_TTL_SECONDS: u64 = 3600;
+
+/// How long a device's retry with the same idempotency key is dropped.
+const IDEMPOTENCY_TTL_SECONDS: u64 = 24 * 3600;
+
+/// Longer idempotency keys are ignored, as KV keys are limited to 512 bytes.
+const IDEMPOTENCY_KEY_LIMIT: usize = 128;
+
+/// How long a forward arriving ahead of its sequence number waits for the
+/// ones before it, short enough to fit in the time `waitUntil` leaves.
+const REORDER_WINDOW_SECONDS: i64 = 10;
+
+/// Forwards buffered while Telegram is unreachable are dropped after this.
+const OUTBOX_TTL_SECONDS: u64 = 2 * 24 * 3600;
+
+/// Cron triggers of wrangler.toml, each running its own jobs: checking
+/// devices, sending digests and batches, sending weekly summaries and pruning
+/// D1.
+const CRON_CHECK: &str = "2-59/5 * * * *";
+const CRON_DIGESTS: &str = "7 * * * *";
+const CRON_SUMMARIES: &str = "17 8 * * *";
+const CRON_PURGE: &str = "37 3 * * *";
+
+/// Bots can only delete messages younger than this, so later deletions are
+/// moved up to it.
+const DELETE_LIMIT_SECONDS: i64 = 48 * 3600 - 600;
+
+const EMAIL_TEXT_LIMIT: usize = 3000;
+
+/// Marks the forwards of devices with the `test` flag.
+const TEST_PREFIX: &str = "🧪 [TEST] ";
+
+/// Characters of the last body of a device kept for `/raw`, and of the
+/// unrecognized ones kept for `/inspect`.
+const RAW_BODY_LIMIT: usize = 3000;
+
+const QUARANTINE_TTL_SECONDS: u64 = 7 * 24 * 3600;
+
+/// Characters of a Telegram message after parsing its entities, longer
+/// messages are sent as a text file instead.
+const TELEGRAM_TEXT_LIMIT: usize = 4096;
+
+/// Characters of the caption of such a file.
+const TELEGRAM_CAPTION_LIMIT: usize = 1024;
+
+/// Items of a Telegram album.
+const MEDIA_GROUP_LIMIT: usize = 10;
+
+const EMAIL_ATTEMPTS: u32 = 3;
+
+const RETRY_BASE_DELAY_MS: u64 = 500;
+
+const DEAD_LETTER_TTL_SECONDS: u64 = 30 * 24 * 3600;
+
+const DELIVERIES_SHOWN: usize = 20;
+
+const TOP_SENDERS_SHOWN: usize = 10;
+
+/// Telegram allows about 20 messages a minute in a group.
+const TELEGRAM_HOURLY_WARNING: u32 = 900;
+
+const TELEGRAM_USAGE_TTL_SECONDS: u64 = 2 * 24 * 3600;
+
+const TELEGRAM_USAGE_FLUSH_SECONDS: i64 = 60;
+
+const FORWARD_COUNTS_FLUSH_SECONDS: i64 = 60;
+
+const QUOTA_FLUSH_SECONDS: i64 = 60;
+
+const KV_USAGE_FLUSH_SECONDS: i64 = 900;
+
+const KV_USAGE_TTL_SECONDS: u64 = 2 * 24 * 3600;
+
+/// Daily operations of the Workers KV free tier.
+const KV_FREE_READS: u64 = 100_000;
+
+const KV_FREE_WRITES: u64 = 1_000;
+
+const KV_USAGE_WARNING_PERCENT: u64 = 80;
+
+const QUOTA_TTL_SECONDS: u64 = 2 * 24 * 3600;
+
+const MESSAGE_ARCHIVE_TTL_SECONDS: u64 = 3 * 24 * 3600;
+
+const CALL_HISTORY_TTL_SECONDS: u64 = 30 * 24 * 3600;
+
+const HISTORY_DAYS: i64 = 7;
+
+const OUTAGE_HISTORY_DAYS: i64 = 30;
+
+const LONGEST_OUTAGES_SHOWN: usize = 3;
+
+/// Senders of each device listed in the weekly report.
+const WEEKLY_SENDERS_SHOWN: usize = 3;
+
+/// Days of battery lows and highs kept for the weekly report.
+const BATTERY_HISTORY_DAYS: usize = 14;
+
+const ONBOARDING_TTL_SECONDS: u64 = 3600;
+
+/// How long a `/pair/{token}` link can be opened.
+const PAIRING_TTL_SECONDS: u64 = 24 * 3600;
+
+/// How long an imported rule set waits for `/rules apply`.
+const RULES_IMPORT_TTL_SECONDS: u64 = 600;
+
+/// Reactions to a forward once its archived copy, delivery receipt and reply
+/// mapping are stored, or once any of them failed. Bots can only react with
+/// Telegram's standard set, which has no ✅ or ⚠️.
+const DELIVERED_REACTION: &str = "👌";
+const PARTIAL_REACTION: &str = "🤷";
+
+/// How long Telegram may reuse the answer to an inline query.
+const INLINE_CACHE_SECONDS: u32 = 10;
+
+/// Device names which would shadow a secret or a route.
+const RESERVED_DEVICE_NAMES: &[&str] = &["assets", "devices", "pair", "status", "v1"];
+
+/// Compiled on first use, so that isolates which never see a code or an
+/// encoded header don't pay for it on cold start.
 static RE_CODE: OnceLock<Regex> = OnceLock::new();
 
-static COMMAND_MAIL: OnceLock<String> = OnceLock::new();
+static RE_ENCODED_WORD: OnceLock<Regex> = OnceLock::new();
 
-#[derive(Debug, Deserialize)]
+// subject and extra headers go above the blank line, the envelope is added by
+// the MIME builder
+const COMMAND_MAIL: &str = "Subject: {{subject}}\n\n{{body}}\nCommand ID: {{command_id}}\n";
+
+/// Command email template in effect, loaded from KV on first use and
+/// falling back to `COMMAND_MAIL` when the KV entry is absent.
+static COMMAND_MAIL_LOADED: Mutex<Option<String>> = Mutex::new(None);
+
+/// Single-page dashboard served at `/admin`, backed by `/admin/api`, its
+/// script and styles are under `/assets/`.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Public status page of a device, served at `/status/{{device}_public_id}`.
+const STATUS_PAGE: &str = include_str!("status.html");
+
+/// Pairing page of a new device, served at `/pair/{token}`.
+const PAIR_PAGE: &str = include_str!("pair.html");
+
+/// Heartbeats seen by this isolate, saving KV reads and writes while a
+/// device keeps checking in.
+static HEARTBEATS: Mutex<BTreeMap<String, CachedHeartbeat>> = Mutex::new(BTreeMap::new());
+
+/// Key of a device in `HEARTBEATS`, as devices of different tenants may
+/// share a name.
+fn heartbeat_key(device: &str) -> String {
+    match log::tenant() {
+        Some(tenant) => format!("{tenant}/{device}"),
+        None => device.to_owned(),
+    }
+}
+
+/// Telegram calls of this isolate not yet added to the chat's usage in KV,
+/// by `heartbeat_key` of the chat.
+static TELEGRAM_CALLS: Mutex<BTreeMap<String, PendingCalls>> = Mutex::new(BTreeMap::new());
+
+/// Forward counts of this isolate not yet added to `metrics/{device}`, by
+/// `heartbeat_key` of the device.
+static FORWARD_COUNTS: Mutex<BTreeMap<String, PendingForwards>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy)]
+struct PendingForwards {
+    counters: ForwardCounters,
+    /// When the first of them was counted.
+    since: i64,
+}
+
+/// Forwards of this isolate not yet added to `quota/{date}`, by tenant.
+static QUOTA_COUNTS: Mutex<BTreeMap<String, PendingQuota>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Default)]
+struct PendingQuota {
+    date: String,
+    devices: BTreeMap<String, u32>,
+    total: u32,
+    /// When the first of them was counted.
+    since: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingCalls {
+    /// Hours since the epoch.
+    hour: i64,
+    calls: u32,
+    /// When the first of them was made.
+    since: i64,
+}
+
+/// Cadences seen by this isolate by `heartbeat_key`, with the intervals not
+/// worth a KV write yet.
+static CADENCES: Mutex<BTreeMap<String, Cadence>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy)]
+struct CachedHeartbeat {
+    seen: i64,
+    written: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 struct AppleMessageFilterQuery {
     #[serde(rename = "query")]
     inner: AppleMessageFilterQueryInner,
+    #[serde(default)]
+    timestamp: Option<i64>,
+    #[serde(default)]
+    message_id: Option<String>,
+    #[serde(default)]
+    seq: Option<u64>,
 }
 
 impl AppleMessageFilterQuery {
     fn sender(&self) -> &str {
-        &self.inner.sender
+        self.inner.sender.as_deref().unwrap_or("unknown")
     }
 
     fn text(&self) -> &str {
-        &self.inner.message.text
+        self.inner
+            .message
+            .as_ref()
+            .and_then(|message| message.text.as_deref())
+            .or(self.inner.text.as_deref())
+            .unwrap_or_default()
     }
 }
 
 impl Display for AppleMessageFilterQuery {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let escaped = escape_html(self.text());
-        let text = RE_CODE
-            .get()
-            .unwrap()
-            .replace_all(&escaped, |c: &Captures| {
-                format!(
-                    "{} 👉 <code>{}</code> 👈  {}",
-                    c.get(1).unwrap().as_str(),
-                    c.get(2).unwrap().as_str(),
-                    c.get(3).unwrap().as_str(),
-                )
-            });
-        write!(f, "<code>{sender}</code>\n\n{text}", sender = self.sender())
+        write!(
+            f,
+            "<code>{sender}</code>",
+            sender = escape_html(self.sender())
+        )?;
+        if let Some(country) = &self.inner.receiver_iso_country_code {
+            write!(f, " ({})", escape_html(country))?;
+        }
+        write!(f, "\n\n{text}", text = highlight_codes(self.text()))
     }
 }
 
-#[derive(Debug, Deserialize)]
+// Fields are optional since the shape varies across iOS versions, only the
+// outer `query` object is required to tell it apart from other payloads.
+#[derive(Debug, Serialize, Deserialize)]
 struct AppleMessageFilterQueryInner {
-    sender: String,
-    message: AppleMessageFilterQueryMessage,
+    #[serde(default)]
+    sender: Option<String>,
+    #[serde(default)]
+    message: Option<AppleMessageFilterQueryMessage>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default, rename = "receiverISOCountryCode")]
+    receiver_iso_country_code: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct AppleMessageFilterQueryMessage {
-    text: String,
+    #[serde(default)]
+    text: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct StatusReport {
-    pub battery: i32,
-    pub charger: bool,
+#[derive(Debug, Serialize, Deserialize)]
+struct RcsMessage {
+    #[serde(rename = "rcs")]
+    inner: RcsMessageInner,
+    #[serde(default)]
+    timestamp: Option<i64>,
+    #[serde(default)]
+    message_id: Option<String>,
+    #[serde(default)]
+    seq: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Update {
-    message: Message,
+impl Display for RcsMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "RCS <code>{sender}</code>",
+            sender = escape_html(&self.inner.sender)
+        )?;
+        if let Some(chatbot) = &self.inner.chatbot {
+            if let Some(name) = &chatbot.name {
+                write!(f, " {}", escape_html(name))?;
+            }
+            if chatbot.verified {
+                write!(f, " ✅")?;
+            }
+        }
+        if let Some(text) = &self.inner.text {
+            write!(f, "\n\n{text}", text = highlight_codes(text))?;
+        }
+        for media in &self.inner.media {
+            let kind = escape_html(media.content_type.as_deref().unwrap_or("media"));
+            match link_href(&media.url) {
+                Some(url) => write!(f, "\n📎 <a href=\"{url}\">{kind}</a>")?,
+                None => write!(f, "\n📎 {kind}")?,
+            }
+        }
+        Ok(())
+    }
 }
 
-impl Update {
-    pub fn user_id(&self) -> Option<i64> {
-        self.message.from.as_ref().map(|user| user.id)
-    }
+#[derive(Debug, Serialize, Deserialize)]
+struct RcsMessageInner {
+    sender: String,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    media: Vec<RcsMedia>,
+    #[serde(default)]
+    chatbot: Option<RcsChatbot>,
+}
 
-    pub fn chat_id(&self) -> i64 {
-        self.message.chat.id
-    }
+#[derive(Debug, Serialize, Deserialize)]
+struct RcsMedia {
+    url: String,
+    #[serde(default)]
+    content_type: Option<String>,
+}
 
-    pub fn text(&self) -> &str {
-        &self.message.text
+impl RcsMedia {
+    /// The kind of `InputMedia` the content type is sent as.
+    fn kind(&self) -> &'static str {
+        match self.content_type.as_deref().and_then(|t| t.split_once('/')) {
+            Some(("image", _)) => "photo",
+            Some(("video", _)) => "video",
+            Some(("audio", _)) => "audio",
+            _ => "document",
+        }
     }
 }
 
-#[derive(Debug, Serialize)]
-struct SendMessageBody<'a> {
-    chat_id: &'a str,
-    text: &'a str,
-    parse_mode: &'a str,
+#[derive(Debug, Serialize, Deserialize)]
+struct RcsChatbot {
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    verified: bool,
 }
 
-#[derive(Debug, Serialize)]
-struct SendStickerBody<'a> {
-    chat_id: &'a str,
-    sticker: &'a str,
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CallDirection {
+    Incoming,
+    Outgoing,
+    Missed,
 }
 
-#[derive(Debug, Deserialize)]
-struct MessageResponse {
-    ok: bool,
-    result: Option<Message>,
+impl CallDirection {
+    fn symbol(self) -> char {
+        match self {
+            CallDirection::Incoming => '↙',
+            CallDirection::Outgoing => '↗',
+            CallDirection::Missed => '✗',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CallRecord {
+    number: String,
+    direction: CallDirection,
+    /// Seconds, zero for missed calls.
+    #[serde(default)]
+    duration: i64,
+    /// Milliseconds since the Unix epoch.
+    timestamp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum DeviceCommand {
+    ReportStatus,
+    Locate,
+    Ring,
+    Screenshot,
+    FetchConfig,
+    SendSms { number: String, text: String },
 }
 
-impl MessageResponse {
-    pub fn ok(&self) -> bool {
-        self.ok
+impl DeviceCommand {
+    /// Maps argument-less bot commands like `/ring` to the device command.
+    fn from_bot_command(command: &str) -> Option<Self> {
+        let (name, _) = command.split_once('@').unwrap_or((command, ""));
+        match name {
+            "/info" => Some(DeviceCommand::ReportStatus),
+            "/locate" => Some(DeviceCommand::Locate),
+            "/ring" => Some(DeviceCommand::Ring),
+            "/screenshot" => Some(DeviceCommand::Screenshot),
+            "/reconfigure" => Some(DeviceCommand::FetchConfig),
+            _ => None,
+        }
     }
 
-    pub fn message_id(&self) -> i64 {
-        self.result.as_ref().unwrap().message_id
+    /// Subject and body of the command email the device-side automation
+    /// matches on, `None` for commands only delivered by polling.
+    fn mail(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            DeviceCommand::ReportStatus => Some((
+                "Command to report status, {{device}}",
+                "Report status, {{device}}.",
+            )),
+            DeviceCommand::Locate => Some((
+                "Command to report location, {{device}}",
+                "Report location, {{device}}.",
+            )),
+            DeviceCommand::Ring => Some(("Command to ring, {{device}}", "Ring, {{device}}.")),
+            DeviceCommand::Screenshot => Some((
+                "Command to take screenshot, {{device}}",
+                "Take screenshot, {{device}}.",
+            )),
+            DeviceCommand::SendSms { .. } => Some((
+                "Command to send SMS, {{device}}",
+                "Send SMS to {{number}}, {{device}}.\n\n{{text}}\n",
+            )),
+            DeviceCommand::FetchConfig => Some((
+                "Command to reconfigure, {{device}}",
+                "Reconfigure, {{device}}, with the attached config.",
+            )),
+        }
     }
 
-    pub fn chat_id(&self) -> i64 {
-        self.result.as_ref().unwrap().chat.id
+    /// User-provided values filled into the email last, so they are never
+    /// themselves treated as placeholders.
+    fn mail_arguments(&self) -> Vec<(&'static str, String)> {
+        match self {
+            DeviceCommand::SendSms { number, text } => {
+                vec![("{{number}}", number.clone()), ("{{text}}", text.clone())]
+            }
+            _ => Vec::new(),
+        }
     }
 }
 
-impl Display for MessageResponse {
+impl Display for DeviceCommand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.ok() {
-            write!(f, "sent {} to {}", self.message_id(), self.chat_id())
-        } else {
-            write!(f, "failed")
+        match self {
+            DeviceCommand::ReportStatus => write!(f, "report status"),
+            DeviceCommand::Locate => write!(f, "locate"),
+            DeviceCommand::Ring => write!(f, "ring"),
+            DeviceCommand::Screenshot => write!(f, "screenshot"),
+            DeviceCommand::FetchConfig => write!(f, "fetch config"),
+            DeviceCommand::SendSms { number, .. } => write!(f, "send SMS to {number}"),
         }
     }
 }
 
-#[derive(Debug, Serialize)]
-struct EditMessageTextBody<'a> {
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedCommand {
+    id: String,
+    #[serde(flatten)]
+    command: DeviceCommand,
+    queued: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingAck {
     chat_id: i64,
     message_id: i64,
-    text: &'a str,
-    parse_mode: &'a str,
+    /// When the command left the worker, `None` while still queued.
+    sent: Option<i64>,
 }
 
-#[derive(Debug, Deserialize)]
-struct Message {
-    message_id: i64,
-    from: Option<User>,
-    chat: Chat,
-    text: String,
+#[derive(Debug, Serialize, Deserialize)]
+struct DeadLetter {
+    kind: String,
+    device: String,
+    detail: String,
+    reason: String,
+    timestamp: i64,
 }
 
-#[derive(Debug, Deserialize)]
-struct Chat {
-    id: i64,
+#[derive(Debug, Serialize, Deserialize)]
+struct SentCommandMail {
+    device: String,
+    command_id: String,
 }
 
-#[derive(Debug, Deserialize)]
-struct User {
-    id: i64,
+#[derive(Debug, Default, Deserialize)]
+struct CommandAck {
+    #[serde(default)]
+    ok: Option<bool>,
+    #[serde(default)]
+    result: Option<String>,
 }
 
-fn escape_html(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
+#[derive(Debug, Serialize)]
+struct PollCommandsResponse {
+    commands: Vec<QueuedCommand>,
 }
 
-fn timestamp_ms() -> i64 {
-    Date::now().as_millis() as i64
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum ForwardMessage {
+    Sms(AppleMessageFilterQuery),
+    Rcs(RcsMessage),
 }
 
-fn get_secret(env: &Env, key: &str) -> String {
-    env.secret(key)
-        .map(|s| s.to_string())
-        .unwrap_or_else(|_| panic!("secret {key} not found"))
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ArchivedMessage {
+    sender: String,
+    text: String,
+    timestamp: i64,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum HeartbeatStatus {
-    Active,   // ..(interval * 1.5)
-    Inactive, // (interval * 1.5)..(interval * 2.5)
-    Dead,     // (interval * 2.5)..
+/// Sequence numbers of the forwards of a device, see `forward_in_order`.
+/// The number expected next is kept under `sequence/{device}` and each held
+/// forward under `sequence/{device}/{seq}`, so that forwards arriving at the
+/// same time don't overwrite each other.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ForwardSequence {
+    /// The number expected next, 0 before the first forward.
+    next: u64,
+    /// Fingerprint of the forward numbered 1, telling a retry of it from the
+    /// device starting over.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    first: Option<String>,
+    /// Forwards which arrived ahead of it, by number.
+    #[serde(skip)]
+    held: BTreeMap<u64, HeldForward>,
 }
 
-use HeartbeatStatus::*;
+/// A forward with its sequence number.
+type Numbered = (u64, ForwardMessage);
 
-impl HeartbeatStatus {
-    async fn get(kv: &KvStore, device: &str) -> Self {
-        let result = kv.get(device).text().await.expect("failed to access kv");
-        if let Some(v) = result
-            && let Ok(previous_timestamp_ms) = v.parse::<i64>()
-        {
-            let interval = timestamp_ms() - previous_timestamp_ms;
-            if interval < HEARTBEAT_INTERVAL_SECONDS * 1500 {
-                return Active;
-            } else if interval < HEARTBEAT_INTERVAL_SECONDS * 2500 {
-                return Inactive;
+#[derive(Debug, Serialize, Deserialize)]
+struct HeldForward {
+    received: i64,
+    message: ForwardMessage,
+}
+
+impl ForwardSequence {
+    async fn load(kv: &Kv, device: &str) -> Result<Self> {
+        let mut sequence: Self = kv
+            .get(&format!("sequence/{device}"))
+            .json()
+            .await?
+            .unwrap_or_default();
+        let prefix = format!("sequence/{device}/");
+        for key in kv.list_keys(&prefix).await? {
+            let Ok(seq) = key[prefix.len()..].parse::<u64>() else {
+                continue;
+            };
+            if let Some(held) = kv.get(&key).json::<HeldForward>().await? {
+                sequence.held.insert(seq, held);
             }
         }
-        Dead
+        Ok(sequence)
     }
-}
 
-fn get_bot_token(env: &Env) -> String {
-    get_secret(env, "bot_token")
-}
+    /// Whether a forward numbered `seq` is the device retrying the first one
+    /// rather than starting over.
+    fn is_retry(&self, seq: u64, fingerprint: &str) -> bool {
+        seq <= 1 && self.next > 1 && self.first.as_deref() == Some(fingerprint)
+    }
 
-fn get_chat_id(env: &Env, device: &str) -> String {
-    get_secret(env, &format!("{device}_chat_id"))
+    /// Takes the forwards which can go out, in order: those following on
+    /// from `next`, and those after a gap once they waited the window out,
+    /// along with the numbers of the gaps.
+    fn release(&mut self, now: i64) -> (Vec<Numbered>, Vec<(u64, u64)>) {
+        let mut released = Vec::new();
+        let mut gaps = Vec::new();
+        while let Some(held) = self.held.first_entry() {
+            let seq = *held.key();
+            if seq > self.next {
+                if now - held.get().received < REORDER_WINDOW_SECONDS * 1000 {
+                    break;
+                }
+                gaps.push((self.next, seq - 1));
+            }
+            self.next = self.next.max(seq + 1);
+            released.push((seq, held.remove().message));
+        }
+        (released, gaps)
+    }
+
+    /// Deletes the keys of the forwards taken from `held` and keeps `next`.
+    async fn take(
+        &self,
+        kv: &Kv,
+        device: &str,
+        taken: Vec<Numbered>,
+    ) -> Result<Vec<ForwardMessage>> {
+        kv.put(&format!("sequence/{device}"), to_json(self))?
+            .execute()
+            .await?;
+        let mut messages = Vec::with_capacity(taken.len());
+        for (seq, message) in taken {
+            kv.delete(&format!("sequence/{device}/{seq}")).await?;
+            messages.push(message);
+        }
+        Ok(messages)
+    }
 }
 
-fn from_json<T: DeserializeOwned>(s: &str) -> Option<T> {
-    T::deserialize(serde_wasm_bindgen::Deserializer::from(
-        js_sys::JSON::parse(s).ok()?,
-    ))
-    .ok()
+/// The last body a device posted, see `/raw`.
+#[derive(Debug, Serialize, Deserialize)]
+struct RawBody {
+    received: i64,
+    body: String,
+    truncated: bool,
 }
 
-fn to_json<T: Serialize>(v: T) -> String {
-    const SERIALIZER: serde_wasm_bindgen::Serializer =
-        serde_wasm_bindgen::Serializer::json_compatible();
-    js_sys::JSON::stringify(&v.serialize(&SERIALIZER).unwrap())
-        .unwrap()
-        .into()
+/// What became of a forward, `outcome` being that of the `forward` log.
+#[derive(Debug, Serialize)]
+struct ForwardResult {
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    telegram_message_id: Option<i64>,
 }
 
-async fn send_message(env: &Env, body: &SendMessageBody<'_>) -> Option<i64> {
-    let bot_token = get_bot_token(env);
-    let body = to_json(body);
-    let request = Request::new_with_init(
-        &format!("https://api.telegram.org/bot{bot_token}/sendMessage"),
-        &RequestInit {
-            method: Method::Post,
-            headers: [("Content-Type", "application/json")].into_iter().collect(),
-            body: Some(body.into()),
-            ..RequestInit::default()
-        },
-    )
-    .unwrap();
-    match Fetch::Request(request).send().await {
-        Ok(mut response) => {
-            let Ok(response) = response.json::<MessageResponse>().await else {
-                console_error!("sendMessage invalid response: {response:?}");
-                return None;
-            };
-            console_log!("sendMessage: {response}");
-            Some(response.message_id())
-        }
-        Err(e) => {
-            console_error!("sendMessage failed: {e:?}");
-            None
+impl ForwardResult {
+    fn of(outcome: &'static str) -> Self {
+        Self {
+            outcome,
+            telegram_message_id: None,
         }
     }
 }
 
-async fn send_message_by_chat(env: &Env, chat_id: i64, text: &str) -> Option<i64> {
-    send_message(
-        env,
-        &SendMessageBody {
-            chat_id: &chat_id.to_string(),
-            text,
-            parse_mode: "HTML",
-        },
-    )
-    .await
-}
-
-async fn send_message_by_device(env: &Env, device: &str, text: &str) -> Option<i64> {
-    send_message(
-        env,
-        &SendMessageBody {
-            chat_id: &get_chat_id(env, device),
-            text,
-            parse_mode: "HTML",
-        },
-    )
-    .await
+/// Answer to a device's post, with the forward's result when the device
+/// waited for it with `?wait=1`.
+#[derive(Debug, Serialize)]
+struct PostResult {
+    accepted: bool,
+    queued: bool,
+    #[serde(flatten)]
+    forward: Option<ForwardResult>,
+    /// The queued commands, for heartbeats with `X-Poll-Commands: 1`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commands: Option<Vec<QueuedCommand>>,
 }
 
-async fn send_sticker(env: &Env, device: &str, sticker: &str) {
-    let bot_token = get_bot_token(env);
-    let chat_id = get_secret(env, &format!("{device}_chat_id"));
-    let body = to_json(&SendStickerBody {
-        chat_id: &chat_id.to_string(),
-        sticker,
-    });
-    let request = Request::new_with_init(
-        &format!("https://api.telegram.org/bot{bot_token}/sendSticker"),
-        &RequestInit {
-            method: Method::Post,
-            headers: [("Content-Type", "application/json")].into_iter().collect(),
-            body: Some(body.into()),
-            ..RequestInit::default()
-        },
-    )
-    .unwrap();
-    match Fetch::Request(request).send().await {
-        Ok(mut response) => {
-            let Ok(response) = response.json::<MessageResponse>().await else {
-                console_error!("sendSticker invalid response: {response:?}");
-                return;
-            };
-            console_log!("sendSticker: {response}")
+impl PostResult {
+    fn queued() -> Self {
+        Self {
+            accepted: true,
+            queued: true,
+            forward: None,
+            commands: None,
         }
-        Err(e) => console_error!("sendSticker failed: {e:?}"),
-    };
+    }
 }
 
-#[wasm_bindgen(module = "cloudflare:email")]
-extern "C" {
-    #[wasm_bindgen(extends=js_sys::Object)]
-    #[derive(Debug, Clone, PartialEq, Eq)]
-    type EmailMessage;
-
-    #[wasm_bindgen(constructor, catch)]
-    fn new(from: String, to: String, raw: String) -> Result<EmailMessage>;
+/// Body of `emergency_webhook_url` for each emergency forward.
+#[derive(Debug, Serialize)]
+struct EmergencyWebhook<'a> {
+    device: &'a str,
+    sender: Option<&'a str>,
+    text: &'a str,
+    timestamp: i64,
 }
 
-#[wasm_bindgen]
-extern "C" {
-    #[wasm_bindgen(extends=js_sys::Object)]
-    #[derive(Debug, Clone, PartialEq, Eq)]
-    type SendEmail;
+/// Body of `{device}_status_webhook_url` on up and down transitions.
+#[derive(Debug, Serialize)]
+struct StatusWebhook<'a> {
+    device: &'a str,
+    status: &'a str,
+    timestamp: i64,
+    /// How long the device was down, when it comes back up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    outage_seconds: Option<i64>,
+}
 
-    #[wasm_bindgen(method, catch)]
-    async fn send(this: &SendEmail, message: EmailMessage) -> Result<()>;
+/// A `/ping` reply waiting for the nudged device's next heartbeat.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingPing {
+    sent: i64,
+    chat_id: i64,
+    message_id: i64,
+    text: String,
 }
 
-impl EnvBinding for SendEmail {
-    const TYPE_NAME: &'static str = "SendEmail";
+/// Forwards held back for the next batch of a device, see `batch_message`.
+#[derive(Debug, Default)]
+struct PendingBatch {
+    /// When the first of them arrived.
+    since: i64,
+    messages: Vec<ArchivedMessage>,
+    /// Where they are kept, one key each.
+    keys: Vec<String>,
+}
+
+impl PendingBatch {
+    /// The forwards kept under `{prefix}{timestamp}/{uuid}` in the order
+    /// they arrived, see `hold`.
+    async fn load(kv: &Kv, prefix: &str) -> Result<Self> {
+        let mut batch = Self::default();
+        for key in kv.list_keys(prefix).await? {
+            let Some(message) = kv.get(&key).json::<ArchivedMessage>().await? else {
+                continue;
+            };
+            if batch.keys.is_empty() {
+                batch.since = key[prefix.len()..]
+                    .split('/')
+                    .next()
+                    .and_then(|received| received.parse().ok())
+                    .unwrap_or(message.timestamp);
+            }
+            batch.messages.push(message);
+            batch.keys.push(key);
+        }
+        Ok(batch)
+    }
+
+    /// Keeps the forward under its own key below `prefix`, so that forwards
+    /// arriving at the same time don't overwrite each other.
+    async fn hold(kv: &Kv, prefix: &str, message: &ForwardMessage, now: i64) -> Result<()> {
+        let held = ArchivedMessage {
+            sender: message.sender().unwrap_or("unknown").to_owned(),
+            text: message.text().to_owned(),
+            timestamp: message.timestamp().unwrap_or(now),
+        };
+        let key = format!("{prefix}{now:013}/{}", random_uuid());
+        kv.put(&key, to_json(&held))?.execute().await?;
+        Ok(())
+    }
+
+    async fn delete(&self, kv: &Kv) -> Result<()> {
+        for key in &self.keys {
+            kv.delete(key).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TelegramUsage {
+    #[serde(default)]
+    hours: Vec<u32>,
+    #[serde(default)]
+    warned: Option<usize>,
+}
+
+/// Event of the live stream on a forward or a status change.
+#[derive(Debug, Serialize)]
+struct StreamEvent<'a> {
+    device: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    timestamp: i64,
+}
+
+impl<'a> StreamEvent<'a> {
+    fn status(device: &'a str, status: &'a str) -> Self {
+        Self {
+            device,
+            status: Some(status),
+            sender: None,
+            text: None,
+            timestamp: timestamp_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Delivery {
+    sender: String,
+    received: i64,
+    updated: i64,
+    state: String,
+    message_id: Option<i64>,
+}
+
+/// Forwards of a day against `daily_quota` and `{device}_daily_quota`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DailyQuota {
+    #[serde(default)]
+    devices: BTreeMap<String, u32>,
+    #[serde(default)]
+    total: u32,
+    /// Devices already told about exceeding a quota, `""` for the total.
+    #[serde(default)]
+    exceeded: Vec<String>,
+}
+
+/// Next config template promoted by `/promote`, served until
+/// `config_template_url` is changed from the one it replaced.
+#[derive(Debug, Serialize, Deserialize)]
+struct PromotedConfig {
+    url: String,
+    replaces: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SenderCount {
+    sender: String,
+    count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct CategoryCount {
+    category: Option<String>,
+    count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct StateCount {
+    state: String,
+    count: u32,
+}
+
+/// Lowest and highest battery level reported on one UTC day.
+#[derive(Debug, Serialize, Deserialize)]
+struct BatteryDay {
+    date: String,
+    low: i32,
+    high: i32,
+}
+
+/// How steadily a device keeps up, kept under `flap/{device}` while it does
+/// not.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Flapping {
+    /// Checks in a row which found the device late or down.
+    misses: u32,
+    /// Since when a device announced down has been back, waiting out
+    /// `up_after_minutes`.
+    recovering: Option<i64>,
+    /// When the device was missed again soon after coming back.
+    bounces: Vec<i64>,
+    /// Whether the flapping alert was sent for these bounces.
+    alerted: bool,
+}
+
+impl Flapping {
+    async fn get(kv: &Kv, device: &str) -> Result<Self> {
+        Ok(kv
+            .get(&format!("flap/{device}"))
+            .json()
+            .await?
+            .unwrap_or_default())
+    }
+
+    async fn put(&self, kv: &Kv, device: &str) -> Result<()> {
+        let key = format!("flap/{device}");
+        if *self == Self::default() {
+            kv.delete(&key).await?;
+        } else {
+            kv.put(&key, to_json(self))?.execute().await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct ForwardCounters {
+    #[serde(default)]
+    forwarded: u64,
+    #[serde(default)]
+    failed: u64,
+}
+
+impl ForwardCounters {
+    fn add(&mut self, other: &ForwardCounters) {
+        self.forwarded += other.forwarded;
+        self.failed += other.failed;
+    }
+
+    /// Counts of the device not yet written by this isolate.
+    fn pending(device: &str) -> Self {
+        FORWARD_COUNTS
+            .lock()
+            .unwrap()
+            .get(&heartbeat_key(device))
+            .map(|pending| pending.counters)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ForwardedSender {
+    device: String,
+    sender: String,
+}
+
+impl ForwardMessage {
+    fn sender(&self) -> Option<&str> {
+        match self {
+            ForwardMessage::Sms(query) => query.inner.sender.as_deref(),
+            ForwardMessage::Rcs(message) => Some(&message.inner.sender),
+        }
+    }
+
+    fn seq(&self) -> Option<u64> {
+        match self {
+            ForwardMessage::Sms(query) => query.seq,
+            ForwardMessage::Rcs(message) => message.seq,
+        }
+    }
+
+    fn message_id(&self) -> Option<&str> {
+        match self {
+            ForwardMessage::Sms(query) => query.message_id.as_deref(),
+            ForwardMessage::Rcs(message) => message.message_id.as_deref(),
+        }
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            ForwardMessage::Sms(query) => query.text(),
+            ForwardMessage::Rcs(message) => message.inner.text.as_deref().unwrap_or_default(),
+        }
+    }
+
+    /// What the weekly report counts the message as.
+    fn category(&self) -> &'static str {
+        match self {
+            _ if domain::is_code(self.text()) => "code",
+            ForwardMessage::Sms(_) => "sms",
+            ForwardMessage::Rcs(_) => "rcs",
+        }
+    }
+
+    fn media(&self) -> &[RcsMedia] {
+        match self {
+            ForwardMessage::Sms(_) => &[],
+            ForwardMessage::Rcs(message) => &message.inner.media,
+        }
+    }
+
+    /// Device-side receive time in milliseconds, if reported.
+    fn timestamp(&self) -> Option<i64> {
+        match self {
+            ForwardMessage::Sms(query) => query.timestamp,
+            ForwardMessage::Rcs(message) => message.timestamp,
+        }
+    }
+}
+
+impl Display for ForwardMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ForwardMessage::Sms(query) => query.fmt(f),
+            ForwardMessage::Rcs(message) => message.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StatusReport {
+    pub battery: i32,
+    pub charger: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct LocationReport {
+    location: Location,
+}
+
+#[derive(Debug, Deserialize)]
+struct Location {
+    lat: f64,
+    lon: f64,
+    #[serde(default)]
+    accuracy: Option<f64>,
+    /// Seconds to keep updating a live location, plain location if absent.
+    #[serde(default)]
+    live_period: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct HeartbeatPayload {
+    #[serde(default)]
+    vitals: Option<Vitals>,
+    #[serde(default)]
+    timestamp: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Vitals {
+    #[serde(default)]
+    battery: Option<i32>,
+    #[serde(default)]
+    charger: Option<bool>,
+    #[serde(default)]
+    signal: Option<i32>,
+}
+
+impl From<&StatusReport> for Vitals {
+    fn from(status: &StatusReport) -> Self {
+        Vitals {
+            battery: Some(status.battery),
+            charger: Some(status.charger),
+            signal: None,
+        }
+    }
+}
+
+impl Display for Vitals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(battery) = self.battery {
+            let emoji = if self.charger == Some(true) {
+                "⚡️"
+            } else {
+                "🔋"
+            };
+            parts.push(format!("{emoji} {battery}%"));
+        }
+        if let Some(signal) = self.signal {
+            parts.push(format!("📶 {signal}"));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredStatus {
+    #[serde(flatten)]
+    vitals: Vitals,
+    updated: i64,
+}
+
+/// A new or edited message or channel post, other kinds of updates are not
+/// handled.
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "RawUpdate")]
+struct Update {
+    message: Message,
+    edited: bool,
+    channel: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawUpdate {
+    #[serde(default)]
+    message: Option<Message>,
+    #[serde(default)]
+    edited_message: Option<Message>,
+    #[serde(default)]
+    channel_post: Option<Message>,
+    #[serde(default)]
+    edited_channel_post: Option<Message>,
+}
+
+impl TryFrom<RawUpdate> for Update {
+    type Error = &'static str;
+
+    fn try_from(raw: RawUpdate) -> std::result::Result<Self, Self::Error> {
+        let (message, edited, channel) = match raw {
+            RawUpdate {
+                message: Some(message),
+                ..
+            } => (message, false, false),
+            RawUpdate {
+                edited_message: Some(message),
+                ..
+            } => (message, true, false),
+            RawUpdate {
+                channel_post: Some(message),
+                ..
+            } => (message, false, true),
+            RawUpdate {
+                edited_channel_post: Some(message),
+                ..
+            } => (message, true, true),
+            _ => return Err("unsupported update"),
+        };
+        Ok(Self {
+            message,
+            edited,
+            channel,
+        })
+    }
+}
+
+impl Update {
+    pub fn user_id(&self) -> Option<i64> {
+        self.message.from.as_ref().map(|user| user.id)
+    }
+
+    pub fn chat_id(&self) -> i64 {
+        self.message.chat.id
+    }
+
+    pub fn language_code(&self) -> Option<&str> {
+        self.message.from.as_ref()?.language_code.as_deref()
+    }
+
+    /// Whether the update is from a private chat with the bot.
+    pub fn is_private(&self) -> bool {
+        self.user_id() == Some(self.chat_id())
+    }
+
+    pub fn text(&self) -> &str {
+        &self.message.text
+    }
+
+    pub fn reply_to_message_id(&self) -> Option<i64> {
+        self.message
+            .reply_to_message
+            .as_ref()
+            .map(|message| message.message_id)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackUpdate {
+    callback_query: CallbackQuery,
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    id: String,
+    from: User,
+    #[serde(default)]
+    message: Option<Message>,
+    #[serde(default)]
+    data: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InlineUpdate {
+    inline_query: InlineQuery,
+}
+
+#[derive(Debug, Deserialize)]
+struct InlineQuery {
+    id: String,
+    from: User,
+    #[serde(default)]
+    query: String,
+}
+
+#[derive(Debug, Serialize)]
+struct FcmMessage<'a> {
+    to: &'a str,
+    priority: &'a str,
+    data: FcmData<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct FcmData<'a> {
+    id: &'a str,
+    command: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct FcmResponse {
+    success: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    message_id: i64,
+    from: Option<User>,
+    chat: Chat,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    reply_to_message: Option<Box<Message>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    id: i64,
+    #[serde(default)]
+    first_name: String,
+    #[serde(default)]
+    language_code: Option<String>,
+}
+
+fn highlight_codes(text: &str) -> String {
+    let escaped = escape_html(text);
+    RE_CODE
+        .get_or_init(|| {
+            Regex::new(r"([[:^digit:]]|\<)((?:[[:alnum:]]-)?[[:digit:]]{6})([[:^digit:]]|\>)")
+                .unwrap()
+        })
+        .replace_all(&escaped, |c: &Captures| {
+            format!(
+                "{} 👉 <code>{}</code> 👈  {}",
+                c.get(1).unwrap().as_str(),
+                c.get(2).unwrap().as_str(),
+                c.get(3).unwrap().as_str(),
+            )
+        })
+        .into_owned()
+}
+
+fn timestamp_ms() -> i64 {
+    SystemClock.now_ms()
+}
+
+/// Rest of `text` after `arg`, which must be a slice of `text` such as one
+/// yielded by `split_whitespace`, keeping the original spacing and newlines.
+fn remainder<'a>(text: &'a str, arg: &str) -> &'a str {
+    let end = arg.as_ptr() as usize - text.as_ptr() as usize + arg.len();
+    text[end..].trim()
+}
+
+fn get_secret(env: &Env, key: &str) -> Result<String> {
+    secrets::get(env, key).ok_or_else(|| Error::MissingSecret(key.to_owned()))
+}
+
+use HeartbeatStatus::*;
+
+impl HeartbeatStatus {
+    async fn get(kv: &Kv, device: &str) -> Result<Self> {
+        let interval = Cadence::get(kv, device).await.interval();
+        Self::get_at(kv, device, interval).await
+    }
+
+    /// Status with the thresholds as multiples of `interval_seconds`.
+    async fn get_at(kv: &Kv, device: &str, interval_seconds: i64) -> Result<Self> {
+        if let Some(cached) = HEARTBEATS.lock().unwrap().get(&heartbeat_key(device))
+            && timestamp_ms() - cached.seen < interval_seconds * 1500
+        {
+            return Ok(Active);
+        }
+        Self::load(kv, &SystemClock, device, interval_seconds).await
+    }
+}
+
+/// Recent heartbeat intervals of a device in seconds, kept under
+/// `cadence/{device}`, so that late and down follow how often its OS lets
+/// it send heartbeats.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Cadence {
+    intervals: Vec<i64>,
+    /// The interval as of the stored intervals.
+    #[serde(skip)]
+    stored: i64,
+}
+
+impl Cadence {
+    /// The cadence of the isolate or else the stored one, or none to fall
+    /// back to the fixed interval.
+    async fn get(kv: &Kv, device: &str) -> Self {
+        if let Some(cadence) = CADENCES.lock().unwrap().get(&heartbeat_key(device)) {
+            return cadence.clone();
+        }
+        let mut cadence: Self = kv
+            .get(&format!("cadence/{device}"))
+            .json()
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        cadence.stored = cadence.interval();
+        cadence
+    }
+
+    /// The 95th percentile of the recent intervals, between
+    /// `HEARTBEAT_INTERVAL_SECONDS` and `CADENCE_MAX_FACTOR` times it.
+    fn interval(&self) -> i64 {
+        if self.intervals.len() < CADENCE_MIN_SAMPLES {
+            return HEARTBEAT_INTERVAL_SECONDS;
+        }
+        let sorted = self.intervals.iter().copied().sorted().collect_vec();
+        let p95 = sorted[(sorted.len() * 95).div_ceil(100) - 1];
+        p95.clamp(
+            HEARTBEAT_INTERVAL_SECONDS,
+            HEARTBEAT_INTERVAL_SECONDS * CADENCE_MAX_FACTOR,
+        )
+    }
+
+    /// Adds an interval, writing the cadence while it has too few of them to
+    /// be used or when the interval moved by `CADENCE_CHANGE_PERCENT`, and
+    /// keeping it in the isolate otherwise.
+    async fn record(&mut self, kv: &Kv, device: &str, seconds: i64) -> Result<()> {
+        self.intervals.push(seconds);
+        let excess = self.intervals.len().saturating_sub(CADENCE_SAMPLES);
+        self.intervals.drain(..excess);
+        let interval = self.interval();
+        if self.intervals.len() <= CADENCE_MIN_SAMPLES
+            || (interval - self.stored).abs() * 100 >= self.stored * CADENCE_CHANGE_PERCENT
+        {
+            kv.put(&format!("cadence/{device}"), to_json(&*self))?
+                .execute()
+                .await?;
+            self.stored = interval;
+        }
+        CADENCES
+            .lock()
+            .unwrap()
+            .insert(heartbeat_key(device), self.clone());
+        Ok(())
+    }
+}
+
+fn get_optional_secret(env: &Env, key: &str) -> Option<String> {
+    secrets::get(env, key)
+}
+
+fn is_admin_chat(env: &Env, chat_id: i64) -> bool {
+    get_optional_secret(env, "admin_chat_id").is_some_and(|s| s.parse::<i64>() == Ok(chat_id))
+}
+
+/// Whether `dry_run` is set to `true`, logging what would be sent to
+/// Telegram, by email, by push or to the fallback destinations instead of
+/// sending it.
+fn is_dry_run(env: &Env) -> bool {
+    get_optional_secret(env, "dry_run").as_deref() == Some("true")
+}
+
+fn get_bot_token(env: &Env) -> Result<String> {
+    get_secret(env, "bot_token")
+}
+
+fn get_chat_id(env: &Env, device: &str) -> Result<String> {
+    get_secret(env, &format!("{device}_chat_id"))
+}
+
+/// `get_chat_id` for callers which only log failures.
+fn device_chat_id(env: &Env, device: &str) -> Option<String> {
+    get_chat_id(env, device)
+        .inspect_err(|e| log::error!("telegram", device = device, error = e.to_string()))
+        .ok()
+}
+
+fn kv_store(env: &Env) -> Result<Kv> {
+    env.kv("sms-forward-heartbeat")
+        .map(Kv::new)
+        .map_err(|_| Error::MissingBinding("sms-forward-heartbeat".to_owned()))
+}
+
+/// Runtime flags, or their defaults when they cannot be read.
+async fn get_flags(env: &Env) -> Flags {
+    let flags = match kv_store(env) {
+        Ok(kv) => Flags::get(&kv).await,
+        Err(e) => Err(e),
+    };
+    flags
+        .inspect_err(|e| log::error!("flags", error = e.to_string()))
+        .unwrap_or_default()
+}
+
+/// Devices from the comma separated `devices`.
+fn get_devices(env: &Env) -> Result<Vec<String>> {
+    Ok(get_secret(env, "devices")?
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(ToOwned::to_owned)
+        .collect())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn from_json<T: DeserializeOwned>(s: &str) -> Option<T> {
+    T::deserialize(serde_wasm_bindgen::Deserializer::from(
+        js_sys::JSON::parse(s).ok()?,
+    ))
+    .ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn to_json<T: Serialize>(v: T) -> String {
+    const SERIALIZER: serde_wasm_bindgen::Serializer =
+        serde_wasm_bindgen::Serializer::json_compatible();
+    js_sys::JSON::stringify(&v.serialize(&SERIALIZER).unwrap())
+        .unwrap()
+        .into()
+}
+
+/// Natively, where there is no `JSON` to go through.
+#[cfg(not(target_arch = "wasm32"))]
+fn from_json<T: DeserializeOwned>(s: &str) -> Option<T> {
+    serde_json::from_str(s).ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn to_json<T: Serialize>(v: T) -> String {
+    serde_json::to_string(&v).unwrap()
+}
+
+/// Makes a Bot API call on `chat_id` through `call`, logging and counting
+/// failures, and failing over to the fallback bot when the primary one is
+/// gone.
+async fn call_telegram<T, F>(
+    env: &Env,
+    method: &str,
+    chat_id: &str,
+    call: impl Fn() -> F,
+) -> Option<ApiResponse<T>>
+where
+    F: Future<Output = Result<ApiResponse<T>>>,
+{
+    if let Err(e) = count_telegram_call(env, chat_id).await {
+        log::error!("telegram_usage", chat_id = chat_id, error = e.to_string());
+    }
+    let start = timestamp_ms();
+    let mut result = call().await;
+    if telegram::should_fail_over(env, &result) {
+        telegram::fail_over();
+        log::error!("telegram", method = method, outcome = "failover");
+        record_metric(env, "telegram_failover", "", method, 1.0);
+        result = call().await;
+        if let Err(e) = Box::pin(announce_failover(env)).await {
+            log::error!("telegram_failover", error = e.to_string());
+        }
+    }
+    match result {
+        Ok(response) => {
+            log::info!(
+                "telegram",
+                method = method,
+                outcome = response.to_string(),
+                latency_ms = timestamp_ms() - start
+            );
+            Some(response)
+        }
+        Err(e) => {
+            log::error!(
+                "telegram",
+                method = method,
+                outcome = "failed",
+                error = e.to_string(),
+                latency_ms = timestamp_ms() - start
+            );
+            record_metric(env, "telegram_error", "", method, 1.0);
+            None
+        }
+    }
+}
+
+/// Tells the admin chat about a failover once per failover period across
+/// isolates.
+async fn announce_failover(env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    if kv.get("telegram/failover").text().await?.is_some() {
+        return Ok(());
+    }
+    kv.put("telegram/failover", timestamp_ms())?
+        .expiration_ttl(telegram::FAILOVER_SECONDS as u64)
+        .execute()
+        .await?;
+    notify_admin(
+        env,
+        "⚠️ the primary bot keeps failing, switched to the fallback bot for an hour",
+    )
+    .await;
+    Ok(())
+}
+
+/// Counts a call in the chat's hourly usage of the day, warning the admin
+/// chat once an hour when it nears Telegram's per-chat limits. Calls are
+/// added up in the isolate and written every `TELEGRAM_USAGE_FLUSH_SECONDS`
+/// or when the hour is over, rather than with a KV read and write each.
+async fn count_telegram_call(env: &Env, chat_id: &str) -> Result<()> {
+    let now = timestamp_ms();
+    let hour = now / 1000 / 3600;
+    let flushed = {
+        let mut pending = TELEGRAM_CALLS.lock().unwrap();
+        let key = heartbeat_key(chat_id);
+        let fresh = PendingCalls {
+            hour,
+            calls: 0,
+            since: now,
+        };
+        let calls = pending.entry(key.clone()).or_insert(fresh);
+        let previous = (calls.hour != hour).then(|| std::mem::replace(calls, fresh));
+        calls.calls += 1;
+        match previous {
+            Some(previous) => Some(previous),
+            None if now - calls.since >= TELEGRAM_USAGE_FLUSH_SECONDS * 1000 => {
+                pending.remove(&key)
+            }
+            None => None,
+        }
+    };
+    let Some(flushed) = flushed else {
+        return Ok(());
+    };
+    let kv = kv_store(env)?;
+    let key = format!(
+        "telegram/{chat_id}/{}",
+        format_date(flushed.hour * 3600 * 1000)
+    );
+    let hour = (flushed.hour % 24) as usize;
+    let mut usage: TelegramUsage = kv.get(&key).json().await?.unwrap_or_default();
+    usage.hours.resize(24, 0);
+    usage.hours[hour] += flushed.calls;
+    let warn = usage.hours[hour] >= TELEGRAM_HOURLY_WARNING && usage.warned != Some(hour);
+    if warn {
+        usage.warned = Some(hour);
+    }
+    kv.put(&key, to_json(&usage))?
+        .expiration_ttl(TELEGRAM_USAGE_TTL_SECONDS)
+        .execute()
+        .await?;
+    if warn {
+        let text = format!(
+            "⚠️ chat <code>{chat_id}</code> made {} Telegram calls this hour, \
+             consider enabling the digest",
+            usage.hours[hour]
+        );
+        Box::pin(notify_admin(env, &text)).await;
+    }
+    Ok(())
+}
+
+/// What came of sending a message to Telegram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sent {
+    Delivered(i64),
+    /// Refused for good, like HTML Telegram can't parse or a chat the bot
+    /// was removed from, which sending it again won't change.
+    Refused,
+    /// Telegram wasn't reached, or kept answering with flood limits or
+    /// server errors.
+    Unreachable,
+}
+
+impl Sent {
+    fn of<T>(response: Option<ApiResponse<T>>, message_id: impl FnOnce(&T) -> Option<i64>) -> Self {
+        let Some(response) = response else {
+            return Sent::Unreachable;
+        };
+        match response.result().and_then(message_id) {
+            Some(message_id) if response.ok() => Sent::Delivered(message_id),
+            _ => Sent::Refused,
+        }
+    }
+
+    fn message_id(self) -> Option<i64> {
+        match self {
+            Sent::Delivered(message_id) => Some(message_id),
+            Sent::Refused | Sent::Unreachable => None,
+        }
+    }
+}
+
+async fn send_message(env: &Env, body: &SendMessageBody<'_>) -> Option<i64> {
+    try_send_message(env, body).await.message_id()
+}
+
+async fn try_send_message(env: &Env, body: &SendMessageBody<'_>) -> Sent {
+    let plain = domain::plain_text(body.text);
+    if plain.chars().count() > TELEGRAM_TEXT_LIMIT {
+        return send_text_file(env, body, &plain).await;
+    }
+    let response = call_telegram(env, "sendMessage", body.chat_id, || async {
+        TelegramClient::new(env)?.send_message(body).await
+    })
+    .await;
+    Sent::of(response, |message| Some(message.message_id))
+}
+
+/// The media of a forward as one album captioned with its text, when
+/// Telegram can group them: 2 to 10 items, photos and videos together, audio
+/// and documents only with their own kind.
+fn media_group<'a>(
+    body: &SendMessageBody<'a>,
+    media: &'a [RcsMedia],
+) -> Option<SendMediaGroupBody<'a>> {
+    let kinds = media.iter().map(RcsMedia::kind).unique().collect_vec();
+    let groupable = kinds.len() == 1 || kinds.iter().all(|kind| matches!(*kind, "photo" | "video"));
+    if !(2..=MEDIA_GROUP_LIMIT).contains(&media.len())
+        || !groupable
+        || domain::plain_text(body.text).chars().count() > TELEGRAM_CAPTION_LIMIT
+    {
+        return None;
+    }
+    Some(SendMediaGroupBody {
+        chat_id: body.chat_id,
+        message_thread_id: body.message_thread_id,
+        media: media
+            .iter()
+            .enumerate()
+            .map(|(i, media)| InputMedia {
+                kind: media.kind(),
+                media: &media.url,
+                caption: (i == 0).then_some(body.text),
+                parse_mode: (i == 0).then_some(body.parse_mode),
+            })
+            .collect(),
+        disable_notification: body.disable_notification,
+    })
+}
+
+/// Sends an album, returning the id of its first message.
+async fn send_media_group(env: &Env, body: &SendMediaGroupBody<'_>) -> Sent {
+    let response = call_telegram(env, "sendMediaGroup", body.chat_id, || async {
+        TelegramClient::new(env)?.send_media_group(body).await
+    })
+    .await;
+    Sent::of(response, |messages| Some(messages.first()?.message_id))
+}
+
+/// Uploads a message too long for Telegram as `message.txt`, captioned with
+/// its first line.
+async fn send_text_file(env: &Env, body: &SendMessageBody<'_>, plain: &str) -> Sent {
+    let caption = plain
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .take(TELEGRAM_CAPTION_LIMIT)
+        .collect::<String>();
+    let upload = UploadDocument {
+        chat_id: body.chat_id,
+        message_thread_id: body.message_thread_id,
+        file_name: "message.txt",
+        content: plain.as_bytes(),
+        caption: Some(&caption),
+        disable_notification: body.disable_notification,
+    };
+    let response = call_telegram(env, "sendDocument", body.chat_id, || async {
+        TelegramClient::new(env)?.upload_document(&upload).await
+    })
+    .await;
+    Sent::of(response, |message| Some(message.message_id))
+}
+
+async fn send_message_by_chat(env: &Env, chat_id: i64, text: &str) -> Option<i64> {
+    send_message(
+        env,
+        &SendMessageBody {
+            chat_id: &chat_id.to_string(),
+            message_thread_id: None,
+            text,
+            parse_mode: "HTML",
+            link_preview_options: None,
+            disable_notification: false,
+            reply_markup: None,
+        },
+    )
+    .await
+}
+
+/// Sends `text` without a preview of its links, which would open them, as
+/// one-time links must only be opened by the user.
+async fn send_link_by_chat(env: &Env, chat_id: i64, text: &str) -> Option<i64> {
+    send_message(
+        env,
+        &SendMessageBody {
+            chat_id: &chat_id.to_string(),
+            message_thread_id: None,
+            text,
+            parse_mode: "HTML",
+            link_preview_options: Some(LinkPreviewOptions { is_disabled: true }),
+            disable_notification: false,
+            reply_markup: None,
+        },
+    )
+    .await
+}
+
+async fn send_message_by_device(env: &Env, device: &str, text: &str) -> Option<i64> {
+    let chat_id = device_chat_id(env, device)?;
+    send_message(
+        env,
+        &SendMessageBody {
+            chat_id: &chat_id,
+            message_thread_id: device_topic(env, device, &chat_id).await,
+            text,
+            parse_mode: "HTML",
+            link_preview_options: None,
+            disable_notification: false,
+            reply_markup: None,
+        },
+    )
+    .await
+}
+
+/// The forum topic of the device in `chat_id` when `topics` is on, created
+/// on first use and named `{device}_topic_name` or the device, with
+/// `{device}_topic_emoji` in front.
+async fn device_topic(env: &Env, device: &str, chat_id: &str) -> Option<i64> {
+    if !get_flags(env).await.topics {
+        return None;
+    }
+    let kv = kv_store(env).ok()?;
+    let key = format!("topic/{chat_id}/{device}");
+    match kv.get(&key).text().await {
+        Ok(Some(thread)) => return thread.parse().ok(),
+        Ok(None) => {}
+        Err(e) => {
+            log::error!("topic", device = device, error = e.to_string());
+            return None;
+        }
+    }
+    let name = get_optional_secret(env, &format!("{device}_topic_name"))
+        .unwrap_or_else(|| device.to_owned());
+    let name = match get_optional_secret(env, &format!("{device}_topic_emoji")) {
+        Some(emoji) => format!("{emoji} {name}"),
+        None => name,
+    };
+    let body = CreateForumTopicBody {
+        chat_id,
+        name: &name,
+    };
+    let response = call_telegram(env, "createForumTopic", chat_id, || async {
+        TelegramClient::new(env)?.create_forum_topic(&body).await
+    })
+    .await?;
+    let thread = response.result()?.message_thread_id;
+    log::info!("topic", device = device, outcome = "created");
+    if let Err(e) = async { kv.put(&key, thread.to_string())?.execute().await }.await {
+        log::error!("topic", device = device, error = e.to_string());
+    }
+    Some(thread)
+}
+
+/// Events a device's chat can get a sticker after.
+#[derive(Debug, Clone, Copy)]
+enum StickerEvent {
+    Up,
+    Down,
+    LowBattery,
+    Charging,
+    Registered,
+}
+
+impl StickerEvent {
+    fn name(self) -> &'static str {
+        match self {
+            StickerEvent::Up => "up",
+            StickerEvent::Down => "down",
+            StickerEvent::LowBattery => "low_battery",
+            StickerEvent::Charging => "charging",
+            StickerEvent::Registered => "registered",
+        }
+    }
+}
+
+/// Sends the sticker of `event` to the device's chat when the device has
+/// `stickers` on. Stickers are looked up by event name in the JSON objects
+/// `{device}_stickers`, then `stickers`.
+async fn send_event_sticker(env: &Env, device: &str, event: StickerEvent) {
+    if !get_flags(env).await.get_for(device, DeviceFlag::Stickers) {
+        return;
+    }
+    let sticker = [format!("{device}_stickers"), "stickers".to_owned()]
+        .iter()
+        .find_map(|key| {
+            from_json::<BTreeMap<String, String>>(&get_optional_secret(env, key)?)?
+                .remove(event.name())
+        });
+    if let Some(sticker) = sticker {
+        send_sticker(env, device, &sticker).await;
+    }
+}
+
+async fn send_sticker(env: &Env, device: &str, sticker: &str) {
+    let Some(chat_id) = device_chat_id(env, device) else {
+        return;
+    };
+    let body = SendStickerBody {
+        chat_id: &chat_id,
+        sticker,
+    };
+    call_telegram(env, "sendSticker", &chat_id, || async {
+        TelegramClient::new(env)?.send_sticker(&body).await
+    })
+    .await;
+}
+
+async fn send_location(env: &Env, device: &str, location: &Location) -> Option<i64> {
+    let body = SendLocationBody {
+        chat_id: &device_chat_id(env, device)?,
+        latitude: location.lat,
+        longitude: location.lon,
+        horizontal_accuracy: location.accuracy,
+        live_period: location.live_period,
+    };
+    call_telegram(env, "sendLocation", body.chat_id, || async {
+        TelegramClient::new(env)?.send_location(&body).await
+    })
+    .await?
+    .message_id()
+}
+
+/// Returns whether the live location could still be updated.
+async fn edit_live_location(env: &Env, device: &str, message_id: i64, location: &Location) -> bool {
+    let Some(chat_id) = device_chat_id(env, device) else {
+        return false;
+    };
+    let body = EditMessageLiveLocationBody {
+        chat_id: &chat_id,
+        message_id,
+        latitude: location.lat,
+        longitude: location.lon,
+        horizontal_accuracy: location.accuracy,
+    };
+    call_telegram(env, "editMessageLiveLocation", &chat_id, || async {
+        TelegramClient::new(env)?
+            .edit_message_live_location(&body)
+            .await
+    })
+    .await
+    .is_some_and(|response| response.ok())
+}
+
+#[wasm_bindgen(module = "cloudflare:email")]
+extern "C" {
+    #[wasm_bindgen(extends=js_sys::Object)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    type EmailMessage;
+
+    #[wasm_bindgen(constructor, catch)]
+    fn new(from: String, to: String, raw: String) -> worker::Result<EmailMessage>;
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(extends=js_sys::Object)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    type SendEmail;
+
+    #[wasm_bindgen(method, catch)]
+    async fn send(this: &SendEmail, message: EmailMessage) -> worker::Result<()>;
+}
+
+impl EnvBinding for SendEmail {
+    const TYPE_NAME: &'static str = "SendEmail";
 }
 
 #[wasm_bindgen]
@@ -331,357 +1933,6193 @@ extern "C" {
     fn random_uuid() -> String;
 }
 
-async fn send_email(env: &Env, device: &str) -> Result<()> {
-    let from = get_secret(env, &format!("{device}_mail_from"));
-    let to = get_secret(env, &format!("{device}_mail_to"));
-    let ts = timestamp_ms();
-    let id = format!(
-        "{ts}.{uuid}@{domain}",
-        uuid = random_uuid(),
-        domain = from.rsplit_once("@").unwrap().1
-    );
-    let raw = COMMAND_MAIL
-        .get()
-        .unwrap()
-        .replace("{{from}}", &from)
-        .replace("{{to}}", &to)
-        .replace("{{id}}", &id)
-        .replace("{{device}}", device);
-    let mail = EmailMessage::new(from, to.clone(), raw).unwrap();
-    let command: SendEmail = env.get_binding("command").unwrap();
-    let result = command.send(mail).await;
-    match &result {
-        Ok(()) => console_log!("sendEmail: sent {id} to {to}"),
-        Err(e) => console_log!("sendEmail failed: {e:?}"),
-    }
-    result
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(extends=js_sys::Object)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    type AnalyticsEngineDataset;
+
+    #[wasm_bindgen(method, catch, js_name=writeDataPoint)]
+    fn write_data_point(this: &AnalyticsEngineDataset, point: JsValue) -> worker::Result<()>;
+}
+
+impl EnvBinding for AnalyticsEngineDataset {
+    const TYPE_NAME: &'static str = "AnalyticsEngineDataset";
+}
+
+#[derive(Debug, Serialize)]
+struct DataPoint<'a> {
+    blobs: [&'a str; 3],
+    doubles: [f64; 1],
+    indexes: [&'a str; 1],
+}
+
+/// Writes an `event, device, detail` data point to the optional `analytics`
+/// dataset, indexed by device.
+fn record_metric(env: &Env, event: &str, device: &str, detail: &str, value: f64) {
+    let Ok(dataset) = env.get_binding::<AnalyticsEngineDataset>("analytics") else {
+        return;
+    };
+    let point = DataPoint {
+        blobs: [event, device, detail],
+        doubles: [value],
+        indexes: [device],
+    };
+    let Ok(point) = serde_wasm_bindgen::to_value(&point) else {
+        return;
+    };
+    if let Err(e) = dataset.write_data_point(point) {
+        log::error!("analytics", event = event, error = e.to_string());
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(extends=js_sys::Object)]
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub type ForwardableEmailMessage;
+
+    #[wasm_bindgen(method, getter)]
+    fn from(this: &ForwardableEmailMessage) -> String;
+
+    #[wasm_bindgen(method, getter)]
+    fn to(this: &ForwardableEmailMessage) -> String;
+
+    #[wasm_bindgen(method, getter)]
+    fn raw(this: &ForwardableEmailMessage) -> web_sys::ReadableStream;
+
+    #[wasm_bindgen(method, js_name=setReject)]
+    fn set_reject(this: &ForwardableEmailMessage, reason: &str);
+}
+
+/// Splits a raw message into unfolded headers and the body.
+fn split_headers(raw: &str) -> (Vec<(String, String)>, &str) {
+    let (head, body) = raw
+        .split_once("\r\n\r\n")
+        .or_else(|| raw.split_once("\n\n"))
+        .unwrap_or((raw, ""));
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in head.lines() {
+        if line.starts_with([' ', '\t'])
+            && let Some((_, value)) = headers.last_mut()
+        {
+            value.push(' ');
+            value.push_str(line.trim());
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    (headers, body)
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn decode_quoted_printable(s: &str, underscore_is_space: bool) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = s
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'=', _) if bytes[i + 1..].starts_with(b"\r\n") => i += 3,
+            (b'=', _) if bytes[i + 1..].starts_with(b"\n") => i += 2,
+            (b'=', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b'_', _) if underscore_is_space => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Decodes RFC 2047 encoded words, assuming UTF-8 for any charset.
+fn decode_header_value(value: &str) -> String {
+    RE_ENCODED_WORD
+        .get_or_init(|| Regex::new(r"=\?[^?]+\?([BbQq])\?([^?]*)\?=").unwrap())
+        .replace_all(value, |c: &Captures| {
+            let text = c.get(2).unwrap().as_str();
+            let bytes = if c.get(1).unwrap().as_str().eq_ignore_ascii_case("b") {
+                BASE64.decode(text).unwrap_or_default()
+            } else {
+                decode_quoted_printable(text, true)
+            };
+            String::from_utf8_lossy(&bytes).into_owned()
+        })
+        .into_owned()
+}
+
+fn decode_transfer_encoding(body: &str, encoding: Option<&str>) -> String {
+    match encoding.map(|e| e.to_ascii_lowercase()).as_deref() {
+        Some("base64") => {
+            let compact: String = body.split_whitespace().collect();
+            String::from_utf8_lossy(&BASE64.decode(compact).unwrap_or_default()).into_owned()
+        }
+        Some("quoted-printable") => {
+            String::from_utf8_lossy(&decode_quoted_printable(body, false)).into_owned()
+        }
+        _ => body.to_owned(),
+    }
+}
+
+/// Minimal HTML rendering of a plain text email body, for device automation
+/// that only looks at the HTML part.
+fn text_to_html(text: &str) -> String {
+    let paragraphs = text
+        .trim()
+        .split("\n\n")
+        .map(|p| format!("<p>{}</p>", escape_html(p.trim()).replace('\n', "<br>\n")))
+        .join("\n");
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"></head><body>\n{paragraphs}\n</body></html>\n"
+    )
+}
+
+fn strip_html_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// Extracts readable text from a MIME entity, preferring `text/plain` parts
+/// of multipart bodies over `text/html` ones.
+fn email_text(raw: &str) -> Option<String> {
+    let (headers, body) = split_headers(raw);
+    let content_type = find_header(&headers, "Content-Type")
+        .unwrap_or("text/plain")
+        .to_owned();
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap()
+        .trim()
+        .to_ascii_lowercase();
+    if mime_type.starts_with("multipart/") {
+        let boundary = content_type.split(';').find_map(|param| {
+            let (name, value) = param.split_once('=')?;
+            (name.trim().eq_ignore_ascii_case("boundary")).then(|| value.trim().trim_matches('"'))
+        })?;
+        let delimiter = format!("--{boundary}");
+        let parts = body
+            .split(delimiter.as_str())
+            .skip(1)
+            .take_while(|part| !part.starts_with("--"))
+            .map(|part| part.trim_start_matches(['\r', '\n']))
+            .collect_vec();
+        let is_html = |part: &&str| {
+            find_header(&split_headers(part).0, "Content-Type")
+                .is_some_and(|t| t.to_ascii_lowercase().starts_with("text/html"))
+        };
+        let (html, plain): (Vec<&str>, Vec<&str>) = parts.into_iter().partition(is_html);
+        return plain.into_iter().chain(html).find_map(email_text);
+    }
+    let text = decode_transfer_encoding(body, find_header(&headers, "Content-Transfer-Encoding"));
+    match mime_type.as_str() {
+        "text/html" => Some(strip_html_tags(&text)),
+        t if t.starts_with("text/") => Some(text),
+        _ => None,
+    }
+}
+
+fn bare_address(address: &str) -> String {
+    address
+        .rsplit_once('<')
+        .map_or(address, |(_, a)| a.trim_end_matches('>'))
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Finds the device an inbound email belongs to, either sent to the address
+/// the worker mails that device from or sent by the device's own address.
+fn email_device(env: &Env, from: &str, to: &str) -> Result<Option<String>> {
+    let (from, to) = (bare_address(from), bare_address(to));
+    Ok(get_devices(env)?.into_iter().find(|device| {
+        get_optional_secret(env, &format!("{device}_mail_from"))
+            .is_some_and(|address| bare_address(&address) == to)
+            || mail_recipients(env, device)
+                .iter()
+                .any(|address| bare_address(address) == from)
+    }))
+}
+
+async fn inbound_email(message: ForwardableEmailMessage, env: Env) -> Result<()> {
+    let (from, to) = (message.from(), message.to());
+    let Some(device) = email_device(&env, &from, &to)? else {
+        log::info!("email_in", from = from, to = to, outcome = "rejected");
+        message.set_reject("Unknown recipient");
+        return Ok(());
+    };
+    let raw = match Response::from_body(ResponseBody::Stream(message.raw())) {
+        Ok(mut response) => response.text().await,
+        Err(e) => Err(e),
+    };
+    let raw = match raw {
+        Ok(raw) => raw,
+        Err(e) => {
+            log::error!(
+                "email_in",
+                from = from,
+                to = to,
+                outcome = "unreadable",
+                error = e.to_string()
+            );
+            return Ok(());
+        }
+    };
+    log::info!(
+        "email_in",
+        device = device,
+        from = from,
+        to = to,
+        outcome = "accepted"
+    );
+    let (headers, _) = split_headers(&raw);
+    let kv = kv_store(&env)?;
+    if let Some(in_reply_to) = find_header(&headers, "In-Reply-To")
+        && let Ok(Some(sent)) = kv
+            .get(&format!(
+                "mail/{}",
+                in_reply_to.trim().trim_matches(['<', '>'])
+            ))
+            .json::<SentCommandMail>()
+            .await
+        && sent.device == device
+    {
+        let result = email_text(&raw)
+            .unwrap_or_default()
+            .lines()
+            .take_while(|line| !line.starts_with('>'))
+            .join("\n")
+            .trim()
+            .to_owned();
+        log::info!("email_reply", device = device, command_id = sent.command_id);
+        let ack = CommandAck {
+            ok: None,
+            result: (!result.is_empty()).then_some(result),
+        };
+        return acknowledge_command(device, sent.command_id, ack, env).await;
+    }
+    let subject = find_header(&headers, "Subject")
+        .map(decode_header_value)
+        .unwrap_or_default();
+    let mut body = email_text(&raw).unwrap_or_default().trim().to_owned();
+    if body.chars().count() > EMAIL_TEXT_LIMIT {
+        body = body.chars().take(EMAIL_TEXT_LIMIT).collect::<String>() + "…";
+    }
+    let text = format!(
+        "📧 {device} <code>{from}</code>\n<b>{subject}</b>\n\n{body}",
+        from = escape_html(&from),
+        subject = escape_html(&subject),
+        body = escape_html(&body),
+    );
+    send_message_by_device(&env, &device, &text).await;
+    Ok(())
+}
+
+// worker-macros has no email event, so the handler is exported the same way
+// `#[event]` does for the others.
+#[wasm_bindgen]
+pub async fn email(message: ForwardableEmailMessage, env: Env, _ctx: worker_sys::Context) {
+    sentry::init(&env);
+    log::scope(random_uuid(), async move {
+        validate_config(&env).await;
+        catch(env.clone(), inbound_email(message, env.clone())).await;
+        catch(env.clone(), flush_kv_usage(env)).await;
+    })
+    .await
+}
+
+/// Last known state of a device, as returned to service bindings.
+#[derive(Debug, Serialize)]
+struct DeviceState {
+    device: String,
+    status: &'static str,
+    vitals: Option<Vitals>,
+    updated: Option<i64>,
+    skew_ms: Option<i64>,
+}
+
+async fn device_state(env: &Env, device: String) -> Result<DeviceState> {
+    if !get_devices(env)?.contains(&device) {
+        return Err(Error::NotFound(device));
+    }
+    let kv = kv_store(env)?;
+    let status = match HeartbeatStatus::get(&kv, &device).await? {
+        Active => "active",
+        Inactive => "inactive",
+        Dead => "dead",
+    };
+    let stored: Option<StoredStatus> = kv.get(&format!("status/{device}")).json().await?;
+    let skew_ms = kv
+        .get(&format!("skew/{device}"))
+        .text()
+        .await?
+        .and_then(|skew| skew.parse().ok());
+    Ok(DeviceState {
+        status,
+        updated: stored.as_ref().map(|stored| stored.updated),
+        vitals: stored.map(|stored| stored.vitals),
+        skew_ms,
+        device,
+    })
+}
+
+/// Forwards `text` from `sender` as if `device` had posted it.
+async fn forward_as_device(env: &Env, device: String, sender: String, text: String) -> Result<()> {
+    if !get_devices(env)?.contains(&device) {
+        return Err(Error::NotFound(device));
+    }
+    let message = ForwardMessage::Sms(AppleMessageFilterQuery {
+        inner: AppleMessageFilterQueryInner {
+            sender: Some(sender),
+            message: None,
+            text: Some(text),
+            receiver_iso_country_code: None,
+        },
+        timestamp: Some(timestamp_ms()),
+        message_id: None,
+        seq: None,
+    });
+    log::info!("rpc", method = "forward", device = device);
+    forward(device, message, env.clone()).await?;
+    Ok(())
+}
+
+/// Runs a call from a service binding on behalf of `tenant`, if any.
+async fn rpc<T: Serialize>(
+    env: Env,
+    tenant: Option<String>,
+    call: impl Future<Output = Result<T>>,
+) -> std::result::Result<JsValue, JsValue> {
+    const SERIALIZER: serde_wasm_bindgen::Serializer =
+        serde_wasm_bindgen::Serializer::json_compatible();
+    sentry::init(&env);
+    log::scope(random_uuid(), async move {
+        let result = match tenant {
+            None => call.await,
+            Some(tenant) => match secrets::load_tenant(&env, &tenant).await {
+                Ok(true) => log::tenant_scope(tenant, call).await,
+                Ok(false) => Err(Error::NotFound(format!("tenant {tenant}"))),
+                Err(e) => Err(e),
+            },
+        };
+        catch(env.clone(), flush_kv_usage(env)).await;
+        match result {
+            Ok(value) => Ok(value.serialize(&SERIALIZER)?),
+            Err(e) => {
+                log::error!("rpc", error = e.to_string());
+                Err(JsValue::from_str(&e.to_string()))
+            }
+        }
+    })
+    .await
+}
+
+// Service bindings reach these through the `WorkerEntrypoint` of the shim,
+// skipping the device token since only workers of the same account can bind.
+#[wasm_bindgen]
+pub async fn rpc_forward(
+    env: Env,
+    tenant: Option<String>,
+    device: String,
+    sender: String,
+    text: String,
+) -> std::result::Result<JsValue, JsValue> {
+    let call = {
+        let env = env.clone();
+        async move { forward_as_device(&env, device, sender, text).await }
+    };
+    rpc(env, tenant, call).await
+}
+
+#[wasm_bindgen]
+pub async fn rpc_status(
+    env: Env,
+    tenant: Option<String>,
+    device: String,
+) -> std::result::Result<JsValue, JsValue> {
+    let call = {
+        let env = env.clone();
+        async move { device_state(&env, device).await }
+    };
+    rpc(env, tenant, call).await
+}
+
+async fn command_mail(env: &Env) -> String {
+    if let Some(mail) = COMMAND_MAIL_LOADED.lock().unwrap().clone() {
+        return mail;
+    }
+    reload_command_mail(env).await
+}
+
+async fn reload_command_mail(env: &Env) -> String {
+    let loaded: Result<Option<String>> =
+        async { Ok(kv_store(env)?.get("config/command_mail").text().await?) }.await;
+    let mail = match loaded {
+        Ok(Some(mail)) => {
+            log::info!("command_mail", outcome = "loaded");
+            mail
+        }
+        Ok(None) => COMMAND_MAIL.to_owned(),
+        Err(e) => {
+            log::error!("kv_get", key = "config/command_mail", error = e.to_string());
+            return COMMAND_MAIL.to_owned();
+        }
+    };
+    *COMMAND_MAIL_LOADED.lock().unwrap() = Some(mail.clone());
+    mail
+}
+
+/// Waits for `duration`, or not at all when running natively without timers.
+async fn sleep(duration: Duration) {
+    #[cfg(target_arch = "wasm32")]
+    Delay::from(duration).await;
+    #[cfg(not(target_arch = "wasm32"))]
+    let _ = duration;
+}
+
+/// Runs `f` up to `attempts` times with exponential backoff, as long as the
+/// error is considered transient by `retryable`.
+async fn with_retry<T, F, Fut>(attempts: u32, retryable: fn(&Error) -> bool, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(e) if attempt < attempts && retryable(&e) => {
+                log::info!("retry", attempt = attempt, error = e.to_string());
+                sleep(Duration::from_millis(RETRY_BASE_DELAY_MS << (attempt - 1))).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn is_transient_email_error(e: &Error) -> bool {
+    let message = e.to_string().to_ascii_lowercase();
+    !(message.contains("not verified")
+        || message.contains("not allowed")
+        || message.contains("invalid"))
+}
+
+/// Turns errors from the SendEmail binding into a reason fit for the chat.
+fn email_error_reason(e: &Error) -> String {
+    let message = e.to_string().to_ascii_lowercase();
+    if message.contains("destination") && message.contains("not verified") {
+        "destination address not verified".to_owned()
+    } else if message.contains("not verified") || message.contains("not allowed") {
+        "sender address not allowed".to_owned()
+    } else if message.contains("invalid") {
+        "invalid message".to_owned()
+    } else if message.contains("limit") {
+        "sending limit exceeded".to_owned()
+    } else {
+        format!("delivery failed ({e})")
+    }
+}
+
+/// Keeps a record of an outbound effect that failed for good and tells the
+/// admin chat about it.
+async fn record_dead_letter(env: &Env, kind: &str, device: &str, detail: &str, reason: &str) {
+    let now = timestamp_ms();
+    let key = format!("deadletter/{now}.{}", random_uuid());
+    let letter = DeadLetter {
+        kind: kind.to_owned(),
+        device: device.to_owned(),
+        detail: detail.to_owned(),
+        reason: reason.to_owned(),
+        timestamp: now,
+    };
+    let stored: Result<()> = async {
+        kv_store(env)?
+            .put(&key, to_json(&letter))?
+            .expiration_ttl(DEAD_LETTER_TTL_SECONDS)
+            .execute()
+            .await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = stored {
+        log::error!("kv_put", key = key, error = e.to_string());
+    }
+    notify_admin(
+        env,
+        &format!(
+            "☠️ {kind} for {device} failed: {reason}\n<code>{detail}</code>",
+            detail = escape_html(detail),
+            reason = escape_html(reason),
+        ),
+    )
+    .await;
+}
+
+async fn notify_admin(env: &Env, text: &str) {
+    let Some(chat_id) =
+        get_optional_secret(env, "admin_chat_id").and_then(|s| s.parse::<i64>().ok())
+    else {
+        return;
+    };
+    send_message_by_chat(env, chat_id, text).await;
+}
+
+/// Awaits a background task, logging its error and telling the admin chat
+/// about it instead of failing the invocation.
+async fn catch(env: Env, task: impl Future<Output = Result<()>>) {
+    if let Err(e) = task.await {
+        log::error!("task", error = e.to_string());
+        notify_admin(&env, &format!("⚠️ {}", escape_html(&e.to_string()))).await;
+    }
+}
+
+/// Addresses from the comma separated `{device}_mail_to`.
+fn mail_recipients(env: &Env, device: &str) -> Vec<String> {
+    get_optional_secret(env, &format!("{device}_mail_to"))
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+async fn send_email(
+    env: &Env,
+    device: &str,
+    command_id: &str,
+    command: &DeviceCommand,
+) -> Result<()> {
+    let Some((subject, body)) = command.mail() else {
+        return Err(Error::Email(format!("{command} cannot be sent by email")));
+    };
+    let from = get_secret(env, &format!("{device}_mail_from"))?;
+    let template = command_mail(env)
+        .await
+        .replace("{{subject}}", subject)
+        .replace("{{body}}", body)
+        .replace("{{command_id}}", command_id)
+        .replace("{{device}}", device);
+    let template = command
+        .mail_arguments()
+        .into_iter()
+        .fold(template, |template, (placeholder, value)| {
+            template.replace(placeholder, &value)
+        });
+    let (headers, text) = split_headers(&template);
+    let name = get_optional_secret(env, &format!("{device}_mail_name"))
+        .unwrap_or_else(|| "Remote Command".to_owned());
+    let mut message = MimeMessage::new().header("From", &mime::mailbox(&name, &from));
+    if let Some(reply_to) = get_optional_secret(env, &format!("{device}_mail_reply_to")) {
+        message = message.header("Reply-To", &reply_to);
+    }
+    // headers from the template take precedence over per-device ones
+    let extra = get_optional_secret(env, &format!("{device}_mail_headers")).unwrap_or_default();
+    let extra = extra
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()));
+    for (name, value) in extra.chain(headers) {
+        message = message.header(&name, &value);
+    }
+    message = message.text(text).html(&text_to_html(text));
+    if let DeviceCommand::FetchConfig = command {
+        let config = render_config(env, device, &get_secret(env, device)?).await?;
+        message = message.attachment(Attachment {
+            filename: "sms-forward.yaml".to_owned(),
+            content_type: "text/plain; charset=utf-8".to_owned(),
+            data: config.into_bytes(),
+        });
+    }
+    let recipients = mail_recipients(env, device);
+    let ids = send_mail(
+        env,
+        device,
+        &from,
+        &recipients,
+        message,
+        &command.to_string(),
+    )
+    .await?;
+    // replies are matched back to the command by In-Reply-To
+    let kv = kv_store(env)?;
+    for id in ids {
+        let key = format!("mail/{id}");
+        let sent = SentCommandMail {
+            device: device.to_owned(),
+            command_id: command_id.to_owned(),
+        };
+        kv.put(&key, to_json(&sent))?
+            .expiration_ttl(COMMAND_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Sends `message` to each recipient, returning the Message-IDs of those
+/// which were delivered.
+async fn send_mail(
+    env: &Env,
+    device: &str,
+    from: &str,
+    recipients: &[String],
+    message: MimeMessage,
+    detail: &str,
+) -> Result<Vec<String>> {
+    let binding: SendEmail = env
+        .get_binding("command")
+        .map_err(|_| Error::MissingBinding("command".to_owned()))?;
+    let (_, domain) = from
+        .rsplit_once("@")
+        .ok_or_else(|| Error::Email(format!("invalid sender address {from}")))?;
+    let mut ids = Vec::new();
+    let mut error = Error::Email(format!("no recipients for {device}"));
+    for to in recipients {
+        let id = format!(
+            "{ts}.{uuid}@{domain}",
+            ts = timestamp_ms(),
+            uuid = random_uuid(),
+        );
+        let raw = message
+            .clone()
+            .header("To", &mime::mailbox(device, to))
+            .header("Message-ID", &format!("<{id}>"))
+            .build();
+        let sent = if is_dry_run(env) {
+            log::info!("dry_run", target = "email", device = device, to = to);
+            Ok(())
+        } else {
+            with_retry(EMAIL_ATTEMPTS, is_transient_email_error, || async {
+                let mail = EmailMessage::new(from.to_owned(), to.clone(), raw.clone())?;
+                Ok(binding.send(mail).await?)
+            })
+            .await
+        };
+        match sent {
+            Ok(()) => {
+                log::info!(
+                    "email_out",
+                    device = device,
+                    message_id = id,
+                    to = to,
+                    outcome = "sent"
+                );
+                ids.push(id);
+            }
+            Err(e) => {
+                log::error!(
+                    "email_out",
+                    device = device,
+                    to = to,
+                    outcome = "failed",
+                    error = e.to_string()
+                );
+                let reason = email_error_reason(&e);
+                let detail = format!("{detail} to {to}");
+                record_dead_letter(env, "email", device, &detail, &reason).await;
+                error = Error::Email(reason);
+            }
+        }
+    }
+    // one reachable recipient is enough for the mail to arrive
+    if ids.is_empty() { Err(error) } else { Ok(ids) }
+}
+
+async fn load_outages(kv: &Kv, device: &str) -> Result<Vec<Outage>> {
+    Ok(kv
+        .get(&format!("outages/{device}"))
+        .json()
+        .await?
+        .unwrap_or_default())
+}
+
+async fn store_outages(kv: &Kv, device: &str, outages: &[Outage]) -> Result<()> {
+    let since = timestamp_ms() - OUTAGE_HISTORY_DAYS * 24 * 3600 * 1000;
+    let outages = outages
+        .iter()
+        .filter(|o| o.end.is_none_or(|end| end >= since))
+        .collect_vec();
+    kv.put(&format!("outages/{device}"), to_json(outages))?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Opens an outage when a device is first reported down.
+async fn record_outage_start(kv: &Kv, device: &str) -> Result<()> {
+    let mut outages = load_outages(kv, device).await?;
+    if outages.last().is_some_and(|o| o.end.is_none()) {
+        return Ok(());
+    }
+    outages.push(Outage {
+        start: timestamp_ms(),
+        end: None,
+    });
+    store_outages(kv, device, &outages).await
+}
+
+/// Closes the open outage, returning how long it lasted.
+async fn record_outage_end(kv: &Kv, device: &str) -> Result<Option<i64>> {
+    let mut outages = load_outages(kv, device).await?;
+    let Some(outage) = outages.last_mut().filter(|o| o.end.is_none()) else {
+        return Ok(None);
+    };
+    let now = timestamp_ms();
+    outage.end = Some(now);
+    let duration = now - outage.start;
+    store_outages(kv, device, &outages).await?;
+    Ok(Some(duration))
+}
+
+/// Sends the past week's health of the devices of a chat to it, once on
+/// Mondays.
+async fn send_weekly_summary(
+    env: &Env,
+    kv: &Kv,
+    chat_id: &str,
+    devices: &[&String],
+    now: i64,
+) -> Result<()> {
+    let today = format_date(now);
+    let key = format!("weekly/{chat_id}");
+    if kv.get(&key).text().await?.as_ref() == Some(&today) {
+        return Ok(());
+    }
+    log::info!("weekly_summary", chat_id = chat_id, devices = devices.len());
+    let mut text = "📅 Weekly report".to_owned();
+    for device in devices {
+        text.push_str(&format!(
+            "\n\n<b>{}</b>\n{}",
+            escape_html(device),
+            weekly_report(env, kv, device, now).await?
+        ));
+    }
+    let body = SendMessageBody {
+        chat_id,
+        message_thread_id: None,
+        text: &text,
+        parse_mode: "HTML",
+        link_preview_options: None,
+        disable_notification: false,
+        reply_markup: None,
+    };
+    send_message(env, &body).await;
+    kv.put(&key, today)?.execute().await?;
+    Ok(())
+}
+
+/// Volume by category, top senders and delivery failures from the
+/// `deliveries` database when there is one, uptime and the battery trend
+/// of a device over the past week.
+async fn weekly_report(env: &Env, kv: &Kv, device: &str, now: i64) -> Result<String> {
+    let since = now - 7 * 24 * 3600 * 1000;
+    let outages = load_outages(kv, device).await?;
+    let mut lines = vec![format!(
+        "⏱ {}",
+        reliability_report(&outages, since, now, LONGEST_OUTAGES_SHOWN)
+    )];
+    if let Ok(db) = env.d1("deliveries") {
+        let bind = [device.into(), (since as f64).into()];
+        let categories: Vec<CategoryCount> = db
+            .prepare(
+                "SELECT category, COUNT(*) AS count FROM deliveries \
+                 WHERE device = ?1 AND received >= ?2 \
+                 GROUP BY category ORDER BY count DESC",
+            )
+            .bind(&bind)?
+            .all()
+            .await?
+            .results()?;
+        let total: u32 = categories.iter().map(|c| c.count).sum();
+        let by_category = categories
+            .iter()
+            .map(|c| format!("{} {}", c.count, c.category.as_deref().unwrap_or("other")))
+            .join(", ");
+        lines.push(if total == 0 {
+            "📨 no messages".to_owned()
+        } else {
+            format!("📨 {total} messages: {by_category}")
+        });
+        let senders: Vec<SenderCount> = db
+            .prepare(
+                "SELECT sender, COUNT(*) AS count FROM deliveries \
+                 WHERE device = ?1 AND received >= ?2 \
+                 GROUP BY sender ORDER BY count DESC, sender LIMIT ?3",
+            )
+            .bind(&[
+                device.into(),
+                (since as f64).into(),
+                (WEEKLY_SENDERS_SHOWN as f64).into(),
+            ])?
+            .all()
+            .await?
+            .results()?;
+        if !senders.is_empty() {
+            let senders = senders
+                .iter()
+                .map(|s| format!("{} ({})", escape_html(&s.sender), s.count))
+                .join(", ");
+            lines.push(format!("👤 {senders}"));
+        }
+        let states: Vec<StateCount> = db
+            .prepare(
+                "SELECT state, COUNT(*) AS count FROM deliveries \
+                 WHERE device = ?1 AND received >= ?2 AND state IN ('failed', 'fallback') \
+                 GROUP BY state ORDER BY state DESC",
+            )
+            .bind(&bind)?
+            .all()
+            .await?
+            .results()?;
+        lines.push(if states.is_empty() {
+            "✅ no delivery failures".to_owned()
+        } else {
+            let failures = states
+                .iter()
+                .map(|s| match s.state.as_str() {
+                    "fallback" => format!("{} via fallback", s.count),
+                    state => format!("{} {state}", s.count),
+                })
+                .join(", ");
+            format!("⚠️ {failures}")
+        });
+    }
+    let days: Vec<BatteryDay> = kv
+        .get(&format!("battery/{device}"))
+        .json()
+        .await?
+        .unwrap_or_default();
+    if let Some(trend) = battery_trend(&days, since) {
+        lines.push(trend);
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Average daily low of the past week, against the week before when known.
+fn battery_trend(days: &[BatteryDay], since: i64) -> Option<String> {
+    let since = format_date(since);
+    let (this_week, last_week): (Vec<_>, Vec<_>) = days.iter().partition(|day| day.date > since);
+    let average = |days: &[&BatteryDay]| {
+        (!days.is_empty()).then(|| days.iter().map(|day| day.low).sum::<i32>() / days.len() as i32)
+    };
+    let low = average(&this_week)?;
+    let lowest = this_week.iter().map(|day| day.low).min()?;
+    let mut text = format!("🔋 daily low {low}% on average, {lowest}% at the lowest");
+    if let Some(before) = average(&last_week) {
+        text.push_str(&format!(", {:+} points on the week before", low - before));
+    }
+    Some(text)
+}
+
+/// Emails yesterday's forwarded messages grouped by sender to the
+/// `{device}_digest_to` addresses, once a day.
+async fn send_digest(env: &Env, kv: &Kv, device: &str) -> Result<()> {
+    let recipients = get_optional_secret(env, &format!("{device}_digest_to"))
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(ToOwned::to_owned)
+        .collect_vec();
+    if recipients.is_empty() {
+        return Ok(());
+    }
+    let now = timestamp_ms();
+    let today = format_date(now);
+    let key = format!("digest/{device}");
+    if kv.get(&key).text().await?.as_ref() == Some(&today) {
+        return Ok(());
+    }
+    let date = format_date(now - 24 * 3600 * 1000);
+    let messages = load_archive(kv, device, &date).await?;
+    if !messages.is_empty() {
+        log::info!(
+            "digest",
+            device = device,
+            date = date,
+            messages = messages.len()
+        );
+        let mut text = format!(
+            "{count} messages forwarded from {device} on {date}\n",
+            count = messages.len()
+        );
+        for (sender, messages) in &messages
+            .into_iter()
+            .sorted_by(|a, b| a.sender.cmp(&b.sender).then(a.timestamp.cmp(&b.timestamp)))
+            .chunk_by(|m| m.sender.clone())
+        {
+            let messages = messages.collect_vec();
+            text.push_str(&format!("\n{sender} ({})\n", messages.len()));
+            for message in messages {
+                text.push_str(&format!(
+                    "{time} {text}\n",
+                    time = format_time(message.timestamp),
+                    text = message.text.replace('\n', " ")
+                ));
+            }
+        }
+        let from = get_secret(env, &format!("{device}_mail_from"))?;
+        let message = MimeMessage::new()
+            .header("From", &mime::mailbox("SMS Forward", &from))
+            .header("Subject", &format!("SMS digest for {device}, {date}"))
+            .text(&text)
+            .html(&text_to_html(&text));
+        send_mail(env, device, &from, &recipients, message, "digest").await?;
+    }
+    kv.put(&key, today)?.execute().await?;
+    Ok(())
+}
+
+async fn send_config_email(env: &Env, device: &str) -> Result<()> {
+    let from = get_secret(env, &format!("{device}_mail_from"))?;
+    let config = render_config(env, device, &get_secret(env, device)?).await?;
+    let text =
+        format!("The config for {device} is attached.\nOpen it on the device to install it.\n");
+    let message = MimeMessage::new()
+        .header("From", &mime::mailbox("SMS Forward", &from))
+        .header("Subject", &format!("Config for {device}"))
+        .text(&text)
+        .html(&text_to_html(&text))
+        .attachment(Attachment {
+            filename: "sms-forward.yaml".to_owned(),
+            content_type: "text/plain; charset=utf-8".to_owned(),
+            data: config.into_bytes(),
+        });
+    send_mail(
+        env,
+        device,
+        &from,
+        &mail_recipients(env, device),
+        message,
+        "config",
+    )
+    .await?;
+    Ok(())
+}
+
+async fn edit_message(env: &Env, body: &EditMessageTextBody<'_>) {
+    call_telegram(
+        env,
+        "editMessageText",
+        &body.chat_id.to_string(),
+        || async { TelegramClient::new(env)?.edit_message_text(body).await },
+    )
+    .await;
+}
+
+async fn edit_message_by_chat(env: &Env, chat_id: i64, message_id: i64, text: &str) {
+    edit_message(
+        env,
+        &EditMessageTextBody {
+            chat_id,
+            message_id,
+            text,
+            parse_mode: "HTML",
+            reply_markup: None,
+        },
+    )
+    .await
+}
+
+fn check_token(device: &str, token: &str, env: &Env) -> bool {
+    token_matches(get_optional_secret(env, device).as_deref(), token)
+}
+
+/// `{device}/{token}` from the `Authorization` header, which takes
+/// precedence over the path.
+fn header_credentials(req: &Request) -> Option<(String, String)> {
+    parse_credentials(&req.headers().get("Authorization").ok().flatten()?)
+}
+
+fn path_credentials(ctx: &RouteContext<Context>) -> Option<(String, String)> {
+    Some((ctx.param("device")?.clone(), ctx.param("token")?.clone()))
+}
+
+/// The device whose token matches, counting failures.
+fn authenticate(env: &Env, credentials: Option<(String, String)>) -> Option<(String, String)> {
+    let Some((device, token)) = credentials else {
+        record_metric(env, "auth_failure", "", "missing_header", 1.0);
+        return None;
+    };
+    if !check_token(&device, &token, env) {
+        record_metric(env, "auth_failure", &device, "token", 1.0);
+        return None;
+    }
+    Some((device, token))
+}
+
+async fn load_commands(kv: &Kv, device: &str) -> Result<Vec<QueuedCommand>> {
+    let queue: Vec<QueuedCommand> = kv
+        .get(&format!("commands/{device}"))
+        .json()
+        .await?
+        .unwrap_or_default();
+    let now = timestamp_ms();
+    Ok(queue
+        .into_iter()
+        .filter(|c| now - c.queued < COMMAND_TTL_SECONDS as i64 * 1000)
+        .collect())
+}
+
+async fn store_commands(kv: &Kv, device: &str, queue: &[QueuedCommand]) -> Result<()> {
+    let key = format!("commands/{device}");
+    if queue.is_empty() {
+        kv.delete(&key).await?;
+    } else {
+        kv.put(&key, to_json(queue))?
+            .expiration_ttl(COMMAND_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn enqueue_command(env: &Env, device: &str, id: &str, command: DeviceCommand) -> Result<()> {
+    let kv = kv_store(env)?;
+    let mut queue = load_commands(&kv, device).await?;
+    queue.push(QueuedCommand {
+        id: id.to_owned(),
+        command,
+        queued: timestamp_ms(),
+    });
+    store_commands(&kv, device, &queue).await?;
+    log::info!(
+        "command",
+        device = device,
+        command_id = id,
+        outcome = "queued"
+    );
+    Ok(())
+}
+
+/// Pushes a command through Firebase Cloud Messaging, `None` when the device
+/// has no `{device}_fcm_token` or `fcm_server_key` is not configured.
+async fn send_push(
+    env: &Env,
+    device: &str,
+    id: &str,
+    command: &DeviceCommand,
+) -> Option<Result<()>> {
+    let server_key = get_optional_secret(env, "fcm_server_key")?;
+    let token = get_optional_secret(env, &format!("{device}_fcm_token"))?;
+    let body = to_json(&FcmMessage {
+        to: &token,
+        priority: "high",
+        data: FcmData {
+            id,
+            command: &to_json(command),
+        },
+    });
+    let result = async {
+        if is_dry_run(env) {
+            log::info!("dry_run", target = "push", device = device, command_id = id);
+            return Ok(());
+        }
+        let request = Request::new_with_init(
+            "https://fcm.googleapis.com/fcm/send",
+            &RequestInit {
+                method: Method::Post,
+                headers: [
+                    ("Content-Type", "application/json"),
+                    ("Authorization", &format!("key={server_key}")),
+                ]
+                .into_iter()
+                .collect(),
+                body: Some(body.into()),
+                ..RequestInit::default()
+            },
+        )?;
+        let response: FcmResponse = Fetch::Request(request).send().await?.json().await?;
+        if response.success > 0 {
+            Ok(())
+        } else {
+            Err(Error::Push(format!("{response:?}")))
+        }
+    }
+    .await;
+    match &result {
+        Ok(()) => log::info!("push", device = device, command_id = id, outcome = "sent"),
+        Err(e) => log::error!(
+            "push",
+            device = device,
+            command_id = id,
+            outcome = "failed",
+            error = e.to_string()
+        ),
+    }
+    Some(result)
+}
+
+/// Delivers commands queued while an email-capable device was offline.
+async fn deliver_queued_emails(env: &Env, device: &str) -> Result<()> {
+    if get_optional_secret(env, &format!("{device}_mail_to")).is_none() {
+        return Ok(());
+    }
+    let kv = kv_store(env)?;
+    let (emails, rest): (Vec<_>, Vec<_>) = load_commands(&kv, device)
+        .await?
+        .into_iter()
+        .partition(|c| c.command.mail().is_some());
+    if emails.is_empty() {
+        return Ok(());
+    }
+    store_commands(&kv, device, &rest).await?;
+    log::info!("deliver_queued", device = device, commands = emails.len());
+    for (i, command) in emails.iter().enumerate() {
+        if let Err(e) = send_email(env, device, &command.id, &command.command).await {
+            // put the unsent ones back in front of whatever was queued since
+            let mut queue = load_commands(&kv, device).await?;
+            queue.splice(0..0, emails[i..].iter().cloned());
+            store_commands(&kv, device, &queue).await?;
+            return Err(e);
+        }
+        mark_command_sent(&kv, device, &command.id).await?;
+    }
+    Ok(())
+}
+
+async fn mark_command_sent(kv: &Kv, device: &str, id: &str) -> Result<()> {
+    let key = format!("ack/{device}/{id}");
+    let Some(mut pending) = kv.get(&key).json::<PendingAck>().await? else {
+        return Ok(());
+    };
+    pending.sent = Some(timestamp_ms());
+    kv.put(&key, to_json(&pending))?
+        .expiration_ttl(COMMAND_TTL_SECONDS + ACK_TIMEOUT_SECONDS as u64)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Sends a command down the command channel or by push or email when the
+/// device can take it right away, or queues it for polling otherwise, then
+/// tracks the status message until acked.
+async fn issue_command(
+    env: &Env,
+    chat_id: i64,
+    device: &str,
+    command: DeviceCommand,
+    lang: Lang,
+) -> Result<()> {
+    log::info!(
+        "command",
+        device = device,
+        command = command.to_string(),
+        outcome = "issued"
+    );
+    let Some(message_id) = send_message_by_chat(env, chat_id, t(lang, Reply::SendingCommand)).await
+    else {
+        return Ok(());
+    };
+    let kv = kv_store(env)?;
+    let id = random_uuid();
+    let pushed = push_to_channel(env, device, &id, &command).await
+        || match send_push(env, device, &id, &command).await {
+            Some(Ok(())) => true,
+            Some(Err(e)) => {
+                log::error!(
+                    "command",
+                    device = device,
+                    command_id = id,
+                    outcome = "push_failed",
+                    error = e.to_string()
+                );
+                false
+            }
+            None => false,
+        };
+    let by_email = !pushed
+        && command.mail().is_some()
+        && get_optional_secret(env, &format!("{device}_mail_to")).is_some()
+        && HeartbeatStatus::get(&kv, device).await? == Active;
+    let (text, sent) = if pushed {
+        (t(lang, Reply::CommandPushed), Some(timestamp_ms()))
+    } else if by_email {
+        if let Err(e) = send_email(env, device, &id, &command).await {
+            log::error!(
+                "command",
+                device = device,
+                command_id = id,
+                outcome = "email_failed",
+                error = e.to_string()
+            );
+            let text = tf(lang, Reply::CommandFailed, &escape_html(&e.to_string()));
+            edit_message_by_chat(env, chat_id, message_id, &text).await;
+            return Ok(());
+        }
+        (t(lang, Reply::CommandSent), Some(timestamp_ms()))
+    } else {
+        if let Err(e) = enqueue_command(env, device, &id, command).await {
+            log::error!(
+                "command",
+                device = device,
+                command_id = id,
+                outcome = "queue_failed",
+                error = e.to_string()
+            );
+            let text = t(lang, Reply::CommandQueueFailed);
+            edit_message_by_chat(env, chat_id, message_id, text).await;
+            return Ok(());
+        }
+        (t(lang, Reply::CommandQueued), None)
+    };
+    edit_message_by_chat(env, chat_id, message_id, text).await;
+    let key = format!("ack/{device}/{id}");
+    let pending = PendingAck {
+        chat_id,
+        message_id,
+        sent,
+    };
+    kv.put(&key, to_json(&pending))?
+        .expiration_ttl(COMMAND_TTL_SECONDS + ACK_TIMEOUT_SECONDS as u64)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Sends the command down the open WebSocket of the device, returning
+/// whether it had one.
+async fn push_to_channel(env: &Env, device: &str, id: &str, command: &DeviceCommand) -> bool {
+    let commands = PollCommandsResponse {
+        commands: vec![QueuedCommand {
+            id: id.to_owned(),
+            command: command.clone(),
+            queued: timestamp_ms(),
+        }],
+    };
+    match channel::push(env, device, &commands).await {
+        Ok(sent) => sent,
+        Err(e) => {
+            log::error!(
+                "command",
+                device = device,
+                command_id = id,
+                outcome = "channel_failed",
+                error = e.to_string()
+            );
+            false
+        }
+    }
+}
+
+async fn acknowledge_command(device: String, id: String, ack: CommandAck, env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    let key = format!("ack/{device}/{id}");
+    let Some(pending) = kv.get(&key).json::<PendingAck>().await? else {
+        log::info!(
+            "ack",
+            device = device,
+            command_id = id,
+            outcome = "untracked"
+        );
+        return Ok(());
+    };
+    log::info!(
+        "ack",
+        device = device,
+        command_id = id,
+        outcome = if ack.ok == Some(false) {
+            "failed"
+        } else {
+            "ok"
+        }
+    );
+    let now = timestamp_ms();
+    let mut text = if ack.ok == Some(false) {
+        format!("❌ Command failed at {}", format_time(now))
+    } else {
+        format!("✅ Command executed at {}", format_time(now))
+    };
+    if let Some(result) = ack.result {
+        text.push_str(&format!("\n\n<pre>{}</pre>", escape_html(&result)));
+    }
+    edit_message_by_chat(&env, pending.chat_id, pending.message_id, &text).await;
+    kv.delete(&key).await?;
+    Ok(())
+}
+
+async fn check_acks(env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    let keys = kv.list_keys("ack/").await?;
+    let now = timestamp_ms();
+    for key in keys {
+        let Some(pending) = kv.get(&key).json::<PendingAck>().await? else {
+            continue;
+        };
+        if pending
+            .sent
+            .is_none_or(|sent| now - sent <= ACK_TIMEOUT_SECONDS * 1000)
+        {
+            continue;
+        }
+        let Some((device, id)) = key["ack/".len()..].split_once('/') else {
+            continue;
+        };
+        log::info!("ack", device = device, command_id = id, outcome = "timeout");
+        edit_message_by_chat(
+            env,
+            pending.chat_id,
+            pending.message_id,
+            "⚠️ Command not acknowledged",
+        )
+        .await;
+        send_message_by_chat(
+            env,
+            pending.chat_id,
+            &format!(
+                "⚠️ {device} did not acknowledge command <code>{}</code>",
+                &id[..8]
+            ),
+        )
+        .await;
+        kv.delete(&key).await?;
+    }
+    Ok(())
+}
+
+async fn poll_commands(device: String, env: Env) -> Result<Response> {
+    let commands = take_commands(&env, &device).await?;
+    Ok(Response::builder()
+        .with_headers([("Content-Type", "application/json")].into_iter().collect())
+        .fixed(to_json(&PollCommandsResponse { commands }).into_bytes()))
+}
+
+/// Empties the queue of the device, marking its commands as sent.
+async fn take_commands(env: &Env, device: &str) -> Result<Vec<QueuedCommand>> {
+    let kv = kv_store(env)?;
+    let commands = load_commands(&kv, device).await?;
+    store_commands(&kv, device, &[]).await?;
+    for command in &commands {
+        mark_command_sent(&kv, device, &command.id).await?;
+    }
+    log::info!("poll", device = device, commands = commands.len());
+    Ok(commands)
+}
+
+fn is_config_canary(env: &Env, device: &str) -> bool {
+    get_optional_secret(env, &format!("{device}_config_canary")).is_some_and(|s| s == "true")
+}
+
+/// Template for `device`: `config_template_next_url` for canaries, otherwise
+/// the last one promoted or `config_template_url`.
+async fn config_template_url(env: &Env, device: &str) -> Result<String> {
+    let kv = kv_store(env)?;
+    if is_config_canary(env, device)
+        && let Some(url) = get_optional_secret(env, "config_template_next_url")
+    {
+        // remembered so that `/promote` can wait for the canary to check in
+        kv.put(&format!("config/served/{device}"), timestamp_ms())?
+            .execute()
+            .await?;
+        return Ok(url);
+    }
+    let current = get_secret(env, "config_template_url")?;
+    match kv.get("config/promoted").json::<PromotedConfig>().await? {
+        Some(promoted) if promoted.replaces == current => Ok(promoted.url),
+        _ => Ok(current),
+    }
+}
+
+/// Last heartbeat of `device` seen by this isolate or written to KV.
+async fn last_seen(kv: &Kv, device: &str) -> Result<Option<i64>> {
+    let stored = kv
+        .get_text(device)
+        .await?
+        .and_then(|s| s.parse::<i64>().ok());
+    let cached = HEARTBEATS
+        .lock()
+        .unwrap()
+        .get(&heartbeat_key(device))
+        .map(|cached| cached.seen);
+    Ok(stored.max(cached))
+}
+
+/// Serves the next config template to every device once each canary has
+/// checked in after fetching it, and tells the devices to re-fetch.
+async fn promote_config(env: &Env, chat_id: i64, lang: Lang) -> Result<()> {
+    let Some(url) = get_optional_secret(env, "config_template_next_url") else {
+        send_message_by_chat(env, chat_id, "No next config template").await;
+        return Ok(());
+    };
+    let devices = get_devices(env)?;
+    let canaries = devices
+        .iter()
+        .filter(|device| is_config_canary(env, device))
+        .collect_vec();
+    if canaries.is_empty() {
+        send_message_by_chat(env, chat_id, "No canary devices").await;
+        return Ok(());
+    }
+    let kv = kv_store(env)?;
+    let mut waiting = Vec::new();
+    for device in &canaries {
+        let served: Option<i64> = kv.get(&format!("config/served/{device}")).json().await?;
+        let seen = last_seen(&kv, device).await?;
+        if !served.is_some_and(|served| seen.is_some_and(|seen| seen > served)) {
+            waiting.push(device.as_str());
+        }
+    }
+    if !waiting.is_empty() {
+        let text = format!(
+            "Waiting for {} to check in with the next config",
+            waiting.join(", ")
+        );
+        send_message_by_chat(env, chat_id, &text).await;
+        return Ok(());
+    }
+    let promoted = PromotedConfig {
+        url,
+        replaces: get_secret(env, "config_template_url")?,
+    };
+    kv.put("config/promoted", to_json(&promoted))?
+        .execute()
+        .await?;
+    for device in &canaries {
+        kv.delete(&format!("config/served/{device}")).await?;
+    }
+    log::info!("config", outcome = "promoted", devices = devices.len());
+    send_message_by_chat(env, chat_id, "Next config promoted").await;
+    for device in devices.iter().filter(|device| !canaries.contains(device)) {
+        issue_command(env, chat_id, device, DeviceCommand::FetchConfig, lang).await?;
+    }
+    Ok(())
+}
+
+async fn render_config(env: &Env, device: &str, token: &str) -> Result<String> {
+    let url = config_template_url(env, device).await?;
+    let request = Request::new(&url, Method::Get)?;
+    let template = Fetch::Request(request).send().await?.text().await?;
+    let path = match log::tenant() {
+        Some(tenant) => format!("{device}/{token}?tenant={tenant}"),
+        None => format!("{device}/{token}"),
+    };
+    Ok(template.replace("{{token}}", &path))
+}
+
+async fn generate_config(device: String, token: String, env: Env) -> Result<Response> {
+    let body = render_config(&env, &device, &token).await?.into_bytes();
+    Ok(Response::builder()
+        .with_headers(
+            [
+                ("Content-Type", "text/plain; charset=utf-8"),
+                (
+                    "Content-Disposition",
+                    "inline; filename=\"sms-forward.yaml\"",
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        )
+        .fixed(body))
+}
+
+/// Forwards the message unless the device already sent it with the same
+/// `Idempotency-Key` header or `message_id`, the header taking precedence.
+async fn forward_once(
+    device: String,
+    message: ForwardMessage,
+    idempotency_key: Option<String>,
+    env: Env,
+) -> Result<ForwardResult> {
+    let key = idempotency_key
+        .or_else(|| message.message_id().map(ToOwned::to_owned))
+        .filter(|key| !key.is_empty() && key.len() <= IDEMPOTENCY_KEY_LIMIT)
+        .map(|key| format!("idempotency/{device}/{key}"));
+    let kv = kv_store(&env)?;
+    if let Some(key) = &key {
+        if kv.get(key).text().await?.is_some() {
+            log::info!("forward", device = device, outcome = "idempotent");
+            record_metric(&env, "forward", &device, "idempotent", 1.0);
+            return Ok(ForwardResult::of("idempotent"));
+        }
+        kv.put(key, timestamp_ms().to_string())?
+            .expiration_ttl(IDEMPOTENCY_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    let result = match message.seq() {
+        Some(seq) => forward_in_order(device, seq, message, env).await,
+        None => forward(device, message, env).await,
+    };
+    // a forward that got nowhere has to go through again on the device's retry
+    if let Some(key) = &key
+        && !matches!(&result, Ok(result) if result.outcome != "failed")
+    {
+        kv.delete(key).await?;
+    }
+    result
+}
+
+/// Forwards messages numbered by the device in the order of their `seq`,
+/// holding those which arrive ahead of a missing one for
+/// `REORDER_WINDOW_SECONDS` before giving up on it and telling the device's
+/// chat that it may have been lost.
+async fn forward_in_order(
+    device: String,
+    seq: u64,
+    message: ForwardMessage,
+    env: Env,
+) -> Result<ForwardResult> {
+    let kv = kv_store(&env)?;
+    let mut sequence = ForwardSequence::load(&kv, &device).await?;
+    let now = timestamp_ms();
+    let mut ready = Vec::new();
+    let mut late = None;
+    let fingerprint = dedup::fingerprint(&[
+        message.sender().unwrap_or_default(),
+        message.text(),
+        &message.timestamp().unwrap_or_default().to_string(),
+    ]);
+    if sequence.is_retry(seq, &fingerprint) {
+        log::info!(
+            "sequence",
+            device = device,
+            seq = seq,
+            outcome = "duplicate"
+        );
+        return Ok(ForwardResult::of("duplicate"));
+    }
+    if seq <= 1 {
+        if sequence.next > 1 {
+            // the device started over, what it sent before goes out first
+            log::info!("sequence", device = device, outcome = "restart");
+            ready.extend(
+                std::mem::take(&mut sequence.held)
+                    .into_iter()
+                    .map(|(seq, held)| (seq, held.message)),
+            );
+            sequence.next = 0;
+        }
+        sequence.first = Some(fingerprint);
+    }
+    if sequence.next == 0 {
+        sequence.next = seq;
+    }
+    if seq < sequence.next {
+        // already given up on, so it goes out late rather than never
+        log::info!("sequence", device = device, seq = seq, outcome = "late");
+        late = Some(message);
+    } else {
+        let held = HeldForward {
+            received: now,
+            message,
+        };
+        kv.put(&format!("sequence/{device}/{seq}"), to_json(&held))?
+            .execute()
+            .await?;
+        sequence.held.insert(seq, held);
+    }
+    let (released, gaps) = sequence.release(now);
+    ready.extend(released);
+    let mut ready = sequence.take(&kv, &device, ready).await?;
+    ready.extend(late);
+    send_released(&env, &device, ready, &gaps).await;
+    if !sequence.held.is_empty() {
+        sleep(Duration::from_secs(REORDER_WINDOW_SECONDS as u64)).await;
+        release_held(&env, &kv, &device).await?;
+    }
+    // sent in order with the others, possibly by a later invocation
+    Ok(ForwardResult::of("in_order"))
+}
+
+/// Sends the forwards of the device whose window is over, in order.
+async fn release_held(env: &Env, kv: &Kv, device: &str) -> Result<()> {
+    let mut sequence = ForwardSequence::load(kv, device).await?;
+    let (released, gaps) = sequence.release(timestamp_ms());
+    if released.is_empty() {
+        return Ok(());
+    }
+    let released = sequence.take(kv, device, released).await?;
+    send_released(env, device, released, &gaps).await;
+    Ok(())
+}
+
+async fn send_released(
+    env: &Env,
+    device: &str,
+    released: Vec<ForwardMessage>,
+    gaps: &[(u64, u64)],
+) {
+    for &(first, last) in gaps {
+        log::info!(
+            "sequence",
+            device = device,
+            first = first,
+            last = last,
+            outcome = "gap"
+        );
+        record_metric(env, "sequence_gap", device, "", (last - first + 1) as f64);
+        let numbers = if first == last {
+            format!("#{first}")
+        } else {
+            format!("#{first} to #{last}")
+        };
+        let text = format!("⚠️ {device} forwards {numbers} never arrived, they may have been lost");
+        send_message_by_device(env, device, &text).await;
+    }
+    for message in released {
+        if let Err(e) = forward(device.to_owned(), message, env.clone()).await {
+            log::error!("forward", device = device, error = e.to_string());
+        }
+    }
+}
+
+/// Sends what is still held for any device, in case the invocation which
+/// held it ended before its window did.
+async fn release_sequences(env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    for device in get_devices(env)? {
+        if let Err(e) = release_held(env, &kv, &device).await {
+            log::error!("sequence", device = device, error = e.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Forwards the message unless another replica already claimed it, giving
+/// the claim up again when this one could not send it.
+async fn forward(device: String, message: ForwardMessage, env: Env) -> Result<ForwardResult> {
+    // without a timestamp two real messages with the same text look the same
+    let Some(timestamp) = message.timestamp().map(|t| t.to_string()) else {
+        return forward_claimed(device, message, env).await;
+    };
+    let sender = message.sender().unwrap_or_default().to_owned();
+    let text = message.text().to_owned();
+    let parts = [device.as_str(), &sender, &text, &timestamp];
+    // a duplicate is better than a missed code when the store is unreachable
+    let claimed = dedup::claim(&env, &parts, timestamp_ms())
+        .await
+        .inspect_err(|e| log::error!("dedup", device = device, error = e.to_string()))
+        .unwrap_or(true);
+    if !claimed {
+        log::info!("forward", device = device, outcome = "duplicate");
+        record_metric(&env, "forward", &device, "duplicate", 1.0);
+        return Ok(ForwardResult::of("duplicate"));
+    }
+    let result = forward_claimed(device.clone(), message, env.clone()).await;
+    if !matches!(&result, Ok(result) if !matches!(result.outcome, "buffered" | "failed"))
+        && let Err(e) = dedup::release(&env, &parts).await
+    {
+        log::error!("dedup", device = device, error = e.to_string());
+    }
+    result
+}
+
+async fn forward_claimed(
+    device: String,
+    message: ForwardMessage,
+    env: Env,
+) -> Result<ForwardResult> {
+    // emergencies get through the spam filter and rules, which only route them
+    let emergency = is_emergency(&env, message.sender(), message.text());
+    let flags = get_flags(&env).await;
+    if !emergency && flags.is_spam(&device, message.sender()) {
+        log::info!("forward", device = device, outcome = "spam");
+        record_metric(&env, "forward", &device, "spam", 1.0);
+        return Ok(ForwardResult::of("spam"));
+    }
+    let rules = rules::list(&env)
+        .await
+        .inspect_err(|e| log::error!("rules", device = device, error = e.to_string()))
+        .unwrap_or_default();
+    // a correction sends itself and drops what a delay rule holds back
+    let cancelled = match message.sender() {
+        Some(sender) => cancel_delayed(&env, &device, sender, message.text())
+            .await
+            .inspect_err(|e| log::error!("delay", device = device, error = e.to_string()))
+            .unwrap_or(false),
+        None => false,
+    };
+    let time = local_time(&env, &device, timestamp_ms());
+    let rule = rules::find(&rules, &device, message.sender(), message.text(), time);
+    match rule.map(|rule| rule.action) {
+        Some(rules::Action::Drop) if !emergency => {
+            log::info!("forward", device = device, outcome = "rule_drop");
+            record_metric(&env, "forward", &device, "rule_drop", 1.0);
+            return Ok(ForwardResult::of("rule_drop"));
+        }
+        Some(rules::Action::Archive) if !emergency => {
+            log::info!("forward", device = device, outcome = "rule_archive");
+            record_metric(&env, "forward", &device, "rule_archive", 1.0);
+            archive_message(&env, &device, &message).await?;
+            return Ok(ForwardResult::of("rule_archive"));
+        }
+        Some(rules::Action::Delay) if !cancelled && !emergency => {
+            log::info!("forward", device = device, outcome = "rule_delay");
+            record_metric(&env, "forward", &device, "rule_delay", 1.0);
+            delay_message(&env, &device, &message, rule).await?;
+            return Ok(ForwardResult::of("rule_delay"));
+        }
+        Some(_) | None => {}
+    }
+    let test = flags.get_for(&device, DeviceFlag::Test);
+    let chat_id = if test {
+        get_optional_secret(&env, "admin_chat_id")
+    } else {
+        rule.and_then(|rule| rule.chat_id.clone())
+            .or_else(|| device_chat_id(&env, &device))
+    };
+    let mut text = forward_text(&device, &message);
+    if emergency {
+        log::info!("emergency", device = device);
+        record_metric(&env, "forward", &device, "emergency", 1.0);
+        text.insert_str(0, "🚨 ");
+        if let Some(mentions) = get_optional_secret(&env, "emergency_mentions") {
+            text.push_str(&format!("\n\n{}", escape_html(&mentions)));
+        }
+        emergency_webhook(&env, &device, &message).await;
+    }
+    if test {
+        text.insert_str(0, TEST_PREFIX);
+    }
+    let digest = !test && get_optional_secret(&env, &format!("{device}_digest_to")).is_some();
+    if digest && !emergency && flags.get_for(&device, DeviceFlag::DigestOnly) {
+        log::info!("forward", device = device, outcome = "digest_only");
+        record_metric(&env, "forward", &device, "digest_only", 1.0);
+        archive_message(&env, &device, &message).await?;
+        return Ok(ForwardResult::of("digest_only"));
+    }
+    if !test && !emergency && batched(&env, &device) && !domain::is_code(message.text()) {
+        log::info!("forward", device = device, outcome = "batched");
+        record_metric(&env, "forward", &device, "batched", 1.0);
+        batch_message(&env, &device, &message).await?;
+        return Ok(ForwardResult::of("batched"));
+    }
+    let within_quota = test
+        || emergency
+        || check_quota(&env, &device, digest)
+            .await
+            .inspect_err(|e| log::error!("quota", device = device, error = e.to_string()))
+            .unwrap_or(true);
+    if !within_quota {
+        log::info!("forward", device = device, outcome = "over_quota");
+        record_metric(&env, "forward", &device, "over_quota", 1.0);
+        archive_message(&env, &device, &message).await?;
+        return Ok(ForwardResult::of("over_quota"));
+    }
+    // the forward itself matters more than its archived copy
+    let mut complete = true;
+    if digest && let Err(e) = archive_message(&env, &device, &message).await {
+        log::error!("archive", device = device, error = e.to_string());
+        complete = false;
+    }
+    let delivery = random_uuid();
+    if !test && let Err(e) = record_delivery(&env, &delivery, &device, &message).await {
+        log::error!("delivery", device = device, error = e.to_string());
+    }
+    let hour = js_sys::Date::new(&JsValue::from_f64(timestamp_ms() as f64)).get_utc_hours();
+    // albums carry the text as a caption, which is left alone
+    let summarizable = message.media().len() < 2 && summary::is_long(&env, message.text());
+    let auto_summary = summarizable && flags.get_for(&device, DeviceFlag::Summarize);
+    let undelivered = fallback::Forward {
+        device: &device,
+        sender: message.sender(),
+        text: &domain::plain_text(&text),
+        timestamp: message.timestamp().unwrap_or_else(timestamp_ms),
+    };
+    let sent = match &chat_id {
+        Some(chat_id) => {
+            let body = SendMessageBody {
+                chat_id,
+                message_thread_id: if test {
+                    None
+                } else {
+                    device_topic(&env, &device, chat_id).await
+                },
+                text: &text,
+                parse_mode: "HTML",
+                link_preview_options: None,
+                disable_notification: !emergency && flags.is_quiet(&device, hour),
+                reply_markup: (summarizable && !auto_summary).then(summary::keyboard),
+            };
+            // while the circuit is open, later forwards queue up behind the
+            // buffered ones instead of overtaking them, a forward Telegram
+            // refuses failing on its own without opening it
+            let sent = if circuit_open(&env, chat_id).await {
+                Sent::Unreachable
+            } else {
+                match media_group(&body, message.media()) {
+                    Some(group) => send_media_group(&env, &group).await,
+                    None => try_send_message(&env, &body).await,
+                }
+            };
+            if sent == Sent::Unreachable {
+                log::info!("forward", device = device, outcome = "buffered");
+                record_metric(&env, "forward", &device, "buffered", 1.0);
+                fallback::deliver(&env, &undelivered, timestamp_ms()).await;
+                if let Err(e) = update_delivery(&env, &delivery, "buffered", None).await {
+                    log::error!("delivery", device = device, error = e.to_string());
+                }
+                let buffered = BufferedForward {
+                    device: device.clone(),
+                    delivery,
+                    message_thread_id: body.message_thread_id,
+                    text: text.clone(),
+                    disable_notification: body.disable_notification,
+                    sender: message.sender().map(ToOwned::to_owned),
+                    message: message.text().to_owned(),
+                };
+                buffer_forward(&env, chat_id, &buffered).await?;
+                return Ok(ForwardResult::of("buffered"));
+            }
+            sent.message_id()
+        }
+        None => None,
+    };
+    let (Some(message_id), Some(chat_id)) = (sent, chat_id) else {
+        if fallback::deliver(&env, &undelivered, timestamp_ms()).await {
+            log::info!("forward", device = device, outcome = "fallback");
+            record_metric(&env, "forward", &device, "fallback", 1.0);
+            if let Err(e) = update_delivery(&env, &delivery, "fallback", None).await {
+                log::error!("delivery", device = device, error = e.to_string());
+            }
+            count_forward(&env, &device, true).await?;
+            return Ok(ForwardResult::of("fallback"));
+        }
+        record_metric(&env, "forward", &device, "failed", 1.0);
+        if let Err(e) = update_delivery(&env, &delivery, "failed", None).await {
+            log::error!("delivery", device = device, error = e.to_string());
+        }
+        count_forward(&env, &device, false).await?;
+        return Ok(ForwardResult::of("failed"));
+    };
+    record_metric(&env, "forward", &device, "ok", 1.0);
+    let event = StreamEvent {
+        device: &device,
+        status: None,
+        sender: message.sender(),
+        text: Some(message.text()),
+        timestamp: message.timestamp().unwrap_or_else(timestamp_ms),
+    };
+    stream::publish(&env, "forward", &event).await;
+    if emergency {
+        pin_message(&env, &chat_id, message_id).await;
+    }
+    if summarizable {
+        let source = SummarySource {
+            text: text.clone(),
+            message: message.text().to_owned(),
+        };
+        if let Err(e) = offer_summary(&env, &chat_id, message_id, source, auto_summary).await {
+            log::error!("summary", device = device, error = e.to_string());
+        }
+    }
+    let (sender, text) = (message.sender(), message.text());
+    complete &= forward_sent(&env, &device, &delivery, &chat_id, message_id, sender, text).await;
+    if flags.reactions {
+        react_sent(&env, &chat_id, message_id, complete).await;
+    }
+    count_forward(&env, &device, true).await?;
+    Ok(ForwardResult {
+        outcome: "sent",
+        telegram_message_id: Some(message_id),
+    })
+}
+
+/// Records a forward which reached its chat, returning whether everything
+/// which follows it did as well.
+async fn forward_sent(
+    env: &Env,
+    device: &str,
+    delivery: &str,
+    chat_id: &str,
+    message_id: i64,
+    sender: Option<&str>,
+    text: &str,
+) -> bool {
+    let mut complete = true;
+    if let Err(e) = update_delivery(env, delivery, "sent", Some(message_id)).await {
+        log::error!("delivery", device = device, error = e.to_string());
+        complete = false;
+    }
+    if let Some(sender) = sender
+        && let Err(e) = remember_reply(env, chat_id, message_id, device, sender).await
+    {
+        log::error!("reply", device = device, error = e.to_string());
+        complete = false;
+    }
+    if let Some(seconds) = delete_after(env, device, text)
+        && let Err(e) = schedule_delete(env, chat_id, message_id, seconds).await
+    {
+        log::error!("expire", device = device, error = e.to_string());
+        complete = false;
+    }
+    complete
+}
+
+/// A long forward, kept under `summary/{chat_id}/{message_id}` for its
+/// Summarize button.
+#[derive(Debug, Serialize, Deserialize)]
+struct SummarySource {
+    /// The Telegram message, in HTML.
+    text: String,
+    /// The text of the SMS itself.
+    message: String,
+}
+
+/// Appends the summary of a long forward right away for devices with
+/// `summarize`, or keeps it for the Summarize button otherwise.
+async fn offer_summary(
+    env: &Env,
+    chat_id: &str,
+    message_id: i64,
+    source: SummarySource,
+    auto: bool,
+) -> Result<()> {
+    let Ok(chat_id) = chat_id.parse::<i64>() else {
+        return Ok(());
+    };
+    if auto {
+        return append_summary(env, chat_id, message_id, &source).await;
+    }
+    kv_store(env)?
+        .put(&format!("summary/{chat_id}/{message_id}"), to_json(&source))?
+        .expiration_ttl(SUMMARY_TTL_SECONDS)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Edits the forward to end with its summary, dropping the button.
+async fn append_summary(
+    env: &Env,
+    chat_id: i64,
+    message_id: i64,
+    source: &SummarySource,
+) -> Result<()> {
+    let summary = summary::summarize(env, &source.message).await?;
+    log::info!(
+        "summary",
+        chat_id = chat_id,
+        message_id = message_id,
+        chars = summary.chars().count()
+    );
+    let text = if summary.is_empty() {
+        source.text.clone()
+    } else {
+        format!("{}\n\n✨ <i>{}</i>", source.text, escape_html(&summary))
+    };
+    let body = EditMessageTextBody {
+        chat_id,
+        message_id,
+        text: &text,
+        parse_mode: "HTML",
+        reply_markup: None,
+    };
+    edit_message(env, &body).await;
+    Ok(())
+}
+
+async fn react_sent(env: &Env, chat_id: &str, message_id: i64, complete: bool) {
+    let emoji = if complete {
+        DELIVERED_REACTION
+    } else {
+        PARTIAL_REACTION
+    };
+    react(env, chat_id, message_id, emoji).await;
+}
+
+/// A forward buffered while the circuit to its chat is open.
+#[derive(Debug, Serialize, Deserialize)]
+struct BufferedForward {
+    device: String,
+    delivery: String,
+    message_thread_id: Option<i64>,
+    /// The Telegram message, in HTML.
+    text: String,
+    disable_notification: bool,
+    sender: Option<String>,
+    /// The text of the SMS itself.
+    message: String,
+}
+
+/// Whether forwards to the chat are buffered, after one failed to reach it.
+async fn circuit_open(env: &Env, chat_id: &str) -> bool {
+    let open = async {
+        Ok::<_, Error>(
+            kv_store(env)?
+                .get(&format!("circuit/{chat_id}"))
+                .text()
+                .await?,
+        )
+    };
+    open.await
+        .inspect_err(|e| log::error!("circuit", chat_id = chat_id, error = e.to_string()))
+        .is_ok_and(|since| since.is_some())
+}
+
+/// Keeps the forward under `outbox/{chat_id}/{timestamp}/{uuid}` and opens
+/// the circuit to the chat until `drain_outbox` sent it, the zero-padded
+/// timestamp keeping the forwards in order.
+async fn buffer_forward(env: &Env, chat_id: &str, buffered: &BufferedForward) -> Result<()> {
+    let kv = kv_store(env)?;
+    let now = timestamp_ms();
+    let key = format!("outbox/{chat_id}/{now:013}/{}", random_uuid());
+    kv.put(&key, to_json(buffered))?
+        .expiration_ttl(OUTBOX_TTL_SECONDS)
+        .execute()
+        .await?;
+    let circuit = format!("circuit/{chat_id}");
+    if kv.get(&circuit).text().await?.is_none() {
+        log::info!("circuit", chat_id = chat_id, outcome = "open");
+        kv.put(&circuit, now.to_string())?.execute().await?;
+    }
+    Ok(())
+}
+
+/// Sends the buffered forwards of each open circuit in order, stopping at
+/// the first one Telegram still can't be reached for, and closes the circuit
+/// once none are left. Forwards Telegram refuses are dropped with a note to
+/// the admin chat, so that one of them doesn't hold up the rest.
+async fn drain_outbox(env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    let reactions = get_flags(env).await.reactions;
+    for circuit in kv.list_keys("circuit/").await? {
+        let chat_id = &circuit["circuit/".len()..];
+        let mut drained = true;
+        for key in kv.list_keys(&format!("outbox/{chat_id}/")).await? {
+            let Some(buffered) = kv.get(&key).json::<BufferedForward>().await? else {
+                continue;
+            };
+            let body = SendMessageBody {
+                chat_id,
+                message_thread_id: buffered.message_thread_id,
+                text: &buffered.text,
+                parse_mode: "HTML",
+                link_preview_options: None,
+                disable_notification: buffered.disable_notification,
+                reply_markup: None,
+            };
+            let device = &buffered.device;
+            let message_id = match try_send_message(env, &body).await {
+                Sent::Delivered(message_id) => message_id,
+                Sent::Refused => {
+                    kv.delete(&key).await?;
+                    dead_letter(env, chat_id, &buffered).await?;
+                    continue;
+                }
+                Sent::Unreachable => {
+                    drained = false;
+                    break;
+                }
+            };
+            kv.delete(&key).await?;
+            log::info!("forward", device = device, outcome = "drained");
+            record_metric(env, "forward", device, "drained", 1.0);
+            let (sender, text) = (buffered.sender.as_deref(), buffered.message.as_str());
+            let delivery = &buffered.delivery;
+            let complete =
+                forward_sent(env, device, delivery, chat_id, message_id, sender, text).await;
+            if reactions {
+                react_sent(env, chat_id, message_id, complete).await;
+            }
+            count_forward(env, device, true).await?;
+        }
+        if drained {
+            log::info!("circuit", chat_id = chat_id, outcome = "closed");
+            kv.delete(&circuit).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives up on a buffered forward Telegram refuses, telling the admin chat
+/// what it said.
+async fn dead_letter(env: &Env, chat_id: &str, buffered: &BufferedForward) -> Result<()> {
+    let device = &buffered.device;
+    log::info!("forward", device = device, outcome = "dead_letter");
+    record_metric(env, "forward", device, "dead_letter", 1.0);
+    if let Err(e) = update_delivery(env, &buffered.delivery, "failed", None).await {
+        log::error!("delivery", device = device, error = e.to_string());
+    }
+    let text = format!(
+        "⚠️ dropped a buffered forward from <code>{device}</code> Telegram refused in chat \
+         <code>{chat_id}</code>\n\n{sender}{message}",
+        device = escape_html(device),
+        chat_id = escape_html(chat_id),
+        sender = buffered
+            .sender
+            .as_deref()
+            .map(|sender| format!("<b>{}</b>\n", escape_html(sender)))
+            .unwrap_or_default(),
+        message = escape_html(&buffered.message),
+    );
+    notify_admin(env, &text).await;
+    count_forward(env, device, false).await
+}
+
+/// Seconds after which a forward of `text` is deleted, from
+/// `{device}_delete_codes_after_minutes` for one-time codes and
+/// `{device}_delete_after_minutes` otherwise.
+fn delete_after(env: &Env, device: &str, text: &str) -> Option<i64> {
+    let minutes = |key: String| {
+        get_optional_secret(env, &key)
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|&minutes| minutes > 0)
+    };
+    let codes = domain::is_code(text)
+        .then(|| minutes(format!("{device}_delete_codes_after_minutes")))
+        .flatten();
+    codes
+        .or_else(|| minutes(format!("{device}_delete_after_minutes")))
+        .map(|minutes| (minutes * 60).min(DELETE_LIMIT_SECONDS))
+}
+
+/// Keeps the forward under `expire/{deadline}/{chat_id}/{message_id}`, the
+/// zero-padded deadline letting the sweep stop at the first one to come.
+async fn schedule_delete(env: &Env, chat_id: &str, message_id: i64, seconds: i64) -> Result<()> {
+    let deadline = timestamp_ms() + seconds * 1000;
+    let key = format!("expire/{deadline:013}/{chat_id}/{message_id}");
+    kv_store(env)?
+        .put(&key, "")?
+        .expiration_ttl((seconds + 24 * 3600) as u64)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// Deletes the forwards whose deadline has passed.
+async fn delete_expired(env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    let now = timestamp_ms();
+    for key in kv.list_keys("expire/").await? {
+        let mut parts = key["expire/".len()..].splitn(3, '/');
+        let (Some(deadline), Some(chat_id), Some(message_id)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        if deadline.parse::<i64>().is_ok_and(|deadline| deadline > now) {
+            break;
+        }
+        if let Ok(message_id) = message_id.parse() {
+            let body = DeleteMessageBody {
+                chat_id,
+                message_id,
+            };
+            // a message deleted by hand answers not ok, which is just as good
+            call_telegram(env, "deleteMessage", chat_id, || async {
+                TelegramClient::new(env)?.delete_message(&body).await
+            })
+            .await;
+        }
+        kv.delete(&key).await?;
+    }
+    Ok(())
+}
+
+/// Remembered so that replying to the forward in Telegram answers by SMS.
+async fn remember_reply(
+    env: &Env,
+    chat_id: &str,
+    message_id: i64,
+    device: &str,
+    sender: &str,
+) -> Result<()> {
+    let key = format!("reply/{chat_id}/{message_id}");
+    let value = ForwardedSender {
+        device: device.to_owned(),
+        sender: sender.to_owned(),
+    };
+    kv_store(env)?
+        .put(&key, to_json(&value))?
+        .expiration_ttl(REPLY_TTL_SECONDS)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+async fn react(env: &Env, chat_id: &str, message_id: i64, emoji: &str) {
+    let body = SetMessageReactionBody {
+        chat_id,
+        message_id,
+        reaction: [ReactionTypeEmoji {
+            kind: "emoji",
+            emoji,
+        }],
+    };
+    call_telegram(env, "setMessageReaction", chat_id, || async {
+        TelegramClient::new(env)?.set_message_reaction(&body).await
+    })
+    .await;
+}
+
+/// Day and hour of `now` in the time zone of `{device}_utc_offset`, e.g.
+/// `+08:00`, UTC without it.
+fn local_time(env: &Env, device: &str, now: i64) -> rules::LocalTime {
+    let offset = get_optional_secret(env, &format!("{device}_utc_offset"))
+        .and_then(|offset| domain::parse_utc_offset(&offset))
+        .unwrap_or_default();
+    let date = js_sys::Date::new(&JsValue::from_f64((now + offset * 60 * 1000) as f64));
+    rules::LocalTime {
+        day: date.get_utc_day(),
+        hour: date.get_utc_hours(),
+    }
+}
+
+/// The Telegram message of a forward.
+fn forward_text(device: &str, message: &ForwardMessage) -> String {
+    let mut text = format!("{device} {message}");
+    if let Some(timestamp) = message.timestamp() {
+        text.push_str(&format!(
+            "\n\n🕒 {date} {time}",
+            date = format_date(timestamp),
+            time = format_time(timestamp)
+        ));
+    }
+    text
+}
+
+/// How a body posted by a device would be handled, see `dry_run`.
+#[derive(Debug, Default, Serialize)]
+struct DryRun {
+    device: String,
+    /// `sms`, `rcs`, `status`, `location`, `heartbeat` or `echo`.
+    kind: &'static str,
+    sender: Option<String>,
+    text: Option<String>,
+    timestamp: Option<i64>,
+    spam: bool,
+    rule: Option<rules::Rule>,
+    /// `sent`, `failed` without a chat, `spam`, `rule_drop`, `rule_archive`
+    /// or `digest_only`, like the outcome of the `forward` log.
+    outcome: Option<&'static str>,
+    /// The Telegram message, in HTML.
+    message: Option<String>,
+    /// `telegram:{chat_id}`, `stream`, `archive` or `quarantine`.
+    destinations: Vec<String>,
+}
+
+fn telegram_destination(chat_id: String) -> String {
+    format!("telegram:{chat_id}")
+}
+
+/// Parses `body` as `device_route` does and follows it through `forward`,
+/// without sending, storing or counting anything. Duplicates and quotas are
+/// not checked, as checking them counts the forward.
+async fn dry_run(env: &Env, device: String, body: &str) -> Result<DryRun> {
+    let mut run = DryRun {
+        device,
+        ..Default::default()
+    };
+    let device = run.device.as_str();
+    let message = if body.is_empty() {
+        run.kind = "heartbeat";
+        None
+    } else if let Some(query) = from_json(body) {
+        run.kind = "sms";
+        Some(ForwardMessage::Sms(query))
+    } else if let Some(message) = from_json(body) {
+        run.kind = "rcs";
+        Some(ForwardMessage::Rcs(message))
+    } else if let Some(status) = from_json::<StatusReport>(body) {
+        run.kind = "status";
+        run.message = Some(format!("{device} {}", Vitals::from(&status)));
+        None
+    } else if from_json::<LocationReport>(body).is_some() {
+        run.kind = "location";
+        None
+    } else if let Some(HeartbeatPayload { vitals, timestamp }) = from_json(body)
+        && (vitals.is_some() || timestamp.is_some())
+    {
+        run.kind = "heartbeat";
+        run.timestamp = timestamp;
+        None
+    } else {
+        run.kind = "echo";
+        run.destinations.push("quarantine".to_owned());
+        if get_flags(env).await.debug_echo {
+            run.message = Some(quarantine_notice(device, 1));
+            run.destinations
+                .extend(device_chat_id(env, device).map(telegram_destination));
+        }
+        None
+    };
+    if matches!(run.kind, "status" | "location") {
+        run.destinations
+            .extend(device_chat_id(env, device).map(telegram_destination));
+    }
+    let Some(message) = message else {
+        return Ok(run);
+    };
+    run.sender = message.sender().map(ToOwned::to_owned);
+    run.text = Some(message.text().to_owned());
+    run.timestamp = message.timestamp();
+    let emergency = is_emergency(env, message.sender(), message.text());
+    let flags = get_flags(env).await;
+    run.spam = !emergency && flags.is_spam(device, message.sender());
+    if run.spam {
+        run.outcome = Some("spam");
+        return Ok(run);
+    }
+    run.destinations.push("stream".to_owned());
+    let rules = rules::list(env).await?;
+    let time = local_time(env, device, timestamp_ms());
+    run.rule = rules::find(&rules, device, message.sender(), message.text(), time).cloned();
+    let test = flags.get_for(device, DeviceFlag::Test);
+    let digest = !test && get_optional_secret(env, &format!("{device}_digest_to")).is_some();
+    match run.rule.as_ref().map(|rule| rule.action) {
+        Some(rules::Action::Drop) if !emergency => {
+            run.outcome = Some("rule_drop");
+            return Ok(run);
+        }
+        Some(rules::Action::Archive) if !emergency => {
+            run.outcome = Some("rule_archive");
+            run.destinations.push("archive".to_owned());
+            return Ok(run);
+        }
+        Some(rules::Action::Delay) if !emergency => {
+            run.outcome = Some("rule_delay");
+            run.destinations.push("delay".to_owned());
+            return Ok(run);
+        }
+        Some(_) | None => {}
+    }
+    if emergency && get_optional_secret(env, "emergency_webhook_url").is_some() {
+        run.destinations.push("emergency_webhook".to_owned());
+    }
+    if digest && !emergency && flags.get_for(device, DeviceFlag::DigestOnly) {
+        run.outcome = Some("digest_only");
+        run.destinations.push("archive".to_owned());
+        return Ok(run);
+    }
+    if digest {
+        run.destinations.push("archive".to_owned());
+    }
+    let chat_id = if test {
+        get_optional_secret(env, "admin_chat_id")
+    } else {
+        run.rule
+            .as_ref()
+            .and_then(|rule| rule.chat_id.clone())
+            .or_else(|| device_chat_id(env, device))
+    };
+    run.outcome = Some(if chat_id.is_some() { "sent" } else { "failed" });
+    run.destinations.extend(chat_id.map(telegram_destination));
+    let mut text = forward_text(device, &message);
+    if emergency {
+        text.insert_str(0, "🚨 ");
+        if let Some(mentions) = get_optional_secret(env, "emergency_mentions") {
+            text.push_str(&format!("\n\n{}", escape_html(&mentions)));
+        }
+    }
+    run.message = Some(if test {
+        format!("{TEST_PREFIX}{text}")
+    } else {
+        text
+    });
+    Ok(run)
+}
+
+/// Inserts a queued delivery receipt into the optional `deliveries` D1
+/// database.
+async fn record_delivery(
+    env: &Env,
+    id: &str,
+    device: &str,
+    message: &ForwardMessage,
+) -> Result<()> {
+    let Ok(db) = env.d1("deliveries") else {
+        return Ok(());
+    };
+    let now = timestamp_ms();
+    db.prepare(
+        "INSERT INTO deliveries (id, device, sender, received, updated, state, category) \
+         VALUES (?1, ?2, ?3, ?4, ?5, 'queued', ?6)",
+    )
+    .bind(&[
+        id.into(),
+        device.into(),
+        message.sender().unwrap_or("unknown").into(),
+        (message.timestamp().unwrap_or(now) as f64).into(),
+        (now as f64).into(),
+        message.category().into(),
+    ])?
+    .run()
+    .await?;
+    Ok(())
+}
+
+/// Latest deliveries of `device`, `None` without the `deliveries` database.
+async fn recent_deliveries(env: &Env, device: &str) -> Result<Option<Vec<Delivery>>> {
+    let Ok(db) = env.d1("deliveries") else {
+        return Ok(None);
+    };
+    let deliveries = db
+        .prepare(
+            "SELECT sender, received, updated, state, message_id FROM deliveries \
+             WHERE device = ?1 ORDER BY received DESC LIMIT ?2",
+        )
+        .bind(&[device.into(), (DELIVERIES_SHOWN as f64).into()])?
+        .all()
+        .await?
+        .results()?;
+    Ok(Some(deliveries))
+}
+
+async fn update_delivery(env: &Env, id: &str, state: &str, message_id: Option<i64>) -> Result<()> {
+    let Ok(db) = env.d1("deliveries") else {
+        return Ok(());
+    };
+    db.prepare("UPDATE deliveries SET state = ?1, message_id = ?2, updated = ?3 WHERE id = ?4")
+        .bind(&[
+            state.into(),
+            message_id.map_or(JsValue::NULL, |id| (id as f64).into()),
+            (timestamp_ms() as f64).into(),
+            id.into(),
+        ])?
+        .run()
+        .await?;
+    Ok(())
+}
+
+/// Counts a forward in `metrics/{device}`, adding the counts up in the
+/// isolate and writing them every `FORWARD_COUNTS_FLUSH_SECONDS`.
+async fn count_forward(env: &Env, device: &str, ok: bool) -> Result<()> {
+    if get_flags(env).await.get_for(device, DeviceFlag::Test) {
+        return Ok(());
+    }
+    let now = timestamp_ms();
+    let flushed = {
+        let mut pending = FORWARD_COUNTS.lock().unwrap();
+        let key = heartbeat_key(device);
+        let counts = pending.entry(key.clone()).or_insert(PendingForwards {
+            counters: ForwardCounters::default(),
+            since: now,
+        });
+        if ok {
+            counts.counters.forwarded += 1;
+        } else {
+            counts.counters.failed += 1;
+        }
+        if now - counts.since >= FORWARD_COUNTS_FLUSH_SECONDS * 1000 {
+            pending.remove(&key)
+        } else {
+            None
+        }
+    };
+    let Some(flushed) = flushed else {
+        return Ok(());
+    };
+    let kv = kv_store(env)?;
+    let key = format!("metrics/{device}");
+    let mut counters: ForwardCounters =
+        kv.get(&key).json().await.ok().flatten().unwrap_or_default();
+    counters.add(&flushed.counters);
+    kv.put(&key, to_json(counters))?.execute().await?;
+    Ok(())
+}
+
+/// Renders per-device counters and heartbeat gauges in the Prometheus text
+/// format.
+async fn render_metrics(env: Env) -> Result<Response> {
+    let kv = kv_store(&env)?;
+    let devices = get_devices(&env)?;
+    let now = timestamp_ms();
+    let mut forwarded = Vec::new();
+    let mut failed = Vec::new();
+    let mut state = Vec::new();
+    let mut age = Vec::new();
+    for device in &devices {
+        let mut counters: ForwardCounters = kv
+            .get(&format!("metrics/{device}"))
+            .json()
+            .await?
+            .unwrap_or_default();
+        counters.add(&ForwardCounters::pending(device));
+        forwarded.push(format!(
+            "sms_forward_messages_forwarded_total{{device=\"{device}\"}} {}",
+            counters.forwarded
+        ));
+        failed.push(format!(
+            "sms_forward_messages_failed_total{{device=\"{device}\"}} {}",
+            counters.failed
+        ));
+        let status = HeartbeatStatus::get(&kv, device).await?;
+        for (s, name) in [(Active, "active"), (Inactive, "inactive"), (Dead, "dead")] {
+            state.push(format!(
+                "sms_forward_device_state{{device=\"{device}\",state=\"{name}\"}} {}",
+                u8::from(s == status)
+            ));
+        }
+        if let Some(last) = last_seen(&kv, device).await? {
+            age.push(format!(
+                "sms_forward_last_heartbeat_age_seconds{{device=\"{device}\"}} {}",
+                (now - last) / 1000
+            ));
+        }
+    }
+    let text = [
+        (
+            "sms_forward_messages_forwarded_total",
+            "counter",
+            "Messages forwarded to Telegram.",
+            forwarded,
+        ),
+        (
+            "sms_forward_messages_failed_total",
+            "counter",
+            "Messages which failed to forward to Telegram.",
+            failed,
+        ),
+        (
+            "sms_forward_device_state",
+            "gauge",
+            "Heartbeat state of the device.",
+            state,
+        ),
+        (
+            "sms_forward_last_heartbeat_age_seconds",
+            "gauge",
+            "Seconds since the last heartbeat, while it is remembered.",
+            age,
+        ),
+    ]
+    .into_iter()
+    .map(|(name, kind, help, samples)| {
+        format!(
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{}\n",
+            samples.join("\n")
+        )
+    })
+    .join("");
+    Ok(Response::builder()
+        .with_headers(
+            [("Content-Type", "text/plain; version=0.0.4")]
+                .into_iter()
+                .collect(),
+        )
+        .fixed(text.into_bytes()))
+}
+
+/// Counts a forward against the daily quotas, returning whether it is still
+/// within them. Exceeding one is announced once a day. The isolate adds its
+/// counts to `quota/{date}` every `QUOTA_FLUSH_SECONDS` rather than with
+/// each forward, so concurrent isolates may let a few more through.
+async fn check_quota(env: &Env, device: &str, digest: bool) -> Result<bool> {
+    let parse = |key: &str| get_optional_secret(env, key).and_then(|s| s.parse::<u32>().ok());
+    let device_quota = parse(&format!("{device}_daily_quota"));
+    let total_quota = parse("daily_quota");
+    if device_quota.is_none() && total_quota.is_none() {
+        return Ok(true);
+    }
+    let kv = kv_store(env)?;
+    let now = timestamp_ms();
+    let date = format_date(now);
+    let key = format!("quota/{date}");
+    let mut quota: DailyQuota = kv.get(&key).json().await?.unwrap_or_default();
+    // the forwards counted by this isolate since it last wrote the quota
+    let tenant = log::tenant().unwrap_or_default();
+    let pending = {
+        let mut counts = QUOTA_COUNTS.lock().unwrap();
+        let pending = counts.entry(tenant.clone()).or_default();
+        if pending.date != date {
+            *pending = PendingQuota {
+                date,
+                since: now,
+                ..PendingQuota::default()
+            };
+        }
+        *pending.devices.entry(device.to_owned()).or_default() += 1;
+        pending.total += 1;
+        pending.clone()
+    };
+    for (device, count) in &pending.devices {
+        *quota.devices.entry(device.clone()).or_default() += count;
+    }
+    quota.total += pending.total;
+    let count = quota.devices.get(device).copied().unwrap_or_default();
+    let exceeded = if device_quota.is_some_and(|limit| count > limit) {
+        Some((device.to_owned(), device_quota))
+    } else if total_quota.is_some_and(|limit| quota.total > limit) {
+        Some((String::new(), total_quota))
+    } else {
+        None
+    };
+    let announce = exceeded
+        .as_ref()
+        .is_some_and(|(name, _)| !quota.exceeded.contains(name));
+    if announce && let Some((name, _)) = &exceeded {
+        quota.exceeded.push(name.clone());
+    }
+    if announce || now - pending.since >= QUOTA_FLUSH_SECONDS * 1000 {
+        if let Some(counts) = QUOTA_COUNTS.lock().unwrap().get_mut(&tenant)
+            && counts.date == pending.date
+        {
+            for (device, count) in &pending.devices {
+                if let Some(left) = counts.devices.get_mut(device) {
+                    *left = left.saturating_sub(*count);
+                }
+            }
+            counts.total = counts.total.saturating_sub(pending.total);
+            counts.since = now;
+        }
+        kv.put(&key, to_json(&quota))?
+            .expiration_ttl(QUOTA_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    let Some((name, limit)) = exceeded else {
+        return Ok(true);
+    };
+    if announce {
+        let what = if name.is_empty() {
+            "all devices"
+        } else {
+            device
+        };
+        let then = if digest {
+            "switching to digest"
+        } else {
+            "further messages are only archived today"
+        };
+        let text = format!(
+            "⚠️ {what} exceeded the daily quota of {} messages, {then}",
+            limit.unwrap_or_default()
+        );
+        send_message_by_device(env, device, &text).await;
+    }
+    Ok(false)
+}
+
+/// Keeps the message for the digest under
+/// `messages/{device}/{date}/{timestamp}/{uuid}`, one key each so that
+/// forwards arriving at the same time don't overwrite each other.
+async fn archive_message(env: &Env, device: &str, message: &ForwardMessage) -> Result<()> {
+    let kv = kv_store(env)?;
+    let now = timestamp_ms();
+    let key = format!(
+        "messages/{device}/{}/{now:013}/{}",
+        format_date(now),
+        random_uuid()
+    );
+    let archived = ArchivedMessage {
+        sender: message.sender().unwrap_or("unknown").to_owned(),
+        text: message.text().to_owned(),
+        timestamp: message.timestamp().unwrap_or(now),
+    };
+    kv.put(&key, to_json(&archived))?
+        .expiration_ttl(MESSAGE_ARCHIVE_TTL_SECONDS)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+/// The messages archived on `date`, in the order they arrived.
+async fn load_archive(kv: &Kv, device: &str, date: &str) -> Result<Vec<ArchivedMessage>> {
+    let mut messages = Vec::new();
+    for key in kv.list_keys(&format!("messages/{device}/{date}/")).await? {
+        messages.extend(kv.get(&key).json::<ArchivedMessage>().await?);
+    }
+    Ok(messages)
+}
+
+/// Whether forwards of the device are sent in batches, by
+/// `{device}_batch_every_hours` or `{device}_batch_at_hours`.
+fn batched(env: &Env, device: &str) -> bool {
+    ["every", "at"]
+        .iter()
+        .any(|kind| get_optional_secret(env, &format!("{device}_batch_{kind}_hours")).is_some())
+}
+
+/// Holds the forward back for the device's next batch under
+/// `batch/{device}/{timestamp}/{uuid}`.
+async fn batch_message(env: &Env, device: &str, message: &ForwardMessage) -> Result<()> {
+    let kv = kv_store(env)?;
+    PendingBatch::hold(&kv, &format!("batch/{device}/"), message, timestamp_ms()).await
+}
+
+fn batch_text(device: &str, batch: &PendingBatch) -> String {
+    let count = batch.messages.len();
+    let mut text = format!(
+        "🗂 {device} {count} message{}",
+        if count == 1 { "" } else { "s" }
+    );
+    for message in &batch.messages {
+        text.push_str(&format!(
+            "\n\n<code>{sender}</code> {time}\n{text}",
+            sender = escape_html(&message.sender),
+            time = format_time(message.timestamp),
+            text = highlight_codes(&message.text),
+        ));
+    }
+    text
+}
+
+/// Sends the held back forwards of the device as one message, every
+/// `{device}_batch_every_hours` after the first of them or at the local hours
+/// of the comma separated `{device}_batch_at_hours`.
+async fn flush_batch(env: &Env, kv: &Kv, device: &str) -> Result<()> {
+    let now = timestamp_ms();
+    let every = get_optional_secret(env, &format!("{device}_batch_every_hours"))
+        .and_then(|hours| hours.parse::<i64>().ok());
+    let at = get_optional_secret(env, &format!("{device}_batch_at_hours")).unwrap_or_default();
+    let batch = PendingBatch::load(kv, &format!("batch/{device}/")).await?;
+    let hour = local_time(env, device, now).hour;
+    let due = every.is_some_and(|hours| now - batch.since >= hours * 3600 * 1000)
+        || at
+            .split(',')
+            .any(|h| h.trim().parse::<u32>().is_ok_and(|h| h == hour));
+    if !due || batch.messages.is_empty() {
+        return Ok(());
+    }
+    log::info!("batch", device = device, messages = batch.messages.len());
+    if send_message_by_device(env, device, &batch_text(device, &batch))
+        .await
+        .is_some()
+    {
+        record_metric(env, "batch", device, "ok", batch.messages.len() as f64);
+        batch.delete(kv).await?;
+    }
+    Ok(())
+}
+
+/// A forward held back by a `delay` rule, kept under
+/// `delayed/{device}/{sender}/{timestamp}/{uuid}`.
+#[derive(Debug, Serialize, Deserialize)]
+struct DelayedForward {
+    due: i64,
+    cancel: Option<String>,
+    message: ArchivedMessage,
+}
+
+/// Forwards of a sender held back by a `delay` rule until the first of them
+/// is due.
+#[derive(Debug)]
+struct DelayedForwards {
+    /// `delayed/{device}/{sender}`.
+    prefix: String,
+    due: i64,
+    cancel: Option<String>,
+    batch: PendingBatch,
+}
+
+impl DelayedForwards {
+    /// Those below `prefix` by sender, in the order they arrived.
+    async fn load(kv: &Kv, prefix: &str) -> Result<Vec<Self>> {
+        let mut senders: Vec<Self> = Vec::new();
+        for key in kv.list_keys(prefix).await? {
+            let Some(sender_prefix) = key.rsplitn(3, '/').nth(2) else {
+                continue;
+            };
+            let Some(delayed) = kv.get(&key).json::<DelayedForward>().await? else {
+                continue;
+            };
+            let sender = match senders.last_mut() {
+                Some(sender) if sender.prefix == sender_prefix => sender,
+                _ => {
+                    senders.push(Self {
+                        prefix: sender_prefix.to_owned(),
+                        due: delayed.due,
+                        cancel: delayed.cancel,
+                        batch: PendingBatch {
+                            since: delayed.message.timestamp,
+                            ..PendingBatch::default()
+                        },
+                    });
+                    senders.last_mut().unwrap()
+                }
+            };
+            sender.batch.messages.push(delayed.message);
+            sender.batch.keys.push(key);
+        }
+        Ok(senders)
+    }
+}
+
+/// Holds the forward back under its own key, going out with those of the
+/// same sender which arrive before the first one is due.
+async fn delay_message(
+    env: &Env,
+    device: &str,
+    message: &ForwardMessage,
+    rule: Option<&rules::Rule>,
+) -> Result<()> {
+    let kv = kv_store(env)?;
+    let now = timestamp_ms();
+    let sender = message.sender().unwrap_or("unknown");
+    let minutes = rule
+        .and_then(|rule| rule.delay.as_deref())
+        .and_then(rules::delay_minutes)
+        .unwrap_or(1);
+    let delayed = DelayedForward {
+        due: now + minutes * 60 * 1000,
+        cancel: rule.and_then(|rule| rule.cancel.clone()),
+        message: ArchivedMessage {
+            sender: sender.to_owned(),
+            text: message.text().to_owned(),
+            timestamp: message.timestamp().unwrap_or(now),
+        },
+    };
+    let key = format!("delayed/{device}/{sender}/{now:013}/{}", random_uuid());
+    kv.put(&key, to_json(&delayed))?.execute().await?;
+    Ok(())
+}
+
+/// Drops the forwards held back for `sender` when `text` matches the
+/// `cancel` pattern of their rule.
+async fn cancel_delayed(env: &Env, device: &str, sender: &str, text: &str) -> Result<bool> {
+    let kv = kv_store(env)?;
+    let prefix = format!("delayed/{device}/{sender}");
+    let mut cancelled = false;
+    // the prefix also matches senders like `{sender}/...`
+    for delayed in DelayedForwards::load(&kv, &format!("{prefix}/")).await? {
+        if delayed.prefix != prefix
+            || !delayed
+                .cancel
+                .as_deref()
+                .is_some_and(|cancel| rules::is_match(cancel, text))
+        {
+            continue;
+        }
+        log::info!(
+            "forward",
+            device = device,
+            outcome = "rule_cancel",
+            dropped = delayed.batch.messages.len()
+        );
+        delayed.batch.delete(&kv).await?;
+        cancelled = true;
+    }
+    Ok(cancelled)
+}
+
+/// Sends the forwards held back by `delay` rules once they are due.
+async fn send_delayed(env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    let now = timestamp_ms();
+    for delayed in DelayedForwards::load(&kv, "delayed/").await? {
+        let Some((device, _)) = delayed.prefix["delayed/".len()..].split_once('/') else {
+            continue;
+        };
+        if delayed.due > now {
+            continue;
+        }
+        let text = batch_text(device, &delayed.batch);
+        if send_message_by_device(env, device, &text).await.is_some() {
+            delayed.batch.delete(&kv).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn flush_batches(env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    for device in get_devices(&env)? {
+        if let Err(e) = flush_batch(&env, &kv, &device).await {
+            log::error!("batch", device = device, error = e.to_string());
+        }
+    }
+    Ok(())
+}
+
+async fn check_clock_skew(device: String, device_timestamp_ms: i64, env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    let key = format!("skew/{device}");
+    let skew = device_timestamp_ms - timestamp_ms();
+    let previous = kv
+        .get(&key)
+        .text()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|v| v.parse::<i64>().ok());
+    log::info!(
+        "clock_skew",
+        device = device,
+        skew_ms = skew,
+        previous_ms = previous
+    );
+    let exceeds = |skew: i64| skew.abs() > CLOCK_SKEW_THRESHOLD_SECONDS * 1000;
+    if exceeds(skew) && !previous.is_some_and(exceeds) {
+        send_message_by_device(
+            &env,
+            &device,
+            &format!(
+                "⏰ {device} clock is off by {sign}{duration}",
+                sign = if skew > 0 { "+" } else { "-" },
+                duration = format_duration(skew.abs() / 1000)
+            ),
+        )
+        .await;
+    }
+    kv.put(&key, skew)?.execute().await?;
+    Ok(())
+}
+
+async fn heartbeat(device: String, env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    let mut cadence = Cadence::get(&kv, &device).await;
+    let interval = cadence.interval();
+    let status = HeartbeatStatus::get_at(&kv, &device, interval).await?;
+    record_metric(&env, "heartbeat", &device, &format!("{status:?}"), 1.0);
+    log::info!(
+        "heartbeat",
+        device = device,
+        previous = format!("{status:?}")
+    );
+    if status != Active {
+        if let Err(e) = recover(&env, &kv, &device).await {
+            log::error!("flapping", device = device, error = e.to_string());
+        }
+        if let Err(e) = deliver_queued_emails(&env, &device).await {
+            log::error!("deliver_queued", device = device, error = e.to_string());
+        }
+    }
+    if let Err(e) = answer_ping(&env, &kv, &device).await {
+        log::error!("ping", device = device, error = e.to_string());
+    }
+    let now = timestamp_ms();
+    let key = heartbeat_key(&device);
+    let written = HEARTBEATS
+        .lock()
+        .unwrap()
+        .get(&key)
+        .map(|cached| cached.written)
+        .unwrap_or_default();
+    if status != Active || now - written >= HEARTBEAT_WRITE_INTERVAL_SECONDS * 1000 {
+        // the gap before a device went down says nothing about its cadence
+        if status != Dead
+            && let Some(previous) = last_seen(&kv, &device).await?
+            && let Err(e) = cadence.record(&kv, &device, (now - previous) / 1000).await
+        {
+            log::error!("cadence", device = device, error = e.to_string());
+        }
+        kv.put_text(
+            &device,
+            &now.to_string(),
+            Some((interval as f64 * 2.5) as u64),
+        )
+        .await?;
+        HEARTBEATS.lock().unwrap().insert(
+            key,
+            CachedHeartbeat {
+                seen: now,
+                written: now,
+            },
+        );
+    } else {
+        HEARTBEATS
+            .lock()
+            .unwrap()
+            .insert(key, CachedHeartbeat { seen: now, written });
+    }
+    Ok(())
+}
+
+/// Announces a device which is back up, unless it was missed fewer than
+/// `down_after_misses` times and never announced down, or has to stay up for
+/// `up_after_minutes` first, which the checks then wait out.
+async fn recover(env: &Env, kv: &Kv, device: &str) -> Result<()> {
+    let mut flapping = Flapping::get(kv, device).await?;
+    let down = load_outages(kv, device)
+        .await?
+        .last()
+        .is_some_and(|outage| outage.end.is_none());
+    let misses = std::mem::take(&mut flapping.misses);
+    if down && minutes_secret(env, "up_after_minutes") > 0 {
+        flapping.recovering.get_or_insert(timestamp_ms());
+    } else if down || misses == 0 {
+        announce_up(env, kv, device).await;
+    }
+    flapping.put(kv, device).await
+}
+
+fn minutes_secret(env: &Env, key: &str) -> i64 {
+    get_optional_secret(env, key)
+        .and_then(|minutes| minutes.parse().ok())
+        .unwrap_or_default()
+}
+
+async fn announce_up(env: &Env, kv: &Kv, device: &str) {
+    let duration = record_outage_end(kv, device)
+        .await
+        .inspect_err(|e| log::error!("outage", device = device, error = e.to_string()))
+        .ok()
+        .flatten();
+    let text = match duration {
+        Some(duration) => format!(
+            "🟢 {device} is now up after {}",
+            format_duration(duration / 1000)
+        ),
+        None => format!("🟢 {device} is now up"),
+    };
+    send_message_by_device(env, device, &text).await;
+    status_webhook(env, device, "up", duration).await;
+    if let Err(e) = kv.delete(&format!("escalate/{device}")).await {
+        log::error!("escalate", device = device, error = e.to_string());
+    }
+    stream::publish(env, "status", &StreamEvent::status(device, "up")).await;
+    send_event_sticker(env, device, StickerEvent::Up).await;
+}
+
+/// Whether the message comes from one of `emergency_senders` or contains
+/// one of `emergency_keywords`, both comma-separated and matched without
+/// regard to case.
+fn is_emergency(env: &Env, sender: Option<&str>, text: &str) -> bool {
+    let list = |key: &str| {
+        get_optional_secret(env, key)
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_lowercase)
+            .collect_vec()
+    };
+    let sender = sender.map(str::to_lowercase);
+    let text = text.to_lowercase();
+    list("emergency_senders")
+        .iter()
+        .any(|s| sender.as_ref() == Some(s))
+        || list("emergency_keywords")
+            .iter()
+            .any(|keyword| text.contains(keyword.as_str()))
+}
+
+/// Posts an emergency forward to `emergency_webhook_url`, with
+/// `emergency_webhook_token` as a bearer token, e.g. for a phone call API.
+async fn emergency_webhook(env: &Env, device: &str, message: &ForwardMessage) {
+    let Some(url) = get_optional_secret(env, "emergency_webhook_url") else {
+        return;
+    };
+    let body = to_json(EmergencyWebhook {
+        device,
+        sender: message.sender(),
+        text: message.text(),
+        timestamp: message.timestamp().unwrap_or_else(timestamp_ms),
+    });
+    if is_dry_run(env) {
+        log::info!("dry_run", target = "emergency_webhook", device = device);
+        return;
+    }
+    let token = get_optional_secret(env, "emergency_webhook_token");
+    match post_webhook(&url, token.as_deref(), body).await {
+        Ok(()) => log::info!("emergency_webhook", device = device, outcome = "sent"),
+        Err(e) => log::error!("emergency_webhook", device = device, error = e.to_string()),
+    }
+}
+
+async fn pin_message(env: &Env, chat_id: &str, message_id: i64) {
+    let body = PinChatMessageBody {
+        chat_id,
+        message_id,
+        disable_notification: false,
+    };
+    call_telegram(env, "pinChatMessage", chat_id, || async {
+        TelegramClient::new(env)?.pin_chat_message(&body).await
+    })
+    .await;
+}
+
+/// POSTs JSON to `url`, failing unless it answers with a 2xx status.
+async fn post_webhook(url: &str, token: Option<&str>, body: String) -> Result<()> {
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    if let Some(token) = token {
+        headers.set("Authorization", &format!("Bearer {token}"))?;
+    }
+    let request = Request::new_with_init(
+        url,
+        &RequestInit {
+            method: Method::Post,
+            headers,
+            body: Some(body.into()),
+            ..RequestInit::default()
+        },
+    )?;
+    let status = Fetch::Request(request).send().await?.status_code();
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(Error::Webhook(format!("{url} answered {status}")))
+    }
+}
+
+/// Posts the transition to `{device}_status_webhook_url`, with
+/// `{device}_status_webhook_token` as a bearer token, for monitoring outside
+/// Telegram.
+async fn status_webhook(env: &Env, device: &str, status: &str, outage_ms: Option<i64>) {
+    let Some(url) = get_optional_secret(env, &format!("{device}_status_webhook_url")) else {
+        return;
+    };
+    let body = to_json(StatusWebhook {
+        device,
+        status,
+        timestamp: timestamp_ms(),
+        outage_seconds: outage_ms.map(|ms| ms / 1000),
+    });
+    if is_dry_run(env) {
+        log::info!(
+            "dry_run",
+            target = "status_webhook",
+            device = device,
+            status = status
+        );
+        return;
+    }
+    let token = get_optional_secret(env, &format!("{device}_status_webhook_token"));
+    match post_webhook(&url, token.as_deref(), body).await {
+        Ok(()) => log::info!(
+            "status_webhook",
+            device = device,
+            status = status,
+            outcome = "sent"
+        ),
+        Err(e) => log::error!("status_webhook", device = device, error = e.to_string()),
+    }
+}
+
+/// Adds the round trip to the `/ping` reply waiting for this heartbeat.
+async fn answer_ping(env: &Env, kv: &Kv, device: &str) -> Result<()> {
+    let key = format!("ping/{device}");
+    let Some(ping) = kv.get(&key).json::<PendingPing>().await? else {
+        return Ok(());
+    };
+    kv.delete(&key).await?;
+    let seconds = (timestamp_ms() - ping.sent) / 1000;
+    log::info!("ping", device = device, round_trip_seconds = seconds);
+    let text = format!(
+        "{}\n⏱️ answered after {}",
+        ping.text,
+        format_duration(seconds)
+    );
+    edit_message_by_chat(env, ping.chat_id, ping.message_id, &text).await;
+    Ok(())
+}
+
+/// The age of the device's last heartbeat, with `nudge` asking it for the
+/// next one and awaiting it in `answer_ping`.
+async fn ping(env: &Env, chat_id: i64, device: &str, nudge: bool) -> Result<()> {
+    let kv = kv_store(env)?;
+    let mut text = match last_seen(&kv, device).await? {
+        Some(seen) => format!(
+            "🏓 {device} sent its last heartbeat {} ago",
+            format_duration((timestamp_ms() - seen) / 1000)
+        ),
+        None => format!("🏓 {device} sent no heartbeat recently"),
+    };
+    let nudged = if nudge {
+        nudge_device(env, device).await
+    } else {
+        None
+    };
+    match nudged {
+        Some(true) => text.push_str("\n📨 nudged, waiting for its next heartbeat"),
+        Some(false) => text.push_str("\n📨 nudge failed"),
+        None if nudge => text.push_str("\n📨 neither push nor email configured"),
+        None => {}
+    }
+    let Some(message_id) = send_message_by_chat(env, chat_id, &text).await else {
+        return Ok(());
+    };
+    if nudged == Some(true) {
+        let ping = PendingPing {
+            sent: timestamp_ms(),
+            chat_id,
+            message_id,
+            text,
+        };
+        kv.put(&format!("ping/{device}"), to_json(&ping))?
+            .expiration_ttl(PING_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn store_raw_body(device: String, body: String, env: Env) -> Result<()> {
+    let truncated = body.chars().count() > RAW_BODY_LIMIT;
+    let raw = RawBody {
+        received: timestamp_ms(),
+        body: body.chars().take(RAW_BODY_LIMIT).collect(),
+        truncated,
+    };
+    kv_store(&env)?
+        .put(&format!("raw/{device}"), to_json(&raw))?
+        .execute()
+        .await?;
+    Ok(())
+}
+
+async fn store_status(device: String, vitals: Vitals, env: Env) -> Result<()> {
+    let (detail, value) = match vitals.battery {
+        Some(battery) => ("battery", f64::from(battery)),
+        None => ("none", 0.0),
+    };
+    record_metric(&env, "status", &device, detail, value);
+    let kv = kv_store(&env)?;
+    let key = format!("status/{device}");
+    let previous: Option<StoredStatus> = kv.get(&key).json().await.ok().flatten();
+    let previous = previous.map(|s| s.vitals).unwrap_or_default();
+    let status = StoredStatus {
+        vitals: Vitals {
+            battery: vitals.battery.or(previous.battery),
+            charger: vitals.charger.or(previous.charger),
+            signal: vitals.signal.or(previous.signal),
+        },
+        updated: timestamp_ms(),
+    };
+    kv.put(&key, to_json(&status))?.execute().await?;
+    if let Some(battery) = vitals.battery {
+        record_battery(&kv, &device, battery).await?;
+    }
+    let low = get_optional_secret(&env, "low_battery_percent")
+        .and_then(|percent| percent.parse().ok())
+        .unwrap_or(LOW_BATTERY_PERCENT);
+    if let Some(battery) = status.vitals.battery
+        && battery <= low
+        && previous.battery.is_none_or(|previous| previous > low)
+        && status.vitals.charger != Some(true)
+    {
+        log::info!("low_battery", device = device, battery = battery);
+        let text = format!("🪫 {device} battery is low, {battery}%");
+        send_message_by_device(&env, &device, &text).await;
+        send_event_sticker(&env, &device, StickerEvent::LowBattery).await;
+    }
+    if vitals.charger == Some(true) && previous.charger == Some(false) {
+        send_event_sticker(&env, &device, StickerEvent::Charging).await;
+    }
+    Ok(())
+}
+
+/// Widens today's battery range of the device, for the trend in the
+/// weekly report.
+async fn record_battery(kv: &Kv, device: &str, battery: i32) -> Result<()> {
+    let key = format!("battery/{device}");
+    let mut days: Vec<BatteryDay> = kv.get(&key).json().await?.unwrap_or_default();
+    let today = format_date(timestamp_ms());
+    match days.last_mut() {
+        Some(day) if day.date == today => {
+            if (day.low..=day.high).contains(&battery) {
+                return Ok(());
+            }
+            day.low = day.low.min(battery);
+            day.high = day.high.max(battery);
+        }
+        _ => days.push(BatteryDay {
+            date: today,
+            low: battery,
+            high: battery,
+        }),
+    }
+    let excess = days.len().saturating_sub(BATTERY_HISTORY_DAYS);
+    days.drain(..excess);
+    kv.put(&key, to_json(&days))?.execute().await?;
+    Ok(())
+}
+
+async fn report_status(device: String, status: StatusReport, env: Env) -> Result<()> {
+    store_status(device.clone(), Vitals::from(&status), env.clone()).await?;
+    send_message_by_device(
+        &env,
+        &device,
+        &format!(
+            "{emoji} {device} {battery:?}% {charger}",
+            emoji = if status.charger { "⚡️" } else { "🔋" },
+            battery = status.battery,
+            charger = if status.charger {
+                "charging"
+            } else {
+                "discharging"
+            }
+        ),
+    )
+    .await;
+    Ok(())
+}
+
+async fn report_location(device: String, location: Location, env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    let key = format!("live/{device}");
+    if location.live_period.is_some()
+        && let Ok(Some(message_id)) = kv.get(&key).text().await
+        && let Ok(message_id) = message_id.parse::<i64>()
+        && edit_live_location(&env, &device, message_id, &location).await
+    {
+        return Ok(());
+    }
+    let mut text = format!("📍 {device}");
+    if let Some(accuracy) = location.accuracy {
+        text.push_str(&format!(" ±{accuracy:.0}m"));
+    }
+    send_message_by_device(&env, &device, &text).await;
+    let message_id = send_location(&env, &device, &location).await;
+    if let Some(live_period) = location.live_period
+        && let Some(message_id) = message_id
+    {
+        kv.put(&key, message_id)?
+            .expiration_ttl(live_period.max(60) as u64)
+            .execute()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn upload_calls(device: String, calls: Vec<CallRecord>, env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    let mut summary = format!("📞 {device} calls");
+    for (date, calls) in &calls
+        .into_iter()
+        .sorted_by_key(|call| call.timestamp)
+        .chunk_by(|call| format_date(call.timestamp))
+    {
+        let calls = calls.collect_vec();
+        let count = |direction| calls.iter().filter(|c| c.direction == direction).count();
+        let duration: i64 = calls.iter().map(|c| c.duration).sum();
+        summary.push_str(&format!(
+            "\n\n<b>{date}</b> ↙️ {incoming} ↗️ {outgoing} ❌ {missed} ⏱ {duration}",
+            incoming = count(CallDirection::Incoming),
+            outgoing = count(CallDirection::Outgoing),
+            missed = count(CallDirection::Missed),
+            duration = format_duration(duration),
+        ));
+        for (number, calls) in &calls
+            .iter()
+            .sorted_by_key(|c| &c.number)
+            .chunk_by(|c| &c.number)
+        {
+            let directions: String = calls.map(|c| c.direction.symbol()).collect();
+            summary.push_str(&format!(
+                "\n<code>{}</code> {directions}",
+                escape_html(number)
+            ));
+        }
+
+        let key = format!("calls/{device}/{date}");
+        let mut stored: Vec<CallRecord> =
+            kv.get(&key).json().await.ok().flatten().unwrap_or_default();
+        stored.extend(calls);
+        stored.sort_by_key(|c| c.timestamp);
+        stored.dedup_by(|a, b| a.timestamp == b.timestamp && a.number == b.number);
+        kv.put(&key, to_json(&stored))?
+            .expiration_ttl(CALL_HISTORY_TTL_SECONDS)
+            .execute()
+            .await?;
+    }
+    send_message_by_device(&env, &device, &summary).await;
+    Ok(())
+}
+
+async fn reply_by_sms(update: &Update, reply_to: i64, env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    let key = format!("reply/{}/{reply_to}", update.chat_id());
+    let Ok(Some(ForwardedSender { device, sender })) = kv.get(&key).json().await else {
+        return Ok(());
+    };
+    log::info!("reply", device = device, sender = sender);
+    let lang = Lang::of(&kv, update.user_id(), update.language_code())
+        .await
+        .inspect_err(|e| log::error!("language", error = e.to_string()))
+        .unwrap_or_default();
+    let command = DeviceCommand::SendSms {
+        number: sender,
+        text: update.text().to_owned(),
+    };
+    issue_command(env, update.chat_id(), &device, command, lang).await
+}
+
+fn trusted_chat_ids(env: &Env) -> Result<Vec<i64>> {
+    Ok(get_secret(env, "trusted_chat_ids")?
+        .split(',')
+        .filter_map(|s| s.parse::<i64>().ok())
+        .collect())
+}
+
+/// Hands private chats of onboarded users to their tenant, and walks
+/// strangers through onboarding.
+async fn route_update(update: Update, env: Env, origin: String) -> Result<()> {
+    // a fixed typo in a command runs it again, other edits must not resend
+    // replies or answer onboarding twice
+    if update.edited && !update.text().starts_with('/') {
+        log::info!(
+            "update",
+            chat_id = update.chat_id(),
+            outcome = "edit_ignored"
+        );
+        return Ok(());
+    }
+    if log::tenant().is_some()
+        || !update.is_private()
+        || trusted_chat_ids(&env)?.contains(&update.chat_id())
+    {
+        return message_update(update, env, origin).await;
+    }
+    let tenant = format!("tg{}", update.chat_id());
+    if secrets::load_tenant(&env, &tenant).await? {
+        return log::tenant_scope(tenant, message_update(update, env, origin)).await;
+    }
+    onboard(update, env, origin).await
+}
+
+/// `/start {invite_code}` asks for the name of the first device, which is
+/// then registered under a new tenant sharing the deployment's bot.
+async fn onboard(update: Update, env: Env, origin: String) -> Result<()> {
+    let Some(invite_code) = get_optional_secret(&env, "invite_code") else {
+        return Ok(());
+    };
+    let chat_id = update.chat_id();
+    let kv = kv_store(&env)?;
+    let key = format!("onboarding/{chat_id}");
+    let mut args = update.text().split_whitespace();
+    if args.next() == Some("/start") {
+        if args.next() != Some(invite_code.as_str()) {
+            return Ok(());
+        }
+        log::info!("onboarding", chat_id = chat_id, step = "start");
+        kv.put(&key, "")?
+            .expiration_ttl(ONBOARDING_TTL_SECONDS)
+            .execute()
+            .await?;
+        send_message_by_chat(
+            &env,
+            chat_id,
+            "Welcome! Send a name for your first device, e.g. <code>phone</code>",
+        )
+        .await;
+        return Ok(());
+    }
+    if kv.get(&key).text().await?.is_none() {
+        return Ok(());
+    }
+    let device = update.text().trim();
+    if device.is_empty()
+        || device.len() > 16
+        || !device
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+        || RESERVED_DEVICE_NAMES.contains(&device)
+    {
+        send_message_by_chat(
+            &env,
+            chat_id,
+            "Device names are up to 16 lowercase letters and digits, try another one",
+        )
+        .await;
+        return Ok(());
+    }
+    let tenant = format!("tg{chat_id}");
+    let name = update
+        .message
+        .from
+        .as_ref()
+        .map(|user| user.first_name.as_str())
+        .filter(|name| !name.is_empty())
+        .unwrap_or(&tenant);
+    let token = random_uuid();
+    let chat_id_text = chat_id.to_string();
+    secrets::create_tenant(
+        &env,
+        &tenant,
+        name,
+        timestamp_ms(),
+        &[
+            ("admin_chat_id", &chat_id_text),
+            ("trusted_chat_ids", &chat_id_text),
+            ("trusted_user_ids", &chat_id_text),
+            ("devices", device),
+            (device, &token),
+            (&format!("{device}_chat_id"), &chat_id_text),
+        ],
+    )
+    .await?;
+    kv.delete(&key).await?;
+    log::info!(
+        "onboarding",
+        chat_id = chat_id,
+        tenant = tenant,
+        step = "done"
+    );
+    // the tenant's KV keeps the pairing, as its page is opened as the tenant
+    let pairing = log::tenant_scope(tenant.clone(), pairing_link(&env, &origin, device)).await?;
+    send_link_by_chat(
+        &env,
+        chat_id,
+        &format!(
+            "{device} is registered, open {pairing} to set up the phone or load its config from\n\n<code>{origin}/{device}/{token}?tenant={tenant}</code>"
+        ),
+    )
+    .await;
+    log::tenant_scope(
+        tenant.clone(),
+        send_event_sticker(&env, device, StickerEvent::Registered),
+    )
+    .await;
+    notify_admin(
+        &env,
+        &format!("👋 {} joined as tenant {tenant}", escape_html(name)),
+    )
+    .await;
+    Ok(())
+}
+
+/// `/rules` lists the rules, `/rules add <device|*> <sender|*>
+/// <drop|archive|delay <minutes>m|chat_id> [pattern]` and `/rules delete <id>` change them.
+/// `/rules export` sends them as YAML, and `/rules import` followed by such a
+/// document previews the changes until `/rules apply` replaces them.
+async fn rules_command<'a>(
+    env: &Env,
+    text: &'a str,
+    mut args: impl Iterator<Item = &'a str>,
+) -> Result<String> {
+    const USAGE: &str = "Arguments add &lt;device|*&gt; &lt;sender|*&gt; \
+                         &lt;drop|archive|delay &lt;minutes&gt;m|chat_id&gt; [pattern], \
+                         delete &lt;id&gt;, \
+                         export, import &lt;document&gt; or apply required";
+    let any = |arg: &str| (arg != "*").then(|| arg.to_owned());
+    match args.next() {
+        None => {
+            let rules = rules::list(env).await?;
+            if rules.is_empty() {
+                return Ok("No rules".to_owned());
+            }
+            Ok(rules
+                .iter()
+                .map(|rule| escape_html(&rule.to_string()))
+                .join("\n"))
+        }
+        Some("add") => {
+            let (Some(device), Some(sender), Some(target)) =
+                (args.next(), args.next(), args.next())
+            else {
+                return Ok(USAGE.to_owned());
+            };
+            let (action, chat_id, delay) = match target {
+                "drop" => (rules::Action::Drop, None, None),
+                "archive" => (rules::Action::Archive, None, None),
+                "delay" => match args.next() {
+                    Some(delay) => (rules::Action::Delay, None, Some(delay)),
+                    None => return Ok(USAGE.to_owned()),
+                },
+                chat_id => (rules::Action::Route, Some(chat_id.to_owned()), None),
+            };
+            let pattern = Some(remainder(text, delay.unwrap_or(target)))
+                .filter(|pattern| !pattern.is_empty())
+                .map(ToOwned::to_owned);
+            let rule = rules::Rule {
+                id: 0,
+                version: 0,
+                device: any(device),
+                sender: any(sender),
+                pattern,
+                action,
+                chat_id,
+                days: None,
+                hours: None,
+                delay: delay.map(ToOwned::to_owned),
+                cancel: None,
+                updated: 0,
+            };
+            if let Err(e) = rule.validate(&get_devices(env)?) {
+                return Ok(escape_html(&e.to_string()));
+            }
+            let rule = rules::create(env, &rule, timestamp_ms()).await?;
+            Ok(format!("Rule added\n{}", escape_html(&rule.to_string())))
+        }
+        Some("delete") => {
+            let Some(id) = args.next().and_then(|id| id.parse::<i64>().ok()) else {
+                return Ok(USAGE.to_owned());
+            };
+            Ok(match rules::delete(env, id, timestamp_ms()).await? {
+                Some(_) => format!("Rule #{id} deleted"),
+                None => "Rule not found".to_owned(),
+            })
+        }
+        Some("export") => Ok(format!(
+            "<pre>{}</pre>",
+            escape_html(&rules::export(&rules::list(env).await?))
+        )),
+        Some(arg @ "import") => {
+            let doc = remainder(text, arg);
+            let imported = match rules::parse(doc, &get_devices(env)?) {
+                Ok(imported) => imported,
+                Err(e @ Error::InvalidRule(_)) => return Ok(escape_html(&e.to_string())),
+                Err(e) => return Err(e),
+            };
+            let diff = rules::diff(&rules::list(env).await?, &imported);
+            if diff.is_empty() {
+                return Ok("No changes".to_owned());
+            }
+            kv_store(env)?
+                .put("rules/import", doc)?
+                .expiration_ttl(RULES_IMPORT_TTL_SECONDS)
+                .execute()
+                .await?;
+            Ok(format!(
+                "{}\n\nSend /rules apply within 10 minutes to import",
+                escape_html(&diff.to_string())
+            ))
+        }
+        Some("apply") => {
+            let kv = kv_store(env)?;
+            let Some(doc) = kv.get("rules/import").text().await? else {
+                return Ok("Nothing to apply, send /rules import first".to_owned());
+            };
+            // the devices may have changed since the preview
+            let imported = match rules::parse(&doc, &get_devices(env)?) {
+                Ok(imported) => imported,
+                Err(e @ Error::InvalidRule(_)) => return Ok(escape_html(&e.to_string())),
+                Err(e) => return Err(e),
+            };
+            let rules = rules::replace(env, &imported, timestamp_ms()).await?;
+            kv.delete("rules/import").await?;
+            Ok(format!("{} rules imported", rules.len()))
+        }
+        Some(_) => Ok(USAGE.to_owned()),
+    }
+}
+
+/// Text and keyboard of the `/settings` menu, listing the devices or the
+/// toggles of one.
+fn settings_menu(
+    env: &Env,
+    flags: &Flags,
+    device: Option<&str>,
+) -> Result<(String, InlineKeyboardMarkup)> {
+    let button = |text: String, callback_data: String| InlineKeyboardButton {
+        text,
+        callback_data,
+    };
+    let Some(device) = device else {
+        let rows = get_devices(env)?
+            .chunks(3)
+            .map(|devices| {
+                devices
+                    .iter()
+                    .map(|device| button(device.clone(), format!("settings:{device}")))
+                    .collect()
+            })
+            .collect();
+        return Ok((
+            "⚙️ Settings of which device?".to_owned(),
+            InlineKeyboardMarkup {
+                inline_keyboard: rows,
+            },
+        ));
+    };
+    let mut rows = DeviceFlag::ALL
+        .into_iter()
+        .map(|flag| {
+            let state = if flags.get_for(device, flag) {
+                "✅"
+            } else {
+                "⬜️"
+            };
+            vec![button(
+                format!("{state} {}", flag.label()),
+                format!("settings:{device}:{}", flag.name()),
+            )]
+        })
+        .collect_vec();
+    rows.push(vec![button("« Devices".to_owned(), "settings:".to_owned())]);
+    let (from, until) = flags.quiet_hours;
+    Ok((
+        format!(
+            "⚙️ {} settings\n\nQuiet hours are {from}:00 to {until}:00 UTC",
+            escape_html(device)
+        ),
+        InlineKeyboardMarkup {
+            inline_keyboard: rows,
+        },
+    ))
+}
+
+/// Taps on a menu, in the tenant of an onboarded user's private chat like
+/// `route_update`.
+async fn callback_update(query: CallbackQuery, env: Env) -> Result<()> {
+    let Some(chat_id) = query.message.as_ref().map(|message| message.chat.id) else {
+        return answer_callback(&env, &query.id, None).await;
+    };
+    let tenant = format!("tg{chat_id}");
+    if log::tenant().is_none()
+        && query.from.id == chat_id
+        && !trusted_chat_ids(&env)?.contains(&chat_id)
+        && secrets::load_tenant(&env, &tenant).await?
+    {
+        return log::tenant_scope(tenant, route_callback(query, env)).await;
+    }
+    route_callback(query, env).await
+}
+
+async fn route_callback(query: CallbackQuery, env: Env) -> Result<()> {
+    match query.data.as_deref() {
+        Some(data) if data.starts_with("escalate:") => escalation_callback(query, env).await,
+        Some(data) if data.starts_with("broadcast:") => broadcast_callback(query, env).await,
+        Some("summarize") => summarize_callback(query, env).await,
+        _ => settings_callback(query, env).await,
+    }
+}
+
+/// Moves through the `/settings` menu and toggles the flags of a device.
+async fn settings_callback(query: CallbackQuery, env: Env) -> Result<()> {
+    let (Some(message), Some(data)) = (
+        &query.message,
+        query
+            .data
+            .as_deref()
+            .and_then(|data| data.strip_prefix("settings:")),
+    ) else {
+        return answer_callback(&env, &query.id, None).await;
+    };
+    let chat_id = message.chat.id;
+    let trusted_user = get_optional_secret(&env, "trusted_user_ids")
+        .map(|ids| {
+            ids.split(',')
+                .filter_map(|id| id.parse::<i64>().ok())
+                .collect_vec()
+        })
+        .filter(|ids| !ids.is_empty())
+        .is_none_or(|ids| ids.contains(&query.from.id));
+    if !is_admin_chat(&env, chat_id) || !trusted_user {
+        return answer_callback(&env, &query.id, Some("Not allowed")).await;
+    }
+    let (device, flag) = match data.rsplit_once(':') {
+        Some((device, flag)) => (Some(device), DeviceFlag::from_name(flag)),
+        None => (Some(data).filter(|device| !device.is_empty()), None),
+    };
+    if let Some(device) = device
+        && !get_devices(&env)?.iter().any(|d| d == device)
+    {
+        return answer_callback(&env, &query.id, Some("Device not found")).await;
+    }
+    let kv = kv_store(&env)?;
+    let mut flags = Flags::get(&kv).await?;
+    let mut answer = None;
+    if let (Some(device), Some(flag)) = (device, flag) {
+        flags.toggle(device, flag);
+        flags.put(&kv).await?;
+        let state = if flags.get_for(device, flag) {
+            "on"
+        } else {
+            "off"
+        };
+        log::info!(
+            "settings",
+            device = device,
+            flag = flag.name(),
+            state = state
+        );
+        answer = Some(format!("{} {state}", flag.label()));
+    }
+    let (text, keyboard) = settings_menu(&env, &flags, device)?;
+    edit_message(
+        &env,
+        &EditMessageTextBody {
+            chat_id,
+            message_id: message.message_id,
+            text: &text,
+            parse_mode: "HTML",
+            reply_markup: Some(keyboard),
+        },
+    )
+    .await;
+    answer_callback(&env, &query.id, answer.as_deref()).await
+}
+
+/// A DOWN alert waiting for its ACK button, see `send_down_alert`.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingEscalation {
+    sent: i64,
+    chat_id: i64,
+    message_id: i64,
+    text: String,
+}
+
+/// Sends a DOWN alert, with an ACK button when `escalation_chat_id` is set so
+/// that the alert goes there unless someone acknowledges it in time.
+async fn send_down_alert(env: &Env, kv: &Kv, device: &str, text: &str) {
+    if get_optional_secret(env, "escalation_chat_id").is_none() {
+        send_message_by_device(env, device, text).await;
+        return;
+    }
+    let Some(chat_id) = device_chat_id(env, device) else {
+        return;
+    };
+    let keyboard = InlineKeyboardMarkup {
+        inline_keyboard: vec![vec![InlineKeyboardButton {
+            text: "ACK".to_owned(),
+            callback_data: format!("escalate:{device}"),
+        }]],
+    };
+    let body = SendMessageBody {
+        chat_id: &chat_id,
+        message_thread_id: device_topic(env, device, &chat_id).await,
+        text,
+        parse_mode: "HTML",
+        link_preview_options: None,
+        disable_notification: false,
+        reply_markup: Some(keyboard),
+    };
+    let (Some(message_id), Ok(chat_id)) = (send_message(env, &body).await, chat_id.parse()) else {
+        return;
+    };
+    let pending = PendingEscalation {
+        sent: timestamp_ms(),
+        chat_id,
+        message_id,
+        text: text.to_owned(),
+    };
+    let put = async {
+        kv.put(&format!("escalate/{device}"), to_json(&pending))?
+            .execute()
+            .await
+    };
+    if let Err(e) = put.await {
+        log::error!("escalate", device = device, error = e.to_string());
+    }
+}
+
+/// Re-sends the DOWN alerts nobody acknowledged within
+/// `escalation_minutes`, 15 by default, to `escalation_chat_id` after
+/// `escalation_mentions`.
+async fn escalate_alerts(env: &Env) -> Result<()> {
+    let Some(escalation_chat_id) = get_optional_secret(env, "escalation_chat_id")
+        .and_then(|chat_id| chat_id.parse::<i64>().ok())
+    else {
+        return Ok(());
+    };
+    let minutes = get_optional_secret(env, "escalation_minutes")
+        .and_then(|minutes| minutes.parse::<i64>().ok())
+        .unwrap_or(ESCALATION_MINUTES);
+    let mentions = get_optional_secret(env, "escalation_mentions").unwrap_or_default();
+    let kv = kv_store(env)?;
+    let now = timestamp_ms();
+    for key in kv.list_keys("escalate/").await? {
+        let Some(pending) = kv.get(&key).json::<PendingEscalation>().await? else {
+            continue;
+        };
+        if now - pending.sent < minutes * 60 * 1000 {
+            continue;
+        }
+        let device = &key["escalate/".len()..];
+        log::info!("escalate", device = device, outcome = "escalated");
+        let text = format!(
+            "🚨 Not acknowledged within {minutes} minutes {}\n\n{}",
+            escape_html(&mentions),
+            pending.text
+        );
+        send_message_by_chat(env, escalation_chat_id, &text).await;
+        edit_message_by_chat(
+            env,
+            pending.chat_id,
+            pending.message_id,
+            &format!("{}\n\n🚨 escalated", pending.text),
+        )
+        .await;
+        kv.delete(&key).await?;
+    }
+    Ok(())
+}
+
+/// Acknowledges the DOWN alert of the device, so that it is not escalated.
+async fn escalation_callback(query: CallbackQuery, env: Env) -> Result<()> {
+    let (Some(message), Some(device)) = (
+        &query.message,
+        query
+            .data
+            .as_deref()
+            .and_then(|data| data.strip_prefix("escalate:")),
+    ) else {
+        return answer_callback(&env, &query.id, None).await;
+    };
+    let in_device_chat = device_chat_id(&env, device)
+        .is_some_and(|chat_id| chat_id.parse::<i64>() == Ok(message.chat.id));
+    if !in_device_chat || !is_trusted_user(&env, query.from.id) {
+        return answer_callback(&env, &query.id, Some("Not allowed")).await;
+    }
+    let kv = kv_store(&env)?;
+    let key = format!("escalate/{device}");
+    let Some(pending) = kv.get(&key).json::<PendingEscalation>().await? else {
+        return answer_callback(&env, &query.id, Some("Already handled")).await;
+    };
+    log::info!("escalate", device = device, outcome = "acknowledged");
+    kv.delete(&key).await?;
+    let text = format!(
+        "{}\n\n✅ acknowledged by {}",
+        pending.text,
+        escape_html(&query.from.first_name)
+    );
+    edit_message_by_chat(&env, pending.chat_id, pending.message_id, &text).await;
+    answer_callback(&env, &query.id, Some("Acknowledged")).await
+}
+
+/// The chats of all devices, each once.
+fn device_chat_ids(env: &Env) -> Result<Vec<i64>> {
+    Ok(get_devices(env)?
+        .iter()
+        .filter_map(|device| device_chat_id(env, device)?.parse::<i64>().ok())
+        .unique()
+        .collect())
+}
+
+/// Asks for confirmation before `/broadcast` sends `text` to every device's
+/// chat, keeping it under `broadcast/{id}` meanwhile.
+async fn confirm_broadcast(env: &Env, chat_id: i64, text: &str) -> Result<()> {
+    let id = random_uuid();
+    kv_store(env)?
+        .put(&format!("broadcast/{id}"), text)?
+        .expiration_ttl(BROADCAST_TTL_SECONDS)
+        .execute()
+        .await?;
+    let chats = device_chat_ids(env)?.len();
+    let button = |text: &str, action: &str| InlineKeyboardButton {
+        text: text.to_owned(),
+        callback_data: format!("broadcast:{id}:{action}"),
+    };
+    let body = SendMessageBody {
+        chat_id: &chat_id.to_string(),
+        message_thread_id: None,
+        text: &format!("📢 Send to {chats} chat(s)?\n\n{}", escape_html(text)),
+        parse_mode: "HTML",
+        link_preview_options: None,
+        disable_notification: false,
+        reply_markup: Some(InlineKeyboardMarkup {
+            inline_keyboard: vec![vec![button("Send", "send"), button("Cancel", "cancel")]],
+        }),
+    };
+    send_message(env, &body).await;
+    Ok(())
+}
+
+/// Sends or cancels a `/broadcast` once confirmed in the admin chat.
+async fn broadcast_callback(query: CallbackQuery, env: Env) -> Result<()> {
+    let (Some(message), Some((id, action))) = (
+        &query.message,
+        query
+            .data
+            .as_deref()
+            .and_then(|data| data.strip_prefix("broadcast:"))
+            .and_then(|data| data.split_once(':')),
+    ) else {
+        return answer_callback(&env, &query.id, None).await;
+    };
+    if !is_admin_chat(&env, message.chat.id) || !is_trusted_user(&env, query.from.id) {
+        return answer_callback(&env, &query.id, Some("Not allowed")).await;
+    }
+    let kv = kv_store(&env)?;
+    let key = format!("broadcast/{id}");
+    let Some(text) = kv.get(&key).text().await? else {
+        return answer_callback(&env, &query.id, Some("Already handled")).await;
+    };
+    kv.delete(&key).await?;
+    let escaped = escape_html(&text);
+    if action != "send" {
+        log::info!("broadcast", outcome = "cancelled");
+        let text = format!("📢 Cancelled\n\n{escaped}");
+        edit_message_by_chat(&env, message.chat.id, message.message_id, &text).await;
+        return answer_callback(&env, &query.id, Some("Cancelled")).await;
+    }
+    let chat_ids = device_chat_ids(&env)?;
+    let announcement = format!("📢 {escaped}");
+    let sent = join_all(
+        chat_ids
+            .iter()
+            .map(|&chat_id| send_message_by_chat(&env, chat_id, &announcement)),
+    )
+    .await
+    .into_iter()
+    .flatten()
+    .count();
+    log::info!(
+        "broadcast",
+        outcome = "sent",
+        chats = chat_ids.len(),
+        sent = sent
+    );
+    let text = format!(
+        "📢 Sent to {sent} of {} chat(s)\n\n{escaped}",
+        chat_ids.len()
+    );
+    edit_message_by_chat(&env, message.chat.id, message.message_id, &text).await;
+    answer_callback(&env, &query.id, Some("Sent")).await
+}
+
+/// Appends the summary of a forward once its Summarize button is pressed.
+async fn summarize_callback(query: CallbackQuery, env: Env) -> Result<()> {
+    let Some(message) = &query.message else {
+        return answer_callback(&env, &query.id, None).await;
+    };
+    let kv = kv_store(&env)?;
+    let key = format!("summary/{}/{}", message.chat.id, message.message_id);
+    let Some(source) = kv.get(&key).json::<SummarySource>().await? else {
+        return answer_callback(&env, &query.id, Some("No longer available")).await;
+    };
+    // the model takes longer than Telegram waits for the answer
+    answer_callback(&env, &query.id, Some("Summarizing")).await?;
+    append_summary(&env, message.chat.id, message.message_id, &source).await?;
+    kv.delete(&key).await?;
+    Ok(())
+}
+
+async fn answer_callback(env: &Env, id: &str, text: Option<&str>) -> Result<()> {
+    let body = AnswerCallbackQueryBody {
+        callback_query_id: id,
+        text,
+    };
+    let response = TelegramClient::new(env)?
+        .answer_callback_query(&body)
+        .await?;
+    if !response.ok() {
+        log::error!("callback_query", error = response.to_string());
+    }
+    Ok(())
+}
+
+/// Heartbeat status, vitals and clock skew of a device, one per line.
+async fn status_text(kv: &Kv, device: &str) -> Result<String> {
+    let mut text = match HeartbeatStatus::get(kv, device).await? {
+        Active => format!("🟢 {device} is up"),
+        Inactive => format!("🟡 {device} is late"),
+        Dead => format!("🔴 {device} is down"),
+    };
+    let interval = Cadence::get(kv, device).await.interval();
+    if interval != HEARTBEAT_INTERVAL_SECONDS {
+        text.push_str(&format!(
+            "\n⏱ late after {}, down after {}",
+            format_duration(interval * 3 / 2),
+            format_duration(interval * 5 / 2)
+        ));
+    }
+    let stored: Option<StoredStatus> = kv
+        .get(&format!("status/{device}"))
+        .json()
+        .await
+        .ok()
+        .flatten();
+    if let Some(stored) = stored {
+        text.push_str(&format!(
+            "\n{vitals} at {date} {time}",
+            vitals = stored.vitals,
+            date = format_date(stored.updated),
+            time = format_time(stored.updated),
+        ));
+    }
+    if let Ok(Some(skew)) = kv.get(&format!("skew/{device}")).text().await
+        && let Ok(skew) = skew.parse::<i64>()
+    {
+        text.push_str(&format!("\n⏰ clock skew {skew}ms"));
+    }
+    Ok(text)
+}
+
+/// Answers `@bot status`, or the start of a device name, with the status of
+/// every matching device. Only trusted users get answers, onboarded users
+/// those of their tenant.
+async fn inline_status(query: InlineQuery, env: Env) -> Result<()> {
+    let user_id = query.from.id;
+    if !is_trusted_user(&env, user_id) {
+        let tenant = format!("tg{user_id}");
+        if !secrets::load_tenant(&env, &tenant).await? {
+            log::info!("inline_query", user_id = user_id, outcome = "untrusted");
+            return answer_inline_query(&env, &query.id, Vec::new()).await;
+        }
+        return log::tenant_scope(tenant, answer_inline_status(query, env)).await;
+    }
+    answer_inline_status(query, env).await
+}
+
+async fn answer_inline_status(query: InlineQuery, env: Env) -> Result<()> {
+    let filter = query.query.trim().trim_start_matches("status").trim();
+    let kv = kv_store(&env)?;
+    let mut results = Vec::new();
+    for device in get_devices(&env)? {
+        if !device.starts_with(filter) {
+            continue;
+        }
+        let text = status_text(&kv, &device).await?;
+        let (title, description) = text.split_once('\n').unwrap_or((&text, ""));
+        results.push(InlineQueryResultArticle {
+            kind: "article",
+            id: device.clone(),
+            title: title.to_owned(),
+            description: description.replace('\n', ", "),
+            input_message_content: InputTextMessageContent {
+                message_text: text.clone(),
+                parse_mode: "HTML",
+            },
+        });
+    }
+    log::info!("inline_query", results = results.len(), outcome = "ok");
+    answer_inline_query(&env, &query.id, results).await
+}
+
+async fn answer_inline_query(
+    env: &Env,
+    id: &str,
+    results: Vec<InlineQueryResultArticle>,
+) -> Result<()> {
+    let body = AnswerInlineQueryBody {
+        inline_query_id: id,
+        results,
+        cache_time: INLINE_CACHE_SECONDS,
+        is_personal: true,
+    };
+    let response = TelegramClient::new(env)?.answer_inline_query(&body).await?;
+    if !response.ok() {
+        log::error!("inline_query", error = response.to_string());
+    }
+    Ok(())
+}
+
+/// A one-time link to the pairing page of `device`.
+async fn pairing_link(env: &Env, origin: &str, device: &str) -> Result<String> {
+    let token = random_uuid();
+    kv_store(env)?
+        .put(&format!("pairing/{token}"), device)?
+        .expiration_ttl(PAIRING_TTL_SECONDS)
+        .execute()
+        .await?;
+    Ok(match log::tenant() {
+        Some(tenant) => format!("{origin}/pair/{token}?tenant={tenant}"),
+        None => format!("{origin}/pair/{token}"),
+    })
+}
+
+async fn message_update(update: Update, env: Env, origin: String) -> Result<()> {
+    if !trusted_chat_ids(&env)?.contains(&update.chat_id()) {
+        return Ok(());
+    }
+    // channel posts carry no user, only admins can post in a trusted channel
+    if !update.channel {
+        let Some(user_id) = update.user_id() else {
+            return Ok(());
+        };
+        let trusted_user_ids = get_secret(&env, "trusted_user_ids")?
+            .split(',')
+            .filter_map(|s| s.parse::<i64>().ok())
+            .collect_vec();
+        if (!trusted_user_ids.is_empty()) && (!trusted_user_ids.contains(&user_id)) {
+            return Ok(());
+        }
+    }
+
+    if let Some(reply_to) = update.reply_to_message_id()
+        && !update.text().is_empty()
+        && !update.text().starts_with('/')
+    {
+        return reply_by_sms(&update, reply_to, &env).await;
+    }
+
+    let mut args = update.text().split_whitespace();
+    let Some(command) = args.next() else {
+        return Ok(());
+    };
+    let lang = Lang::of(&kv_store(&env)?, update.user_id(), update.language_code())
+        .await
+        .inspect_err(|e| log::error!("language", error = e.to_string()))
+        .unwrap_or_default();
+    if command.starts_with("/language@") || command == "/language" {
+        log::info!("bot_command", command = "language");
+        let Some(user_id) = update.user_id() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::LanguageNoUser)).await;
+            return Ok(());
+        };
+        let Some(lang) = args.next().and_then(Lang::from_code) else {
+            let languages = Lang::ALL
+                .iter()
+                .map(|lang| format!("<code>{}</code> {}", lang.code(), lang.name()))
+                .join(", ");
+            let text = tf(lang, Reply::LanguageUnknown, &languages);
+            send_message_by_chat(&env, update.chat_id(), &text).await;
+            return Ok(());
+        };
+        lang.set(&kv_store(&env)?, user_id).await?;
+        send_message_by_chat(&env, update.chat_id(), t(lang, Reply::LanguageSet)).await;
+    } else if command.starts_with("/version@") || command == "/version" {
+        log::info!("bot_command", command = "version");
+        let version: WorkerVersionMetadata = env
+            .get_binding("version")
+            .map_err(|_| Error::MissingBinding("version".to_owned()))?;
+        send_message_by_chat(
+            &env,
+            update.chat_id(),
+            &format!("<code>{}</code> at {}", version.id(), version.timestamp()),
+        )
+        .await;
+    } else if (command.starts_with("/reloadmail@") || command == "/reloadmail")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        log::info!("bot_command", command = "reloadmail");
+        let mail = reload_command_mail(&env).await;
+        send_message_by_chat(
+            &env,
+            update.chat_id(),
+            &format!(
+                "{}\n\n<pre>{}</pre>",
+                t(lang, Reply::CommandMailReloaded),
+                escape_html(&mail)
+            ),
+        )
+        .await;
+    } else if (command.starts_with("/settings@") || command == "/settings")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        log::info!("bot_command", command = "settings");
+        let (text, keyboard) = settings_menu(&env, &get_flags(&env).await, None)?;
+        send_message(
+            &env,
+            &SendMessageBody {
+                chat_id: &update.chat_id().to_string(),
+                message_thread_id: None,
+                text: &text,
+                parse_mode: "HTML",
+                link_preview_options: None,
+                disable_notification: false,
+                reply_markup: Some(keyboard),
+            },
+        )
+        .await;
+    } else if (command.starts_with("/pair@") || command == "/pair")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "pair", device = device);
+        let link = pairing_link(&env, &origin, device).await?;
+        send_link_by_chat(
+            &env,
+            update.chat_id(),
+            &tfs(lang, Reply::PairLink, &[&link, device]),
+        )
+        .await;
+    } else if (command.starts_with("/backup@") || command == "/backup")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        log::info!("bot_command", command = "backup");
+        let text = match backup::create(&env, timestamp_ms()).await {
+            Ok((name, count)) => tfs(lang, Reply::BackupSaved, &[&count.to_string(), &name]),
+            Err(e) => tf(lang, Reply::BackupFailed, &escape_html(&e.to_string())),
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if (command.starts_with("/restore@") || command == "/restore")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        let Some(name) = args.next() else {
+            let names = backup::list(&env).await?;
+            let text = if names.is_empty() {
+                t(lang, Reply::NoSnapshots).to_owned()
+            } else {
+                names
+                    .iter()
+                    .map(|name| format!("<code>{}</code>", escape_html(name)))
+                    .join("\n")
+            };
+            send_message_by_chat(&env, update.chat_id(), &text).await;
+            return Ok(());
+        };
+        log::info!("bot_command", command = "restore", name = name);
+        let text = match backup::restore(&env, name, timestamp_ms()).await {
+            Ok(count) => tf(lang, Reply::Restored, &count.to_string()),
+            Err(e) => tf(lang, Reply::RestoreFailed, &escape_html(&e.to_string())),
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if (command.starts_with("/exportdevice@") || command == "/exportdevice")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "exportdevice", device = device);
+        let text = match migrate::export(&env, device).await {
+            Ok(blob) => format!(
+                "{}\n\n<code>/importdevice {blob}</code>",
+                t(lang, Reply::ExportReady)
+            ),
+            Err(e) => tf(lang, Reply::ExportFailed, &escape_html(&e.to_string())),
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if (command.starts_with("/importdevice@") || command == "/importdevice")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        let Some(blob) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::BlobRequired)).await;
+            return Ok(());
+        };
+        let export = match migrate::open(&env, blob).await {
+            Ok(export) => export,
+            Err(e) => {
+                let text = tf(lang, Reply::ImportFailed, &escape_html(&e.to_string()));
+                send_message_by_chat(&env, update.chat_id(), &text).await;
+                return Ok(());
+            }
+        };
+        log::info!(
+            "bot_command",
+            command = "importdevice",
+            device = export.device
+        );
+        let text = match migrate::import(&env, &export).await {
+            Ok(missing) if missing.is_empty() => tf(lang, Reply::Imported, &export.device),
+            Ok(missing) => {
+                let missing = missing
+                    .iter()
+                    .map(|key| format!("<code>{key}</code>"))
+                    .join(", ");
+                tfs(lang, Reply::ImportedIncomplete, &[&export.device, &missing])
+            }
+            Err(e) => tf(lang, Reply::ImportFailed, &escape_html(&e.to_string())),
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if (command.starts_with("/mailconfig@") || command == "/mailconfig")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        if get_optional_secret(&env, &format!("{device}_mail_to")).is_none() {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::MailNotConfigured)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "mailconfig", device = device);
+        let text = match send_config_email(&env, device).await {
+            Ok(()) => t(lang, Reply::ConfigMailed).to_owned(),
+            Err(e) => tf(lang, Reply::ConfigMailFailed, &escape_html(&e.to_string())),
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if let Some(device_command) = DeviceCommand::from_bot_command(command) {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        issue_command(&env, update.chat_id(), device, device_command, lang).await?;
+    } else if command.starts_with("/send@") || command == "/send" {
+        let (Some(device), Some(number)) = (args.next(), args.next()) else {
+            send_message_by_chat(
+                &env,
+                update.chat_id(),
+                t(lang, Reply::SendArgumentsRequired),
+            )
+            .await;
+            return Ok(());
+        };
+        let text = remainder(update.text(), number);
+        if text.is_empty() {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::TextRequired)).await;
+            return Ok(());
+        }
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        let command = DeviceCommand::SendSms {
+            number: number.to_owned(),
+            text: text.to_owned(),
+        };
+        issue_command(&env, update.chat_id(), device, command, lang).await?;
+    } else if command.starts_with("/commands@") || command == "/commands" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "commands", device = device);
+        let kv = kv_store(&env)?;
+        let queue = load_commands(&kv, device).await.unwrap_or_default();
+        let text = if queue.is_empty() {
+            tf(lang, Reply::NoCommands, device)
+        } else {
+            let now = timestamp_ms();
+            queue
+                .iter()
+                .map(|c| {
+                    format!(
+                        "<code>{id}</code> {command} {age} ago",
+                        id = &c.id[..8],
+                        command = escape_html(&c.command.to_string()),
+                        age = format_duration((now - c.queued) / 1000),
+                    )
+                })
+                .join("\n")
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/status@") || command == "/status" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "status", device = device);
+        let text = status_text(&kv_store(&env)?, device).await?;
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if (command.starts_with("/broadcast@") || command == "/broadcast")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        let text = remainder(update.text(), command);
+        if text.is_empty() {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::TextRequired)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "broadcast");
+        confirm_broadcast(&env, update.chat_id(), text).await?;
+    } else if (command.starts_with("/raw@") || command == "/raw")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "raw", device = device);
+        let raw: Option<RawBody> = kv_store(&env)?.get(&format!("raw/{device}")).json().await?;
+        let text = match raw {
+            Some(raw) => format!(
+                "📥 {device} at {date} {time}{truncated}\n\n<pre>{body}</pre>",
+                date = format_date(raw.received),
+                time = format_time(raw.received),
+                truncated = if raw.truncated { ", truncated" } else { "" },
+                body = escape_html(&raw.body)
+            ),
+            None => tf(lang, Reply::NoRawBody, device),
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/inspect@") || command == "/inspect" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "inspect", device = device);
+        let text = inspect_text(&kv_store(&env)?, device)
+            .await?
+            .unwrap_or_else(|| tf(lang, Reply::NoQuarantined, device));
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/discard@") || command == "/discard" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "discard", device = device);
+        let count = discard_quarantine(&kv_store(&env)?, device).await?;
+        let text = tf(lang, Reply::Discarded, &count.to_string());
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/ping@") || command == "/ping" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "ping", device = device);
+        let nudge = args.next() == Some("nudge");
+        ping(&env, update.chat_id(), device, nudge).await?;
+    } else if command.starts_with("/pending@") || command == "/pending" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "pending", device = device);
+        let batch = PendingBatch::load(&kv_store(&env)?, &format!("batch/{device}/")).await?;
+        let text = if batch.messages.is_empty() {
+            tf(lang, Reply::NoPending, device)
+        } else {
+            batch_text(device, &batch)
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/history@") || command == "/history" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "history", device = device);
+        let kv = kv_store(&env)?;
+        let mut text = tf(lang, Reply::CallHistory, device);
+        for days_ago in (0..HISTORY_DAYS).rev() {
+            let date = format_date(timestamp_ms() - days_ago * 24 * 3600 * 1000);
+            let calls: Vec<CallRecord> = kv
+                .get(&format!("calls/{device}/{date}"))
+                .json()
+                .await
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            if calls.is_empty() {
+                continue;
+            }
+            text.push_str(&format!("\n\n<b>{date}</b>"));
+            for call in calls {
+                text.push_str(&format!(
+                    "\n{time} {direction} <code>{number}</code> {duration}",
+                    time = format_time(call.timestamp),
+                    direction = call.direction.symbol(),
+                    number = escape_html(&call.number),
+                    duration = format_duration(call.duration),
+                ));
+            }
+        }
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/stats@") || command == "/stats" {
+        log::info!("bot_command", command = "stats");
+        let kv = kv_store(&env)?;
+        let mut chats: Vec<(String, Vec<String>)> = Vec::new();
+        for device in get_devices(&env)? {
+            let Some(chat_id) = device_chat_id(&env, &device) else {
+                continue;
+            };
+            match chats.iter_mut().find(|(id, _)| *id == chat_id) {
+                Some((_, labels)) => labels.push(device),
+                None => chats.push((chat_id, vec![device])),
+            }
+        }
+        if let Some(admin) = get_optional_secret(&env, "admin_chat_id")
+            && !chats.iter().any(|(id, _)| *id == admin)
+        {
+            chats.push((admin, vec!["admin".to_owned()]));
+        }
+        let now = timestamp_ms();
+        let hour = ((now / 1000 / 3600) % 24) as usize;
+        let mut text = t(lang, Reply::TelegramCalls).to_owned();
+        for (chat_id, labels) in chats {
+            let usage: TelegramUsage = kv
+                .get(&format!("telegram/{chat_id}/{}", format_date(now)))
+                .json()
+                .await?
+                .unwrap_or_default();
+            let hourly = usage.hours.get(hour).copied().unwrap_or_default();
+            let daily = usage.hours.iter().sum::<u32>();
+            text.push_str(&format!(
+                "\n{labels} <code>{chat_id}</code> {}",
+                tfs(
+                    lang,
+                    Reply::TelegramCallCounts,
+                    &[&hourly.to_string(), &daily.to_string()]
+                ),
+                labels = labels.join(", "),
+            ));
+        }
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/deliveries@") || command == "/deliveries" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        let Some(deliveries) = recent_deliveries(&env, device).await? else {
+            send_message_by_chat(
+                &env,
+                update.chat_id(),
+                t(lang, Reply::DeliveriesNotConfigured),
+            )
+            .await;
+            return Ok(());
+        };
+        log::info!("bot_command", command = "deliveries", device = device);
+        let text = if deliveries.is_empty() {
+            tf(lang, Reply::NoDeliveries, device)
+        } else {
+            let mut lines = deliveries.iter().map(|d| {
+                let emoji = match d.state.as_str() {
+                    "sent" => "✅",
+                    "failed" => "❌",
+                    _ => "⏳",
+                };
+                let mut line = format!(
+                    "{emoji} {date} {time} <code>{sender}</code> {state} {updated}",
+                    date = format_date(d.received),
+                    time = format_time(d.received),
+                    sender = escape_html(&d.sender),
+                    state = d.state,
+                    updated = format_time(d.updated),
+                );
+                if let Some(message_id) = d.message_id {
+                    line.push_str(&format!(" #{message_id}"));
+                }
+                line
+            });
+            format!(
+                "{}\n\n{}",
+                tf(lang, Reply::Deliveries, device),
+                lines.join("\n")
+            )
+        };
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if (command.starts_with("/rules@") || command == "/rules")
+        && is_admin_chat(&env, update.chat_id())
+    {
+        log::info!("bot_command", command = "rules");
+        let text = rules_command(&env, update.text(), args).await?;
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/promote@") || command == "/promote" {
+        log::info!("bot_command", command = "promote");
+        promote_config(&env, update.chat_id(), lang).await?;
+    } else if command.starts_with("/reliability@") || command == "/reliability" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        log::info!("bot_command", command = "reliability", device = device);
+        let outages = load_outages(&kv_store(&env)?, device).await?;
+        let now = timestamp_ms();
+        let mut text = tf(lang, Reply::Reliability, device);
+        for days in [7, OUTAGE_HISTORY_DAYS] {
+            text.push_str(&format!(
+                "\n\n<b>{}</b>\n{}",
+                tf(lang, Reply::LastDays, &days.to_string()),
+                reliability_report(
+                    &outages,
+                    now - days * 24 * 3600 * 1000,
+                    now,
+                    LONGEST_OUTAGES_SHOWN,
+                )
+            ));
+        }
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    } else if command.starts_with("/topsenders@") || command == "/topsenders" {
+        let Some(device) = args.next() else {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceRequired)).await;
+            return Ok(());
+        };
+        if !get_devices(&env)?.iter().any(|d| d == device) {
+            send_message_by_chat(&env, update.chat_id(), t(lang, Reply::DeviceNotFound)).await;
+            return Ok(());
+        }
+        let Ok(db) = env.d1("deliveries") else {
+            send_message_by_chat(
+                &env,
+                update.chat_id(),
+                t(lang, Reply::DeliveriesNotConfigured),
+            )
+            .await;
+            return Ok(());
+        };
+        log::info!("bot_command", command = "topsenders", device = device);
+        let mut text = tf(lang, Reply::TopSenders, device);
+        for days in [7, 30] {
+            let since = timestamp_ms() - days * 24 * 3600 * 1000;
+            let senders: Vec<SenderCount> = db
+                .prepare(
+                    "SELECT sender, COUNT(*) AS count FROM deliveries \
+                     WHERE device = ?1 AND received >= ?2 \
+                     GROUP BY sender ORDER BY count DESC, sender LIMIT ?3",
+                )
+                .bind(&[
+                    device.into(),
+                    (since as f64).into(),
+                    (TOP_SENDERS_SHOWN as f64).into(),
+                ])?
+                .all()
+                .await?
+                .results()?;
+            let last_days = tf(lang, Reply::LastDays, &days.to_string());
+            text.push_str(&format!("\n\n<b>{last_days}</b>"));
+            if senders.is_empty() {
+                text.push_str(&format!("\n{}", t(lang, Reply::NoMessages)));
+            }
+            for s in senders {
+                text.push_str(&format!(
+                    "\n{count} <code>{sender}</code>",
+                    count = s.count,
+                    sender = escape_html(&s.sender),
+                ));
+            }
+        }
+        send_message_by_chat(&env, update.chat_id(), &text).await;
+    }
+    Ok(())
+}
+
+/// Keeps a body which is none of the known payloads under
+/// `quarantine/{device}/{timestamp}/{uuid}` for `/inspect`, telling the
+/// device's chat with a one-line notice instead of the body itself.
+async fn quarantine(device: String, body: String, env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    let now = timestamp_ms();
+    let raw = RawBody {
+        received: now,
+        body: body.chars().take(RAW_BODY_LIMIT).collect(),
+        truncated: body.chars().count() > RAW_BODY_LIMIT,
+    };
+    let key = format!("quarantine/{device}/{now:013}/{}", random_uuid());
+    kv.put(&key, to_json(&raw))?
+        .expiration_ttl(QUARANTINE_TTL_SECONDS)
+        .execute()
+        .await?;
+    if !get_flags(&env).await.debug_echo {
+        log::info!("quarantine", device = device, outcome = "silent");
+        return Ok(());
+    }
+    log::info!("quarantine", device = device, outcome = "notified");
+    let count = kv
+        .list_keys(&format!("quarantine/{device}/"))
+        .await?
+        .len()
+        .max(1);
+    send_message_by_device(&env, &device, &quarantine_notice(&device, count)).await;
+    Ok(())
+}
+
+fn quarantine_notice(device: &str, count: usize) -> String {
+    let payloads = if count == 1 { "payload" } else { "payloads" };
+    format!("❓ {count} unrecognized {payloads} from {device}, /inspect {device} to view")
+}
+
+/// The newest quarantined body of the device with how many there are.
+async fn inspect_text(kv: &Kv, device: &str) -> Result<Option<String>> {
+    let keys = kv.list_keys(&format!("quarantine/{device}/")).await?;
+    let Some(key) = keys.last() else {
+        return Ok(None);
+    };
+    let Some(raw) = kv.get(key).json::<RawBody>().await? else {
+        return Ok(None);
+    };
+    Ok(Some(format!(
+        "❓ {device} at {date} {time}{truncated}, 1 of {count}, /discard {device} to drop \
+         them\n\n<pre>{body}</pre>",
+        date = format_date(raw.received),
+        time = format_time(raw.received),
+        truncated = if raw.truncated { ", truncated" } else { "" },
+        count = keys.len(),
+        body = escape_html(&raw.body)
+    )))
+}
+
+/// Drops every quarantined body of the device, returning how many.
+async fn discard_quarantine(kv: &Kv, device: &str) -> Result<usize> {
+    let keys = kv.list_keys(&format!("quarantine/{device}/")).await?;
+    for key in &keys {
+        kv.delete(key).await?;
+    }
+    Ok(keys.len())
+}
+
+#[event(fetch)]
+async fn fetch(req: Request, env: Env, ctx: Context) -> worker::Result<Response> {
+    sentry::init(&env);
+    // Cloudflare's ray id where there is one, so logs can be matched up with
+    // the dashboard
+    let request_id = req
+        .headers()
+        .get("cf-ray")
+        .ok()
+        .flatten()
+        .unwrap_or_else(random_uuid);
+    log::scope(request_id, async move {
+        // tenants point their devices and webhook at `?tenant={id}`
+        let tenant = req
+            .url()?
+            .query_pairs()
+            .find(|(key, _)| key == "tenant")
+            .map(|(_, value)| value.into_owned());
+        let response = match tenant {
+            None => serve(req, env.clone(), ctx).await?,
+            Some(tenant) => match secrets::load_tenant(&env, &tenant).await {
+                Ok(true) => log::tenant_scope(tenant, serve(req, env.clone(), ctx)).await?,
+                Ok(false) => Response::error("Not Found", 404)?,
+                Err(e) => {
+                    log::error!("tenant", tenant = tenant, error = e.to_string());
+                    Response::error("Internal Server Error", 500)?
+                }
+            },
+        };
+        catch(env.clone(), flush_kv_usage(env)).await;
+        Ok(response)
+    })
+    .await
+}
+
+async fn serve(req: Request, env: Env, ctx: Context) -> worker::Result<Response> {
+    validate_config(&env).await;
+    let start = timestamp_ms();
+    let path = req.path();
+    let response = match route(req, env.clone(), ctx).await {
+        Ok(response) => response,
+        Err(e) => {
+            log::error!("request", path = path, error = e.to_string());
+            notify_admin(&env, &format!("⚠️ {}", escape_html(&e.to_string()))).await;
+            Response::error("Internal Server Error", 500)?
+        }
+    };
+    log::info!(
+        "request",
+        path = path,
+        outcome = response.status_code(),
+        latency_ms = timestamp_ms() - start
+    );
+    Ok(response)
+}
+
+async fn route(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    Ok(Router::with_data(ctx)
+        .get_async("/metrics", metrics_route)
+        .get_async("/healthz", healthz_route)
+        .get_async("/", config_route)
+        .post_async("/", root_route)
+        .get_async("/:device/:token", config_route)
+        .get_async("/:device/:token/", config_route)
+        .post_async("/:device/:token", device_route)
+        .post_async("/:device/:token/", device_route)
+        .post_async("/v1/calls", calls_route)
+        .get_async("/v1/commands", poll_route)
+        .get_async("/v1/channel", channel_route)
+        .post_async("/v1/commands/:id/ack", ack_route)
+        .get("/admin", |_, _| Response::from_html(DASHBOARD))
+        .get("/assets/:name", assets_route)
+        .get_async("/status/:public_id", status_page_route)
+        .get_async("/pair/:token", pair_page_route)
+        .post_async("/pair/:token", pair_page_route)
+        .get("/api/openapi.json", |_, _| {
+            json_response(&openapi::document())
+        })
+        .get_async("/api/stream", stream_route)
+        .post_async("/api/dryrun", dry_run_route)
+        .get_async("/api/rules", rules_route)
+        .post_async("/api/rules", create_rule_route)
+        .get_async("/api/rules/export", export_rules_route)
+        .post_async("/api/rules/import", import_rules_route)
+        .get_async("/api/rules/:id", rule_route)
+        .put_async("/api/rules/:id", update_rule_route)
+        .delete_async("/api/rules/:id", delete_rule_route)
+        .get_async("/admin/api/login", login_info_route)
+        .post_async("/admin/api/login", login_route)
+        .get_async("/admin/api/devices", admin_devices_route)
+        .get_async(
+            "/admin/api/devices/:device/deliveries",
+            admin_deliveries_route,
+        )
+        .get_async("/admin/api/devices/:device/messages", admin_messages_route)
+        .get_async("/admin/api/flags", admin_flags_route)
+        .put_async("/admin/api/flags", admin_put_flags_route)
+        .run(req, env)
+        .await?)
+}
+
+fn json_response<T: Serialize>(value: &T) -> worker::Result<Response> {
+    Ok(Response::builder()
+        .with_headers([("Content-Type", "application/json")].into_iter().collect())
+        .fixed(to_json(value).into_bytes()))
+}
+
+/// Whether the request carries `admin_token` or the session of a trusted
+/// Telegram user as its bearer token, or in `?token=` for clients which
+/// cannot set headers such as `EventSource`.
+async fn admin_authorized(req: &Request, env: &Env) -> bool {
+    let header = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .map(|header| header.trim().trim_start_matches("Bearer ").to_owned());
+    let token = header.or_else(|| {
+        req.url()
+            .ok()?
+            .query_pairs()
+            .find_map(|(key, value)| (key == "token").then(|| value.into_owned()))
+    });
+    let Some(token) = token else {
+        return false;
+    };
+    if token_matches(get_optional_secret(env, "admin_token").as_deref(), &token) {
+        return true;
+    }
+    let Ok(bot_token) = get_bot_token(env) else {
+        return false;
+    };
+    match login::verify_session(&bot_token, &token, timestamp_ms()).await {
+        Ok(Some(user_id)) => is_trusted_user(env, user_id),
+        Ok(None) => false,
+        Err(e) => {
+            log::error!("login", error = e.to_string());
+            false
+        }
+    }
+}
+
+/// Users allowed on the dashboard: those of `trusted_user_ids` and the
+/// admin chat when it is a private one.
+fn is_trusted_user(env: &Env, user_id: i64) -> bool {
+    let trusted = get_optional_secret(env, "trusted_user_ids")
+        .is_some_and(|ids| ids.split(',').any(|id| id.parse::<i64>() == Ok(user_id)));
+    trusted || is_admin_chat(env, user_id)
+}
+
+#[derive(Debug, Serialize)]
+struct LoginInfo {
+    bot: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Session {
+    token: String,
+    expires: i64,
+}
+
+/// Username of the bot for the Telegram Login Widget.
+async fn login_info_route(_req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let response = TelegramClient::new(&ctx.env)?.get_me().await?;
+    let bot = response.result().and_then(|bot| bot.username.clone());
+    json_response(&LoginInfo { bot })
+}
+
+/// Exchanges a Telegram login of a trusted user for a session token.
+async fn login_route(mut req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let Ok(data) = req.json::<login::LoginData>().await else {
+        return Response::error("Bad Request", 400);
+    };
+    let bot_token = get_bot_token(&ctx.env)?;
+    let now = timestamp_ms();
+    let user_id = login::verify_login(&bot_token, &data, now).await?;
+    let Some(user_id) = user_id.filter(|id| is_trusted_user(&ctx.env, *id)) else {
+        log::info!("login", outcome = "denied");
+        return Response::error("Unauthorized", 401);
+    };
+    log::info!("login", user_id = user_id, outcome = "ok");
+    json_response(&Session {
+        token: login::issue_session(&bot_token, user_id, now).await?,
+        expires: now + login::SESSION_SECONDS * 1000,
+    })
+}
+
+/// A rule or the problem with it, which is the client's fault.
+fn rule_response(rule: Result<Option<rules::Rule>>) -> worker::Result<Response> {
+    match rule {
+        Ok(Some(rule)) => json_response(&rule),
+        Ok(None) => Response::error("Not Found", 404),
+        Err(e @ Error::InvalidRule(_)) => Response::error(e.to_string(), 400),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn rule_id(ctx: &RouteContext<Context>) -> Option<i64> {
+    ctx.param("id")?.parse().ok()
+}
+
+async fn rules_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    json_response(&rules::list(&ctx.env).await?)
+}
+
+/// The rules as a YAML document for `POST /api/rules/import`.
+async fn export_rules_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let mut response = Response::ok(rules::export(&rules::list(&ctx.env).await?))?;
+    response
+        .headers_mut()
+        .set("Content-Type", "application/yaml; charset=utf-8")?;
+    Ok(response)
+}
+
+#[derive(Debug, Serialize)]
+struct RulesImport {
+    diff: rules::Diff,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rules: Option<Vec<rules::Rule>>,
+}
+
+/// Replaces every rule with those of a YAML or JSON document once all of
+/// them are valid, only previewing the changes with `?dry_run=1`.
+async fn import_rules_route(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let dry_run = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "dry_run" && value != "0");
+    let doc = req.text().await?;
+    let imported = match rules::parse(&doc, &get_devices(&ctx.env)?) {
+        Ok(imported) => imported,
+        Err(e @ Error::InvalidRule(_)) => return Response::error(e.to_string(), 400),
+        Err(e) => return Err(e.into()),
+    };
+    let diff = rules::diff(&rules::list(&ctx.env).await?, &imported);
+    let rules = match dry_run {
+        true => None,
+        false => Some(rules::replace(&ctx.env, &imported, timestamp_ms()).await?),
+    };
+    json_response(&RulesImport { diff, rules })
+}
+
+async fn rule_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Some(id) = rule_id(&ctx) else {
+        return Response::error("Not Found", 404);
+    };
+    rule_response(rules::get(&ctx.env, id).await)
+}
+
+async fn create_rule_route(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Ok(rule) = req.json::<rules::Rule>().await else {
+        return Response::error("Bad Request", 400);
+    };
+    let created = match rule.validate(&get_devices(&ctx.env)?) {
+        Ok(()) => rules::create(&ctx.env, &rule, timestamp_ms())
+            .await
+            .map(Some),
+        Err(e) => Err(e),
+    };
+    Ok(rule_response(created)?.with_status(201))
+}
+
+/// Replaces a rule, answering 409 when `version` is not the current one.
+async fn update_rule_route(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Some(id) = rule_id(&ctx) else {
+        return Response::error("Not Found", 404);
+    };
+    let Ok(rule) = req.json::<rules::Rule>().await else {
+        return Response::error("Bad Request", 400);
+    };
+    if let Err(e) = rule.validate(&get_devices(&ctx.env)?) {
+        return rule_response(Err(e));
+    }
+    match rules::update(&ctx.env, id, &rule, timestamp_ms()).await? {
+        Some(updated) => json_response(&updated),
+        None if rules::get(&ctx.env, id).await?.is_some() => Response::error("Conflict", 409),
+        None => Response::error("Not Found", 404),
+    }
+}
+
+async fn delete_rule_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Some(id) = rule_id(&ctx) else {
+        return Response::error("Not Found", 404);
+    };
+    rule_response(rules::delete(&ctx.env, id, timestamp_ms()).await)
+}
+
+fn assets_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    match assets::bundled(&req, ctx.param("name").map_or("", String::as_str))? {
+        Some(response) => Ok(response),
+        None => Response::error("Not Found", 404),
+    }
+}
+
+/// `dry_run` of the body for `?device=`.
+async fn dry_run_route(mut req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let device = req
+        .url()?
+        .query_pairs()
+        .find_map(|(key, value)| (key == "device").then(|| value.into_owned()));
+    let Some(device) =
+        device.filter(|device| get_devices(&ctx.env).is_ok_and(|d| d.contains(device)))
+    else {
+        return Response::error("Device not found", 404);
+    };
+    let body = req.text().await?;
+    json_response(&dry_run(&ctx.env, device, &body).await?)
+}
+
+/// Server-sent events of forwards and status changes as they happen.
+async fn stream_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Some(stub) = stream::stub(&ctx.env) else {
+        return Response::error("Not Found", 404);
+    };
+    stub.fetch_with_str("https://stream/").await
+}
+
+/// The device named in the route, if it is configured.
+fn route_device(ctx: &RouteContext<Context>) -> Result<Option<String>> {
+    let devices = get_devices(&ctx.env)?;
+    Ok(ctx
+        .param("device")
+        .filter(|device| devices.contains(device))
+        .cloned())
+}
+
+async fn admin_devices_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let mut states = Vec::new();
+    for device in get_devices(&ctx.env)? {
+        states.push(device_state(&ctx.env, device).await?);
+    }
+    json_response(&states)
+}
+
+async fn admin_deliveries_route(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Some(device) = route_device(&ctx)? else {
+        return Response::error("Not Found", 404);
+    };
+    let deliveries = recent_deliveries(&ctx.env, &device).await?;
+    json_response(&deliveries.unwrap_or_default())
+}
+
+/// Messages archived for the digest today and yesterday, newest first.
+async fn admin_messages_route(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Some(device) = route_device(&ctx)? else {
+        return Response::error("Not Found", 404);
+    };
+    let kv = kv_store(&ctx.env)?;
+    let now = timestamp_ms();
+    let mut messages = Vec::new();
+    for day in [now, now - 24 * 3600 * 1000] {
+        let archived = load_archive(&kv, &device, &format_date(day)).await?;
+        messages.extend(archived.into_iter().rev());
+    }
+    json_response(&messages)
+}
+
+async fn admin_flags_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    json_response(&Flags::get(&kv_store(&ctx.env)?).await?)
+}
+
+async fn admin_put_flags_route(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> worker::Result<Response> {
+    if !admin_authorized(&req, &ctx.env).await {
+        return Response::error("Unauthorized", 401);
+    }
+    let Ok(flags) = req.json::<Flags>().await else {
+        return Response::error("Bad Request", 400);
+    };
+    flags.put(&kv_store(&ctx.env)?).await?;
+    log::info!("admin", outcome = "flags_updated");
+    json_response(&flags)
+}
+
+async fn metrics_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let token = get_optional_secret(&ctx.env, "metrics_token");
+    let header = req
+        .headers()
+        .get("Authorization")
+        .ok()
+        .flatten()
+        .map(|s| s.trim().trim_start_matches("Bearer ").to_owned())
+        .unwrap_or_default();
+    if !token_matches(token.as_deref(), &header) {
+        return Response::empty();
+    }
+    Ok(render_metrics(ctx.env).await?)
+}
+
+async fn healthz_route(_req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    match Config::load(&ctx.env) {
+        Ok(config) => Response::ok(format!(
+            "ok, {} devices in {} chats",
+            config.devices.len(),
+            config.chat_ids.values().unique().count()
+        )),
+        Err(report) => Ok(Response::ok(report.to_string())?.with_status(503)),
+    }
+}
+
+/// The device whose `{device}_public_id` is `public_id`.
+fn public_device(env: &Env, public_id: &str) -> Result<Option<String>> {
+    Ok(get_devices(env)?.into_iter().find(|device| {
+        token_matches(
+            get_optional_secret(env, &format!("{device}_public_id")).as_deref(),
+            public_id,
+        )
+    }))
+}
+
+/// Uptime, last-seen and battery of a device for those who do not use the
+/// bot. Nothing of the messages is shown, and the device is only found by
+/// its unguessable `{device}_public_id`.
+async fn status_page_route(_: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let public_id = ctx.param("public_id").map_or("", String::as_str);
+    let Some(device) = public_device(&ctx.env, public_id)? else {
+        return Response::error("Not Found", 404);
+    };
+    log::info!("status_page", device = device);
+    let kv = kv_store(&ctx.env)?;
+    let state = device_state(&ctx.env, device.clone()).await?;
+    let now = timestamp_ms();
+    let outages = load_outages(&kv, &device).await?;
+    let status = match state.status {
+        "active" => "Online",
+        "inactive" => "Not seen recently",
+        _ => "Offline",
+    };
+    let last_seen = match last_seen(&kv, &device).await? {
+        Some(seen) => format!("{} ago", format_duration((now - seen).max(0) / 1000)),
+        None => "never".to_owned(),
+    };
+    let battery = match state
+        .vitals
+        .and_then(|vitals| Some((vitals.battery?, vitals.charger)))
+    {
+        Some((battery, Some(true))) => format!("{battery}%, charging"),
+        Some((battery, _)) => format!("{battery}%"),
+        None => "unknown".to_owned(),
+    };
+    let uptime_since = |days: i64| {
+        format!(
+            "{:.2}%",
+            uptime(&outages, now - days * 24 * 3600 * 1000, now)
+        )
+    };
+    let page = STATUS_PAGE
+        .replace("{{name}}", &escape_html(&device))
+        .replace("{{status}}", status)
+        .replace("{{last_seen}}", &last_seen)
+        .replace("{{battery}}", &battery)
+        .replace("{{uptime_7}}", &uptime_since(7))
+        .replace("{{uptime_30}}", &uptime_since(OUTAGE_HISTORY_DAYS));
+    let mut response = Response::from_html(page)?;
+    response.headers_mut().set("Cache-Control", "no-store")?;
+    response
+        .headers_mut()
+        .set("Referrer-Policy", "no-referrer")?;
+    Ok(response)
 }
 
-async fn edit_message(env: &Env, body: &EditMessageTextBody<'_>) {
-    let bot_token = get_bot_token(env);
-    let body = to_json(body);
-    let request = Request::new_with_init(
-        &format!("https://api.telegram.org/bot{bot_token}/editMessageText"),
-        &RequestInit {
-            method: Method::Post,
-            headers: [("Content-Type", "application/json")].into_iter().collect(),
-            body: Some(body.into()),
-            ..RequestInit::default()
-        },
-    )
-    .unwrap();
-    match Fetch::Request(request).send().await {
-        Ok(mut response) => {
-            let Ok(response) = response.json::<MessageResponse>().await else {
-                console_error!("editMessageText invalid response: {response:?}");
-                return;
-            };
-            console_log!("editMessageText: {response}")
-        }
-        Err(e) => console_error!("editMessageText failed: {e:?}"),
+/// Walks through setting up the phone of a device. The QR code of its config
+/// URL is only shown once the button on the page is pressed, which uses up
+/// the link, so that previews and scanners opening it don't.
+async fn pair_page_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let kv = kv_store(&ctx.env)?;
+    let key = format!("pairing/{}", ctx.param("token").map_or("", String::as_str));
+    let Some(device) = kv.get(&key).text().await? else {
+        return Response::error("This link has expired or was already used", 404);
     };
+    let config = if req.method() == Method::Post {
+        kv.delete(&key).await?;
+        let Some(token) = get_optional_secret(&ctx.env, &device) else {
+            return Response::error("Not Found", 404);
+        };
+        log::info!("pairing", device = device);
+        let origin = req.url()?.origin().ascii_serialization();
+        let config_url = match log::tenant() {
+            Some(tenant) => format!("{origin}/{device}/{token}?tenant={tenant}"),
+            None => format!("{origin}/{device}/{token}"),
+        };
+        format!(
+            "In the app, scan this code to load the config.{qr}Or copy the link into the app \
+             instead:<br><code>{config_url}</code>",
+            qr = qr::svg(&config_url).unwrap_or_default(),
+            config_url = escape_html(&config_url),
+        )
+    } else {
+        "<form method=\"post\">Show the code to scan with the app, this link only works \
+         once.<br><button>Show the code</button></form>"
+            .to_owned()
+    };
+    let page = PAIR_PAGE
+        .replace("{{config}}", &config)
+        .replace("{{device}}", &escape_html(&device));
+    let mut response = Response::from_html(page)?;
+    response.headers_mut().set("Cache-Control", "no-store")?;
+    response
+        .headers_mut()
+        .set("Referrer-Policy", "no-referrer")?;
+    Ok(response)
 }
 
-async fn edit_message_by_chat(env: &Env, chat_id: i64, message_id: i64, text: &str) {
-    edit_message(
-        env,
-        &EditMessageTextBody {
-            chat_id,
-            message_id,
-            text,
-            parse_mode: "HTML",
-        },
-    )
-    .await
+async fn config_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let credentials = header_credentials(&req).or_else(|| path_credentials(&ctx));
+    let Some((device, token)) = authenticate(&ctx.env, credentials) else {
+        return Response::empty();
+    };
+    Ok(generate_config(device, token, ctx.env).await?)
 }
 
-fn check_token(device: &str, token: &str, env: &Env) -> bool {
-    let Ok(secret) = env.secret(device) else {
-        return false;
+/// The Telegram webhook, or the device endpoint when authorized by header.
+async fn root_route(mut req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    if header_credentials(&req).is_some() {
+        return device_route(req, ctx).await;
+    }
+    let origin = req.url()?.origin().ascii_serialization();
+    if let Some(s) = req
+        .headers()
+        .get("X-Telegram-Bot-Api-Secret-Token")
+        .ok()
+        .flatten()
+        && get_optional_secret(&ctx.env, "update_secret") == Some(s)
+    {
+        let body = req.text().await?;
+        if let Ok(update) = serde_json::from_str(&body) {
+            spawn(
+                &ctx.data,
+                &ctx.env,
+                route_update(update, ctx.env.clone(), origin),
+            );
+        } else if let Ok(CallbackUpdate { callback_query }) = serde_json::from_str(&body) {
+            spawn(
+                &ctx.data,
+                &ctx.env,
+                callback_update(callback_query, ctx.env.clone()),
+            );
+        } else if let Ok(InlineUpdate { inline_query }) = serde_json::from_str(&body) {
+            spawn(
+                &ctx.data,
+                &ctx.env,
+                inline_status(inline_query, ctx.env.clone()),
+            );
+        }
+    }
+    Response::empty()
+}
+
+/// Tells forwards, heartbeats and reports apart by the shape of the body.
+async fn device_route(mut req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let credentials = header_credentials(&req).or_else(|| path_credentials(&ctx));
+    let Some((device, _)) = authenticate(&ctx.env, credentials) else {
+        return Response::from_json(&PostResult::queued());
     };
-    token == secret.to_string()
+    let idempotency_key = req.headers().get("Idempotency-Key")?;
+    let wait = req
+        .url()?
+        .query_pairs()
+        .any(|(key, value)| key == "wait" && value == "1");
+    let poll = req.headers().get("X-Poll-Commands")?.as_deref() == Some("1");
+    let body = req.text().await?;
+    if !body.is_empty() {
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            store_raw_body(device.clone(), body.clone(), ctx.env.clone()),
+        );
+    }
+    if body.is_empty() {
+        return handle_heartbeat(&ctx, device, None, None, poll).await;
+    } else if let Some(query) = from_json(&body) {
+        let message = ForwardMessage::Sms(query);
+        return handle_forward(&ctx, device, message, idempotency_key, wait).await;
+    } else if let Some(message) = from_json(&body) {
+        let message = ForwardMessage::Rcs(message);
+        return handle_forward(&ctx, device, message, idempotency_key, wait).await;
+    } else if let Some(status) = from_json(&body) {
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            heartbeat(device.clone(), ctx.env.clone()),
+        );
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            report_status(device, status, ctx.env.clone()),
+        );
+    } else if let Some(LocationReport { location }) = from_json(&body) {
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            heartbeat(device.clone(), ctx.env.clone()),
+        );
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            report_location(device, location, ctx.env.clone()),
+        );
+    } else if let Some(HeartbeatPayload { vitals, timestamp }) = from_json(&body)
+        && (vitals.is_some() || timestamp.is_some())
+    {
+        return handle_heartbeat(&ctx, device, vitals, timestamp, poll).await;
+    } else {
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            quarantine(device, body, ctx.env.clone()),
+        );
+    }
+    Response::from_json(&PostResult::queued())
 }
 
-async fn authorize(req: &mut Request, env: &Env) -> Option<AuthorizedRequest> {
-    if !matches!(req.method(), Method::Get | Method::Post) {
-        return None;
+/// Forwards in the background, or before answering with `wait` so that the
+/// device learns the outcome and the Telegram message.
+async fn handle_forward(
+    ctx: &RouteContext<Context>,
+    device: String,
+    message: ForwardMessage,
+    idempotency_key: Option<String>,
+    wait: bool,
+) -> worker::Result<Response> {
+    if let Some(timestamp) = message.timestamp() {
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            check_clock_skew(device.clone(), timestamp, ctx.env.clone()),
+        );
     }
-    let authorization = match req.headers().get("Authorization").unwrap() {
-        Some(s) => s.trim().trim_start_matches("Bearer ").to_owned(),
-        None => {
-            let path = req
-                .path()
-                .trim_start_matches("/")
-                .trim_end_matches("/")
-                .to_owned();
-            if path.is_empty() {
-                if req.method() == Method::Post
-                    && let Some(s) = req
-                        .headers()
-                        .get("X-Telegram-Bot-Api-Secret-Token")
-                        .unwrap()
-                    && s == get_secret(env, "update_secret")
-                    && let Ok(update) = req.json().await
-                {
-                    return Some(AuthorizedRequest::MessageUpdate { update });
-                } else {
-                    return None;
-                }
-            } else {
-                path
-            }
+    spawn(
+        &ctx.data,
+        &ctx.env,
+        heartbeat(device.clone(), ctx.env.clone()),
+    );
+    let forward = forward_once(device, message, idempotency_key, ctx.env.clone());
+    if !wait {
+        spawn(&ctx.data, &ctx.env, async { forward.await.map(|_| ()) });
+        return Response::from_json(&PostResult::queued());
+    }
+    let result = match forward.await {
+        Ok(result) => result,
+        Err(e) => {
+            log::error!("forward", error = e.to_string());
+            notify_admin(&ctx.env, &format!("⚠️ {}", escape_html(&e.to_string()))).await;
+            ForwardResult::of("failed")
         }
     };
-    let (device, token) = authorization
-        .splitn(2, '/')
-        .map(ToOwned::to_owned)
-        .collect_tuple()?;
-    if !check_token(&device, &token, env) {
-        return None;
+    Response::from_json(&PostResult {
+        forward: Some(result),
+        queued: false,
+        ..PostResult::queued()
+    })
+}
+
+/// Answers with the queued commands when the device polls for them with
+/// its heartbeats, sparing it the requests to `/v1/commands`.
+async fn handle_heartbeat(
+    ctx: &RouteContext<Context>,
+    device: String,
+    vitals: Option<Vitals>,
+    timestamp: Option<i64>,
+    poll: bool,
+) -> worker::Result<Response> {
+    if let Some(timestamp) = timestamp {
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            check_clock_skew(device.clone(), timestamp, ctx.env.clone()),
+        );
     }
-    match req.method() {
-        Method::Get => Some(AuthorizedRequest::GetConfig { device, token }),
-        Method::Post => {
-            let body = req.text().await.ok()?;
-            if body.is_empty() {
-                Some(AuthorizedRequest::Heartbeat { device })
-            } else if let Some(query) = from_json(&body) {
-                Some(AuthorizedRequest::Forward { device, query })
-            } else if let Some(status) = from_json(&body) {
-                Some(AuthorizedRequest::ReportStatus { device, status })
-            } else {
-                Some(AuthorizedRequest::Unknown { device, body })
-            }
+    if let Some(vitals) = vitals {
+        spawn(
+            &ctx.data,
+            &ctx.env,
+            store_status(device.clone(), vitals, ctx.env.clone()),
+        );
+    }
+    let commands = if poll {
+        Some(take_commands(&ctx.env, &device).await?)
+    } else {
+        None
+    };
+    spawn(&ctx.data, &ctx.env, heartbeat(device, ctx.env.clone()));
+    Response::from_json(&PostResult {
+        commands,
+        ..PostResult::queued()
+    })
+}
+
+/// Heartbeat sent over the command channel, answered with the queued
+/// commands like a heartbeat with `X-Poll-Commands`.
+async fn channel_heartbeat(
+    env: Env,
+    device: String,
+    payload: Option<HeartbeatPayload>,
+) -> Result<Vec<QueuedCommand>> {
+    if let Some(HeartbeatPayload { vitals, timestamp }) = payload {
+        if let Some(timestamp) = timestamp {
+            check_clock_skew(device.clone(), timestamp, env.clone()).await?;
+        }
+        if let Some(vitals) = vitals {
+            store_status(device.clone(), vitals, env.clone()).await?;
         }
-        _ => None,
     }
+    heartbeat(device.clone(), env.clone()).await?;
+    take_commands(&env, &device).await
 }
 
-async fn generate_config(device: String, token: String, env: Env) -> Result<Response> {
-    let url = get_secret(&env, "config_template_url");
-    let request = Request::new(&url, Method::Get)?;
-    let template = Fetch::Request(request).send().await?.text().await?;
-    let body = template
-        .replace("{{token}}", &format!("{device}/{token}"))
-        .into_bytes();
-    Ok(Response::builder()
-        .with_headers(
-            [
-                ("Content-Type", "text/plain; charset=utf-8"),
-                (
-                    "Content-Disposition",
-                    "inline; filename=\"sms-forward.yaml\"",
-                ),
-            ]
-            .into_iter()
-            .collect(),
-        )
-        .fixed(body))
+async fn calls_route(mut req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let Some((device, _)) = authenticate(&ctx.env, header_credentials(&req)) else {
+        return Response::empty();
+    };
+    let Some(calls) = from_json(&req.text().await?) else {
+        return Response::error("Bad Request", 400);
+    };
+    spawn(
+        &ctx.data,
+        &ctx.env,
+        heartbeat(device.clone(), ctx.env.clone()),
+    );
+    spawn(
+        &ctx.data,
+        &ctx.env,
+        upload_calls(device, calls, ctx.env.clone()),
+    );
+    Response::empty()
 }
 
-async fn forward(device: String, query: AppleMessageFilterQuery, env: Env) {
-    send_message_by_device(&env, &device, &format!("{device} {query}")).await;
+async fn poll_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let Some((device, _)) = authenticate(&ctx.env, header_credentials(&req)) else {
+        return Response::empty();
+    };
+    spawn(
+        &ctx.data,
+        &ctx.env,
+        heartbeat(device.clone(), ctx.env.clone()),
+    );
+    Ok(poll_commands(device, ctx.env).await?)
 }
 
-async fn heartbeat(device: String, env: Env) {
-    let kv = env.kv("sms-forward-heartbeat").unwrap();
-    let status = HeartbeatStatus::get(&kv, &device).await;
-    console_log!("refresh {device}, previous {status:?}");
-    if status != Active {
-        send_message_by_device(&env, &device, &format!("🟢 {device} is now up")).await;
-        send_sticker(&env, &device, &get_secret(&env, "up_sticker")).await;
+/// Upgrades to the WebSocket of the command channel, which the device is
+/// expected to leave for polling `/v1/commands` whenever it drops.
+async fn channel_route(req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let Some((device, _)) = authenticate(&ctx.env, header_credentials(&req)) else {
+        return Response::error("Unauthorized", 401);
+    };
+    if req.headers().get("Upgrade")?.as_deref() != Some("websocket") {
+        return Response::error("Upgrade Required", 426);
     }
-    if let Err(e) = kv
-        .put(&device, timestamp_ms())
-        .unwrap()
-        .expiration_ttl((HEARTBEAT_INTERVAL_SECONDS as f64 * 2.5) as u64)
-        .execute()
-        .await
-    {
-        console_error!("failed to put kv for key {device:?}: {e:?}");
+    let Some(response) = channel::connect(&ctx.env, &device).await? else {
+        return Response::error("Not Found", 404);
     };
+    log::info!("channel", device = device, outcome = "connected");
+    spawn(&ctx.data, &ctx.env, heartbeat(device, ctx.env.clone()));
+    Ok(response)
 }
 
-async fn report_status(device: String, status: StatusReport, env: Env) {
-    send_message_by_device(
-        &env,
-        &device,
-        &format!(
-            "{emoji} {device} {battery:?}% {charger}",
-            emoji = if status.charger { "⚡️" } else { "🔋" },
-            battery = status.battery,
-            charger = if status.charger {
-                "charging"
-            } else {
-                "discharging"
-            }
-        ),
-    )
-    .await;
+async fn ack_route(mut req: Request, ctx: RouteContext<Context>) -> worker::Result<Response> {
+    let Some((device, _)) = authenticate(&ctx.env, header_credentials(&req)) else {
+        return Response::empty();
+    };
+    let Some(id) = ctx.param("id").cloned() else {
+        return Response::empty();
+    };
+    let body = req.text().await?;
+    let ack = if body.is_empty() {
+        CommandAck::default()
+    } else {
+        let Some(ack) = from_json(&body) else {
+            return Response::error("Bad Request", 400);
+        };
+        ack
+    };
+    spawn(
+        &ctx.data,
+        &ctx.env,
+        heartbeat(device.clone(), ctx.env.clone()),
+    );
+    spawn(
+        &ctx.data,
+        &ctx.env,
+        acknowledge_command(device, id, ack, ctx.env.clone()),
+    );
+    Response::empty()
 }
 
-async fn message_update(update: Update, env: Env) {
-    let Some(user_id) = update.user_id() else {
-        return;
+/// Adds this isolate's KV operations to the day's total every
+/// `KV_USAGE_FLUSH_SECONDS`, warning the admin chat once a day when nearing
+/// the free tier.
+async fn flush_kv_usage(env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    let now = timestamp_ms();
+    let key = format!("usage/kv/{}", format_date(now));
+    let Some(usage) = kv
+        .flush(
+            &key,
+            now as f64,
+            (KV_USAGE_FLUSH_SECONDS * 1000) as f64,
+            KV_USAGE_TTL_SECONDS,
+        )
+        .await?
+    else {
+        return Ok(());
     };
-    let trusted_chat_ids = get_secret(&env, "trusted_chat_ids")
-        .split(',')
-        .filter_map(|s| s.parse::<i64>().ok())
-        .collect_vec();
-    if !trusted_chat_ids.contains(&update.chat_id()) {
-        return;
+    log::info!(
+        "kv_usage",
+        reads = usage.reads,
+        writes = usage.writes,
+        deletes = usage.deletes,
+        lists = usage.lists
+    );
+    let percent = (usage.reads * 100 / KV_FREE_READS)
+        .max(usage.writes * 100 / KV_FREE_WRITES)
+        .max(usage.deletes * 100 / KV_FREE_WRITES)
+        .max(usage.lists * 100 / KV_FREE_WRITES);
+    if percent < KV_USAGE_WARNING_PERCENT || usage.warned {
+        return Ok(());
     }
-    let trusted_user_ids = get_secret(&env, "trusted_user_ids")
-        .split(',')
-        .filter_map(|s| s.parse::<i64>().ok())
-        .collect_vec();
-    if (!trusted_user_ids.is_empty()) && (!trusted_user_ids.contains(&user_id)) {
+    let text = format!(
+        "⚠️ KV usage today is at {percent}% of the free tier\n\
+         reads: {}/{KV_FREE_READS}\n\
+         writes: {}/{KV_FREE_WRITES}\n\
+         deletes: {}/{KV_FREE_WRITES}\n\
+         lists: {}/{KV_FREE_WRITES}",
+        usage.reads, usage.writes, usage.deletes, usage.lists
+    );
+    kv.mark_warned(&key, usage, KV_USAGE_TTL_SECONDS).await?;
+    notify_admin(&env, &text).await;
+    Ok(())
+}
+
+/// Checks the configuration up front, telling the admin chat about every
+/// problem at once the first time this isolate finds any.
+async fn validate_config(env: &Env) {
+    let Err(report) = Config::load(env) else {
         return;
+    };
+    log::error!("config", error = report.to_string());
+    if !config::REPORTED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+        notify_admin(env, &format!("⚠️ {}", escape_html(&report.to_string()))).await;
     }
+}
 
-    let mut args = update.text().split_whitespace();
-    let Some(command) = args.next() else {
-        return;
+/// Runs a task after the response, reporting its failure under the current
+/// request id.
+fn spawn(ctx: &Context, env: &Env, task: impl Future<Output = Result<()>> + 'static) {
+    ctx.wait_until(log::scoped(catch(env.clone(), task)));
+}
+
+/// Nudges an unresponsive device with a report status command by push or
+/// email if enabled by `{device}_auto_wake`, returning whether it was sent.
+async fn wake_device(env: &Env, device: &str) -> Option<bool> {
+    if get_optional_secret(env, &format!("{device}_auto_wake")).as_deref() != Some("true") {
+        return None;
+    }
+    log::info!("wake", device = device);
+    nudge_device(env, device).await
+}
+
+/// Asks the device to report its status by push, or by email without push,
+/// `None` with neither configured.
+async fn nudge_device(env: &Env, device: &str) -> Option<bool> {
+    let id = random_uuid();
+    let command = DeviceCommand::ReportStatus;
+    let pushed = send_push(env, device, &id, &command).await;
+    if let Some(Ok(())) = pushed {
+        return Some(true);
+    }
+    if get_optional_secret(env, &format!("{device}_mail_to")).is_none() {
+        return pushed.map(|_| false);
+    }
+    Some(send_email(env, device, &id, &command).await.is_ok())
+}
+
+/// Emails the report status command every `{device}_report_interval_hours`.
+async fn scheduled_report(env: &Env, kv: &Kv, device: &str) -> Result<()> {
+    let Some(hours) = get_optional_secret(env, &format!("{device}_report_interval_hours"))
+        .and_then(|s| s.parse::<i64>().ok())
+        .filter(|&hours| hours > 0)
+    else {
+        return Ok(());
     };
-    if command.starts_with("/version@") || command == "/version" {
-        console_log!("answer version");
-        let version: WorkerVersionMetadata = env.get_binding("version").unwrap();
-        send_message_by_chat(
-            &env,
-            update.chat_id(),
-            &format!("<code>{}</code> at {}", version.id(), version.timestamp()),
-        )
-        .await;
-    } else if command.starts_with("/info@") || command == "/info" {
-        let Some(device) = args.next() else {
-            send_message_by_chat(&env, update.chat_id(), "Argument &lt;device&gt; required").await;
-            return;
-        };
-        if !get_secret(&env, "devices").split(',').contains(&device) {
-            send_message_by_chat(&env, update.chat_id(), "Device not found").await;
-            return;
+    if get_optional_secret(env, &format!("{device}_mail_to")).is_none() {
+        return Ok(());
+    }
+    let key = format!("report/{device}");
+    let last = kv
+        .get(&key)
+        .text()
+        .await?
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or_default();
+    let now = timestamp_ms();
+    if now - last < hours * 3600 * 1000 {
+        return Ok(());
+    }
+    log::info!("scheduled_report", device = device);
+    send_email(env, device, &random_uuid(), &DeviceCommand::ReportStatus).await?;
+    kv.put(&key, now)?.execute().await?;
+    Ok(())
+}
+
+#[allow(unused)]
+#[event(scheduled)]
+async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    sentry::init(&env);
+    let cron = event.cron();
+    log::scope(random_uuid(), async move {
+        log::info!("scheduled", cron = cron);
+        let cron = cron.as_str();
+        // any other schedule, e.g. the only one of an older configuration,
+        // runs every job
+        let all = ![CRON_CHECK, CRON_DIGESTS, CRON_SUMMARIES, CRON_PURGE].contains(&cron);
+        if all || cron == CRON_CHECK {
+            validate_config(&env).await;
+            catch(env.clone(), check_version(env.clone())).await;
+            catch(env.clone(), for_each_tenant(&env, check_devices)).await;
+            catch(env.clone(), flush_kv_usage(env.clone())).await;
         }
-        if env.secret(&format!("{device}_mail_to")).is_err() {
-            send_message_by_chat(&env, update.chat_id(), "Device email not configured").await;
-            return;
+        if all || cron == CRON_DIGESTS {
+            catch(env.clone(), for_each_tenant(&env, send_digests)).await;
+            catch(env.clone(), for_each_tenant(&env, flush_batches)).await;
         }
-        console_log!("command {device}");
-        let Some(message_id) =
-            send_message_by_chat(&env, update.chat_id(), "Sending command").await
-        else {
-            return;
-        };
-        match send_email(&env, device).await {
-            Ok(()) => {
-                edit_message_by_chat(&env, update.chat_id(), message_id, "Command sent").await
-            }
-            Err(e) => {
-                console_error!("sendEmail failed: {e:?}");
-                edit_message_by_chat(&env, update.chat_id(), message_id, "failed to send command")
-                    .await
-            }
-        };
-    }
+        if all || cron == CRON_SUMMARIES {
+            catch(env.clone(), for_each_tenant(&env, send_weekly_summaries)).await;
+        }
+        if all || cron == CRON_PURGE {
+            catch(env.clone(), dedup::prune(&env, timestamp_ms())).await;
+        }
+    })
+    .await
 }
 
-async fn echo(device: String, body: String, env: Env) {
-    let text = format!("{}\n\n<pre>{}</pre>", device, escape_html(&body));
-    send_message_by_device(&env, &device, &text).await;
+/// Runs the self-test once per deployed version, reporting to the admin chat
+/// so that a broken deploy is noticed before a forward is missed.
+async fn check_version(env: Env) -> Result<()> {
+    let Ok(version) = env.get_binding::<WorkerVersionMetadata>("version") else {
+        return Ok(());
+    };
+    let kv = kv_store(&env)?;
+    let id = version.id();
+    if kv.get("version").text().await?.as_deref() == Some(id.as_str()) {
+        return Ok(());
+    }
+    kv.put("version", &id)?.execute().await?;
+    let checks = selftest::run(&env).await;
+    let passed = checks.iter().all(selftest::Check::passed);
+    log::info!(
+        "selftest",
+        version = id,
+        outcome = if passed { "passed" } else { "failed" }
+    );
+    let text = format!(
+        "{} <code>{id}</code> {}\n\n{}",
+        if passed { "🚀" } else { "🚨" },
+        if passed {
+            "passed the self-test"
+        } else {
+            "failed the self-test"
+        },
+        checks
+            .iter()
+            .map(|c| escape_html(&c.to_string()))
+            .join("\n")
+    );
+    notify_admin(&env, &text).await;
+    Ok(())
 }
 
-#[derive(Debug)]
-enum AuthorizedRequest {
-    GetConfig {
-        device: String,
-        token: String,
-    },
-    Forward {
-        device: String,
-        query: AppleMessageFilterQuery,
-    },
-    Heartbeat {
-        device: String,
-    },
-    ReportStatus {
-        device: String,
-        status: StatusReport,
-    },
-    MessageUpdate {
-        update: Update,
-    },
-    Unknown {
-        device: String,
-        body: String,
-    },
+/// Runs `job` for the deployment, then for every tenant with their own
+/// configuration.
+async fn for_each_tenant<F, Fut>(env: &Env, job: F) -> Result<()>
+where
+    F: Fn(Env) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    catch(env.clone(), job(env.clone())).await;
+    for tenant in secrets::tenant_ids(env).await? {
+        if !secrets::load_tenant(env, &tenant).await? {
+            continue;
+        }
+        log::tenant_scope(tenant, catch(env.clone(), job(env.clone()))).await;
+    }
+    Ok(())
 }
 
-#[event(fetch)]
-async fn fetch(mut req: Request, env: Env, ctx: Context) -> Result<Response> {
-    let Some(request) = authorize(&mut req, &env).await else {
-        return Response::empty();
-    };
-    match request {
-        AuthorizedRequest::GetConfig { device, token } => generate_config(device, token, env).await,
-        AuthorizedRequest::Forward { device, query } => {
-            ctx.wait_until(heartbeat(device.clone(), env.clone()));
-            ctx.wait_until(forward(device, query, env));
-            Response::empty()
+async fn send_digests(env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    for device in get_devices(&env)? {
+        if let Err(e) = send_digest(&env, &kv, &device).await {
+            log::error!("digest", device = device, error = e.to_string());
         }
-        AuthorizedRequest::Heartbeat { device } => {
-            ctx.wait_until(heartbeat(device, env));
-            Response::empty()
+    }
+    Ok(())
+}
+
+/// Sends each chat the weekly report of its devices on the first check of
+/// every Monday.
+async fn send_weekly_summaries(env: Env) -> Result<()> {
+    let now = timestamp_ms();
+    if js_sys::Date::new(&JsValue::from_f64(now as f64)).get_utc_day() != 1 {
+        return Ok(());
+    }
+    let kv = kv_store(&env)?;
+    let devices = get_devices(&env)?;
+    let chats = devices
+        .iter()
+        .filter_map(|device| Some((device_chat_id(&env, device)?, device)))
+        .into_group_map();
+    for (chat_id, devices) in chats.into_iter().sorted() {
+        if let Err(e) = send_weekly_summary(&env, &kv, &chat_id, &devices, now).await {
+            log::error!("weekly_summary", chat_id = chat_id, error = e.to_string());
         }
-        AuthorizedRequest::ReportStatus { device, status } => {
-            ctx.wait_until(heartbeat(device.clone(), env.clone()));
-            ctx.wait_until(report_status(device, status, env));
-            Response::empty()
+    }
+    Ok(())
+}
+
+async fn check_devices(env: Env) -> Result<()> {
+    let kv = kv_store(&env)?;
+    // devices are checked concurrently so that a large fleet fits in the
+    // scheduled handler's budget
+    let (env, kv) = (&env, &kv);
+    join_all(get_devices(env)?.iter().map(|device| async move {
+        // one misconfigured device must not keep the others from being checked
+        if let Err(e) = check_device(env, kv, device).await {
+            let e = e.for_device(device);
+            log::error!("check", device = device, error = e.to_string());
+            notify_admin(env, &format!("⚠️ {}", escape_html(&e.to_string()))).await;
         }
-        AuthorizedRequest::MessageUpdate { update } => {
-            ctx.wait_until(message_update(update, env));
-            Response::empty()
+    }))
+    .await;
+    if let Err(e) = check_acks(env).await {
+        log::error!("check_acks", error = e.to_string());
+    }
+    if let Err(e) = delete_expired(env).await {
+        log::error!("expire", error = e.to_string());
+    }
+    if let Err(e) = send_delayed(env).await {
+        log::error!("delay", error = e.to_string());
+    }
+    if let Err(e) = escalate_alerts(env).await {
+        log::error!("escalate", error = e.to_string());
+    }
+    if let Err(e) = release_sequences(env).await {
+        log::error!("sequence", error = e.to_string());
+    }
+    if let Err(e) = drain_outbox(env).await {
+        log::error!("circuit", error = e.to_string());
+    }
+    if let Err(e) = fallback_catch_up(env, kv).await {
+        log::error!("fallback", error = e.to_string());
+    }
+    Ok(())
+}
+
+/// Tells the admin chat about the forwards which went to the fallback
+/// destinations, once Telegram takes messages again.
+async fn fallback_catch_up(env: &Env, kv: &Kv) -> Result<()> {
+    let Some(chat_id) =
+        get_optional_secret(env, "admin_chat_id").and_then(|s| s.parse::<i64>().ok())
+    else {
+        return Ok(());
+    };
+    let Some(text) = fallback::catch_up_notice(kv).await? else {
+        return Ok(());
+    };
+    if send_message_by_chat(env, chat_id, &text).await.is_some() {
+        fallback::caught_up(kv).await?;
+    }
+    Ok(())
+}
+
+/// Counts the misses and bounces of the device, returning whether it is to
+/// be announced down.
+async fn track_flapping(env: &Env, kv: &Kv, device: &str, status: HeartbeatStatus) -> Result<bool> {
+    let previous = Flapping::get(kv, device).await?;
+    if status == Active && previous == Flapping::default() {
+        return Ok(false);
+    }
+    let mut flapping = previous.clone();
+    let outages = load_outages(kv, device).await?;
+    let down = outages.last().is_some_and(|outage| outage.end.is_none());
+    let now = timestamp_ms();
+    let window = FLAP_WINDOW_MINUTES * 60 * 1000;
+    let mut announce_down = false;
+    if status == Active {
+        flapping.misses = 0;
+        let up_after = minutes_secret(env, "up_after_minutes") * 60 * 1000;
+        if down && now - *flapping.recovering.get_or_insert(now) >= up_after {
+            flapping.recovering = None;
+            announce_up(env, kv, device).await;
         }
-        AuthorizedRequest::Unknown { device, body } => {
-            ctx.wait_until(echo(device, body, env));
-            Response::empty()
+    } else if status == Inactive || flapping.misses > 0 {
+        // devices long gone or never seen are not counted from scratch
+        let recently_up = outages
+            .last()
+            .and_then(|outage| outage.end)
+            .is_some_and(|end| now - end < window);
+        if flapping.misses == 0 && (flapping.recovering.is_some() || recently_up) {
+            flapping.bounces.push(now);
         }
+        flapping.recovering = None;
+        flapping.misses += 1;
+        let down_after = get_optional_secret(env, "down_after_misses")
+            .and_then(|misses| misses.parse().ok())
+            .unwrap_or(1);
+        announce_down = !down && flapping.misses >= down_after;
+    }
+    flapping.bounces.retain(|&bounce| now - bounce < window);
+    let threshold = get_optional_secret(env, "flap_threshold")
+        .and_then(|threshold| threshold.parse().ok())
+        .unwrap_or(FLAP_THRESHOLD);
+    if flapping.bounces.is_empty() {
+        flapping.alerted = false;
+    } else if flapping.bounces.len() >= threshold && !flapping.alerted {
+        flapping.alerted = true;
+        log::info!(
+            "flapping",
+            device = device,
+            bounces = flapping.bounces.len()
+        );
+        let text = format!(
+            "〰️ {device} is flapping, it dropped {} times within {FLAP_WINDOW_MINUTES} minutes \
+             of coming back",
+            flapping.bounces.len()
+        );
+        send_message_by_device(env, device, &text).await;
     }
+    if flapping != previous {
+        flapping.put(kv, device).await?;
+    }
+    Ok(announce_down)
 }
 
-#[allow(unused)]
-#[event(scheduled)]
-async fn scheduled(event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
-    let kv = env.kv("sms-forward-heartbeat").unwrap();
-    for device in get_secret(&env, "devices")
-        .split(",")
-        .skip_while(|s| s.is_empty())
-    {
-        let status = HeartbeatStatus::get(&kv, device).await;
-        console_log!("check {device}, previous {status:?}");
-        if status == Inactive {
-            send_message_by_device(&env, device, &format!("🔴 {device} is DOWN ⚠️")).await;
-            send_sticker(&env, device, &get_secret(&env, "down_sticker")).await;
+/// Announces a device down once `down_after_misses` checks in a row, 1 by
+/// default, found it late or down, and back up once it stayed up for
+/// `up_after_minutes`, telling the chat when it bounces `flap_threshold`
+/// times within an hour.
+async fn check_device(env: &Env, kv: &Kv, device: &str) -> Result<()> {
+    let status = HeartbeatStatus::get(kv, device).await?;
+    log::info!("check", device = device, previous = format!("{status:?}"));
+    let announce_down = track_flapping(env, kv, device, status).await?;
+    if announce_down {
+        if let Err(e) = record_outage_start(kv, device).await {
+            log::error!("outage", device = device, error = e.to_string());
         }
+        let text = match wake_device(env, device).await {
+            Some(true) => format!("🔴 {device} is DOWN ⚠️\n📨 wake command sent"),
+            Some(false) => format!("🔴 {device} is DOWN ⚠️\n📨 wake command failed"),
+            None => format!("🔴 {device} is DOWN ⚠️"),
+        };
+        send_down_alert(env, kv, device, &text).await;
+        stream::publish(env, "status", &StreamEvent::status(device, "down")).await;
+        status_webhook(env, device, "down", None).await;
+        send_event_sticker(env, device, StickerEvent::Down).await;
     }
+    if status == Active
+        && let Err(e) = scheduled_report(env, kv, device).await
+    {
+        log::error!("scheduled_report", device = device, error = e.to_string());
+    }
+    Ok(())
 }
 
 #[event(start)]
 fn start() {
-    console_error_panic_hook::set_once();
-    RE_CODE.get_or_init(|| {
-        Regex::new(r"([[:^digit:]]|\<)((?:[[:alnum:]]-)?[[:digit:]]{6})([[:^digit:]]|\>)").unwrap()
-    });
-    COMMAND_MAIL.get_or_init(|| {
-        indoc! {r#"
-        From: "Remote Command" <{{from}}>
-        To: "{{device}}" <{{to}}>
-        Message-ID: <{{id}}>
-        Subject: Command to report status, {{device}}
-        MIME-Version: 1.0
-        Content-Type: text/plain; charset="utf-8"
-
-        Report status, {{device}}.
-    "#}
-        .replace("\n", "\r\n")
-    });
-    console_debug!("{}", COMMAND_MAIL.get().unwrap());
+    sentry::set_panic_hook();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sms(text: &str) -> ForwardMessage {
+        serde_json::from_value(serde_json::json!({
+            "query": {"sender": "10086", "message": {"text": text}}
+        }))
+        .unwrap()
+    }
+
+    fn held(sequence: &mut ForwardSequence, seq: u64, received: i64) {
+        let message = sms(&seq.to_string());
+        sequence.held.insert(seq, HeldForward { received, message });
+    }
+
+    fn numbers(released: &[Numbered]) -> Vec<u64> {
+        released.iter().map(|(seq, _)| *seq).collect()
+    }
+
+    #[test]
+    fn headers() {
+        let raw = "Subject: =?UTF-8?B?5rWL6K+V?=\r\nX-Long: a\r\n\tb\r\nBroken\r\n\r\nbody\r\n";
+        let (headers, body) = split_headers(raw);
+        assert_eq!(
+            find_header(&headers, "subject"),
+            Some("=?UTF-8?B?5rWL6K+V?=")
+        );
+        assert_eq!(find_header(&headers, "X-Long"), Some("a b"));
+        assert_eq!(headers.len(), 2);
+        assert_eq!(body, "body\r\n");
+        assert_eq!(decode_header_value("=?utf-8?B?5rWL6K+V?= ok"), "测试 ok");
+        let (headers, body) = split_headers("To: a@example.com\n\nbody");
+        assert_eq!(find_header(&headers, "to"), Some("a@example.com"));
+        assert_eq!(body, "body");
+        assert_eq!(split_headers("no body").1, "");
+    }
+
+    #[test]
+    fn quoted_printable() {
+        assert_eq!(decode_quoted_printable("a=3Db=\r\nc", false), b"a=bc");
+        assert_eq!(decode_quoted_printable("soft=\nbreak", false), b"softbreak");
+        assert_eq!(decode_quoted_printable("a_b", false), b"a_b");
+        assert_eq!(decode_quoted_printable("a_b=5F", true), b"a b_");
+        assert_eq!(
+            String::from_utf8(decode_quoted_printable("=E6=B5=8B", false)).unwrap(),
+            "测"
+        );
+        // what is not an escape is kept as it is
+        assert_eq!(decode_quoted_printable("=ZZ=", false), b"=ZZ=");
+    }
+
+    #[test]
+    fn email_texts() {
+        assert_eq!(email_text("\n\nplain").as_deref(), Some("plain"));
+        assert_eq!(
+            email_text("Content-Type: text/html\n\n<p>a &amp; <b>b</b></p>").as_deref(),
+            Some("a &amp; b")
+        );
+        assert_eq!(
+            email_text("Content-Transfer-Encoding: base64\n\n5rWL\n6K+V").as_deref(),
+            Some("测试")
+        );
+        assert_eq!(email_text("Content-Type: image/png\n\n..."), None);
+        let multipart = "Content-Type: multipart/alternative; boundary=\"b\"\n\n\
+                         preamble\n\
+                         --b\n\
+                         Content-Type: text/html\n\n<i>html</i>\n\
+                         --b\n\
+                         Content-Type: text/plain\n\
+                         Content-Transfer-Encoding: quoted-printable\n\n\
+                         pl=\nain\n\
+                         --b--\n";
+        assert_eq!(email_text(multipart).as_deref(), Some("plain\n"));
+        assert_eq!(email_text("Content-Type: multipart/mixed\n\n--b"), None);
+    }
+
+    #[test]
+    fn sequence_in_order() {
+        let mut sequence = ForwardSequence {
+            next: 2,
+            ..ForwardSequence::default()
+        };
+        held(&mut sequence, 3, 0);
+        held(&mut sequence, 2, 0);
+        let (released, gaps) = sequence.release(0);
+        assert_eq!(numbers(&released), [2, 3]);
+        assert_eq!(released[0].1.text(), "2");
+        assert!(gaps.is_empty());
+        assert_eq!(sequence.next, 4);
+        assert!(sequence.held.is_empty());
+    }
+
+    #[test]
+    fn sequence_gap() {
+        let mut sequence = ForwardSequence {
+            next: 2,
+            ..ForwardSequence::default()
+        };
+        held(&mut sequence, 4, 0);
+        held(&mut sequence, 5, 1000);
+        let (released, gaps) = sequence.release(REORDER_WINDOW_SECONDS * 1000 - 1);
+        assert!(released.is_empty() && gaps.is_empty());
+        assert_eq!(sequence.next, 2);
+        // the first after the gap waited the window out, the next follows on
+        let (released, gaps) = sequence.release(REORDER_WINDOW_SECONDS * 1000);
+        assert_eq!(numbers(&released), [4, 5]);
+        assert_eq!(gaps, [(2, 3)]);
+        assert_eq!(sequence.next, 6);
+    }
+
+    #[test]
+    fn sequence_late() {
+        let mut sequence = ForwardSequence {
+            next: 5,
+            ..ForwardSequence::default()
+        };
+        held(&mut sequence, 3, 0);
+        let (released, gaps) = sequence.release(0);
+        assert_eq!(numbers(&released), [3]);
+        assert!(gaps.is_empty());
+        assert_eq!(sequence.next, 5);
+    }
+
+    #[test]
+    fn sequence_retry() {
+        let sequence = ForwardSequence {
+            next: 5,
+            first: Some("a".to_owned()),
+            ..ForwardSequence::default()
+        };
+        assert!(sequence.is_retry(1, "a"));
+        assert!(!sequence.is_retry(1, "b"));
+        assert!(!sequence.is_retry(4, "a"));
+        let started = ForwardSequence {
+            next: 1,
+            ..sequence
+        };
+        assert!(!started.is_retry(1, "a"));
+        assert!(!ForwardSequence::default().is_retry(1, ""));
+    }
 }
@@ -0,0 +1,130 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use serde_json::{Map, Value};
+#[cfg(target_arch = "wasm32")]
+use worker::{console_error, console_log};
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    static TENANT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Error,
+}
+
+/// Logs one JSON line with the event name, the current request id and the
+/// given fields, e.g. `log::info!("forward", device = device, outcome = "ok")`.
+macro_rules! info {
+    ($event:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::log::emit(
+            $crate::log::Level::Info,
+            $event,
+            vec![$((stringify!($key), ::serde_json::json!($value))),*],
+        )
+    };
+}
+
+macro_rules! error {
+    ($event:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::log::emit(
+            $crate::log::Level::Error,
+            $event,
+            vec![$((stringify!($key), ::serde_json::json!($value))),*],
+        )
+    };
+}
+
+pub(crate) use {error, info};
+
+pub fn emit(level: Level, event: &str, fields: Vec<(&str, Value)>) {
+    let mut entry = Map::new();
+    entry.insert("event".to_owned(), event.into());
+    if let Some(id) = current() {
+        entry.insert("request_id".to_owned(), id.into());
+    }
+    if let Some(tenant) = tenant() {
+        entry.insert("tenant".to_owned(), tenant.into());
+    }
+    for (key, value) in fields {
+        entry.insert(key.to_owned(), value);
+    }
+    if level == Level::Error {
+        let message = match entry.get("error").and_then(Value::as_str) {
+            Some(error) => format!("{event}: {error}"),
+            None => event.to_owned(),
+        };
+        crate::sentry::capture("error", &message, &entry);
+    }
+    let line = Value::Object(entry).to_string();
+    #[cfg(target_arch = "wasm32")]
+    match level {
+        Level::Info => console_log!("{line}"),
+        Level::Error => console_error!("{line}"),
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    eprintln!("{line}");
+}
+
+pub fn current() -> Option<String> {
+    REQUEST_ID.with_borrow(Clone::clone)
+}
+
+/// The tenant being served, `None` for the deployment's own configuration.
+pub fn tenant() -> Option<String> {
+    TENANT.with_borrow(Clone::clone)
+}
+
+/// Runs `future` with `id` as the request id of every entry logged while it
+/// is polled, so that interleaved tasks keep their own ids.
+pub fn scope<F: Future>(id: String, future: F) -> Scoped<F> {
+    Scoped {
+        id: Some(id),
+        tenant: tenant(),
+        future: Box::pin(future),
+    }
+}
+
+/// Runs `future` on behalf of `tenant` under the current request id.
+pub fn tenant_scope<F: Future>(tenant: String, future: F) -> Scoped<F> {
+    Scoped {
+        id: current(),
+        tenant: Some(tenant),
+        future: Box::pin(future),
+    }
+}
+
+/// Carries the current request id and tenant over to a `wait_until` task.
+pub fn scoped<F: Future>(future: F) -> Scoped<F> {
+    Scoped {
+        id: current(),
+        tenant: tenant(),
+        future: Box::pin(future),
+    }
+}
+
+pub struct Scoped<F> {
+    id: Option<String>,
+    tenant: Option<String>,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let previous = REQUEST_ID.replace(self.id.clone());
+        let previous_tenant = TENANT.replace(self.tenant.clone());
+        let poll = self.future.as_mut().poll(cx);
+        REQUEST_ID.set(previous);
+        TENANT.set(previous_tenant);
+        poll
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::{
+    crypto::{hex, hmac_sha256, sha256},
+    domain::token_matches,
+    error::Result,
+};
+
+/// How old a Telegram login may be when it is exchanged for a session.
+const LOGIN_MAX_AGE_SECONDS: i64 = 24 * 3600;
+
+pub const SESSION_SECONDS: i64 = 7 * 24 * 3600;
+
+/// Fields of a user as sent by the Telegram Login Widget, `hash` included.
+pub type LoginData = BTreeMap<String, Value>;
+
+/// Checks the widget's `hash` against the bot token as described at
+/// https://core.telegram.org/widgets/login#checking-authorization,
+/// returning the user id of a recent and genuine login.
+pub async fn verify_login(bot_token: &str, data: &LoginData, now: i64) -> Result<Option<i64>> {
+    let (Some(Value::String(hash)), Some(id), Some(auth_date)) = (
+        data.get("hash"),
+        data.get("id").and_then(Value::as_i64),
+        data.get("auth_date").and_then(Value::as_i64),
+    ) else {
+        return Ok(None);
+    };
+    if now / 1000 - auth_date > LOGIN_MAX_AGE_SECONDS {
+        return Ok(None);
+    }
+    let check = data
+        .iter()
+        .filter(|(key, _)| *key != "hash")
+        .map(|(key, value)| match value {
+            Value::String(s) => format!("{key}={s}"),
+            value => format!("{key}={value}"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let key = sha256(bot_token.as_bytes()).await?;
+    let expected = hex(&hmac_sha256(&key, check.as_bytes()).await?);
+    Ok(token_matches(Some(&expected), hash).then_some(id))
+}
+
+async fn session_signature(bot_token: &str, user_id: i64, expires: i64) -> Result<String> {
+    let data = format!("session {user_id} {expires}");
+    Ok(hex(
+        &hmac_sha256(bot_token.as_bytes(), data.as_bytes()).await?
+    ))
+}
+
+/// A token standing in for `admin_token` on behalf of a Telegram user.
+pub async fn issue_session(bot_token: &str, user_id: i64, now: i64) -> Result<String> {
+    let expires = now + SESSION_SECONDS * 1000;
+    let signature = session_signature(bot_token, user_id, expires).await?;
+    Ok(format!("tg.{user_id}.{expires}.{signature}"))
+}
+
+/// The user id of a session which is still valid.
+pub async fn verify_session(bot_token: &str, session: &str, now: i64) -> Result<Option<i64>> {
+    let mut parts = session.split('.');
+    let (Some("tg"), Some(user_id), Some(expires), Some(signature), None) = (
+        parts.next(),
+        parts.next().and_then(|s| s.parse::<i64>().ok()),
+        parts.next().and_then(|s| s.parse::<i64>().ok()),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return Ok(None);
+    };
+    if expires < now {
+        return Ok(None);
+    }
+    let expected = session_signature(bot_token, user_id, expires).await?;
+    Ok(token_matches(Some(&expected), signature).then_some(user_id))
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use worker::Env;
+
+use crate::{
+    crypto,
+    error::{Error, Result},
+    get_devices, get_optional_secret, get_secret, kv_store, log, secrets, to_json,
+};
+
+/// Per-device secrets carried over, by suffix of `{device}`.
+const SECRETS: &[&str] = &[
+    "",
+    "_chat_id",
+    "_fcm_token",
+    "_mail_to",
+    "_mail_from",
+    "_mail_name",
+    "_mail_reply_to",
+    "_mail_headers",
+    "_auto_wake",
+    "_report_interval_hours",
+    "_digest_to",
+    "_daily_quota",
+];
+
+/// KV entries carried over, by prefix of `{device}`. Archived messages and
+/// call history stay behind.
+const ENTRIES: &[&str] = &[
+    "", "status/", "outages/", "skew/", "battery/", "cadence/", "report/",
+];
+
+/// A device as moved between deployments.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceExport {
+    pub device: String,
+    secrets: BTreeMap<String, String>,
+    entries: BTreeMap<String, String>,
+}
+
+/// Collects a device's secrets and state into a blob only a deployment
+/// with the same `migration_key` can read.
+pub async fn export(env: &Env, device: &str) -> Result<String> {
+    let kv = kv_store(env)?;
+    let secrets = SECRETS
+        .iter()
+        .filter_map(|suffix| {
+            let key = format!("{device}{suffix}");
+            Some((key.clone(), get_optional_secret(env, &key)?))
+        })
+        .collect();
+    let mut entries = BTreeMap::new();
+    for prefix in ENTRIES {
+        let key = format!("{prefix}{device}");
+        if let Some(value) = kv.get(&key).text().await? {
+            entries.insert(key, value);
+        }
+    }
+    let export = DeviceExport {
+        device: device.to_owned(),
+        secrets,
+        entries,
+    };
+    let key = get_secret(env, "migration_key")?;
+    let blob = crypto::seal(&key, to_json(&export).as_bytes()).await?;
+    Ok(BASE64.encode(blob))
+}
+
+fn invalid_blob() -> Error {
+    Error::Worker(worker::Error::RustError("invalid device blob".to_owned()))
+}
+
+/// Reads a blob of `export`, sealed with the same `migration_key`.
+pub async fn open(env: &Env, blob: &str) -> Result<DeviceExport> {
+    let blob = BASE64.decode(blob.trim()).map_err(|_| invalid_blob())?;
+    let key = get_secret(env, "migration_key")?;
+    let data = crypto::open(&key, &blob).await?.ok_or_else(invalid_blob)?;
+    serde_json::from_slice(&data).map_err(|_| invalid_blob())
+}
+
+/// Puts an exported device's state into this deployment. A tenant's
+/// secrets are written to D1, the names of those the deployment's own
+/// configuration still needs are returned.
+pub async fn import(env: &Env, export: &DeviceExport) -> Result<Vec<String>> {
+    let kv = kv_store(env)?;
+    for (key, value) in &export.entries {
+        kv.put(key, value.as_str())?.execute().await?;
+    }
+    let Some(tenant) = log::tenant() else {
+        let mut missing = export
+            .secrets
+            .keys()
+            .filter(|key| get_optional_secret(env, key).is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+        if !get_devices(env)
+            .unwrap_or_default()
+            .contains(&export.device)
+        {
+            missing.push("devices".to_owned());
+        }
+        return Ok(missing);
+    };
+    let mut devices = get_devices(env).unwrap_or_default();
+    if !devices.contains(&export.device) {
+        devices.push(export.device.clone());
+    }
+    let devices = devices.join(",");
+    let mut values = vec![("devices", devices.as_str())];
+    values.extend(export.secrets.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+    secrets::set_tenant_secrets(env, &tenant, &values).await?;
+    secrets::load_tenant(env, &tenant).await?;
+    Ok(Vec::new())
+}
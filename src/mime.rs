@@ -0,0 +1,176 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use itertools::Itertools;
+
+use crate::random_uuid;
+
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Builds RFC 5322 messages with a text body, an optional HTML alternative
+/// and optional attachments.
+#[derive(Debug, Clone, Default)]
+pub struct MimeMessage {
+    headers: Vec<(String, String)>,
+    text: String,
+    html: Option<String>,
+    attachments: Vec<Attachment>,
+}
+
+impl MimeMessage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets a header, replacing any previous one of the same name and encoding
+    /// the value if it is not plain ASCII.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_owned(), encode_header(value)));
+        self
+    }
+
+    pub fn text(mut self, text: &str) -> Self {
+        self.text = text.to_owned();
+        self
+    }
+
+    pub fn html(mut self, html: &str) -> Self {
+        self.html = Some(html.to_owned());
+        self
+    }
+
+    pub fn attachment(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    pub fn build(&self) -> String {
+        let mut raw = String::new();
+        for (name, value) in &self.headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str("MIME-Version: 1.0\r\n");
+        if self.attachments.is_empty() {
+            raw.push_str(&self.body_part());
+            return raw;
+        }
+        let boundary = format!("=_{}", random_uuid());
+        raw.push_str(&format!(
+            "Content-Type: multipart/mixed; boundary=\"{boundary}\"\r\n\r\n"
+        ));
+        raw.push_str(&format!("--{boundary}\r\n"));
+        raw.push_str(&self.body_part());
+        for attachment in &self.attachments {
+            raw.push_str(&format!("\r\n--{boundary}\r\n"));
+            raw.push_str(&attachment_part(attachment));
+        }
+        raw.push_str(&format!("\r\n--{boundary}--\r\n"));
+        raw
+    }
+
+    fn body_part(&self) -> String {
+        let Some(html) = &self.html else {
+            return text_part("plain", &self.text);
+        };
+        let boundary = format!("=_{}", random_uuid());
+        format!(
+            "Content-Type: multipart/alternative; boundary=\"{boundary}\"\r\n\r\n\
+             --{boundary}\r\n{text}\r\n\
+             --{boundary}\r\n{html}\r\n\
+             --{boundary}--\r\n",
+            text = text_part("plain", &self.text),
+            html = text_part("html", html),
+        )
+    }
+}
+
+/// Formats an address with a display name, e.g. `"Remote Command" <a@b.c>`.
+pub fn mailbox(name: &str, address: &str) -> String {
+    if name.is_ascii() {
+        format!("\"{}\" <{address}>", name.replace(['\\', '"'], ""))
+    } else {
+        format!("{} <{address}>", encode_word(name))
+    }
+}
+
+fn encode_word(s: &str) -> String {
+    format!("=?UTF-8?B?{}?=", BASE64.encode(s))
+}
+
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_owned();
+    }
+    // keep already-encoded ASCII runs intact and encode each word on its own
+    value
+        .split(' ')
+        .map(|word| {
+            if word.is_ascii() {
+                word.to_owned()
+            } else {
+                encode_word(word)
+            }
+        })
+        .join(" ")
+}
+
+fn text_part(subtype: &str, text: &str) -> String {
+    let text = text.replace("\r\n", "\n");
+    format!(
+        "Content-Type: text/{subtype}; charset=\"utf-8\"\r\n\
+         Content-Transfer-Encoding: quoted-printable\r\n\r\n{}",
+        encode_quoted_printable(&text)
+    )
+}
+
+fn attachment_part(attachment: &Attachment) -> String {
+    let filename = if attachment.filename.is_ascii() {
+        format!("\"{}\"", attachment.filename.replace(['\\', '"'], ""))
+    } else {
+        format!("\"{}\"", encode_word(&attachment.filename))
+    };
+    let data = BASE64.encode(&attachment.data);
+    let lines = data
+        .as_bytes()
+        .chunks(76)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap())
+        .join("\r\n");
+    format!(
+        "Content-Type: {content_type}; name={filename}\r\n\
+         Content-Disposition: attachment; filename={filename}\r\n\
+         Content-Transfer-Encoding: base64\r\n\r\n{lines}",
+        content_type = attachment.content_type,
+    )
+}
+
+/// Encodes text with `\n` line breaks into CRLF quoted-printable.
+fn encode_quoted_printable(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            out.push_str("\r\n");
+        }
+        let mut width = 0;
+        let bytes = line.as_bytes();
+        for (j, &b) in bytes.iter().enumerate() {
+            let last = j + 1 == bytes.len();
+            let encoded = match b {
+                b' ' | b'\t' if last => format!("={b:02X}"),
+                b'=' => format!("={b:02X}"),
+                b' ' | b'\t' | 0x21..=0x7e => (b as char).to_string(),
+                _ => format!("={b:02X}"),
+            };
+            if width + encoded.len() > 75 {
+                out.push_str("=\r\n");
+                width = 0;
+            }
+            width += encoded.len();
+            out.push_str(&encoded);
+        }
+    }
+    out
+}
@@ -0,0 +1,585 @@
+//! OpenAPI 3 description of the HTTP routes, kept next to the structs of
+//! `lib.rs` they describe.
+
+use serde_json::{Value, json};
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+fn json_body(name: &str) -> Value {
+    json!({ "required": true, "content": { "application/json": { "schema": schema(name) } } })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+fn array(name: &str) -> Value {
+    json!({ "type": "array", "items": schema(name) })
+}
+
+fn nullable(kind: &str) -> Value {
+    json!({ "type": [kind, "null"] })
+}
+
+fn empty(description: &str) -> Value {
+    json!({ "description": description })
+}
+
+fn device_parameters() -> Value {
+    json!([
+        { "name": "device", "in": "path", "required": true, "schema": { "type": "string" } },
+        { "name": "token", "in": "path", "required": true, "schema": { "type": "string" } },
+    ])
+}
+
+fn forward_parameters() -> Value {
+    let mut parameters = device_parameters();
+    if let Value::Array(parameters) = &mut parameters {
+        parameters.push(json!({
+            "name": "Idempotency-Key",
+            "in": "header",
+            "description": "Forwards repeating the key of an earlier one within a day are dropped.",
+            "schema": { "type": "string", "maxLength": 128 },
+        }));
+        parameters.push(json!({
+            "name": "X-Poll-Commands",
+            "in": "header",
+            "description": "`1` to receive the queued commands with the answer to a heartbeat.",
+            "schema": { "type": "string", "enum": ["0", "1"] },
+        }));
+        parameters.push(json!({
+            "name": "wait",
+            "in": "query",
+            "description": "`1` to answer once a forward was handled, with its outcome.",
+            "schema": { "type": "string", "enum": ["0", "1"] },
+        }));
+    }
+    parameters
+}
+
+fn schemas() -> Value {
+    json!({
+        "Forward": {
+            "description": "An SMS as queried by a message filter extension.",
+            "type": "object",
+            "required": ["query"],
+            "properties": {
+                "query": {
+                    "type": "object",
+                    "properties": {
+                        "sender": { "type": "string" },
+                        "message": { "type": "object", "properties": { "text": { "type": "string" } } },
+                        "text": { "type": "string" },
+                        "receiverISOCountryCode": { "type": "string" },
+                    },
+                },
+                "timestamp": { "type": "integer", "description": "Milliseconds since the Unix epoch." },
+                "message_id": { "type": "string", "description": "Like the Idempotency-Key header." },
+                "seq": {
+                    "type": "integer",
+                    "description": "Increasing by 1 with each forward, for the worker to put them in order and tell about missing ones.",
+                },
+            },
+        },
+        "RcsForward": {
+            "type": "object",
+            "required": ["rcs"],
+            "properties": {
+                "rcs": {
+                    "type": "object",
+                    "required": ["sender"],
+                    "properties": {
+                        "sender": { "type": "string" },
+                        "text": { "type": "string" },
+                        "media": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["url"],
+                                "properties": { "url": { "type": "string" }, "content_type": { "type": "string" } },
+                            },
+                        },
+                        "chatbot": {
+                            "type": "object",
+                            "properties": { "name": { "type": "string" }, "verified": { "type": "boolean" } },
+                        },
+                    },
+                },
+                "timestamp": { "type": "integer" },
+                "message_id": { "type": "string" },
+                "seq": { "type": "integer" },
+            },
+        },
+        "PostResult": {
+            "type": "object",
+            "required": ["accepted", "queued"],
+            "properties": {
+                "accepted": { "type": "boolean" },
+                "queued": { "type": "boolean", "description": "Whether it is handled after answering." },
+                "outcome": {
+                    "type": "string",
+                    "description": "With `wait=1`, e.g. `sent`, `duplicate`, `batched`, `buffered` or `failed`.",
+                },
+                "telegram_message_id": { "type": "integer" },
+                "commands": array("QueuedCommand"),
+            },
+        },
+        "StatusReport": {
+            "type": "object",
+            "required": ["battery", "charger"],
+            "properties": { "battery": { "type": "integer" }, "charger": { "type": "boolean" } },
+        },
+        "LocationReport": {
+            "type": "object",
+            "required": ["location"],
+            "properties": {
+                "location": {
+                    "type": "object",
+                    "required": ["lat", "lon"],
+                    "properties": {
+                        "lat": { "type": "number" },
+                        "lon": { "type": "number" },
+                        "accuracy": { "type": "number" },
+                        "live_period": { "type": "integer", "description": "Seconds to keep updating a live location." },
+                    },
+                },
+            },
+        },
+        "Vitals": {
+            "type": "object",
+            "properties": {
+                "battery": { "type": "integer" },
+                "charger": { "type": "boolean" },
+                "signal": { "type": "integer" },
+            },
+        },
+        "Heartbeat": {
+            "type": "object",
+            "properties": { "vitals": schema("Vitals"), "timestamp": { "type": "integer" } },
+        },
+        "CallRecord": {
+            "type": "object",
+            "required": ["number", "direction", "timestamp"],
+            "properties": {
+                "number": { "type": "string" },
+                "direction": { "type": "string", "enum": ["incoming", "outgoing", "missed"] },
+                "duration": { "type": "integer", "description": "Seconds, zero for missed calls." },
+                "timestamp": { "type": "integer" },
+            },
+        },
+        "QueuedCommand": {
+            "type": "object",
+            "required": ["id", "type", "queued"],
+            "properties": {
+                "id": { "type": "string" },
+                "type": {
+                    "type": "string",
+                    "enum": ["report_status", "locate", "ring", "screenshot", "fetch_config", "send_sms"],
+                },
+                "number": { "type": "string", "description": "Only for send_sms." },
+                "text": { "type": "string", "description": "Only for send_sms." },
+                "queued": { "type": "integer" },
+            },
+        },
+        "PollCommandsResponse": {
+            "type": "object",
+            "required": ["commands"],
+            "properties": { "commands": array("QueuedCommand") },
+        },
+        "CommandAck": {
+            "type": "object",
+            "properties": { "ok": { "type": "boolean" }, "result": { "type": "string" } },
+        },
+        "DeviceState": {
+            "type": "object",
+            "required": ["device", "status"],
+            "properties": {
+                "device": { "type": "string" },
+                "status": { "type": "string", "enum": ["active", "inactive", "dead"] },
+                "vitals": schema("Vitals"),
+                "updated": nullable("integer"),
+                "skew_ms": nullable("integer"),
+            },
+        },
+        "Delivery": {
+            "type": "object",
+            "required": ["sender", "received", "updated", "state"],
+            "properties": {
+                "sender": { "type": "string" },
+                "received": { "type": "integer" },
+                "updated": { "type": "integer" },
+                "state": { "type": "string" },
+                "message_id": nullable("integer"),
+            },
+        },
+        "ArchivedMessage": {
+            "type": "object",
+            "required": ["sender", "text", "timestamp"],
+            "properties": {
+                "sender": { "type": "string" },
+                "text": { "type": "string" },
+                "timestamp": { "type": "integer" },
+            },
+        },
+        "Flags": {
+            "type": "object",
+            "properties": {
+                "stickers": { "type": "boolean" },
+                "digest_only": { "type": "boolean" },
+                "spam_filter": { "type": "boolean" },
+                "spam_senders": { "type": "array", "items": { "type": "string" } },
+                "debug_echo": { "type": "boolean" },
+            },
+        },
+        "Rule": {
+            "type": "object",
+            "required": ["action"],
+            "properties": {
+                "id": { "type": "integer", "readOnly": true },
+                "version": { "type": "integer", "description": "The version an update replaces." },
+                "device": nullable("string"),
+                "sender": nullable("string"),
+                "pattern": nullable("string"),
+                "action": { "type": "string", "enum": ["drop", "archive", "route", "delay"] },
+                "chat_id": nullable("string"),
+                "days": nullable("string"),
+                "hours": nullable("string"),
+                "delay": nullable("string"),
+                "cancel": nullable("string"),
+                "updated": { "type": "integer", "readOnly": true },
+            },
+        },
+        "RulesImport": {
+            "type": "object",
+            "required": ["diff"],
+            "properties": {
+                "diff": {
+                    "type": "object",
+                    "properties": {
+                        "added": array("Rule"),
+                        "removed": array("Rule"),
+                        "unchanged": { "type": "integer" },
+                        "reordered": { "type": "boolean" },
+                    },
+                },
+                "rules": array("Rule"),
+            },
+        },
+        "DryRun": {
+            "type": "object",
+            "required": ["device", "kind", "spam", "destinations"],
+            "properties": {
+                "device": { "type": "string" },
+                "kind": { "type": "string", "enum": ["sms", "rcs", "status", "location", "heartbeat", "echo"] },
+                "sender": nullable("string"),
+                "text": nullable("string"),
+                "timestamp": nullable("integer"),
+                "spam": { "type": "boolean" },
+                "rule": { "oneOf": [schema("Rule"), { "type": "null" }] },
+                "outcome": {
+                    "type": ["string", "null"],
+                    "enum": ["sent", "failed", "spam", "rule_drop", "rule_archive", "digest_only", null],
+                },
+                "message": nullable("string"),
+                "destinations": { "type": "array", "items": { "type": "string" } },
+            },
+        },
+        "LoginInfo": {
+            "type": "object",
+            "properties": { "bot": nullable("string") },
+        },
+        "TelegramLogin": {
+            "description": "As passed to the callback of the Telegram Login Widget.",
+            "type": "object",
+            "required": ["id", "auth_date", "hash"],
+            "additionalProperties": true,
+            "properties": {
+                "id": { "type": "integer" },
+                "auth_date": { "type": "integer" },
+                "hash": { "type": "string" },
+            },
+        },
+        "Session": {
+            "type": "object",
+            "required": ["token", "expires"],
+            "properties": { "token": { "type": "string" }, "expires": { "type": "integer" } },
+        },
+    })
+}
+
+fn device_paths() -> Value {
+    let device = json!({
+        "summary": "Forward, heartbeat, status or location of a device",
+        "description": "Told apart by the shape of the body, an empty body being a heartbeat. \
+                        Unauthorized requests are answered like authorized ones.",
+        "security": [{ "device": [] }, {}],
+        "parameters": forward_parameters(),
+        "requestBody": {
+            "content": {
+                "application/json": {
+                    "schema": {
+                        "oneOf": [
+                            schema("Forward"),
+                            schema("RcsForward"),
+                            schema("StatusReport"),
+                            schema("LocationReport"),
+                            schema("Heartbeat"),
+                        ],
+                    },
+                },
+            },
+        },
+        "responses": {
+            "200": {
+                "description": "Accepted, the same for unauthorized requests except with `wait=1`",
+                "content": { "application/json": { "schema": schema("PostResult") } },
+            },
+        },
+    });
+    let config = json!({
+        "summary": "Config of a device rendered from the config template",
+        "security": [{ "device": [] }, {}],
+        "parameters": device_parameters(),
+        "responses": {
+            "200": { "description": "The config, empty when unauthorized", "content": { "text/plain": {} } },
+        },
+    });
+    json!({
+        "/{device}/{token}": { "get": config, "post": device },
+        "/v1/calls": {
+            "post": {
+                "summary": "Call log of a device",
+                "security": [{ "device": [] }],
+                "requestBody": { "required": true, "content": { "application/json": { "schema": array("CallRecord") } } },
+                "responses": { "200": empty("Accepted"), "400": empty("Not a call log") },
+            },
+        },
+        "/v1/commands": {
+            "get": {
+                "summary": "Commands queued for a device",
+                "security": [{ "device": [] }],
+                "responses": { "200": json_response("Queued commands", schema("PollCommandsResponse")) },
+            },
+        },
+        "/v1/channel": {
+            "get": {
+                "summary": "WebSocket pushing commands to a device",
+                "description": "Every message sent is taken as a heartbeat, optionally with `vitals` and `timestamp`, and answered with the queued commands like `/v1/commands`. Commands issued while the socket is open are pushed in the same shape.",
+                "security": [{ "device": [] }],
+                "responses": {
+                    "101": empty("Switched to the WebSocket"),
+                    "401": empty("Unknown device"),
+                    "426": empty("Not a WebSocket upgrade"),
+                },
+            },
+        },
+        "/v1/commands/{id}/ack": {
+            "post": {
+                "summary": "Acknowledges a command",
+                "security": [{ "device": [] }],
+                "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "requestBody": { "content": { "application/json": { "schema": schema("CommandAck") } } },
+                "responses": { "200": empty("Acknowledged"), "400": empty("Not an acknowledgement") },
+            },
+        },
+    })
+}
+
+fn admin_paths() -> Value {
+    let device = json!([{ "name": "device", "in": "path", "required": true, "schema": { "type": "string" } }]);
+    let id =
+        json!([{ "name": "id", "in": "path", "required": true, "schema": { "type": "integer" } }]);
+    let admin = json!([{ "admin": [] }]);
+    json!({
+        "/admin/api/login": {
+            "get": {
+                "summary": "Bot of the Telegram Login Widget",
+                "responses": { "200": json_response("The bot", schema("LoginInfo")) },
+            },
+            "post": {
+                "summary": "Exchanges a Telegram login for a session",
+                "requestBody": json_body("TelegramLogin"),
+                "responses": {
+                    "200": json_response("A session token for the admin routes", schema("Session")),
+                    "401": empty("Not a trusted user"),
+                },
+            },
+        },
+        "/admin/api/devices": {
+            "get": {
+                "security": admin,
+                "responses": { "200": json_response("Every device", array("DeviceState")) },
+            },
+        },
+        "/admin/api/devices/{device}/deliveries": {
+            "get": {
+                "security": admin,
+                "parameters": device,
+                "responses": { "200": json_response("Recent deliveries", array("Delivery")) },
+            },
+        },
+        "/admin/api/devices/{device}/messages": {
+            "get": {
+                "security": admin,
+                "parameters": device,
+                "responses": { "200": json_response("Archived messages", array("ArchivedMessage")) },
+            },
+        },
+        "/admin/api/flags": {
+            "get": {
+                "security": admin,
+                "responses": { "200": json_response("The flags", schema("Flags")) },
+            },
+            "put": {
+                "security": admin,
+                "requestBody": json_body("Flags"),
+                "responses": { "200": json_response("The flags", schema("Flags")) },
+            },
+        },
+        "/api/dryrun": {
+            "post": {
+                "summary": "How a device body would be handled, without sending anything",
+                "security": admin,
+                "parameters": [{ "name": "device", "in": "query", "required": true, "schema": { "type": "string" } }],
+                "requestBody": { "content": { "application/json": {} } },
+                "responses": {
+                    "200": json_response("The parsed body, matching rule, message and destinations", schema("DryRun")),
+                    "404": empty("Device not found"),
+                },
+            },
+        },
+        "/api/stream": {
+            "get": {
+                "summary": "Server-sent forward and status events",
+                "security": [{ "admin": [] }, { "admin_query": [] }],
+                "responses": { "200": { "description": "The stream", "content": { "text/event-stream": {} } } },
+            },
+        },
+        "/api/rules": {
+            "get": {
+                "security": admin,
+                "responses": { "200": json_response("Rules in the order they apply", array("Rule")) },
+            },
+            "post": {
+                "security": admin,
+                "requestBody": json_body("Rule"),
+                "responses": { "201": json_response("The rule", schema("Rule")), "400": empty("Invalid rule") },
+            },
+        },
+        "/api/rules/export": {
+            "get": {
+                "security": admin,
+                "responses": {
+                    "200": { "description": "The rules as YAML", "content": { "application/yaml": {} } },
+                },
+            },
+        },
+        "/api/rules/import": {
+            "post": {
+                "summary": "Replaces every rule with those of a document",
+                "security": admin,
+                "parameters": [{ "name": "dry_run", "in": "query", "schema": { "type": "string", "enum": ["0", "1"] } }],
+                "requestBody": { "required": true, "content": { "application/yaml": {}, "application/json": {} } },
+                "responses": {
+                    "200": json_response("The changes, and the rules unless a dry run", schema("RulesImport")),
+                    "400": empty("Invalid document"),
+                },
+            },
+        },
+        "/api/rules/{id}": {
+            "get": {
+                "security": admin,
+                "parameters": id,
+                "responses": { "200": json_response("The rule", schema("Rule")), "404": empty("Not found") },
+            },
+            "put": {
+                "security": admin,
+                "parameters": id,
+                "requestBody": json_body("Rule"),
+                "responses": {
+                    "200": json_response("The rule", schema("Rule")),
+                    "404": empty("Not found"),
+                    "409": empty("Changed since version"),
+                },
+            },
+            "delete": {
+                "security": admin,
+                "parameters": id,
+                "responses": { "200": json_response("The deleted rule", schema("Rule")), "404": empty("Not found") },
+            },
+        },
+    })
+}
+
+fn public_paths() -> Value {
+    json!({
+        "/healthz": {
+            "get": {
+                "responses": {
+                    "200": { "description": "Configuration complete", "content": { "text/plain": {} } },
+                    "503": { "description": "Every problem of the configuration", "content": { "text/plain": {} } },
+                },
+            },
+        },
+        "/metrics": {
+            "get": {
+                "security": [{ "metrics": [] }],
+                "responses": { "200": { "description": "Prometheus metrics", "content": { "text/plain": {} } } },
+            },
+        },
+        "/status/{public_id}": {
+            "get": {
+                "parameters": [{ "name": "public_id", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": { "200": { "description": "Status page", "content": { "text/html": {} } }, "404": empty("Not found") },
+            },
+        },
+        "/pair/{token}": {
+            "get": {
+                "parameters": [{ "name": "token", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": { "200": { "description": "Pairing page, asking to show the config", "content": { "text/html": {} } }, "404": empty("Expired or used") },
+            },
+            "post": {
+                "parameters": [{ "name": "token", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "responses": { "200": { "description": "Pairing page with the QR code of the config URL, using up the link", "content": { "text/html": {} } }, "404": empty("Expired or used") },
+            },
+        },
+    })
+}
+
+/// The document served at `/api/openapi.json`.
+pub fn document() -> Value {
+    let mut paths = device_paths();
+    for more in [admin_paths(), public_paths()] {
+        if let (Value::Object(paths), Value::Object(more)) = (&mut paths, more) {
+            paths.extend(more);
+        }
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "sms-fwd-workers",
+            "version": env!("CARGO_PKG_VERSION"),
+            "license": { "name": "AGPL-3.0-only", "identifier": "AGPL-3.0-only" },
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "device": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "`{device}/{token}`, instead of the path parameters.",
+                },
+                "admin": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "`admin_token`, or a session from `POST /admin/api/login`.",
+                },
+                "admin_query": { "type": "apiKey", "in": "query", "name": "token" },
+                "metrics": { "type": "http", "scheme": "bearer", "description": "`metrics_token`." },
+            },
+        },
+    })
+}
@@ -0,0 +1,373 @@
+//! QR codes of up to 213 bytes, in byte mode at error correction level M,
+//! drawn as SVG.
+
+/// Per version from 1: EC codewords per block, and the count and data
+/// codewords of the blocks of both groups.
+const VERSIONS: [(usize, [(usize, usize); 2]); 10] = [
+    (10, [(1, 16), (0, 0)]),
+    (16, [(1, 28), (0, 0)]),
+    (26, [(1, 44), (0, 0)]),
+    (18, [(2, 32), (0, 0)]),
+    (24, [(2, 43), (0, 0)]),
+    (16, [(4, 27), (0, 0)]),
+    (18, [(4, 31), (0, 0)]),
+    (22, [(2, 38), (2, 39)]),
+    (22, [(3, 36), (2, 37)]),
+    (26, [(4, 43), (1, 44)]),
+];
+
+/// Centers of alignment patterns per version from 2, in both directions.
+const ALIGNMENT: [&[usize]; 10] = [
+    &[],
+    &[6, 18],
+    &[6, 22],
+    &[6, 26],
+    &[6, 30],
+    &[6, 34],
+    &[6, 22, 38],
+    &[6, 24, 42],
+    &[6, 26, 46],
+    &[6, 28, 50],
+];
+
+/// Format bits of level M.
+const LEVEL_M: u32 = 0;
+
+/// Modules of quiet zone around the code.
+const BORDER: usize = 4;
+
+struct Grid {
+    size: usize,
+    dark: Vec<bool>,
+    function: Vec<bool>,
+}
+
+impl Grid {
+    fn new(version: usize) -> Self {
+        let size = version * 4 + 17;
+        Self {
+            size,
+            dark: vec![false; size * size],
+            function: vec![false; size * size],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.dark[y * self.size + x]
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.dark[y * self.size + x] = dark;
+        self.function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4i32..=4 {
+                for dx in -4i32..=4 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if (0..size as i32).contains(&x) && (0..size as i32).contains(&y) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+        let centers = ALIGNMENT[version - 1];
+        let last = centers.len().saturating_sub(1);
+        for (i, &cx) in centers.iter().enumerate() {
+            for (j, &cy) in centers.iter().enumerate() {
+                // those overlapping the finder patterns are left out
+                if (i, j) == (0, 0) || (i, j) == (0, last) || (i, j) == (last, 0) {
+                    continue;
+                }
+                for dy in -2i32..=2 {
+                    for dx in -2i32..=2 {
+                        let x = (cx as i32 + dx) as usize;
+                        let y = (cy as i32 + dy) as usize;
+                        self.set_function(x, y, dx.abs().max(dy.abs()) != 1);
+                    }
+                }
+            }
+        }
+        // reserved for now, drawn for real once the mask is chosen
+        self.draw_format(0);
+        if version >= 7 {
+            let mut rem = version as u32;
+            for _ in 0..12 {
+                rem = (rem << 1) ^ ((rem >> 11) * 0x1F25);
+            }
+            let bits = (version as u32) << 12 | rem;
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 == 1;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    fn draw_format(&mut self, mask: u32) {
+        let data = LEVEL_M << 3 | mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = (data << 10 | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 == 1;
+        let size = self.size;
+        for i in 0..6 {
+            self.set_function(8, i, bit(i));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i));
+        }
+        self.set_function(8, size - 8, true);
+    }
+
+    /// Places the codewords in the zigzag of two-module columns.
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.function[y * size + x] && i < codewords.len() * 8 {
+                        self.dark[y * size + x] = (codewords[i >> 3] >> (7 - (i & 7))) & 1 == 1;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = y * self.size + x;
+                if invert && !self.function[index] {
+                    self.dark[index] = !self.dark[index];
+                }
+            }
+        }
+    }
+
+    /// Penalty of runs, 2x2 blocks and imbalance of dark modules, leaving
+    /// out the rarely decisive finder-like patterns.
+    fn penalty(&self) -> usize {
+        let size = self.size;
+        let mut penalty = 0;
+        for line in 0..size {
+            for horizontal in [true, false] {
+                let mut run = 0;
+                let mut previous = None;
+                for i in 0..size {
+                    let dark = if horizontal {
+                        self.get(i, line)
+                    } else {
+                        self.get(line, i)
+                    };
+                    if previous == Some(dark) {
+                        run += 1;
+                        if run == 5 {
+                            penalty += 3;
+                        } else if run > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        run = 1;
+                        previous = Some(dark);
+                    }
+                }
+            }
+        }
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.get(x, y);
+                if dark == self.get(x + 1, y)
+                    && dark == self.get(x, y + 1)
+                    && dark == self.get(x + 1, y + 1)
+                {
+                    penalty += 3;
+                }
+            }
+        }
+        let dark = self.dark.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        penalty
+            + (dark * 20)
+                .abs_diff(total * 10)
+                .div_ceil(total)
+                .saturating_sub(1)
+                * 10
+    }
+}
+
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u32 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u32 >> i) & 1) * x as u32;
+    }
+    z as u8
+}
+
+fn reed_solomon(data: &[u8], degree: usize) -> Vec<u8> {
+    let mut divisor = vec![0u8; degree];
+    divisor[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    let mut remainder = vec![0u8; degree];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, &coefficient) in remainder.iter_mut().zip(&divisor) {
+            *r ^= gf_multiply(coefficient, factor);
+        }
+    }
+    remainder
+}
+
+/// Data codewords of `text` in byte mode, padded to `capacity`.
+fn encode(text: &[u8], version: usize, capacity: usize) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::new();
+    let mut push = |value: usize, length: usize| {
+        bits.extend((0..length).rev().map(|i| (value >> i) & 1 == 1));
+    };
+    push(0b0100, 4);
+    push(text.len(), if version < 10 { 8 } else { 16 });
+    for &byte in text {
+        push(byte as usize, 8);
+    }
+    let terminator = (capacity * 8 - bits.len()).min(4);
+    bits.extend(std::iter::repeat_n(false, terminator));
+    bits.resize(bits.len().div_ceil(8) * 8, false);
+    let mut codewords = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, &bit| acc << 1 | bit as u8))
+        .collect::<Vec<_>>();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= capacity {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+/// Splits the data into blocks and interleaves them with their EC codewords.
+fn interleave(data: &[u8], ec_length: usize, groups: [(usize, usize); 2]) -> Vec<u8> {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    for (count, length) in groups {
+        for _ in 0..count {
+            blocks.push(&data[offset..offset + length]);
+            offset += length;
+        }
+    }
+    let ec = blocks
+        .iter()
+        .map(|block| reed_solomon(block, ec_length))
+        .collect::<Vec<_>>();
+    let longest = blocks.iter().map(|block| block.len()).max().unwrap_or(0);
+    let mut codewords = Vec::new();
+    for i in 0..longest {
+        codewords.extend(blocks.iter().filter_map(|block| block.get(i)));
+    }
+    for i in 0..ec_length {
+        codewords.extend(ec.iter().map(|ec| ec[i]));
+    }
+    codewords
+}
+
+/// The QR code of `text` as an SVG document, `None` when it is too long.
+pub fn svg(text: &str) -> Option<String> {
+    let text = text.as_bytes();
+    let (version, &(ec_length, groups)) =
+        VERSIONS.iter().enumerate().find(|&(index, &(_, groups))| {
+            let capacity = groups
+                .iter()
+                .map(|(count, length)| count * length)
+                .sum::<usize>();
+            let count_bits = if index + 1 < 10 { 8 } else { 16 };
+            4 + count_bits + text.len() * 8 <= capacity * 8
+        })?;
+    let version = version + 1;
+    let capacity = groups.iter().map(|(count, length)| count * length).sum();
+    let codewords = interleave(&encode(text, version, capacity), ec_length, groups);
+    let mut grid = Grid::new(version);
+    grid.draw_function_patterns(version);
+    grid.draw_codewords(&codewords);
+    let mask = (0..8)
+        .min_by_key(|&mask| {
+            grid.apply_mask(mask);
+            grid.draw_format(mask);
+            let penalty = grid.penalty();
+            grid.apply_mask(mask);
+            penalty
+        })
+        .unwrap_or(0);
+    grid.apply_mask(mask);
+    grid.draw_format(mask);
+    let size = grid.size + BORDER * 2;
+    let mut path = String::new();
+    for y in 0..grid.size {
+        for x in 0..grid.size {
+            if grid.get(x, y) {
+                path.push_str(&format!("M{},{}h1v1h-1z", x + BORDER, y + BORDER));
+            }
+        }
+    }
+    Some(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {size} {size}\" \
+         shape-rendering=\"crispEdges\"><rect width=\"{size}\" height=\"{size}\" \
+         fill=\"#fff\"/><path d=\"{path}\" fill=\"#000\"/></svg>"
+    ))
+}
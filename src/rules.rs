@@ -0,0 +1,709 @@
+use std::fmt::Display;
+
+use regex::RegexBuilder;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use worker::{D1Database, Env, wasm_bindgen::JsValue};
+
+use crate::{
+    error::{Error, Result},
+    log, to_json, yaml,
+};
+
+/// Compiled size limit of a rule's pattern, so that one rule cannot slow
+/// down every forward.
+const PATTERN_SIZE_LIMIT: usize = 64 * 1024;
+
+const COLUMNS: &str =
+    "id, version, device, sender, pattern, action, chat_id, days, hours, delay, cancel, updated";
+
+/// Longest a `delay` rule may hold forwards back.
+const DELAY_LIMIT_MINUTES: i64 = 24 * 60;
+
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Day of the week, Sunday being 0, and hour of a forward in the device's
+/// time zone.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalTime {
+    pub day: u32,
+    pub hour: u32,
+}
+
+/// Days of `days`, e.g. `mon-fri` or `sat,sun`, as bits by day. Ranges may
+/// wrap around the week, e.g. `fri-mon`.
+fn day_mask(days: &str) -> Option<u8> {
+    let day = |name: &str| {
+        DAYS.iter()
+            .position(|day| name.trim().eq_ignore_ascii_case(day))
+    };
+    days.split(',').try_fold(0u8, |mask, part| {
+        let (from, to) = match part.split_once('-') {
+            Some((from, to)) => (day(from)?, day(to)?),
+            None => (day(part)?, day(part)?),
+        };
+        let span = (to + 7 - from) % 7;
+        Some((0..=span).fold(mask, |mask, i| mask | 1 << ((from + i) % 7)))
+    })
+}
+
+/// Hours of `hours`, e.g. `9-18`, from the first up to the second. Ranges
+/// may wrap around midnight, e.g. `22-7`.
+fn hour_range(hours: &str) -> Option<(u32, u32)> {
+    let (from, to) = hours.split_once('-')?;
+    let (from, to) = (from.trim().parse().ok()?, to.trim().parse().ok()?);
+    (from <= 24 && to <= 24 && from != to).then_some((from, to))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Drop the forward.
+    Drop,
+    /// Only archive it for the digest.
+    Archive,
+    /// Send it to `chat_id` instead of the device's chat.
+    Route,
+    /// Hold it back for `delay`, together with the sender's next forwards.
+    Delay,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Action::Drop => "drop",
+            Action::Archive => "archive",
+            Action::Route => "route",
+            Action::Delay => "delay",
+        }
+    }
+}
+
+/// Minutes of a `delay` like `5m`.
+pub fn delay_minutes(delay: &str) -> Option<i64> {
+    let minutes = delay
+        .strip_suffix('m')
+        .unwrap_or(delay)
+        .trim()
+        .parse()
+        .ok()?;
+    (1..=DELAY_LIMIT_MINUTES)
+        .contains(&minutes)
+        .then_some(minutes)
+}
+
+/// Whether `text` matches `pattern` as the pattern of a rule.
+pub fn is_match(pattern: &str, text: &str) -> bool {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(PATTERN_SIZE_LIMIT)
+        .build()
+        .is_ok_and(|regex| regex.is_match(text))
+}
+
+fn is_valid_pattern(pattern: &str) -> bool {
+    RegexBuilder::new(pattern)
+        .size_limit(PATTERN_SIZE_LIMIT)
+        .build()
+        .is_ok()
+}
+
+/// A filtering or routing rule, matching forwards by device, sender and a
+/// pattern of the text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    #[serde(default)]
+    pub id: i64,
+    /// Bumped by every update, which must name the version it replaces.
+    #[serde(default)]
+    pub version: i64,
+    /// Every device when absent.
+    #[serde(default)]
+    pub device: Option<String>,
+    /// Every sender when absent.
+    #[serde(default)]
+    pub sender: Option<String>,
+    /// Case-insensitive regex of the text, any text when absent.
+    #[serde(default)]
+    pub pattern: Option<String>,
+    pub action: Action,
+    #[serde(default)]
+    pub chat_id: Option<String>,
+    /// Days of the week in the device's time zone, e.g. `mon-fri`, every day
+    /// when absent.
+    #[serde(default)]
+    pub days: Option<String>,
+    /// Hours in the device's time zone, e.g. `9-18` or `22-7`, any hour when
+    /// absent.
+    #[serde(default)]
+    pub hours: Option<String>,
+    /// How long `delay` holds forwards back, e.g. `5m`.
+    #[serde(default)]
+    pub delay: Option<String>,
+    /// Regex of a forward from the same sender which drops the ones held
+    /// back by `delay`, e.g. a correction, and is sent right away.
+    #[serde(default)]
+    pub cancel: Option<String>,
+    #[serde(default)]
+    pub updated: i64,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{id} {device} {sender}",
+            id = self.id,
+            device = self.device.as_deref().unwrap_or("*"),
+            sender = self.sender.as_deref().unwrap_or("*"),
+        )?;
+        if let Some(pattern) = &self.pattern {
+            write!(f, " /{pattern}/")?;
+        }
+        if let Some(days) = &self.days {
+            write!(f, " {days}")?;
+        }
+        if let Some(hours) = &self.hours {
+            write!(f, " {hours}h")?;
+        }
+        match self.action {
+            Action::Drop => write!(f, " → drop"),
+            Action::Archive => write!(f, " → archive"),
+            Action::Route => write!(f, " → {}", self.chat_id.as_deref().unwrap_or_default()),
+            Action::Delay => {
+                write!(f, " → delay {}", self.delay.as_deref().unwrap_or_default())?;
+                match &self.cancel {
+                    Some(cancel) => write!(f, " unless /{cancel}/"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Rule {
+    /// Checks the rule on its own, `devices` being the ones configured.
+    pub fn validate(&self, devices: &[String]) -> Result<()> {
+        let invalid = |problem: &str| Err(Error::InvalidRule(problem.to_owned()));
+        if self.sender.is_none() && self.pattern.is_none() {
+            return invalid("a sender or a pattern is required");
+        }
+        if let Some(device) = &self.device
+            && !devices.contains(device)
+        {
+            return invalid("device not found");
+        }
+        if self
+            .pattern
+            .as_deref()
+            .is_some_and(|p| !is_valid_pattern(p))
+        {
+            return invalid("pattern is not a valid regex");
+        }
+        if self
+            .days
+            .as_deref()
+            .is_some_and(|days| day_mask(days).is_none())
+        {
+            return invalid("days must be like mon-fri or sat,sun");
+        }
+        if self
+            .hours
+            .as_deref()
+            .is_some_and(|hours| hour_range(hours).is_none())
+        {
+            return invalid("hours must be like 9-18 or 22-7");
+        }
+        match (self.action, &self.delay, &self.cancel) {
+            (Action::Delay, Some(delay), _) if delay_minutes(delay).is_some() => {}
+            (Action::Delay, _, _) => return invalid("delay must be like 5m, up to a day"),
+            (_, Some(_), _) | (_, _, Some(_)) => {
+                return invalid("delay and cancel are only for delay");
+            }
+            _ => {}
+        }
+        if self.cancel.as_deref().is_some_and(|p| !is_valid_pattern(p)) {
+            return invalid("cancel is not a valid regex");
+        }
+        match (self.action, &self.chat_id) {
+            (Action::Route, Some(chat_id)) if chat_id.parse::<i64>().is_ok() => Ok(()),
+            (Action::Route, _) => invalid("route requires a numeric chat_id"),
+            (_, Some(_)) => invalid("chat_id is only for route"),
+            (_, None) => Ok(()),
+        }
+    }
+
+    fn matches(&self, device: &str, sender: Option<&str>, text: &str, time: LocalTime) -> bool {
+        if self.device.as_deref().is_some_and(|d| d != device) {
+            return false;
+        }
+        if let Some(days) = &self.days
+            && day_mask(days).is_none_or(|mask| mask & 1 << time.day == 0)
+        {
+            return false;
+        }
+        if let Some(hours) = &self.hours
+            && hour_range(hours).is_none_or(|(from, to)| match from < to {
+                true => !(from..to).contains(&time.hour),
+                false => (to..from).contains(&time.hour),
+            })
+        {
+            return false;
+        }
+        if let Some(expected) = &self.sender
+            && sender != Some(expected.as_str())
+        {
+            return false;
+        }
+        self.pattern
+            .as_deref()
+            .is_none_or(|pattern| is_match(pattern, text))
+    }
+}
+
+/// The first rule matching a forward received at `time`.
+pub fn find<'a>(
+    rules: &'a [Rule],
+    device: &str,
+    sender: Option<&str>,
+    text: &str,
+    time: LocalTime,
+) -> Option<&'a Rule> {
+    rules
+        .iter()
+        .find(|rule| rule.matches(device, sender, text, time))
+}
+
+fn database(env: &Env) -> Result<D1Database> {
+    env.d1("rules")
+        .map_err(|_| Error::MissingBinding("rules".to_owned()))
+}
+
+fn tenant_id() -> String {
+    log::tenant().unwrap_or_default()
+}
+
+fn nullable(value: &Option<String>) -> JsValue {
+    value.as_deref().map_or(JsValue::NULL, JsValue::from)
+}
+
+/// Rules of the current tenant in the order they apply, none without the
+/// `rules` database.
+pub async fn list(env: &Env) -> Result<Vec<Rule>> {
+    let Ok(db) = database(env) else {
+        return Ok(Vec::new());
+    };
+    Ok(db
+        .prepare(format!(
+            "SELECT {COLUMNS} FROM rules WHERE tenant_id = ?1 ORDER BY id"
+        ))
+        .bind(&[tenant_id().into()])?
+        .all()
+        .await?
+        .results()?)
+}
+
+pub async fn get(env: &Env, id: i64) -> Result<Option<Rule>> {
+    Ok(database(env)?
+        .prepare(format!(
+            "SELECT {COLUMNS} FROM rules WHERE id = ?1 AND tenant_id = ?2"
+        ))
+        .bind(&[(id as f64).into(), tenant_id().into()])?
+        .first(None)
+        .await?)
+}
+
+async fn record_change(db: &D1Database, rule: &Rule, change: &str, now: i64) -> Result<()> {
+    db.prepare(
+        "INSERT INTO rule_changes (rule_id, tenant_id, version, change, rule, changed) \
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )
+    .bind(&[
+        (rule.id as f64).into(),
+        tenant_id().into(),
+        (rule.version as f64).into(),
+        change.into(),
+        to_json(rule).into(),
+        (now as f64).into(),
+    ])?
+    .run()
+    .await?;
+    Ok(())
+}
+
+pub async fn create(env: &Env, rule: &Rule, now: i64) -> Result<Rule> {
+    let db = database(env)?;
+    let created: Rule = db
+        .prepare(format!(
+            "INSERT INTO rules \
+             (tenant_id, device, sender, pattern, action, chat_id, days, hours, delay, cancel, \
+             updated) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11) RETURNING {COLUMNS}"
+        ))
+        .bind(&[
+            tenant_id().into(),
+            nullable(&rule.device),
+            nullable(&rule.sender),
+            nullable(&rule.pattern),
+            rule.action.as_str().into(),
+            nullable(&rule.chat_id),
+            nullable(&rule.days),
+            nullable(&rule.hours),
+            nullable(&rule.delay),
+            nullable(&rule.cancel),
+            (now as f64).into(),
+        ])?
+        .first(None)
+        .await?
+        .ok_or_else(|| Error::InvalidRule("not created".to_owned()))?;
+    record_change(&db, &created, "create", now).await?;
+    log::info!("rules", rule_id = created.id, change = "create");
+    Ok(created)
+}
+
+/// Replaces version `rule.version` of rule `id`, `None` when the rule is
+/// gone or was changed since.
+pub async fn update(env: &Env, id: i64, rule: &Rule, now: i64) -> Result<Option<Rule>> {
+    let db = database(env)?;
+    let updated: Option<Rule> = db
+        .prepare(format!(
+            "UPDATE rules SET device = ?1, sender = ?2, pattern = ?3, action = ?4, \
+             chat_id = ?5, days = ?10, hours = ?11, delay = ?12, cancel = ?13, updated = ?6, \
+             version = version + 1 \
+             WHERE id = ?7 AND tenant_id = ?8 AND version = ?9 RETURNING {COLUMNS}"
+        ))
+        .bind(&[
+            nullable(&rule.device),
+            nullable(&rule.sender),
+            nullable(&rule.pattern),
+            rule.action.as_str().into(),
+            nullable(&rule.chat_id),
+            (now as f64).into(),
+            (id as f64).into(),
+            tenant_id().into(),
+            (rule.version as f64).into(),
+            nullable(&rule.days),
+            nullable(&rule.hours),
+            nullable(&rule.delay),
+            nullable(&rule.cancel),
+        ])?
+        .first(None)
+        .await?;
+    if let Some(updated) = &updated {
+        record_change(&db, updated, "update", now).await?;
+        log::info!("rules", rule_id = id, change = "update");
+    }
+    Ok(updated)
+}
+
+/// Deletes rule `id`, returning it unless it was already gone.
+pub async fn delete(env: &Env, id: i64, now: i64) -> Result<Option<Rule>> {
+    let db = database(env)?;
+    let deleted: Option<Rule> = db
+        .prepare(format!(
+            "DELETE FROM rules WHERE id = ?1 AND tenant_id = ?2 RETURNING {COLUMNS}"
+        ))
+        .bind(&[(id as f64).into(), tenant_id().into()])?
+        .first(None)
+        .await?;
+    if let Some(deleted) = &deleted {
+        record_change(&db, deleted, "delete", now).await?;
+        log::info!("rules", rule_id = id, change = "delete");
+    }
+    Ok(deleted)
+}
+
+/// Fields of a rule in an exported document, in the order they are written.
+const FIELDS: [&str; 9] = [
+    "device", "sender", "pattern", "action", "chat_id", "days", "hours", "delay", "cancel",
+];
+
+/// Same as `to_json` of a rule, for the changes recorded inside a batch.
+const CHANGE_JSON: &str = "json_object('id', id, 'version', version, 'device', device, \
+                           'sender', sender, 'pattern', pattern, 'action', action, \
+                           'chat_id', chat_id, 'days', days, 'hours', hours, \
+                           'delay', delay, 'cancel', cancel, 'updated', updated)";
+
+impl Rule {
+    /// Whether both rules do the same to the same forwards.
+    fn same_as(&self, other: &Rule) -> bool {
+        (
+            &self.device,
+            &self.sender,
+            &self.pattern,
+            self.action,
+            &self.chat_id,
+            &self.days,
+            &self.hours,
+            &self.delay,
+            &self.cancel,
+        ) == (
+            &other.device,
+            &other.sender,
+            &other.pattern,
+            other.action,
+            &other.chat_id,
+            &other.days,
+            &other.hours,
+            &other.delay,
+            &other.cancel,
+        )
+    }
+}
+
+/// The rules as a YAML document, without ids and versions so that it can be
+/// kept in a repository and imported anywhere.
+pub fn export(rules: &[Rule]) -> String {
+    let items = rules
+        .iter()
+        .map(|rule| {
+            let values = [
+                rule.device.as_deref(),
+                rule.sender.as_deref(),
+                rule.pattern.as_deref(),
+                Some(rule.action.as_str()),
+                rule.chat_id.as_deref(),
+                rule.days.as_deref(),
+                rule.hours.as_deref(),
+                rule.delay.as_deref(),
+                rule.cancel.as_deref(),
+            ];
+            FIELDS.into_iter().zip(values).collect()
+        })
+        .collect::<Vec<_>>();
+    yaml::write_list(
+        "rules of sms-fwd-workers, the first match wins",
+        "rules",
+        &items,
+    )
+}
+
+/// Reads and validates every rule of an exported document, telling which
+/// rule is the first invalid one.
+pub fn parse(doc: &str, devices: &[String]) -> Result<Vec<Rule>> {
+    let items = yaml::parse_list(doc, "rules").map_err(Error::InvalidRule)?;
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(index, fields)| {
+            let invalid =
+                |problem: String| Error::InvalidRule(format!("rule {}: {problem}", index + 1));
+            if let Some(field) = fields
+                .keys()
+                .find(|field| !FIELDS.contains(&field.as_str()))
+            {
+                return Err(invalid(format!("unknown field {field}")));
+            }
+            let object = fields
+                .into_iter()
+                .map(|(field, value)| (field, value.map_or(Value::Null, Value::String)))
+                .collect();
+            let rule: Rule = serde_json::from_value(Value::Object(object))
+                .map_err(|e| invalid(e.to_string()))?;
+            rule.validate(devices).map_err(|e| match e {
+                Error::InvalidRule(problem) => invalid(problem),
+                e => e,
+            })?;
+            Ok(rule)
+        })
+        .collect()
+}
+
+/// What importing a document would change.
+#[derive(Debug, Default, Serialize)]
+pub struct Diff {
+    pub added: Vec<Rule>,
+    pub removed: Vec<Rule>,
+    pub unchanged: usize,
+    /// Whether the rules kept apply in another order.
+    pub reordered: bool,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && !self.reordered
+    }
+}
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "No changes");
+        }
+        for rule in &self.removed {
+            writeln!(f, "- {rule}")?;
+        }
+        for rule in &self.added {
+            writeln!(f, "+ {rule}")?;
+        }
+        write!(f, "{} unchanged", self.unchanged)?;
+        if self.reordered {
+            write!(f, ", reordered")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn diff(current: &[Rule], imported: &[Rule]) -> Diff {
+    let mut diff = Diff::default();
+    let mut remaining = current.to_vec();
+    let mut kept = Vec::new();
+    for rule in imported {
+        match remaining.iter().position(|r| r.same_as(rule)) {
+            Some(index) => {
+                kept.push(remaining.remove(index).id);
+                diff.unchanged += 1;
+            }
+            None => diff.added.push(rule.clone()),
+        }
+    }
+    diff.removed = remaining;
+    diff.reordered = !kept.is_sorted();
+    diff
+}
+
+/// Replaces the rules of the current tenant with `rules` in one batch, so
+/// that forwards see either the old or the new set. Every rule removed and
+/// created is recorded in `rule_changes`.
+pub async fn replace(env: &Env, rules: &[Rule], now: i64) -> Result<Vec<Rule>> {
+    let db = database(env)?;
+    let tenant = JsValue::from(tenant_id());
+    let now = JsValue::from(now as f64);
+    let mut statements = vec![
+        db.prepare(format!(
+            "INSERT INTO rule_changes (rule_id, tenant_id, version, change, rule, changed) \
+             SELECT id, tenant_id, version, 'delete', {CHANGE_JSON}, ?2 \
+             FROM rules WHERE tenant_id = ?1"
+        ))
+        .bind(&[tenant.clone(), now.clone()])?,
+        db.prepare("DELETE FROM rules WHERE tenant_id = ?1")
+            .bind(std::slice::from_ref(&tenant))?,
+    ];
+    for rule in rules {
+        statements.push(
+            db.prepare(
+                "INSERT INTO rules \
+                 (tenant_id, device, sender, pattern, action, chat_id, days, hours, delay, \
+                 cancel, updated) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )
+            .bind(&[
+                tenant.clone(),
+                nullable(&rule.device),
+                nullable(&rule.sender),
+                nullable(&rule.pattern),
+                rule.action.as_str().into(),
+                nullable(&rule.chat_id),
+                nullable(&rule.days),
+                nullable(&rule.hours),
+                nullable(&rule.delay),
+                nullable(&rule.cancel),
+                now.clone(),
+            ])?,
+        );
+        statements.push(db.prepare(format!(
+            "INSERT INTO rule_changes (rule_id, tenant_id, version, change, rule, changed) \
+             SELECT id, tenant_id, version, 'create', {CHANGE_JSON}, updated \
+             FROM rules WHERE id = last_insert_rowid()"
+        )));
+    }
+    db.batch(statements).await?;
+    log::info!("rules", change = "import", rules = rules.len());
+    list(env).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices() -> Vec<String> {
+        vec!["dev0".to_owned()]
+    }
+
+    const DOC: &str = "# rules\n\
+                       rules:\n  \
+                         - sender: \"10086\"\n    \
+                           action: drop\n  \
+                         - device: dev0\n    \
+                           pattern: 'it''s'\n    \
+                           action: route\n    \
+                           chat_id: \"-100\"\n    \
+                           days: mon-fri # weekdays\n  \
+                         - pattern: code\n    \
+                           action: delay\n    \
+                           delay: 5m\n    \
+                           cancel: ~\n";
+
+    #[test]
+    fn parse_rules() {
+        let rules = parse(DOC, &devices()).unwrap();
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].sender.as_deref(), Some("10086"));
+        assert_eq!(rules[0].action, Action::Drop);
+        assert_eq!(rules[1].pattern.as_deref(), Some("it's"));
+        assert_eq!(rules[1].chat_id.as_deref(), Some("-100"));
+        assert_eq!(rules[1].days.as_deref(), Some("mon-fri"));
+        assert_eq!(rules[2].delay.as_deref(), Some("5m"));
+        assert_eq!(rules[2].cancel, None);
+        let exported = parse(&export(&rules), &devices()).unwrap();
+        assert!(rules.iter().zip(&exported).all(|(a, b)| a.same_as(b)));
+        let json = r#"{"rules": [{"sender": 10086, "action": "archive"}]}"#;
+        assert_eq!(
+            parse(json, &[]).unwrap()[0].sender.as_deref(),
+            Some("10086")
+        );
+        assert!(parse("rules: []\n", &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_rules() {
+        let problem = |doc: &str| parse(doc, &devices()).unwrap_err().to_string();
+        assert_eq!(
+            problem("rules:\n  - sender: a\n    action: drop\n  - action: drop\n"),
+            "invalid rule: rule 2: a sender or a pattern is required"
+        );
+        assert_eq!(
+            problem("rules:\n  - sender: a\n    action: drop\n    colour: red\n"),
+            "invalid rule: rule 1: unknown field colour"
+        );
+        assert_eq!(
+            problem("rules:\n  - sender: a\n    device: dev1\n    action: drop\n"),
+            "invalid rule: rule 1: device not found"
+        );
+        assert_eq!(
+            problem("rules:\n  - sender: a\n    action: route\n"),
+            "invalid rule: rule 1: route requires a numeric chat_id"
+        );
+        assert_eq!(
+            problem("rules:\n  - pattern: a\n    action: delay\n    delay: 2d\n"),
+            "invalid rule: rule 1: delay must be like 5m, up to a day"
+        );
+        assert_eq!(problem("other: []\n"), "invalid rule: rules not found");
+        assert_eq!(
+            problem("rules:\n  sender: a\n"),
+            "invalid rule: line 2: expected -"
+        );
+    }
+
+    #[test]
+    fn diff_rules() {
+        let mut current = parse(DOC, &devices()).unwrap();
+        for (id, rule) in current.iter_mut().enumerate() {
+            rule.id = id as i64 + 1;
+        }
+        assert!(diff(&current, &current).is_empty());
+        assert_eq!(diff(&current, &current).to_string(), "No changes");
+        let mut imported = current.clone();
+        imported.swap(0, 1);
+        imported[2].delay = Some("10m".to_owned());
+        let changes = diff(&current, &imported);
+        assert_eq!(changes.unchanged, 2);
+        assert!(changes.reordered);
+        assert_eq!(changes.added[0].delay.as_deref(), Some("10m"));
+        assert_eq!(changes.removed[0].id, 3);
+        assert_eq!(
+            changes.to_string(),
+            "- #3 * * /code/ → delay 5m\n+ #3 * * /code/ → delay 10m\n2 unchanged, reordered"
+        );
+    }
+}
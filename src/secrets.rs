@@ -0,0 +1,150 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use serde::Deserialize;
+use worker::Env;
+
+use crate::{
+    error::{Error, Result},
+    log,
+};
+
+/// Secrets looked up by the current invocation. They are not kept any
+/// longer, nor put in the Cache API.
+static CACHE: Mutex<Option<Invocation>> = Mutex::new(None);
+
+/// Configuration of the tenants served by this isolate, refreshed from D1 on
+/// every request for them.
+static TENANTS: Mutex<BTreeMap<String, BTreeMap<String, String>>> = Mutex::new(BTreeMap::new());
+
+/// Secrets of the deployment which tenants share, `bot_token` unless they
+/// bring their own bot.
+const SHARED: &[&str] = &[
+    "bot_token",
+    "config_template_url",
+    "config_template_next_url",
+    "fcm_server_key",
+    "sentry_dsn",
+    "stickers",
+];
+
+struct Invocation {
+    request_id: String,
+    secrets: BTreeMap<String, Option<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TenantSecret {
+    key: String,
+    value: String,
+}
+
+pub fn get(env: &Env, key: &str) -> Option<String> {
+    if let Some(tenant) = log::tenant() {
+        return match TENANTS.lock().unwrap().get(&tenant)?.get(key) {
+            Some(value) => Some(value.clone()),
+            None if SHARED.contains(&key) => env.secret(key).ok().map(|s| s.to_string()),
+            None => None,
+        };
+    }
+    let Some(request_id) = log::current() else {
+        return env.secret(key).ok().map(|s| s.to_string());
+    };
+    let mut cache = CACHE.lock().unwrap();
+    let invocation = match &mut *cache {
+        Some(invocation) if invocation.request_id == request_id => invocation,
+        cache => cache.insert(Invocation {
+            request_id,
+            secrets: BTreeMap::new(),
+        }),
+    };
+    invocation
+        .secrets
+        .entry(key.to_owned())
+        .or_insert_with(|| env.secret(key).ok().map(|s| s.to_string()))
+        .clone()
+}
+
+/// Loads a tenant's secrets from the optional `tenants` D1 database,
+/// returning whether the tenant exists.
+pub async fn load_tenant(env: &Env, tenant: &str) -> Result<bool> {
+    let Ok(db) = env.d1("tenants") else {
+        return Ok(false);
+    };
+    let secrets: Vec<TenantSecret> = db
+        .prepare(
+            "SELECT key, value FROM tenant_secrets \
+             WHERE tenant_id = ?1 AND EXISTS (SELECT 1 FROM tenants WHERE id = ?1)",
+        )
+        .bind(&[tenant.into()])?
+        .all()
+        .await?
+        .results()?;
+    if secrets.is_empty() {
+        TENANTS.lock().unwrap().remove(tenant);
+        return Ok(false);
+    }
+    let secrets = secrets.into_iter().map(|s| (s.key, s.value)).collect();
+    TENANTS.lock().unwrap().insert(tenant.to_owned(), secrets);
+    Ok(true)
+}
+
+/// Ids of every tenant, empty without the `tenants` database.
+pub async fn tenant_ids(env: &Env) -> Result<Vec<String>> {
+    #[derive(Debug, Deserialize)]
+    struct Tenant {
+        id: String,
+    }
+    let Ok(db) = env.d1("tenants") else {
+        return Ok(Vec::new());
+    };
+    let tenants: Vec<Tenant> = db
+        .prepare("SELECT id FROM tenants ORDER BY id")
+        .all()
+        .await?
+        .results()?;
+    Ok(tenants.into_iter().map(|t| t.id).collect())
+}
+
+/// Adds a tenant with its secrets in one batch.
+pub async fn create_tenant(
+    env: &Env,
+    tenant: &str,
+    name: &str,
+    created: i64,
+    secrets: &[(&str, &str)],
+) -> Result<()> {
+    let db = env
+        .d1("tenants")
+        .map_err(|_| Error::MissingBinding("tenants".to_owned()))?;
+    let mut statements = vec![
+        db.prepare("INSERT INTO tenants (id, name, created) VALUES (?1, ?2, ?3)")
+            .bind(&[tenant.into(), name.into(), (created as f64).into()])?,
+    ];
+    for (key, value) in secrets {
+        statements.push(
+            db.prepare("INSERT INTO tenant_secrets (tenant_id, key, value) VALUES (?1, ?2, ?3)")
+                .bind(&[tenant.into(), (*key).into(), (*value).into()])?,
+        );
+    }
+    db.batch(statements).await?;
+    Ok(())
+}
+
+/// Adds or replaces secrets of an existing tenant in one batch.
+pub async fn set_tenant_secrets(env: &Env, tenant: &str, secrets: &[(&str, &str)]) -> Result<()> {
+    let db = env
+        .d1("tenants")
+        .map_err(|_| Error::MissingBinding("tenants".to_owned()))?;
+    let mut statements = Vec::new();
+    for (key, value) in secrets {
+        statements.push(
+            db.prepare(
+                "INSERT INTO tenant_secrets (tenant_id, key, value) VALUES (?1, ?2, ?3) \
+                 ON CONFLICT (tenant_id, key) DO UPDATE SET value = excluded.value",
+            )
+            .bind(&[tenant.into(), (*key).into(), (*value).into()])?,
+        );
+    }
+    db.batch(statements).await?;
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::fmt::Display;
+
+use worker::{Env, Fetch, Method, Request};
+
+use crate::{
+    config::Config,
+    domain::format_date,
+    error::{Error, Result},
+    highlight_codes, kv_store,
+    telegram::TelegramClient,
+};
+
+/// D1 databases checked when they are bound.
+const DATABASES: &[&str] = &["deliveries", "tenants", "dedup"];
+
+/// Outcome of one self-test check.
+pub struct Check {
+    name: String,
+    problem: Option<String>,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<()>) -> Self {
+        Self {
+            name: name.to_owned(),
+            problem: result.err().map(|e| e.to_string()),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.problem {
+            None => write!(f, "✅ {}", self.name),
+            Some(problem) => write!(f, "❌ {}: {problem}", self.name),
+        }
+    }
+}
+
+/// Exercises the configuration, bindings and outside services the worker
+/// depends on, without sending anything to the devices' chats.
+pub async fn run(env: &Env) -> Vec<Check> {
+    let mut checks = vec![
+        Check::new(
+            "config",
+            Config::load(env)
+                .map(|_| ())
+                .map_err(|report| fail(report.to_string())),
+        ),
+        Check::new("kv", check_kv(env).await),
+        Check::new("telegram", check_telegram(env).await),
+        Check::new("config template", check_config_template(env).await),
+        Check::new("formatting", check_formatting()),
+    ];
+    for database in DATABASES {
+        if let Ok(db) = env.d1(database) {
+            let result = db.prepare("SELECT 1").run().await.map(|_| ());
+            checks.push(Check::new(
+                &format!("d1 {database}"),
+                result.map_err(Error::from),
+            ));
+        }
+    }
+    checks
+}
+
+fn fail(problem: String) -> Error {
+    Error::Worker(worker::Error::RustError(problem))
+}
+
+async fn check_kv(env: &Env) -> Result<()> {
+    let kv = kv_store(env)?;
+    let value = crate::random_uuid();
+    kv.put("selftest", &value)?
+        .expiration_ttl(60)
+        .execute()
+        .await?;
+    let read = kv.get("selftest").text().await?;
+    kv.delete("selftest").await?;
+    if read.as_deref() != Some(value.as_str()) {
+        return Err(fail("read back a different value".to_owned()));
+    }
+    Ok(())
+}
+
+async fn check_telegram(env: &Env) -> Result<()> {
+    let response = TelegramClient::new(env)?.get_me().await?;
+    match response.result() {
+        Some(bot) if bot.username.is_some() => Ok(()),
+        _ => Err(Error::Telegram(response.to_string())),
+    }
+}
+
+async fn check_config_template(env: &Env) -> Result<()> {
+    let url = crate::get_secret(env, "config_template_url")?;
+    let response = Fetch::Request(Request::new(&url, Method::Get)?)
+        .send()
+        .await?;
+    match response.status_code() {
+        200..300 => Ok(()),
+        status => Err(fail(format!("status {status}"))),
+    }
+}
+
+fn check_formatting() -> Result<()> {
+    if format_date(86_400_000) != "1970-01-02" {
+        return Err(fail("dates are off".to_owned()));
+    }
+    if !highlight_codes("code 114514.").contains("<code>114514</code>") {
+        return Err(fail("codes are not highlighted".to_owned()));
+    }
+    Ok(())
+}
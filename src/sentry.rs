@@ -0,0 +1,99 @@
+use std::{panic, sync::OnceLock};
+
+use serde_json::{Map, Value, json};
+use worker::{
+    Env,
+    wasm_bindgen::{self, prelude::*},
+};
+
+use crate::{log, random_uuid};
+
+static DSN: OnceLock<Option<Dsn>> = OnceLock::new();
+
+#[derive(Debug)]
+struct Dsn {
+    key: String,
+    store_url: String,
+}
+
+impl Dsn {
+    /// Parses `https://{key}@{host}/{project}`.
+    fn parse(dsn: &str) -> Option<Self> {
+        let (scheme, rest) = dsn.split_once("://")?;
+        let (key, rest) = rest.split_once('@')?;
+        let (host, project) = rest.trim_end_matches('/').rsplit_once('/')?;
+        Some(Self {
+            key: key.split(':').next()?.to_owned(),
+            store_url: format!("{scheme}://{host}/api/{project}/store/"),
+        })
+    }
+}
+
+#[wasm_bindgen]
+extern "C" {
+    // the global fetch, so that a report can leave even from the panic hook
+    #[wasm_bindgen(js_name = fetch)]
+    fn global_fetch(url: &str, init: &JsValue) -> js_sys::Promise;
+}
+
+/// Reads the optional `sentry_dsn` secret once per isolate.
+pub fn init(env: &Env) {
+    DSN.get_or_init(|| {
+        let dsn = env.secret("sentry_dsn").ok()?.to_string();
+        let parsed = Dsn::parse(&dsn);
+        if parsed.is_none() {
+            log::error!("sentry", outcome = "invalid_dsn");
+        }
+        parsed
+    });
+}
+
+/// Reports panics to Sentry on top of logging them to the console.
+pub fn set_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        console_error_panic_hook::hook(info);
+        capture("fatal", &info.to_string(), &Map::new());
+    }));
+}
+
+/// Sends an event with the log fields as tags and extra data. The request is
+/// not awaited, so delivery is best effort.
+pub fn capture(level: &str, message: &str, fields: &Map<String, Value>) {
+    let Some(Some(dsn)) = DSN.get() else {
+        return;
+    };
+    let mut tags = Map::new();
+    for key in ["device", "request_id", "event"] {
+        if let Some(value) = fields.get(key) {
+            tags.insert(key.to_owned(), value.clone());
+        }
+    }
+    if let Some(id) = log::current() {
+        tags.insert("request_id".to_owned(), id.into());
+    }
+    let event = json!({
+        "event_id": random_uuid().replace('-', ""),
+        "timestamp": js_sys::Date::now() / 1000.0,
+        "platform": "other",
+        "level": level,
+        "logger": "sms-fwd-workers",
+        "release": env!("CARGO_PKG_VERSION"),
+        "message": { "formatted": message },
+        "tags": tags,
+        "extra": fields,
+    });
+    let init = json!({
+        "method": "POST",
+        "headers": {
+            "Content-Type": "application/json",
+            "X-Sentry-Auth": format!(
+                "Sentry sentry_version=7, sentry_client=sms-fwd-workers/{}, sentry_key={}",
+                env!("CARGO_PKG_VERSION"),
+                dsn.key
+            ),
+        },
+        "body": event.to_string(),
+    });
+    let init = js_sys::JSON::parse(&init.to_string()).unwrap();
+    let _ = global_fetch(&dsn.store_url, &init);
+}
@@ -0,0 +1,72 @@
+use futures_channel::mpsc::{UnboundedSender, unbounded};
+use futures_util::StreamExt;
+use serde::Serialize;
+use worker::*;
+
+use crate::{log, to_json};
+
+/// Fans events out to the `GET /api/stream` clients of one tenant, the
+/// deployment's own under the empty name.
+#[durable_object]
+pub struct EventStream {
+    clients: Vec<UnboundedSender<String>>,
+}
+
+#[durable_object]
+impl DurableObject for EventStream {
+    fn new(state: State, _env: Env) -> Self {
+        // nothing is stored, the clients only live as long as the object
+        drop(state);
+        Self {
+            clients: Vec::new(),
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        if req.method() == Method::Post {
+            let event = req.text().await?;
+            // clients which went away have dropped their receiver
+            self.clients
+                .retain(|client| client.unbounded_send(event.clone()).is_ok());
+            return Response::empty();
+        }
+        let (sender, receiver) = unbounded();
+        // a comment, so that clients see the stream open right away
+        sender.unbounded_send(": connected\n\n".to_owned()).ok();
+        self.clients.push(sender);
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "text/event-stream")?;
+        headers.set("Cache-Control", "no-cache")?;
+        Ok(Response::from_stream(receiver.map(Ok::<_, Error>))?.with_headers(headers))
+    }
+}
+
+/// Stub of the current tenant's stream, `None` without the `stream` binding.
+pub fn stub(env: &Env) -> Option<Stub> {
+    let namespace = env.durable_object("stream").ok()?;
+    let id = namespace
+        .id_from_name(&log::tenant().unwrap_or_default())
+        .ok()?;
+    id.get_stub().ok()
+}
+
+/// Pushes an event to the clients watching the stream, if any.
+pub async fn publish<T: Serialize>(env: &Env, event: &str, data: &T) {
+    let Some(stub) = stub(env) else {
+        return;
+    };
+    let body = format!("event: {event}\ndata: {}\n\n", to_json(data));
+    let request = Request::new_with_init(
+        "https://stream/publish",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(body.into())),
+    );
+    let result = match request {
+        Ok(request) => stub.fetch_with_request(request).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        log::error!("stream", event = event, error = e.to_string());
+    }
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use worker::Env;
+
+use crate::{
+    error::Result,
+    get_optional_secret,
+    telegram::{InlineKeyboardButton, InlineKeyboardMarkup},
+};
+
+/// Text generation model run on the `ai` binding.
+const MODEL: &str = "@cf/meta/llama-3.1-8b-instruct";
+
+/// Length from which a forward can be summarized, unless
+/// `summary_min_chars` says otherwise.
+const MIN_CHARS: usize = 280;
+
+const PROMPT: &str = "Summarize the SMS in one or two short sentences, in the language it is \
+                      written in. Keep amounts, dates, codes and what the reader has to do. \
+                      Answer with the summary only.";
+
+#[derive(Debug, Serialize)]
+struct Prompt<'a> {
+    messages: [Message<'a>; 2],
+    max_tokens: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct Message<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct Output {
+    response: Option<String>,
+}
+
+/// Whether `text` is long enough to summarize, with the `ai` binding there
+/// to do it.
+pub fn is_long(env: &Env, text: &str) -> bool {
+    let min_chars = get_optional_secret(env, "summary_min_chars")
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(MIN_CHARS);
+    text.chars().count() >= min_chars && env.ai("ai").is_ok()
+}
+
+pub fn keyboard() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup {
+        inline_keyboard: vec![vec![InlineKeyboardButton {
+            text: "✨ Summarize".to_owned(),
+            callback_data: "summarize".to_owned(),
+        }]],
+    }
+}
+
+/// One or two sentences on `text`, empty when the model had nothing to say.
+pub async fn summarize(env: &Env, text: &str) -> Result<String> {
+    let prompt = Prompt {
+        messages: [
+            Message {
+                role: "system",
+                content: PROMPT,
+            },
+            Message {
+                role: "user",
+                content: text,
+            },
+        ],
+        max_tokens: 120,
+    };
+    let output: Output = env.ai("ai")?.run(MODEL, prompt).await?;
+    Ok(output.response.unwrap_or_default().trim().to_owned())
+}
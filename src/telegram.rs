@@ -0,0 +1,613 @@
+use std::{collections::BTreeMap, fmt::Display, sync::Mutex};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use worker::Env;
+
+use crate::{
+    Message,
+    domain::{FetchHttp, Http},
+    error::{Error, Result},
+    get_bot_token, get_optional_secret, is_dry_run, log, timestamp_ms, to_json, with_retry,
+};
+
+const TELEGRAM_ATTEMPTS: u32 = 3;
+
+/// How long calls stay with `bot_token_fallback` before the primary bot is
+/// tried again.
+pub const FAILOVER_SECONDS: i64 = 3600;
+
+/// Failed calls in a row after which the primary bot is given up on.
+const FAILOVER_THRESHOLD: u32 = 5;
+
+/// Failover state of this isolate by tenant, the deployment's own bot under
+/// the empty key.
+static FAILOVER: Mutex<BTreeMap<String, Failover>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Default)]
+struct Failover {
+    until: i64,
+    primary_failures: u32,
+}
+
+fn with_failover<R>(f: impl FnOnce(&mut Failover) -> R) -> R {
+    f(FAILOVER
+        .lock()
+        .unwrap()
+        .entry(log::tenant().unwrap_or_default())
+        .or_default())
+}
+
+/// Bot API client authenticated with the `bot_token` secret.
+pub struct TelegramClient<H = FetchHttp> {
+    token: String,
+    http: H,
+    /// Only log the calls which would change anything, see `dry_run`.
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    #[serde(default)]
+    error_code: Option<i64>,
+    #[serde(default)]
+    description: Option<String>,
+}
+
+pub type MessageResponse = ApiResponse<Message>;
+
+impl<T> ApiResponse<T> {
+    pub fn ok(&self) -> bool {
+        self.ok
+    }
+
+    pub fn result(&self) -> Option<&T> {
+        self.result.as_ref()
+    }
+
+    pub fn error_code(&self) -> Option<i64> {
+        self.error_code
+    }
+}
+
+impl MessageResponse {
+    pub fn message_id(&self) -> Option<i64> {
+        Some(self.result()?.message_id)
+    }
+}
+
+impl<T> Display for ApiResponse<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.ok, &self.description) {
+            (true, _) => write!(f, "ok"),
+            (false, Some(description)) => write!(f, "failed: {description}"),
+            (false, None) => write!(f, "failed"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BotUser {
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GetMeBody {}
+
+#[derive(Debug, Serialize)]
+pub struct InlineKeyboardButton {
+    pub text: String,
+    pub callback_data: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InlineKeyboardMarkup {
+    pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SendMessageBody<'a> {
+    pub chat_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_thread_id: Option<i64>,
+    pub text: &'a str,
+    pub parse_mode: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_preview_options: Option<LinkPreviewOptions>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub disable_notification: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkPreviewOptions {
+    pub is_disabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SendStickerBody<'a> {
+    pub chat_id: &'a str,
+    pub sticker: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SendLocationBody<'a> {
+    pub chat_id: &'a str,
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub horizontal_accuracy: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub live_period: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EditMessageLiveLocationBody<'a> {
+    pub chat_id: &'a str,
+    pub message_id: i64,
+    pub latitude: f64,
+    pub longitude: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub horizontal_accuracy: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EditMessageTextBody<'a> {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub text: &'a str,
+    pub parse_mode: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+/// A document uploaded as `multipart/form-data`, with a plain caption.
+#[derive(Debug)]
+pub struct UploadDocument<'a> {
+    pub chat_id: &'a str,
+    pub message_thread_id: Option<i64>,
+    pub file_name: &'a str,
+    pub content: &'a [u8],
+    pub caption: Option<&'a str>,
+    pub disable_notification: bool,
+}
+
+impl UploadDocument<'_> {
+    /// The content type and body of the form, the boundary being random so
+    /// that it cannot occur in the content.
+    fn form(&self) -> (String, Vec<u8>) {
+        let boundary = format!("----sms-fwd-{}", crate::random_uuid());
+        let mut body = Vec::new();
+        let mut field = |name: &str, file_name: Option<&str>, value: &[u8]| {
+            body.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+            let disposition = match file_name {
+                Some(file_name) => format!(
+                    "Content-Disposition: form-data; name=\"{name}\"; filename=\"{}\"\r\n\
+                     Content-Type: application/octet-stream\r\n\r\n",
+                    file_name.replace(['"', '\r', '\n'], "_")
+                ),
+                None => format!("Content-Disposition: form-data; name=\"{name}\"\r\n\r\n"),
+            };
+            body.extend_from_slice(disposition.as_bytes());
+            body.extend_from_slice(value);
+            body.extend_from_slice(b"\r\n");
+        };
+        field("chat_id", None, self.chat_id.as_bytes());
+        if let Some(thread) = self.message_thread_id {
+            field("message_thread_id", None, thread.to_string().as_bytes());
+        }
+        if let Some(caption) = self.caption {
+            field("caption", None, caption.as_bytes());
+        }
+        if self.disable_notification {
+            field("disable_notification", None, b"true");
+        }
+        field("document", Some(self.file_name), self.content);
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+        (format!("multipart/form-data; boundary={boundary}"), body)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputMedia<'a> {
+    /// `photo`, `video`, `audio` or `document`.
+    #[serde(rename = "type")]
+    pub kind: &'a str,
+    pub media: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SendMediaGroupBody<'a> {
+    pub chat_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_thread_id: Option<i64>,
+    pub media: Vec<InputMedia<'a>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub disable_notification: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputTextMessageContent {
+    pub message_text: String,
+    pub parse_mode: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InlineQueryResultArticle {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub input_message_content: InputTextMessageContent,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnswerInlineQueryBody<'a> {
+    pub inline_query_id: &'a str,
+    pub results: Vec<InlineQueryResultArticle>,
+    pub cache_time: u32,
+    pub is_personal: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReactionTypeEmoji<'a> {
+    #[serde(rename = "type")]
+    pub kind: &'a str,
+    pub emoji: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SetMessageReactionBody<'a> {
+    pub chat_id: &'a str,
+    pub message_id: i64,
+    pub reaction: [ReactionTypeEmoji<'a>; 1],
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateForumTopicBody<'a> {
+    pub chat_id: &'a str,
+    pub name: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForumTopic {
+    pub message_thread_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteMessageBody<'a> {
+    pub chat_id: &'a str,
+    pub message_id: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PinChatMessageBody<'a> {
+    pub chat_id: &'a str,
+    pub message_id: i64,
+    pub disable_notification: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnswerCallbackQueryBody<'a> {
+    pub callback_query_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<&'a str>,
+}
+
+impl TelegramClient {
+    /// A client for the fallback bot while failed over, the primary otherwise.
+    pub fn new(env: &Env) -> Result<Self> {
+        let token = match get_optional_secret(env, "bot_token_fallback") {
+            Some(token) if failed_over() => token,
+            _ => get_bot_token(env)?,
+        };
+        Ok(Self {
+            dry_run: is_dry_run(env),
+            ..Self::with_http(token, FetchHttp)
+        })
+    }
+}
+
+/// A successful response as far as the caller can tell, the result being a
+/// message with id 0, a list of them, `true` or nothing, whichever `T` is.
+fn dry_run_response<T: DeserializeOwned>() -> ApiResponse<T> {
+    let message = serde_json::json!({ "message_id": 0, "chat": { "id": 0 } });
+    [
+        message.clone(),
+        serde_json::json!([message]),
+        serde_json::json!(true),
+    ]
+    .into_iter()
+    .find_map(|result| {
+        serde_json::from_value(serde_json::json!({ "ok": true, "result": result })).ok()
+    })
+    .unwrap_or(ApiResponse {
+        ok: true,
+        result: None,
+        error_code: None,
+        description: None,
+    })
+}
+
+pub fn failed_over() -> bool {
+    with_failover(|failover| failover.until > timestamp_ms())
+}
+
+/// Counts a call made with the primary bot, returning whether to fail over
+/// to `bot_token_fallback`. A revoked token fails over right away, other
+/// failures once they keep happening.
+pub fn should_fail_over<T>(env: &Env, result: &Result<ApiResponse<T>>) -> bool {
+    if failed_over() || get_optional_secret(env, "bot_token_fallback").is_none() {
+        return false;
+    }
+    let code = match result {
+        Ok(response) if response.ok() => {
+            with_failover(|failover| failover.primary_failures = 0);
+            return false;
+        }
+        Ok(response) => response.error_code(),
+        Err(_) => None,
+    };
+    match code {
+        Some(401 | 404) => true,
+        Some(403) | None => with_failover(|failover| {
+            failover.primary_failures += 1;
+            failover.primary_failures >= FAILOVER_THRESHOLD
+        }),
+        Some(_) => false,
+    }
+}
+
+pub fn fail_over() {
+    with_failover(|failover| {
+        failover.primary_failures = 0;
+        failover.until = timestamp_ms() + FAILOVER_SECONDS * 1000;
+    });
+}
+
+impl<H: Http> TelegramClient<H> {
+    pub fn with_http(token: String, http: H) -> Self {
+        Self {
+            token,
+            http,
+            dry_run: false,
+        }
+    }
+
+    /// Calls `method`, retrying on network errors, flood limits and server
+    /// errors. Other API errors are returned as a response which is not ok.
+    pub async fn call<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: &B,
+    ) -> Result<ApiResponse<T>> {
+        self.post(method, "application/json", to_json(body).as_bytes())
+            .await
+    }
+
+    async fn post<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        content_type: &str,
+        body: &[u8],
+    ) -> Result<ApiResponse<T>> {
+        if self.dry_run && method != "getMe" {
+            log::info!(
+                "dry_run",
+                target = "telegram",
+                method = method,
+                bytes = body.len()
+            );
+            return Ok(dry_run_response());
+        }
+        let url = format!("https://api.telegram.org/bot{}/{method}", self.token);
+        with_retry(TELEGRAM_ATTEMPTS, is_transient_telegram_error, || async {
+            let (status, text) = self.http.post(&url, content_type, body).await?;
+            let response: ApiResponse<T> = serde_json::from_str(&text)
+                .map_err(|_| Error::Telegram(format!("invalid response with status {status}")))?;
+            match response.error_code {
+                Some(code) if code == 429 || code >= 500 => Err(Error::Telegram(format!(
+                    "{code} {}",
+                    response.description.unwrap_or_default()
+                ))),
+                _ => Ok(response),
+            }
+        })
+        .await
+    }
+
+    pub async fn get_me(&self) -> Result<ApiResponse<BotUser>> {
+        self.call("getMe", &GetMeBody {}).await
+    }
+
+    pub async fn send_message(&self, body: &SendMessageBody<'_>) -> Result<MessageResponse> {
+        self.call("sendMessage", body).await
+    }
+
+    pub async fn send_sticker(&self, body: &SendStickerBody<'_>) -> Result<MessageResponse> {
+        self.call("sendSticker", body).await
+    }
+
+    pub async fn send_location(&self, body: &SendLocationBody<'_>) -> Result<MessageResponse> {
+        self.call("sendLocation", body).await
+    }
+
+    pub async fn edit_message_live_location(
+        &self,
+        body: &EditMessageLiveLocationBody<'_>,
+    ) -> Result<MessageResponse> {
+        self.call("editMessageLiveLocation", body).await
+    }
+
+    pub async fn edit_message_text(
+        &self,
+        body: &EditMessageTextBody<'_>,
+    ) -> Result<MessageResponse> {
+        self.call("editMessageText", body).await
+    }
+
+    pub async fn send_media_group(
+        &self,
+        body: &SendMediaGroupBody<'_>,
+    ) -> Result<ApiResponse<Vec<Message>>> {
+        self.call("sendMediaGroup", body).await
+    }
+
+    pub async fn upload_document(&self, upload: &UploadDocument<'_>) -> Result<MessageResponse> {
+        let (content_type, body) = upload.form();
+        self.post("sendDocument", &content_type, &body).await
+    }
+
+    pub async fn set_message_reaction(
+        &self,
+        body: &SetMessageReactionBody<'_>,
+    ) -> Result<ApiResponse<bool>> {
+        self.call("setMessageReaction", body).await
+    }
+
+    pub async fn create_forum_topic(
+        &self,
+        body: &CreateForumTopicBody<'_>,
+    ) -> Result<ApiResponse<ForumTopic>> {
+        self.call("createForumTopic", body).await
+    }
+
+    pub async fn delete_message(&self, body: &DeleteMessageBody<'_>) -> Result<ApiResponse<bool>> {
+        self.call("deleteMessage", body).await
+    }
+
+    pub async fn pin_chat_message(
+        &self,
+        body: &PinChatMessageBody<'_>,
+    ) -> Result<ApiResponse<bool>> {
+        self.call("pinChatMessage", body).await
+    }
+
+    pub async fn answer_inline_query(
+        &self,
+        body: &AnswerInlineQueryBody<'_>,
+    ) -> Result<ApiResponse<bool>> {
+        self.call("answerInlineQuery", body).await
+    }
+
+    pub async fn answer_callback_query(
+        &self,
+        body: &AnswerCallbackQueryBody<'_>,
+    ) -> Result<ApiResponse<bool>> {
+        self.call("answerCallbackQuery", body).await
+    }
+}
+
+fn is_transient_telegram_error(e: &Error) -> bool {
+    matches!(e, Error::Worker(_) | Error::Telegram(_))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque};
+
+    use super::*;
+    use crate::domain::block_on;
+
+    const OK: &str = r#"{"ok":true,"result":{"message_id":42,"from":{"id":1,"is_bot":true,"first_name":"sms","username":"sms_bot"},"chat":{"id":-100123,"title":"SMS","type":"supergroup"},"date":1760000000,"text":"hello"}}"#;
+    const FLOOD: &str = r#"{"ok":false,"error_code":429,"description":"Too Many Requests: retry after 3","parameters":{"retry_after":3}}"#;
+    const BAD_GATEWAY: &str = r#"{"ok":false,"error_code":502,"description":"Bad Gateway"}"#;
+    const BAD_REQUEST: &str = r#"{"ok":false,"error_code":400,"description":"Bad Request: can't parse entities: Unsupported start tag \"x\" at byte offset 0"}"#;
+
+    /// Answers with recorded responses in turn, keeping the urls it was
+    /// called with.
+    struct FakeHttp {
+        responses: RefCell<VecDeque<(u16, &'static str)>>,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl FakeHttp {
+        fn new(responses: &[(u16, &'static str)]) -> Self {
+            Self {
+                responses: RefCell::new(responses.iter().copied().collect()),
+                calls: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Http for &FakeHttp {
+        async fn post(&self, url: &str, _: &str, _: &[u8]) -> Result<(u16, String)> {
+            self.calls.borrow_mut().push(url.to_owned());
+            let (status, text) = self
+                .responses
+                .borrow_mut()
+                .pop_front()
+                .expect("no response");
+            Ok((status, text.to_owned()))
+        }
+    }
+
+    fn send(http: &FakeHttp) -> Result<MessageResponse> {
+        let client = TelegramClient::with_http("123:abc".to_owned(), http);
+        block_on(client.send_message(&SendMessageBody {
+            chat_id: "-100123",
+            message_thread_id: None,
+            text: "hello",
+            parse_mode: "HTML",
+            link_preview_options: None,
+            disable_notification: false,
+            reply_markup: None,
+        }))
+    }
+
+    #[test]
+    fn ok_response() {
+        let http = FakeHttp::new(&[(200, OK)]);
+        let response = send(&http).unwrap();
+        assert!(response.ok());
+        assert_eq!(response.message_id(), Some(42));
+        assert_eq!(
+            *http.calls.borrow(),
+            ["https://api.telegram.org/bot123:abc/sendMessage"]
+        );
+    }
+
+    #[test]
+    fn flood_limit_is_retried() {
+        let http = FakeHttp::new(&[(429, FLOOD), (200, OK)]);
+        assert_eq!(send(&http).unwrap().message_id(), Some(42));
+        assert_eq!(http.calls.borrow().len(), 2);
+    }
+
+    #[test]
+    fn server_error_is_retried() {
+        let http = FakeHttp::new(&[(502, BAD_GATEWAY), (502, BAD_GATEWAY), (200, OK)]);
+        assert_eq!(send(&http).unwrap().message_id(), Some(42));
+        assert_eq!(http.calls.borrow().len(), 3);
+    }
+
+    #[test]
+    fn server_error_gives_up() {
+        let http = FakeHttp::new(&[(502, BAD_GATEWAY); TELEGRAM_ATTEMPTS as usize]);
+        assert!(matches!(send(&http), Err(Error::Telegram(_))));
+        assert_eq!(http.calls.borrow().len(), TELEGRAM_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn bad_request_is_not_retried() {
+        let http = FakeHttp::new(&[(400, BAD_REQUEST)]);
+        let response = send(&http).unwrap();
+        assert!(!response.ok());
+        assert_eq!(response.error_code(), Some(400));
+        assert_eq!(http.calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn invalid_response_is_retried() {
+        let http = FakeHttp::new(&[(502, "<html>Bad Gateway</html>"), (200, OK)]);
+        assert_eq!(send(&http).unwrap().message_id(), Some(42));
+    }
+}
@@ -0,0 +1,132 @@
+//! Just enough YAML for a list of flat maps under one key, as written by
+//! `write_list`. Every JSON document is accepted too, being valid YAML.
+
+use std::collections::BTreeMap;
+
+use serde_json::{Map, Value};
+
+/// A map of the list, `None` for `null` or `~`.
+pub type Fields = BTreeMap<String, Option<String>>;
+
+/// Double-quoted, so that no value is mistaken for another type.
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
+
+pub fn write_list(comment: &str, key: &str, items: &[Vec<(&str, Option<&str>)>]) -> String {
+    let mut doc = format!("# {comment}\n");
+    if items.is_empty() {
+        doc.push_str(&format!("{key}: []\n"));
+        return doc;
+    }
+    doc.push_str(&format!("{key}:\n"));
+    for item in items {
+        let mut fields = item
+            .iter()
+            .filter_map(|(name, value)| Some((name, (*value)?)))
+            .peekable();
+        let mut prefix = "  - ";
+        if fields.peek().is_none() {
+            doc.push_str("  - {}\n");
+        }
+        for (name, value) in fields {
+            doc.push_str(&format!("{prefix}{name}: {}\n", quote(value)));
+            prefix = "    ";
+        }
+    }
+    doc
+}
+
+fn scalar(raw: &str, line: usize) -> Result<Option<String>, String> {
+    let invalid = || format!("line {line}: invalid value");
+    let raw = raw.trim();
+    if raw.starts_with('"') {
+        return serde_json::from_str(raw).map(Some).map_err(|_| invalid());
+    }
+    if let Some(inner) = raw.strip_prefix('\'') {
+        let inner = inner.strip_suffix('\'').ok_or_else(invalid)?;
+        return Ok(Some(inner.replace("''", "'")));
+    }
+    // a plain scalar ends at a comment
+    let raw = raw.split(" #").next().unwrap_or_default().trim();
+    Ok(match raw {
+        "" | "~" | "null" => None,
+        raw => Some(raw.to_owned()),
+    })
+}
+
+fn json_scalar(value: Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        value => Some(value.to_string()),
+    }
+}
+
+fn parse_json(doc: &str, key: &str) -> Result<Vec<Fields>, String> {
+    let mut root: Map<String, Value> =
+        serde_json::from_str(doc).map_err(|e| format!("invalid document: {e}"))?;
+    let Some(Value::Array(items)) = root.remove(key) else {
+        return Err(format!("{key} not found"));
+    };
+    items
+        .into_iter()
+        .map(|item| match item {
+            Value::Object(map) => Ok(map.into_iter().map(|(k, v)| (k, json_scalar(v))).collect()),
+            _ => Err(format!("{key} must be a list of maps")),
+        })
+        .collect()
+}
+
+/// Reads the list under `key`, telling the line of the first problem.
+pub fn parse_list(doc: &str, key: &str) -> Result<Vec<Fields>, String> {
+    if doc.trim_start().starts_with('{') {
+        return parse_json(doc, key);
+    }
+    let mut items: Vec<Fields> = Vec::new();
+    // whether the lines are under `key`, and whether it was seen at all
+    let mut found = false;
+    let mut seen = false;
+    for (index, line) in doc.lines().enumerate() {
+        let number = index + 1;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed == "---" {
+            continue;
+        }
+        if !line.starts_with([' ', '-']) {
+            let (name, rest) = trimmed
+                .split_once(':')
+                .ok_or_else(|| format!("line {number}: expected {key}:"))?;
+            found = name.trim() == key;
+            seen |= found;
+            if found && scalar(rest, number)?.is_some_and(|rest| rest != "[]") {
+                return Err(format!("line {number}: {key} must be a list"));
+            }
+            continue;
+        }
+        if !found {
+            continue;
+        }
+        let field = match trimmed.strip_prefix('-') {
+            Some(rest) => {
+                items.push(Fields::new());
+                match rest.trim() {
+                    "" | "{}" => continue,
+                    rest => rest,
+                }
+            }
+            None => trimmed,
+        };
+        let item = items
+            .last_mut()
+            .ok_or_else(|| format!("line {number}: expected -"))?;
+        let (name, value) = field
+            .split_once(':')
+            .ok_or_else(|| format!("line {number}: expected name: value"))?;
+        item.insert(name.trim().to_owned(), scalar(value, number)?);
+    }
+    if !seen {
+        return Err(format!("{key} not found"));
+    }
+    Ok(items)
+}